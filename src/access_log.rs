@@ -0,0 +1,320 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Result as IOResult, Error as IOError, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use backend::BackendId;
+use config::AccessLogConfig;
+
+const DEFAULT_FORMAT: &str = "conn={conn} client={client} backend={backend} bytes_up={bytes_up} \
+                              bytes_down={bytes_down} duration_ms={duration_ms}";
+/// Lines in a file have no logger to stamp them, so they get a time of
+/// their own.
+const DEFAULT_FILE_FORMAT: &str = "time={time} conn={conn} client={client} backend={backend} \
+                                   bytes_up={bytes_up} bytes_down={bytes_down} \
+                                   duration_ms={duration_ms}";
+const DEFAULT_ROTATE_KEEP: usize = 5;
+
+/// What gets logged about a connection when it closes.
+pub struct AccessLogEntry {
+    pub time: SystemTime,
+    pub conn: usize,
+    pub client: SocketAddr,
+    pub backend: BackendId,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Time,
+    Conn,
+    Client,
+    Backend,
+    BytesUp,
+    BytesDown,
+    DurationMs,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "time" => Some(Field::Time),
+            "conn" => Some(Field::Conn),
+            "client" => Some(Field::Client),
+            "backend" => Some(Field::Backend),
+            "bytes_up" => Some(Field::BytesUp),
+            "bytes_down" => Some(Field::BytesDown),
+            "duration_ms" => Some(Field::DurationMs),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Field(Field),
+}
+
+/// An access log line template, with fields such as `{client}` filled in
+/// for each connection. `{time}` is seconds since the Unix epoch, to the
+/// millisecond.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogFormat {
+    segments: Vec<Segment>,
+}
+
+impl Default for AccessLogFormat {
+    fn default() -> AccessLogFormat {
+        AccessLogFormat::parse(DEFAULT_FORMAT).unwrap()
+    }
+}
+
+impl AccessLogFormat {
+    pub fn parse(template: &str) -> IOResult<AccessLogFormat> {
+        let invalid = |reason: &str| {
+            IOError::new(ErrorKind::InvalidInput,
+                         format!("Invalid access log format {:?}: {}", template, reason))
+        };
+        let mut segments = Vec::new();
+        let mut rest = template;
+
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                segments.push(Segment::Text(rest[..open].to_owned()));
+            }
+            let close = match rest[open..].find('}') {
+                Some(close) => open + close,
+                None => return Err(invalid("unclosed {")),
+            };
+            let name = &rest[open + 1..close];
+            match Field::from_name(name) {
+                Some(field) => segments.push(Segment::Field(field)),
+                None => return Err(invalid(&format!("no field named {:?}", name))),
+            }
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_owned()));
+        }
+
+        Ok(AccessLogFormat { segments: segments })
+    }
+
+    pub fn render(&self, entry: &AccessLogEntry) -> String {
+        let mut line = String::new();
+
+        for segment in &self.segments {
+            match *segment {
+                Segment::Text(ref text) => line.push_str(text),
+                Segment::Field(field) => line.push_str(&field_value(field, entry)),
+            }
+        }
+        line
+    }
+}
+
+fn field_value(field: Field, entry: &AccessLogEntry) -> String {
+    match field {
+        Field::Time => {
+            let since_epoch = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            format!("{}.{:03}", since_epoch.as_secs(), since_epoch.subsec_millis())
+        }
+        Field::Conn => entry.conn.to_string(),
+        Field::Client => entry.client.to_string(),
+        Field::Backend => entry.backend.to_string(),
+        Field::BytesUp => entry.bytes_up.to_string(),
+        Field::BytesDown => entry.bytes_down.to_string(),
+        Field::DurationMs => entry.duration_ms.to_string(),
+    }
+}
+
+/// Where access log lines go: by default the `access` target of the
+/// logger, or a file of their own set up in an `[access_log]` section.
+/// Lines for a file are handed to a writer thread, so a slow disk never
+/// stalls relaying.
+#[derive(Default)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    lines: Option<Sender<String>>,
+}
+
+impl AccessLog {
+    /// Opens the file up front, so a path that can't be written to fails
+    /// the configuration rather than every line.
+    pub fn from_config(config: &AccessLogConfig) -> IOResult<AccessLog> {
+        let format = try!(AccessLogFormat::parse(config.format
+                                                     .as_ref()
+                                                     .map_or(DEFAULT_FILE_FORMAT, |f| &f[..])));
+        let mut file = try!(LogFile::open(Path::new(&config.path),
+                                          config.rotate_bytes,
+                                          config.rotate_keep.unwrap_or(DEFAULT_ROTATE_KEEP)));
+        let (sender, lines) = mpsc::channel();
+
+        thread::spawn(move || write_lines(&mut file, &lines));
+
+        Ok(AccessLog {
+               format: format,
+               lines: Some(sender),
+           })
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let line = self.format.render(entry);
+
+        match self.lines {
+            Some(ref lines) => {
+                if lines.send(line).is_err() {
+                    error!("Access log writer thread is gone, dropping a line");
+                }
+            }
+            None => info!(target: "access", "{}", line),
+        }
+    }
+}
+
+/// Writes lines as they come, flushing whenever it runs out of them. It
+/// exits, after writing what is left, once the access log is dropped.
+fn write_lines(file: &mut LogFile, lines: &Receiver<String>) {
+    while let Ok(line) = lines.recv() {
+        let mut next = Some(line);
+
+        while let Some(line) = next {
+            if let Err(e) = file.write_line(&line) {
+                error!("Could not write to access log {}: {}", file.path.display(), e);
+            }
+            next = match lines.try_recv() {
+                Ok(line) => Some(line),
+                Err(TryRecvError::Empty) |
+                Err(TryRecvError::Disconnected) => None,
+            };
+        }
+        if let Err(e) = file.writer.flush() {
+            error!("Could not write to access log {}: {}", file.path.display(), e);
+        }
+    }
+}
+
+/// An access log file that, once it has `rotate_bytes` in it, is moved to
+/// `<path>.1` for a fresh one, keeping `rotate_keep` of the older files.
+struct LogFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    len: u64,
+    rotate_bytes: Option<u64>,
+    rotate_keep: usize,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotate_bytes: Option<u64>, rotate_keep: usize) -> IOResult<LogFile> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        let len = try!(file.metadata()).len();
+
+        Ok(LogFile {
+               path: path.to_owned(),
+               writer: BufWriter::new(file),
+               len: len,
+               rotate_bytes: rotate_bytes,
+               rotate_keep: rotate_keep,
+           })
+    }
+
+    fn write_line(&mut self, line: &str) -> IOResult<()> {
+        let line_len = line.len() as u64 + 1;
+        if let Some(rotate_bytes) = self.rotate_bytes {
+            if self.len > 0 && self.len + line_len > rotate_bytes {
+                try!(self.rotate());
+            }
+        }
+
+        try!(writeln!(self.writer, "{}", line));
+        self.len += line_len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> IOResult<()> {
+        try!(self.writer.flush());
+
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.rotate_keep == 0 {
+            try!(fs::remove_file(&self.path));
+        } else {
+            for n in (1..self.rotate_keep).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                    result => try!(result),
+                }
+            }
+            try!(fs::rename(&self.path, rotated(1)));
+        }
+
+        *self = try!(LogFile::open(&self.path, self.rotate_bytes, self.rotate_keep));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AccessLogEntry, AccessLogFormat, LogFile};
+
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use stream::Address;
+
+    #[test]
+    fn formats_fill_in_their_fields() {
+        let entry = AccessLogEntry {
+            time: UNIX_EPOCH + Duration::from_millis(1500000000042),
+            conn: 7,
+            client: "192.0.2.1:5000".parse().unwrap(),
+            backend: Address::resolve("127.0.0.1:8000").unwrap(),
+            bytes_up: 12,
+            bytes_down: 3400,
+            duration_ms: 250,
+        };
+
+        let format = AccessLogFormat::parse("[{time}] {client} -> {backend} \
+                                             {bytes_up}/{bytes_down} in {duration_ms}ms")
+            .unwrap();
+        assert_eq!(format.render(&entry),
+                   "[1500000000.042] 192.0.2.1:5000 -> 127.0.0.1:8000 12/3400 in 250ms");
+        assert_eq!(AccessLogFormat::parse("conn {conn}").unwrap().render(&entry), "conn 7");
+
+        assert!(AccessLogFormat::parse("{client").is_err());
+        assert!(AccessLogFormat::parse("{user}").is_err());
+    }
+
+    #[test]
+    fn files_are_rotated_once_full() {
+        let path = env::temp_dir().join(format!("lb-test-{}-access.log", process::id()));
+        let rotated = |n| format!("{}.{}", path.display(), n);
+        for n in 1..4 {
+            let _ = fs::remove_file(rotated(n));
+        }
+        let _ = fs::remove_file(&path);
+
+        let mut file = LogFile::open(&path, Some(10), 2).unwrap();
+        for line in &["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+        drop(file);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(rotated(1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(rotated(2)).unwrap(), "second\n");
+        assert!(fs::metadata(rotated(3)).is_err());
+
+        for n in 1..3 {
+            fs::remove_file(rotated(n)).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
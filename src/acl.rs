@@ -0,0 +1,138 @@
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// A range of IP addresses, written `10.0.0.0/8` or `2001:db8::/32`. A
+/// bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    pub fn parse(range: &str) -> IOResult<Cidr> {
+        let invalid = || {
+            IOError::new(ErrorKind::InvalidInput,
+                         format!("{} is not an IP address range", range))
+        };
+        let (addr, prefix_len) = match range.find('/') {
+            Some(slash) => (&range[..slash], Some(&range[slash + 1..])),
+            None => (range, None),
+        };
+
+        let network = try!(addr.parse::<IpAddr>().map_err(|_| invalid()));
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => try!(len.parse::<u32>().map_err(|_| invalid())),
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+
+        Ok(Cidr {
+               network: network,
+               prefix_len: prefix_len,
+           })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, unmap(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = (!0u32).checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = (!0u128).checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Clients of a dual-stack listener show up with IPv4-mapped IPv6
+/// addresses, which should match IPv4 ranges.
+fn unmap(ip: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = ip {
+        if let [0, 0, 0, 0, 0, 0xffff, high, low] = v6.segments() {
+            return IpAddr::V4(Ipv4Addr::from((high as u32) << 16 | low as u32));
+        }
+    }
+    ip
+}
+
+/// Which client addresses a frontend accepts. A client in any `deny`
+/// range is turned away even if it is also in an `allow` range; with no
+/// `allow` ranges, everyone else is let in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn parse(allow: &[String], deny: &[String]) -> IOResult<Acl> {
+        let parse_all = |ranges: &[String]| {
+            ranges.iter().map(|range| Cidr::parse(range)).collect::<IOResult<Vec<_>>>()
+        };
+
+        Ok(Acl {
+               allow: try!(parse_all(allow)),
+               deny: try!(parse_all(deny)),
+           })
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip)) &&
+        (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Acl, Cidr};
+
+    fn acl(allow: &[&str], deny: &[&str]) -> Acl {
+        let owned = |ranges: &[&str]| ranges.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+
+        Acl::parse(&owned(allow), &owned(deny)).unwrap()
+    }
+
+    #[test]
+    fn ranges_contain_their_addresses() {
+        let range = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(range.contains("10.1.255.3".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!range.contains("2001:db8::1".parse().unwrap()));
+
+        let range = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(range.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!range.contains("2001:db9::1".parse().unwrap()));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("192.0.2.1".parse().unwrap()));
+        assert!(Cidr::parse("::/0").unwrap().contains("::1".parse().unwrap()));
+        assert!(Cidr::parse("192.0.2.1").unwrap().contains("192.0.2.1".parse().unwrap()));
+        assert!(!Cidr::parse("192.0.2.1").unwrap().contains("192.0.2.2".parse().unwrap()));
+
+        for invalid in &["10.0.0.0/33", "::/129", "10.0.0.0/", "example.com/8"] {
+            assert!(Cidr::parse(invalid).is_err(), "{} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let both = acl(&["10.0.0.0/8", "2001:db8::/32"], &["10.0.0.0/24"]);
+
+        assert!(both.permits("10.1.2.3".parse().unwrap()));
+        assert!(both.permits("2001:db8::7".parse().unwrap()));
+        assert!(!both.permits("10.0.0.5".parse().unwrap()));
+        assert!(!both.permits("192.168.0.1".parse().unwrap()));
+
+        let deny_only = acl(&[], &["192.168.0.0/16"]);
+        assert!(deny_only.permits("10.0.0.5".parse().unwrap()));
+        assert!(!deny_only.permits("192.168.4.4".parse().unwrap()));
+        assert!(Acl::default().permits("::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::Instant;
+
+use control::Pools;
+use metrics_server::{http_response, request_target};
+
+/// Answers the probes of an orchestrator such as Kubernetes on the admin
+/// listener.
+///
+/// * `GET /healthz` is 200 whenever the event loop gets round to it.
+/// * `GET /ready` is 200 while at least one target of any backend is up,
+///   and 503 when none is, so no clients are sent this way that could
+///   only be turned away.
+pub fn render_response(request: &[u8], pools: &Pools, now: Instant) -> Vec<u8> {
+    let (method, path) = request_target(request);
+
+    let (status, body) = if method != "GET" {
+        ("405 Method Not Allowed", String::new())
+    } else if path == "/healthz" {
+        ("200 OK", "ok\n".to_owned())
+    } else if path == "/ready" {
+        let (up, total) = count_targets(pools, now);
+
+        if up > 0 {
+            ("200 OK", format!("ready, {} of {} targets up\n", up, total))
+        } else {
+            ("503 Service Unavailable", format!("not ready, 0 of {} targets up\n", total))
+        }
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    http_response(status, "text/plain", &body)
+}
+
+fn count_targets(pools: &Pools, now: Instant) -> (usize, usize) {
+    pools.values().fold((0, 0), |(up, total), pool| {
+        let pool = pool.borrow();
+        let backends = pool.backends();
+
+        (up + backends.iter().filter(|b| b.is_available(now)).count(), total + backends.len())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::render_response;
+
+    use std::time::Instant;
+
+    use backend::{Backend, BackendPool};
+    use control::Pools;
+    use selector::RoundRobin;
+    use stream::Address;
+
+    fn respond(request: &[u8], pools: &Pools) -> String {
+        String::from_utf8(render_response(request, pools, Instant::now())).unwrap()
+    }
+
+    #[test]
+    fn is_ready_while_any_target_is_up() {
+        let addr = Address::resolve("127.0.0.1:8000").unwrap();
+        let pool = BackendPool::new(vec![Backend::new(addr.clone(), 1)],
+                                    Box::new(RoundRobin::new()),
+                                    &Default::default());
+        let mut pools = Pools::new();
+        pools.insert("web".to_owned(), pool.clone());
+
+        let ready = respond(b"GET /ready HTTP/1.1\r\n\r\n", &pools);
+        assert!(ready.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(ready.ends_with("\r\n\r\nready, 1 of 1 targets up\n"));
+
+        pool.borrow_mut().remove(&addr);
+        let not_ready = respond(b"GET /ready HTTP/1.1\r\n\r\n", &pools);
+        assert!(not_ready.starts_with("HTTP/1.0 503 Service Unavailable\r\n"));
+
+        let healthy = respond(b"GET /healthz HTTP/1.1\r\n\r\n", &pools);
+        assert!(healthy.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(healthy.ends_with("\r\n\r\nok\n"));
+    }
+
+    #[test]
+    fn rejects_other_paths_and_methods() {
+        let pools = Pools::new();
+
+        assert!(respond(b"GET /metrics HTTP/1.1\r\n\r\n", &pools)
+                    .starts_with("HTTP/1.0 404 Not Found\r\n"));
+        assert!(respond(b"POST /ready HTTP/1.1\r\n\r\n", &pools)
+                    .starts_with("HTTP/1.0 405 Method Not Allowed\r\n"));
+    }
+}
@@ -1,24 +1,734 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::time::{Duration, Instant};
+
+use selector::BackendSelector;
+use config::{BackendConfig, KeepaliveConfig};
+use proxy_protocol::{HeaderFormat, Tlv};
+use stream::{Address, Stream};
+use warm_pool::WarmConnections;
+
+pub type BackendId = Address;
+
+/// How many percent of a target's latency averages the newest sample
+/// makes up.
+const LATENCY_SMOOTHING_PERCENT: u32 = 30;
 
 pub struct Backend {
-    targets: Vec<SocketAddr>,
-    next_target: usize,
+    pub addr: BackendId,
+    pub weight: u32,
+    pub healthy: bool,
+    /// Set by an operator to take the target out of rotation gently: it
+    /// gets no new clients, but the ones relaying to it carry on.
+    pub draining: bool,
+    pub active_connections: usize,
+    /// The host name target this address was resolved from, if any.
+    pub host: Option<String>,
+    /// Whether the target is sent a PROXY protocol header, when the
+    /// backend sends them at all.
+    pub proxy_protocol: bool,
+    /// Moving average of how long connects to the target take. None until
+    /// one has finished.
+    pub connect_time: Option<Duration>,
+    /// Moving average of how long the target takes to send its first byte
+    /// once connected. None until it has sent one.
+    pub first_byte_time: Option<Duration>,
+    /// When the target last came back after failing its health checks or
+    /// being ejected. None if it has been up from the start.
+    pub up_since: Option<Instant>,
+    check_successes: u32,
+    check_failures: u32,
+    recent_failures: VecDeque<Instant>,
+    ejected_until: Option<Instant>,
+}
+
+pub struct BackendPool {
+    name: String,
+    backends: Vec<Backend>,
+    selector: Box<dyn BackendSelector>,
+    config: BackendConfig,
+    source_addr: Option<SocketAddr>,
+    proxy_header_format: Option<HeaderFormat>,
+    warm: Option<WarmConnections>,
+}
+
+/// Reads a `source_addr`, which is an IP address, optionally with the port
+/// to connect from.
+pub fn parse_source_addr(addr: &str) -> IOResult<SocketAddr> {
+    addr.parse::<SocketAddr>()
+        .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+        .map_err(|_| {
+                     IOError::new(ErrorKind::InvalidInput,
+                                  format!("Source address {} is not an IP address", addr))
+                 })
+}
+
+/// Reads which PROXY protocol header, if any, a backend's targets expect.
+/// TLVs only fit in version 2.
+pub fn parse_proxy_header_format(config: &BackendConfig) -> IOResult<Option<HeaderFormat>> {
+    if !config.proxy_protocol.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let names = config.proxy_protocol_tlvs.as_ref().map(|names| &names[..]).unwrap_or(&[]);
+    match config.proxy_protocol_version.unwrap_or(1) {
+        1 if names.is_empty() => Ok(Some(HeaderFormat::V1)),
+        1 => {
+            Err(IOError::new(ErrorKind::InvalidInput,
+                             "PROXY protocol TLVs need proxy_protocol_version = 2"))
+        }
+        2 => {
+            let mut tlvs = Vec::new();
+            for name in names {
+                match Tlv::from_name(name) {
+                    Some(tlv) => tlvs.push(tlv),
+                    None => {
+                        return Err(IOError::new(ErrorKind::InvalidInput,
+                                                format!("Unknown PROXY protocol TLV {}", name)))
+                    }
+                }
+            }
+            Ok(Some(HeaderFormat::V2(tlvs)))
+        }
+        version => {
+            Err(IOError::new(ErrorKind::InvalidInput,
+                             format!("Unknown PROXY protocol version {}", version)))
+        }
+    }
+}
+
+/// Whether the entry `target` of `target_addrs` is one the PROXY protocol
+/// header is sent to. Without `proxy_protocol_targets`, every one is.
+pub fn sends_proxy_protocol(config: &BackendConfig, target: &str) -> bool {
+    config
+        .proxy_protocol_targets
+        .as_ref()
+        .map_or(true, |targets| targets.iter().any(|t| t == target))
+}
+
+/// Checks that `proxy_protocol_targets` only names entries of
+/// `target_addrs`, of a backend that sends the header.
+pub fn check_proxy_protocol_targets(config: &BackendConfig) -> IOResult<()> {
+    let targets = match config.proxy_protocol_targets {
+        Some(ref targets) => targets,
+        None => return Ok(()),
+    };
+
+    if !config.proxy_protocol.unwrap_or(false) {
+        return Err(IOError::new(ErrorKind::InvalidInput,
+                                "proxy_protocol_targets needs proxy_protocol = true"));
+    }
+    match targets.iter().find(|t| !config.target_addrs.contains(t)) {
+        Some(target) => {
+            Err(IOError::new(ErrorKind::InvalidInput,
+                             format!("PROXY protocol target {} is not in target_addrs", target)))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Whether `a` and `b` are TCP addresses of different IP versions.
+fn is_other_family(a: &BackendId, b: &BackendId) -> bool {
+    match (a, b) {
+        (&Address::Tcp(a), &Address::Tcp(b)) => a.is_ipv4() != b.is_ipv4(),
+        _ => false,
+    }
+}
+
+fn is_local_error(e: &IOError) -> bool {
+    matches!(e.kind(),
+             ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable | ErrorKind::InvalidInput)
 }
 
 impl Backend {
-    pub fn new(targets: Vec<SocketAddr>) -> Rc<RefCell<Backend>> {
-        Rc::new(RefCell::new(Backend {
-                                 targets: targets,
-                                 next_target: 0,
+    pub fn new(addr: BackendId, weight: u32) -> Backend {
+        Backend {
+            addr: addr,
+            weight: weight,
+            healthy: true,
+            draining: false,
+            active_connections: 0,
+            host: None,
+            proxy_protocol: true,
+            connect_time: None,
+            first_byte_time: None,
+            up_since: None,
+            check_successes: 0,
+            check_failures: 0,
+            recent_failures: VecDeque::new(),
+            ejected_until: None,
+        }
+    }
+
+    /// Whether the target can be given new clients.
+    pub fn is_available(&self, now: Instant) -> bool {
+        self.healthy && !self.draining && self.ejected_until.map_or(true, |until| until <= now)
+    }
+
+    /// How long the target has recently taken to connect and answer. Parts
+    /// without a sample yet count as instant, so new targets get tried.
+    pub fn response_time(&self) -> Duration {
+        self.connect_time.unwrap_or_default() + self.first_byte_time.unwrap_or_default()
+    }
+}
+
+fn smooth(average: Option<Duration>, sample: Duration) -> Duration {
+    match average {
+        Some(average) => {
+            (average * (100 - LATENCY_SMOOTHING_PERCENT) + sample * LATENCY_SMOOTHING_PERCENT) /
+            100
+        }
+        None => sample,
+    }
+}
+
+impl BackendPool {
+    pub fn new(backends: Vec<Backend>,
+               selector: Box<dyn BackendSelector>,
+               config: &BackendConfig)
+               -> Rc<RefCell<BackendPool>> {
+        Rc::new(RefCell::new(BackendPool {
+                                 name: String::new(),
+                                 backends: backends,
+                                 selector: selector,
+                                 config: config.clone(),
+                                 source_addr: config
+                                     .source_addr
+                                     .as_ref()
+                                     .and_then(|addr| parse_source_addr(addr).ok()),
+                                 proxy_header_format: parse_proxy_header_format(config)
+                                     .unwrap_or(None),
+                                 warm: WarmConnections::from_config(config, Instant::now())
+                                     .unwrap_or(None),
                              }))
     }
 
-    pub fn decide_target(&mut self) -> SocketAddr {
-        let target = self.targets[self.next_target];
-        self.next_target = (self.next_target + 1) % self.targets.len();
+    pub fn set_selector(&mut self, selector: Box<dyn BackendSelector>) {
+        self.selector = selector;
+    }
+
+    /// The pool's name under `[backends]`, which its metrics go by.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_owned();
+    }
+
+    /// How many other targets a client may be sent to after a connect
+    /// fails. Unless configured, every target gets one attempt.
+    pub fn connect_retries(&self) -> u32 {
+        self.config
+            .connect_retries
+            .unwrap_or(self.backends.len().saturating_sub(1) as u32)
+    }
+
+    /// How long a connect to an address resolved from a host name may go
+    /// unanswered before an address of the other IP version is raced
+    /// against it. 250 ms unless configured, as RFC 8305 suggests.
+    pub fn happy_eyeballs_delay(&self) -> Duration {
+        Duration::from_millis(self.config.happy_eyeballs_delay_ms.unwrap_or(250))
+    }
+
+    /// The PROXY protocol header targets expect ahead of the client's
+    /// bytes, if any.
+    pub fn proxy_header_format(&self) -> Option<HeaderFormat> {
+        self.proxy_header_format.clone()
+    }
+
+    /// Whether connections to `addr` start with the PROXY protocol header.
+    /// Only targets set to go without are left out, so an address that
+    /// has since been removed keeps getting it.
+    pub fn sends_proxy_header_to(&self, addr: &BackendId) -> bool {
+        self.proxy_header_format.is_some() &&
+        !self.backends.iter().any(|b| b.addr == *addr && !b.proxy_protocol)
+    }
+
+    /// Whether a target added as `target` should be sent the PROXY
+    /// protocol header.
+    pub fn sends_proxy_protocol(&self, target: &str) -> bool {
+        sends_proxy_protocol(&self.config, target)
+    }
+
+    /// Whether both sides of connections relayed to this backend send small
+    /// writes straight away instead of waiting to coalesce them. On unless
+    /// configured otherwise, since a proxy should not add latency.
+    pub fn tcp_nodelay(&self) -> bool {
+        self.config.tcp_nodelay.unwrap_or(true)
+    }
+
+    /// Whether both sides of connections relayed to this backend are torn
+    /// down with a RST instead of a FIN, by an SO_LINGER of zero. Off
+    /// unless configured, leaving the OS default.
+    pub fn resets_on_close(&self) -> bool {
+        self.config.reset_on_close.unwrap_or(false)
+    }
+
+    /// How many bytes, both ways together, a connection to this backend may
+    /// relay before it is closed, so a target leaking memory per connection
+    /// gets a fresh one now and then. No limit unless configured.
+    pub fn max_connection_bytes(&self) -> Option<u64> {
+        self.config.max_connection_bytes
+    }
+
+    /// TCP keepalive for both sides of relayed connections, so the OS notices
+    /// a peer that went away behind a NAT or firewall. Off unless configured.
+    pub fn keepalive(&self) -> Option<&KeepaliveConfig> {
+        self.config.keepalive.as_ref()
+    }
+
+    pub fn add(&mut self, backend: Backend) -> bool {
+        if self.backends.iter().any(|b| b.addr == backend.addr) {
+            return false;
+        }
+
+        self.backends.push(backend);
+        true
+    }
+
+    pub fn remove(&mut self, addr: &BackendId) -> Option<Backend> {
+        self.backends
+            .iter()
+            .position(|b| b.addr == *addr)
+            .map(|index| self.backends.remove(index))
+    }
+
+    /// Makes the addresses resolved from the host name `host` exactly
+    /// `addrs`. New ones start out healthy, and connections already relaying
+    /// to an address that went away carry on until they close.
+    pub fn update_resolved(&mut self, host: &str, addrs: &[BackendId], weight: u32) {
+        let (kept, gone): (Vec<Backend>, Vec<Backend>) = self.backends
+            .drain(..)
+            .partition(|b| b.host.as_ref().map_or(true, |h| h != host) || addrs.contains(&b.addr));
+        self.backends = kept;
+
+        for backend in gone {
+            info!("Removed {} from the targets of {}", backend.addr, host);
+        }
+
+        for addr in addrs {
+            let mut backend = Backend::new(addr.clone(), weight);
+            backend.host = Some(host.to_owned());
+            backend.proxy_protocol = sends_proxy_protocol(&self.config, host);
+
+            if self.add(backend) {
+                info!("Added {} to the targets of {}", addr, host);
+            }
+        }
+    }
+
+    /// Stops or resumes giving `addr` new clients. Returns false if it is
+    /// not one of the targets.
+    pub fn set_draining(&mut self, addr: &BackendId, draining: bool) -> bool {
+        match self.backends.iter_mut().find(|b| b.addr == *addr) {
+            Some(backend) => {
+                backend.draining = draining;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn backends(&self) -> &[Backend] {
+        &self.backends
+    }
+
+    pub fn backend_ids(&self) -> Vec<BackendId> {
+        self.backends.iter().map(|b| b.addr.clone()).collect()
+    }
+
+    pub fn healthy_backends(&self) -> Vec<&Backend> {
+        let now = Instant::now();
+
+        self.backends.iter().filter(|b| b.is_available(now)).collect()
+    }
+
+    pub fn decide_target(&mut self, client: &SocketAddr) -> Option<BackendId> {
+        self.decide_target_excluding(client, &[])
+    }
+
+    /// Like `decide_target`, but never picks one of `exclude`.
+    pub fn decide_target_excluding(&mut self,
+                                   client: &SocketAddr,
+                                   exclude: &[BackendId])
+                                   -> Option<BackendId> {
+        let now = Instant::now();
+
+        for backend in self.backends.iter_mut() {
+            if backend.ejected_until.map_or(false, |until| until <= now) {
+                info!("Backend {} re-admitted after ejection cooldown", backend.addr);
+                backend.ejected_until = None;
+                backend.up_since = Some(now);
+            }
+        }
+
+        let target = {
+            let healthy = self.backends
+                .iter()
+                .filter(|b| b.is_available(now) && !exclude.contains(&b.addr))
+                .collect::<Vec<&Backend>>();
+            if healthy.is_empty() {
+                return None;
+            }
+
+            healthy[self.selector.select(&healthy, client)].addr.clone()
+        };
+
+        if let Some(backend) = self.backends.iter_mut().find(|b| b.addr == target) {
+            backend.active_connections += 1;
+        }
+
+        Some(target)
+    }
+
+    /// Starts connecting to a target that isn't in `tried`, and adds it
+    /// there whether or not the connect could be started.
+    pub fn connect(&mut self,
+                   client: &SocketAddr,
+                   tried: &mut Vec<BackendId>)
+                   -> IOResult<(Stream, BackendId)> {
+        let target = try!(self.decide_target_excluding(client, tried)
+                              .ok_or(IOError::new(ErrorKind::NotConnected,
+                                                  "No healthy backend available")));
+        tried.push(target.clone());
+
+        let result = match self.warm.as_mut().and_then(|warm| warm.take(&target)) {
+            Some(stream) => {
+                debug!("Handing out an idle connection to {}", target);
+                Ok(stream)
+            }
+            None => self.connect_to(&target),
+        };
+        self.warm_up(&target);
+
+        match result {
+            Ok(stream) => Ok((stream, target)),
+            Err(e) => {
+                self.release_target(&target);
+                // Running out of source ports says nothing about the target.
+                if !is_local_error(&e) {
+                    self.report_failure(&target);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Starts a connect to an address of the other IP version than
+    /// `target`, resolved from the same host name, and adds it to `tried`.
+    /// None if the host has no such address that is available and hasn't
+    /// been tried yet.
+    pub fn connect_other_family(&mut self,
+                                target: &BackendId,
+                                tried: &mut Vec<BackendId>)
+                                -> Option<IOResult<(Stream, BackendId)>> {
+        let now = Instant::now();
+        let host = match self.backends.iter().find(|b| b.addr == *target) {
+            Some(&Backend { host: Some(ref host), .. }) => host.clone(),
+            _ => return None,
+        };
+        let other = match self.backends.iter_mut().find(|b| {
+            b.host.as_ref() == Some(&host) && is_other_family(&b.addr, target) &&
+            b.is_available(now) && !tried.contains(&b.addr)
+        }) {
+            Some(backend) => {
+                backend.active_connections += 1;
+                backend.addr.clone()
+            }
+            None => return None,
+        };
+        tried.push(other.clone());
+
+        match self.connect_to(&other) {
+            Ok(stream) => Some(Ok((stream, other))),
+            Err(e) => {
+                self.release_target(&other);
+                if !is_local_error(&e) {
+                    self.report_failure(&other);
+                }
+                Some(Err(e))
+            }
+        }
+    }
+
+    /// Starts a connect to `target`, from the configured source address
+    /// if there is one.
+    pub fn connect_to(&self, target: &BackendId) -> IOResult<Stream> {
+        match self.source_addr {
+            Some(ref source) => Stream::connect_from(target, source),
+            None => Stream::connect(target),
+        }
+    }
+
+    /// When idle connections to the targets are next due to be checked, if
+    /// the backend keeps any.
+    pub fn next_warm_sweep(&self) -> Option<Instant> {
+        self.warm.as_ref().map(WarmConnections::next_sweep)
+    }
+
+    /// Drops idle connections the targets have closed and makes new ones
+    /// for available targets that are short, once a sweep is due.
+    pub fn sweep_warm(&mut self, now: Instant) {
+        let available = self.backends
+            .iter()
+            .filter(|b| b.is_available(now))
+            .map(|b| b.addr.clone())
+            .collect::<Vec<_>>();
+        let shortfalls = match self.warm {
+            Some(ref mut warm) if warm.next_sweep() <= now => warm.sweep(&available, now),
+            _ => return,
+        };
+
+        for (target, _) in shortfalls {
+            self.warm_up(&target);
+        }
+    }
+
+    /// Connects to `target` until it has as many idle connections as it
+    /// should, giving up on the first connect that can't be started.
+    fn warm_up(&mut self, target: &BackendId) {
+        let shortfall = self.warm.as_mut().map_or(0, |warm| warm.shortfall(target));
+
+        for _ in 0..shortfall {
+            match self.connect_to(target) {
+                Ok(stream) => {
+                    if let Some(ref mut warm) = self.warm {
+                        warm.put(target, stream);
+                    }
+                }
+                Err(e) => {
+                    debug!("Could not make an idle connection to {}: {}", target, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn update_weights(&mut self, weights: &[(BackendId, u32)]) {
+        for &(ref id, weight) in weights {
+            if let Some(backend) = self.backends.iter_mut().find(|b| b.addr == *id) {
+                backend.weight = weight;
+            }
+        }
+
+        self.selector.update_weights(weights);
+    }
+
+    /// Records a health check result, flipping the backend down after `fall`
+    /// consecutive failures and back up after `rise` consecutive successes.
+    pub fn report_check(&mut self, addr: &BackendId, success: bool, rise: u32, fall: u32) {
+        if let Some(backend) = self.backends.iter_mut().find(|b| b.addr == *addr) {
+            if success {
+                backend.check_failures = 0;
+                backend.check_successes += 1;
+
+                if !backend.healthy && backend.check_successes >= rise {
+                    info!("Backend {} is up after {} successful checks",
+                          addr,
+                          backend.check_successes);
+                    backend.healthy = true;
+                    backend.up_since = Some(Instant::now());
+                }
+            } else {
+                backend.check_successes = 0;
+                backend.check_failures += 1;
+
+                if backend.healthy && backend.check_failures >= fall {
+                    warn!("Backend {} is down after {} failed checks",
+                          addr,
+                          backend.check_failures);
+                    backend.healthy = false;
+                }
+            }
+        }
+    }
+
+    /// Records a failed connection to a backend, ejecting it for the
+    /// configured cooldown once too many failures land within the window.
+    pub fn report_failure(&mut self, addr: &BackendId) {
+        let config = match self.config.passive_health_check {
+            Some(ref config) => config.clone(),
+            None => return,
+        };
+
+        if let Some(backend) = self.backends.iter_mut().find(|b| b.addr == *addr) {
+            let now = Instant::now();
+            let window = Duration::from_millis(config.window_ms);
+
+            backend.recent_failures.push_back(now);
+            while backend
+                      .recent_failures
+                      .front()
+                      .map_or(false, |t| now.duration_since(*t) > window) {
+                backend.recent_failures.pop_front();
+            }
+
+            if backend.ejected_until.is_none() &&
+               backend.recent_failures.len() >= config.max_failures as usize {
+                warn!("Ejecting backend {} after {} failures within {}ms",
+                      addr,
+                      backend.recent_failures.len(),
+                      config.window_ms);
+                backend.recent_failures.clear();
+                backend.ejected_until = Some(now + Duration::from_millis(config.cooldown_ms));
+            }
+        }
+    }
+
+    /// Folds a finished connect into the target's average, returning the
+    /// new average.
+    pub fn report_connect_time(&mut self,
+                               addr: &BackendId,
+                               elapsed: Duration)
+                               -> Option<Duration> {
+        self.backends.iter_mut().find(|b| b.addr == *addr).map(|backend| {
+            let average = smooth(backend.connect_time, elapsed);
+            backend.connect_time = Some(average);
+            average
+        })
+    }
+
+    /// Folds the wait for a connected target's first byte into its
+    /// average, returning the new average.
+    pub fn report_first_byte_time(&mut self,
+                                  addr: &BackendId,
+                                  elapsed: Duration)
+                                  -> Option<Duration> {
+        self.backends.iter_mut().find(|b| b.addr == *addr).map(|backend| {
+            let average = smooth(backend.first_byte_time, elapsed);
+            backend.first_byte_time = Some(average);
+            average
+        })
+    }
+
+    pub fn release_target(&mut self, addr: &BackendId) {
+        if let Some(backend) = self.backends.iter_mut().find(|b| b.addr == *addr) {
+            backend.active_connections = backend.active_connections.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backend, BackendPool, BackendId};
+
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use config::{BackendConfig, PassiveHealthCheckConfig};
+    use selector::RoundRobin;
+    use stream::Address;
+
+    fn socket_addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    fn addr(port: u16) -> BackendId {
+        Address::Tcp(socket_addr(port))
+    }
+
+    #[test]
+    fn retries_each_untried_target_once_by_default() {
+        let pool = BackendPool::new(vec![Backend::new(addr(8000), 1),
+                                         Backend::new(addr(8001), 1),
+                                         Backend::new(addr(8002), 1)],
+                                    Box::new(RoundRobin::new()),
+                                    &Default::default());
+        let mut pool = pool.borrow_mut();
+        let client = socket_addr(5000);
+
+        assert_eq!(pool.connect_retries(), 2);
+        assert_eq!(pool.decide_target_excluding(&client, &[addr(8000), addr(8002)]),
+                   Some(addr(8001)));
+        let all = pool.backend_ids();
+        assert_eq!(pool.decide_target_excluding(&client, &all), None);
+    }
+
+    #[test]
+    fn decide_target_skips_unhealthy_backends() {
+        let pool = BackendPool::new(vec![Backend::new(addr(8000), 1)],
+                                    Box::new(RoundRobin::new()),
+                                    &Default::default());
+        let mut pool = pool.borrow_mut();
+        let client = socket_addr(5000);
+
+        assert!(pool.add(Backend::new(addr(8001), 1)));
+        assert!(!pool.add(Backend::new(addr(8001), 1)));
+
+        pool.backends[0].healthy = false;
+        assert_eq!(pool.decide_target(&client), Some(addr(8001)));
+        assert_eq!(pool.decide_target(&client), Some(addr(8001)));
+        assert_eq!(pool.backends[1].active_connections, 2);
+
+        assert!(pool.remove(&addr(8001)).is_some());
+        assert_eq!(pool.decide_target(&client), None);
+    }
+
+    #[test]
+    fn report_check_applies_rise_and_fall() {
+        let pool = BackendPool::new(vec![Backend::new(addr(8000), 1)],
+                                    Box::new(RoundRobin::new()),
+                                    &Default::default());
+        let mut pool = pool.borrow_mut();
+
+        pool.report_check(&addr(8000), false, 2, 2);
+        assert!(pool.backends[0].healthy);
+        pool.report_check(&addr(8000), false, 2, 2);
+        assert!(!pool.backends[0].healthy);
+
+        pool.report_check(&addr(8000), true, 2, 2);
+        pool.report_check(&addr(8000), false, 2, 2);
+        pool.report_check(&addr(8000), true, 2, 2);
+        assert!(!pool.backends[0].healthy);
+        pool.report_check(&addr(8000), true, 2, 2);
+        assert!(pool.backends[0].healthy);
+    }
+
+    #[test]
+    fn report_failure_ejects_backend() {
+        let config = BackendConfig {
+            passive_health_check: Some(PassiveHealthCheckConfig {
+                                           max_failures: 2,
+                                           window_ms: 60000,
+                                           cooldown_ms: 60000,
+                                       }),
+            ..Default::default()
+        };
+        let pool = BackendPool::new(vec![Backend::new(addr(8000), 1), Backend::new(addr(8001), 1)],
+                                    Box::new(RoundRobin::new()),
+                                    &config);
+        let mut pool = pool.borrow_mut();
+        let client = socket_addr(5000);
+
+        pool.report_failure(&addr(8000));
+        assert_eq!(pool.healthy_backends().len(), 2);
+
+        pool.report_failure(&addr(8000));
+        assert_eq!(pool.decide_target(&client), Some(addr(8001)));
+        assert_eq!(pool.decide_target(&client), Some(addr(8001)));
+
+        pool.backends[0].ejected_until = Some(Instant::now());
+        assert_eq!(pool.healthy_backends().len(), 2);
+    }
+
+    #[test]
+    fn latency_reports_are_smoothed() {
+        let pool = BackendPool::new(vec![Backend::new(addr(8000), 1)],
+                                    Box::new(RoundRobin::new()),
+                                    &Default::default());
+        let mut pool = pool.borrow_mut();
+        let ms = Duration::from_millis;
+
+        assert_eq!(pool.backends[0].response_time(), ms(0));
+        assert_eq!(pool.report_connect_time(&addr(8000), ms(100)), Some(ms(100)));
+        assert_eq!(pool.report_connect_time(&addr(8000), ms(200)), Some(ms(130)));
+        assert_eq!(pool.report_first_byte_time(&addr(8000), ms(20)), Some(ms(20)));
+        assert_eq!(pool.backends[0].response_time(), ms(150));
 
-        target
+        assert_eq!(pool.report_connect_time(&addr(8001), ms(100)), None);
     }
 }
@@ -12,23 +12,140 @@ pub struct RootConfig {
     pub frontends: HashMap<String, FrontendConfig>,
     pub backends: HashMap<String, BackendConfig>,
     pub buffers: BufferConfig,
+    pub timeouts: Option<TimeoutConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub limits: Option<LimitConfig>,
+    pub control: Option<ControlConfig>,
+    pub admin: Option<AdminConfig>,
+    pub access_log: Option<AccessLogConfig>,
 }
 
 #[derive(Debug, RustcDecodable, Default, Clone)]
 pub struct FrontendConfig {
     pub listen_addr: String,
     pub backend: String,
+    pub backend_split: Option<HashMap<String, u32>>,
+    pub accept_proxy_protocol: Option<bool>,
+    pub sni_backends: Option<HashMap<String, String>>,
+    pub host_backends: Option<HashMap<String, String>>,
+    pub reuse_addr: Option<bool>,
+    pub backlog: Option<i32>,
+    pub endpoint_size: Option<usize>,
+    pub access_log: Option<bool>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub protocol: Option<String>,
+    pub max_routing_bytes: Option<usize>,
+    pub routing_overflow: Option<String>,
 }
 
 #[derive(Debug, RustcDecodable, Default, Clone)]
 pub struct BackendConfig {
     pub target_addrs: Vec<String>,
+    pub strategy: Option<String>,
+    pub slow_start_ms: Option<u64>,
+    pub weights: Option<Vec<u32>>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub passive_health_check: Option<PassiveHealthCheckConfig>,
+    pub connect_retries: Option<u32>,
+    pub happy_eyeballs_delay_ms: Option<u64>,
+    pub proxy_protocol: Option<bool>,
+    pub proxy_protocol_version: Option<u8>,
+    pub proxy_protocol_tlvs: Option<Vec<String>>,
+    pub proxy_protocol_targets: Option<Vec<String>>,
+    pub tcp_nodelay: Option<bool>,
+    pub reset_on_close: Option<bool>,
+    pub max_connection_bytes: Option<u64>,
+    pub keepalive: Option<KeepaliveConfig>,
+    pub connection_pool: Option<ConnectionPoolConfig>,
+    pub resolve_interval_ms: Option<u64>,
+    pub random_seed: Option<u64>,
+    pub source_addr: Option<String>,
+}
+
+#[derive(Debug, RustcDecodable, Clone)]
+pub struct HealthCheckConfig {
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    pub fall: u32,
+    pub rise: u32,
+    pub send: Option<String>,
+    pub expect: Option<String>,
+    pub expect_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, RustcDecodable, Clone)]
+pub struct KeepaliveConfig {
+    pub idle_ms: u64,
+    pub interval_ms: Option<u64>,
+}
+
+#[derive(Debug, RustcDecodable, Clone)]
+pub struct ConnectionPoolConfig {
+    pub min_idle: usize,
+    pub max_idle: usize,
+}
+
+#[derive(Debug, RustcDecodable, Clone)]
+pub struct PassiveHealthCheckConfig {
+    pub max_failures: u32,
+    pub window_ms: u64,
+    pub cooldown_ms: u64,
 }
 
 #[derive(Debug, RustcDecodable, Clone)]
 pub struct BufferConfig {
     pub connections: usize,
     pub listeners: usize,
+    pub endpoint_size: Option<usize>,
+    pub splice: Option<bool>,
+}
+
+#[derive(Debug, RustcDecodable, Default, Clone)]
+pub struct TimeoutConfig {
+    pub idle_ms: Option<u64>,
+    pub connect_ms: Option<u64>,
+    pub drain_ms: Option<u64>,
+    pub read_ms: Option<u64>,
+    pub write_ms: Option<u64>,
+    pub max_lifetime_ms: Option<u64>,
+    pub stall_ms: Option<u64>,
+    pub handshake_ms: Option<u64>,
+    pub udp_idle_ms: Option<u64>,
+}
+
+#[derive(Debug, RustcDecodable, Default, Clone)]
+pub struct LimitConfig {
+    pub max_connections: Option<usize>,
+    pub connection_rate: Option<u32>,
+    pub connection_burst: Option<u32>,
+    pub rate_limited_clients: Option<usize>,
+    pub max_buffered_bytes: Option<usize>,
+    pub max_bytes_per_sec: Option<u64>,
+    pub total_bytes_per_sec: Option<u64>,
+}
+
+#[derive(Debug, RustcDecodable, Clone)]
+pub struct MetricsConfig {
+    pub listen_addr: String,
+}
+
+#[derive(Debug, RustcDecodable, Clone)]
+pub struct ControlConfig {
+    pub listen_addr: String,
+}
+
+#[derive(Debug, RustcDecodable, Clone)]
+pub struct AdminConfig {
+    pub listen_addr: String,
+}
+
+#[derive(Debug, RustcDecodable, Clone, PartialEq, Eq)]
+pub struct AccessLogConfig {
+    pub path: String,
+    pub format: Option<String>,
+    pub rotate_bytes: Option<u64>,
+    pub rotate_keep: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -39,6 +156,7 @@ pub enum ReadError {
 }
 
 impl RootConfig {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(config: &str) -> Result<RootConfig, ReadError> {
         let mut parser = toml::Parser::new(&config);
         let table = try!(parser.parse().ok_or(parser.errors.clone()));
@@ -81,6 +199,8 @@ impl Default for BufferConfig {
         BufferConfig {
             connections: 4096,
             listeners: 128,
+            endpoint_size: None,
+            splice: None,
         }
     }
 }
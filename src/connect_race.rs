@@ -0,0 +1,63 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Instant;
+
+use mio::Ready;
+
+use backend::{BackendPool, BackendId};
+use connection::IncomingToken;
+use stream::Stream;
+
+/// A second connect for a client, to an address of the other IP version
+/// than its target, started when the connect to the target is slow to
+/// finish (RFC 8305, "happy eyeballs"). Whichever connect completes first
+/// is the one the client is relayed over, and the other is dropped.
+pub struct ConnectRace {
+    pub stream: Stream,
+    pub target: BackendId,
+    /// The connection the race is for. Its slot may have gone to another
+    /// connection since, so the id is checked as well.
+    pub connection: IncomingToken,
+    pub conn_id: usize,
+    pool: Rc<RefCell<BackendPool>>,
+    deadline: Instant,
+}
+
+impl ConnectRace {
+    pub fn new(connected: (Stream, BackendId),
+               connection: IncomingToken,
+               conn_id: usize,
+               pool: Rc<RefCell<BackendPool>>,
+               deadline: Instant)
+               -> ConnectRace {
+        let (stream, target) = connected;
+
+        ConnectRace {
+            stream: stream,
+            target: target,
+            connection: connection,
+            conn_id: conn_id,
+            pool: pool,
+            deadline: deadline,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn connect_result(&self, ready: Ready) -> Option<bool> {
+        self.stream.connect_result(ready)
+    }
+
+    /// Gives the target's place up for a connect that lost the race, or
+    /// failed, in which case it also counts against the target's health.
+    pub fn cancel(&self, failed: bool) {
+        let mut pool = self.pool.borrow_mut();
+
+        pool.release_target(&self.target);
+        if failed {
+            pool.report_failure(&self.target);
+        }
+    }
+}
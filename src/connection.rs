@@ -1,19 +1,47 @@
 //use std::io;
-use mio::{Token, Ready};
+use mio::{Poll, Token, Ready};
 use mio::unix::UnixReady;
-use mio::tcp::TcpStream;
 use std::io::prelude::*;
-use std::io::ErrorKind;
-use std::ptr;
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::mem;
+use std::net::Shutdown;
 use std::ops::{Index, IndexMut};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use access_log::AccessLogEntry;
+use backend::{BackendPool, BackendId};
+use config::TimeoutConfig;
+use metrics::{Metrics, BackendMetrics, PoolMetrics};
+use observer::{ConnectionInfo, ConnectionStats};
+use proxy_protocol::{self, HeaderFormat, Parsed};
+use rate_limit::{BandwidthCap, Throttle};
+use splice::SplicePipe;
+use stream::{Address, Stream};
 
 #[derive(Debug, Copy, Clone)]
 pub enum TokenType {
     Listener(ListenerToken),
     Incoming(IncomingToken),
     Outgoing(OutgoingToken),
+    Auxiliary(AuxiliaryToken),
+    Shutdown,
+    Inspect,
 }
 
+/// The raw token shutdown requests wake the event loop with. Its index is
+/// one past `MAX_TOKEN_INDEX`, which no slab slot ever gets.
+pub const SHUTDOWN_TOKEN: Token = Token(usize::MAX - 1);
+
+/// The raw token inspect requests wake the event loop with, also past
+/// every slab slot.
+pub const INSPECT_TOKEN: Token = Token(usize::MAX - 2);
+
 #[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
 pub struct ListenerToken(pub usize);
 
@@ -23,16 +51,76 @@ pub struct IncomingToken(pub usize);
 #[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
 pub struct OutgoingToken(pub usize);
 
-type BufferArray = [u8; 4096];
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
+pub struct AuxiliaryToken(pub usize);
+
+pub const DEFAULT_BUFFER_SIZE: usize = 4096;
+
+/// This many partial writes to one peer within `PARTIAL_WRITE_BURST_MS`
+/// are taken as a sign of a peer struggling to keep up.
+const PARTIAL_WRITE_BURST: u64 = 64;
+const PARTIAL_WRITE_BURST_MS: u64 = 1000;
+
+/// The largest slab index a token can carry. The low two bits of a raw
+/// token hold its kind, and the very last raw token is reserved by mio.
+pub const MAX_TOKEN_INDEX: usize = (usize::MAX >> 2) - 1;
+
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone)]
+/// The discriminants are the positions in an `EndPointList`.
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
 pub enum EndPointType {
-    Front,
-    Back,
+    Front = 0,
+    Back = 1,
+}
+
+/// Both endpoint types, in the order of their positions.
+const END_POINT_TYPES: [EndPointType; 2] = [EndPointType::Front, EndPointType::Back];
+
+/// The way bytes are relayed through a connection: upstream from the
+/// client to the backend, or downstream back to the client.
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
+pub enum Direction {
+    Upstream,
+    Downstream,
+}
+
+impl Direction {
+    /// The endpoint bytes going this way are read from, and buffered in
+    /// until they are written.
+    pub fn source(self) -> EndPointType {
+        match self {
+            Direction::Upstream => EndPointType::Front,
+            Direction::Downstream => EndPointType::Back,
+        }
+    }
+
+    /// The endpoint bytes going this way are written to.
+    pub fn destination(self) -> EndPointType {
+        match self {
+            Direction::Upstream => EndPointType::Back,
+            Direction::Downstream => EndPointType::Front,
+        }
+    }
 }
 
+/// One `T` for each side of a connection, looked up by `EndPointType`.
 pub struct EndPointList<T>([T; 2]);
 
+impl<T> EndPointList<T> {
+    pub fn new(front: T, back: T) -> EndPointList<T> {
+        EndPointList([front, back])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EndPointType, &T)> {
+        END_POINT_TYPES.iter().cloned().zip(self.0.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (EndPointType, &mut T)> {
+        END_POINT_TYPES.iter().cloned().zip(self.0.iter_mut())
+    }
+}
+
 impl<T> Index<EndPointType> for EndPointList<T> {
     type Output = T;
     fn index(&self, end_type: EndPointType) -> &T {
@@ -64,101 +152,660 @@ macro_rules! create_trait {
     }
 }
 
-pub struct EndPoint {
+/// What an endpoint reads its bytes from, and writes its peer's bytes to.
+/// Relayed connections use a `Stream`; tests can put a scripted stand-in
+/// in its place.
+pub trait EndPointIo: Read + Write + Sized {
+    fn try_clone(&self) -> IOResult<Self>;
+
+    fn shutdown(&self, how: Shutdown) -> IOResult<()>;
+
+    /// The descriptor to splice from and to. Only asked for once splicing
+    /// has been turned on.
+    fn raw_fd(&self) -> RawFd;
+}
+
+impl EndPointIo for Stream {
+    fn try_clone(&self) -> IOResult<Stream> {
+        Stream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> IOResult<()> {
+        Stream::shutdown(self, how)
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.as_raw_fd()
+    }
+}
+
+/// The endpoints relayed connections are made of.
+pub type SocketEndPoint = EndPoint<Stream>;
+
+pub struct EndPoint<S> {
     state: Ready,
-    stream: TcpStream,
-    buffer: BufferArray,
+    stream: S,
+    buffer: Vec<u8>,
     buffer_index: usize,
-    peer_stream: Option<TcpStream>,
+    preamble: Vec<u8>,
+    peer_stream: Option<S>,
+    bytes_absorbed: u64,
+    bytes_piped: u64,
+    read_closed: bool,
+    write_closed: bool,
+    io_failed: bool,
+    awaiting_proxy_header: bool,
+    splice_pipe: Option<SplicePipe>,
+    conn_id: usize,
+    read_paused: bool,
+    /// Since when the endpoint has wanted to read without getting a byte.
+    read_waiting_since: Option<Instant>,
+    /// Since when bytes have been waiting in the buffer without any of them
+    /// getting written to the peer.
+    write_waiting_since: Option<Instant>,
+    throttle: Option<Throttle>,
+    /// Set once the throttle has run dry, until when reading waits.
+    read_throttled_until: Option<Instant>,
+    /// Set once the shared bandwidth cap has run dry, until when writing
+    /// this endpoint's bytes to the peer waits.
+    write_throttled_until: Option<Instant>,
+    /// How many of the bytes written to this endpoint's socket the peer
+    /// had acknowledged when last asked.
+    acked: u64,
+    /// Since when bytes written to this endpoint's socket have sat
+    /// unacknowledged without the peer acknowledging any more.
+    unacked_since: Option<Instant>,
+    /// Writes to the peer that left bytes behind to be moved to the front
+    /// of the buffer.
+    partial_writes: u64,
+    /// When the partial writes were last checked for a burst, and how many
+    /// there had been then.
+    burst_checked: (Instant, u64),
 }
 
-impl EndPoint {
-    pub fn new(tcp_stream: TcpStream) -> EndPoint {
+impl<S: EndPointIo> EndPoint<S> {
+    pub fn new(stream: S, buffer_size: usize, conn_id: usize) -> EndPoint<S> {
         EndPoint {
             state: Ready::empty(),
-            stream: tcp_stream,
-            buffer: [0; 4096],
+            stream: stream,
+            buffer: vec![0; buffer_size],
             buffer_index: 0,
+            preamble: Vec::new(),
             peer_stream: None,
+            bytes_absorbed: 0,
+            bytes_piped: 0,
+            read_closed: false,
+            write_closed: false,
+            io_failed: false,
+            awaiting_proxy_header: false,
+            splice_pipe: None,
+            conn_id: conn_id,
+            read_paused: false,
+            read_waiting_since: Some(Instant::now()),
+            write_waiting_since: None,
+            throttle: None,
+            read_throttled_until: None,
+            write_throttled_until: None,
+            acked: 0,
+            unacked_since: None,
+            partial_writes: 0,
+            burst_checked: (Instant::now(), 0),
+        }
+    }
+
+    /// Keeps a duplicate of the peer's socket to write to. Fails when it
+    /// can't be duplicated, as when out of file descriptors, since an
+    /// endpoint without one would never relay a byte.
+    pub fn set_peer_stream(&mut self, peer_stream: &S) -> IOResult<()> {
+        self.peer_stream = Some(try!(peer_stream.try_clone()));
+        Ok(())
+    }
+
+    /// Reads what the socket has into the buffer, until it would block,
+    /// the buffer fills up or the peer sends EOF. Sockets are registered
+    /// edge-triggered, so a readable event isn't repeated for bytes that
+    /// were left unread. `Ok(0)` means nothing could be read right now; an
+    /// error means the socket is dead.
+    ///
+    /// A throttled endpoint reads no more than its throttle allows, and
+    /// stops reading until `read_throttled_until` once that has been used up.
+    pub fn absorb(&mut self) -> IOResult<usize> {
+        let mut n_total = 0;
+        let mut allowance = self.throttle.as_mut().map(|t| t.allowance(Instant::now()));
+
+        while self.wants_read() {
+            if allowance == Some(0) {
+                self.read_throttled_until = self.throttle.as_ref().map(Throttle::resumes_at);
+                break;
+            }
+
+            let max_len = allowance.unwrap_or(usize::MAX);
+            let splicing = self.is_splicing();
+            let result = match self.splice_pipe {
+                Some(ref mut pipe) if splicing => {
+                    pipe.fill_at_most_from(self.stream.raw_fd(), max_len)
+                }
+                _ => {
+                    let end = self.buffer.len().min(self.buffer_index.saturating_add(max_len));
+                    self.stream.read(&mut self.buffer[self.buffer_index..end])
+                }
+            };
+
+            match result {
+                Ok(0) => {
+                    debug!("[conn={}] Read side closed", self.conn_id);
+                    self.read_closed = true;
+                }
+                Ok(n_read) => {
+                    if !splicing {
+                        self.buffer_index += n_read;
+                    }
+                    self.bytes_absorbed += n_read as u64;
+                    n_total += n_read;
+                    if let (Some(allowance), Some(throttle)) = (allowance.as_mut(),
+                                                                 self.throttle.as_mut()) {
+                        *allowance -= n_read;
+                        throttle.take(n_read);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(n_total)
+    }
+
+    /// The peer has sent EOF and everything it sent has been relayed, so the
+    /// other side can be told there is nothing more coming.
+    pub fn is_drained(&self) -> bool {
+        self.read_closed && !self.wants_write()
+    }
+
+    pub fn is_buffer_full(&self) -> bool {
+        match self.splice_pipe {
+            Some(ref pipe) if self.is_splicing() => pipe.is_full(),
+            _ => self.buffer_index >= self.buffer.len(),
         }
     }
 
-    pub fn set_peer_stream(&mut self, tcp_stream: &TcpStream) {
-        if let Ok(stream) = tcp_stream.try_clone() {
-            self.peer_stream = Some(stream);
+    /// Whether reads go straight into the splice pipe. Bytes that were put
+    /// in the buffer, like those read while looking for a PROXY protocol
+    /// header, are relayed from there first, so the buffer and the pipe
+    /// never both hold bytes.
+    fn is_splicing(&self) -> bool {
+        self.splice_pipe.is_some() && self.buffer_index == 0 && !self.awaiting_proxy_header
+    }
+
+    fn spliced_len(&self) -> usize {
+        self.splice_pipe.as_ref().map_or(0, SplicePipe::len)
+    }
+
+    /// Reading is paused while the buffer is full so a fast sender can't
+    /// outrun a slow peer, while the driver holds it back to keep memory
+    /// in check, or while the throttle refills, and stops for good after
+    /// EOF.
+    pub fn wants_read(&self) -> bool {
+        !self.read_closed && !self.read_paused && self.read_throttled_until.is_none() &&
+        !self.is_buffer_full()
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        self.buffer_index + self.spliced_len()
+    }
+
+    /// There are buffered bytes waiting to be written to the peer. Nothing
+    /// is relayed until an expected PROXY protocol header has been stripped.
+    pub fn wants_write(&self) -> bool {
+        !self.awaiting_proxy_header &&
+        (!self.preamble.is_empty() || self.buffer_index > 0 || self.spliced_len() > 0)
+    }
+
+    pub fn is_errored(&self) -> bool {
+        self.io_failed || UnixReady::from(self.state).is_error()
+    }
+
+    /// Restarts the read and write timers on progress, and stops them while
+    /// there is nothing to wait for.
+    fn update_timers(&mut self, now: Instant, n_read: usize, n_written: usize) {
+        self.read_waiting_since = if !self.wants_read() {
+            None
+        } else if n_read > 0 {
+            Some(now)
+        } else {
+            self.read_waiting_since.or(Some(now))
+        };
+
+        self.write_waiting_since = if !self.wants_write() || self.write_throttled_until.is_some() {
+            None
+        } else if n_written > 0 {
+            Some(now)
+        } else {
+            self.write_waiting_since.or(Some(now))
+        };
+    }
+
+    /// Marks the socket as dead after a failed read or write, so the
+    /// connection is torn down even if no error readiness ever arrives.
+    fn fail(&mut self, action: &str, e: IOError) {
+        match e.kind() {
+            // The peer went away without a proper shutdown, which is
+            // nothing out of the ordinary for a proxy to see.
+            ErrorKind::ConnectionReset |
+            ErrorKind::ConnectionAborted |
+            ErrorKind::BrokenPipe => {
+                info!("[conn={}] {} failed, peer is gone: {}", self.conn_id, action, e)
+            }
+            _ => error!("[conn={}] {} caused error: {}", self.conn_id, action, e),
         }
+        self.io_failed = true;
     }
-    pub fn absorb(&mut self) -> usize {
-        if self.buffer_index >= 4096 {
-            return 0;
+
+    /// Closes the write half of this endpoint's socket, leaving the read half
+    /// open so the other direction keeps flowing.
+    pub fn shutdown_write(&mut self) {
+        if self.write_closed {
+            return;
         }
-        match self.stream
-                  .read(self.buffer.split_at_mut(self.buffer_index).1) {
-            Ok(n_read) => {
-                self.buffer_index += n_read;
-                return n_read;
+        if let Err(e) = self.stream.shutdown(Shutdown::Write) {
+            debug!("[conn={}] Shutdown of write side failed: {}", self.conn_id, e);
+        }
+        self.write_closed = true;
+    }
+
+    /// Writes buffered bytes to the peer, preceded by whatever is left of
+    /// the preamble, until the peer would block or the buffer is empty.
+    /// Only buffered bytes are counted in the return value, and an error
+    /// means the peer's socket is dead.
+    pub fn pipe_to_peer(&mut self, max_len: usize) -> IOResult<usize> {
+        if let Some(mut dest) = self.peer_stream.take() {
+            let mut result = self.pipe_into(&mut dest, max_len);
+            if self.preamble.is_empty() {
+                result = result.and_then(|n_written| {
+                                             self.splice_into(&dest, max_len - n_written)
+                                                 .map(|n| n_written + n)
+                                         });
             }
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    //                    info!("WouldBlock when read");
-                    return 0;
+            self.peer_stream = Some(dest);
+            return result;
+        }
+        Ok(0)
+    }
+
+    /// A preamble is always written whole; `max_len` only holds back
+    /// relayed bytes.
+    fn pipe_into<W: Write>(&mut self, dest: &mut W, max_len: usize) -> IOResult<usize> {
+        let mut n_total = 0;
+
+        while self.wants_write() {
+            let preamble_len = self.preamble.len();
+            if !try!(self.write_preamble(dest)) {
+                if self.preamble.len() == preamble_len {
+                    break;
                 }
-                error!("Reading caused error: {}", e);
+                continue;
+            }
+            if self.buffer_index == 0 || n_total >= max_len {
+                break;
+            }
+
+            let n_written = try!(self.write_buffer(dest, max_len - n_total));
+            if n_written == 0 {
+                break;
             }
+            n_total += n_written;
         }
-        return 0;
+        Ok(n_total)
     }
 
-    pub fn pipe_to_peer(&mut self) -> usize {
-        if self.buffer_index == 0 {
-            return 0;
-        }
-        if let Some(mut dest) = self.peer_stream.as_mut() {
-            match dest.write(self.buffer.split_at(self.buffer_index).0) {
-                Ok(n_written) => {
-                    let left = self.buffer_index - n_written;
-                    if left > 0 {
-                        unsafe {
-                            ptr::copy(&self.buffer[n_written], &mut self.buffer[0], left);
-                        }
-                        info!("in shorten writeen");
+    fn splice_into(&mut self, dest: &S, max_len: usize) -> IOResult<usize> {
+        let mut n_total = 0;
+
+        if let Some(ref mut pipe) = self.splice_pipe {
+            while !pipe.is_empty() && n_total < max_len {
+                match pipe.drain_at_most_into(dest.raw_fd(), max_len - n_total) {
+                    Ok(n_written) => {
+                        self.bytes_piped += n_written as u64;
+                        n_total += n_written;
                     }
-                    self.buffer_index = left;
-                    return n_written;
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
                 }
-                Err(e) => {
-                    if e.kind() == ErrorKind::WouldBlock {
-                        // info!("WouldBlock when read");
-                        return 0;
-                    }
+            }
+        }
+        Ok(n_total)
+    }
 
-                    error!("Reading caused error: {}", e);
-                    return 0;
+    /// Returns true once the whole preamble has been written. A partial
+    /// write keeps the rest for the next time the peer is writable.
+    fn write_preamble<W: Write>(&mut self, dest: &mut W) -> IOResult<bool> {
+        if self.preamble.is_empty() {
+            return Ok(true);
+        }
+        match dest.write(&self.preamble).and_then(|n| checked_write_len(n, self.preamble.len())) {
+            Ok(n_written) => {
+                self.preamble.drain(..n_written);
+                Ok(self.preamble.is_empty())
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the last `PARTIAL_WRITE_BURST` partial writes all happened
+    /// within `PARTIAL_WRITE_BURST_MS`. Only true once per that many.
+    fn is_partial_write_burst(&mut self, now: Instant) -> bool {
+        let (checked_at, checked_count) = self.burst_checked;
+        if self.partial_writes - checked_count < PARTIAL_WRITE_BURST {
+            return false;
+        }
+
+        self.burst_checked = (now, self.partial_writes);
+        now.duration_since(checked_at) < Duration::from_millis(PARTIAL_WRITE_BURST_MS)
+    }
+
+    fn write_buffer<W: Write>(&mut self, dest: &mut W, max_len: usize) -> IOResult<usize> {
+        let n_given = self.buffer_index.min(max_len);
+
+        match dest.write(&self.buffer[..n_given]).and_then(|n| checked_write_len(n, n_given)) {
+            Ok(n_written) => {
+                let left = self.buffer_index - n_written;
+                if left > 0 {
+                    self.buffer.copy_within(n_written..self.buffer_index, 0);
+                    self.partial_writes += 1;
+                    // Normal under backpressure, so not worth more than a trace.
+                    trace!("[conn={}] Partial write of {} bytes, {} left buffered",
+                           self.conn_id,
+                           n_written,
+                           left);
                 }
+                self.buffer_index = left;
+                self.bytes_piped += n_written as u64;
+                Ok(n_written)
             }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e),
         }
-        return 0;
     }
 }
 
+/// A write can't take more bytes than it was given. One that says it did
+/// is treated as a failed write, rather than throwing the buffer's
+/// bookkeeping off.
+fn checked_write_len(n_written: usize, n_given: usize) -> IOResult<usize> {
+    if n_written > n_given {
+        return Err(IOError::new(ErrorKind::Other,
+                                format!("Write of {} bytes reported {} written",
+                                        n_given,
+                                        n_written)));
+    }
+    Ok(n_written)
+}
+
+/// Progress of the non-blocking connect to the backend.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum ConnectState {
+    Connecting,
+    Connected,
+    Failed,
+}
+
 pub struct Connection {
-    points: EndPointList<EndPoint>,
+    id: usize,
+    points: EndPointList<SocketEndPoint>,
+    front_token: IncomingToken,
     backend_token: OutgoingToken,
+    pool: Rc<RefCell<BackendPool>>,
+    target: BackendId,
+    client_addr: SocketAddr,
+    source_addr: Option<SocketAddr>,
+    header_rejected: bool,
+    opened: Instant,
+    last_activity: Instant,
+    access_log: bool,
+    connect_state: ConnectState,
+    connect_started: Instant,
+    /// When the connect to the target finished, until its first byte comes
+    /// in.
+    connected_at: Option<Instant>,
+    tried_targets: Vec<BackendId>,
+    target_failure_reported: bool,
+    metrics: Arc<Metrics>,
+    backend_metrics: Arc<BackendMetrics>,
+    pool_metrics: Arc<PoolMetrics>,
+    proxy_format: Option<HeaderFormat>,
+    /// The address the client connected to, as announced to the backend.
+    proxy_destination: Option<Address>,
+    proxy_header: Option<Vec<u8>>,
+    server_name: Option<String>,
+    /// How long a connect may take before another is raced against it.
+    race_delay: Option<Duration>,
+    race: Option<AuxiliaryToken>,
+    bandwidth: Option<Rc<RefCell<BandwidthCap>>>,
+    detects_stalls: bool,
 }
 
 impl Connection {
-    pub fn new(incoming_stream: TcpStream,
-               outgoing_stream: TcpStream,
-               outgoing_token: OutgoingToken)
-               -> Connection {
-        let mut front = EndPoint::new(incoming_stream);
-        let mut backend = EndPoint::new(outgoing_stream);
-        front.set_peer_stream(&backend.stream);
-        backend.set_peer_stream(&front.stream);
-        Connection {
-            points: EndPointList([front, backend]),
-            backend_token: outgoing_token,
+    pub fn new(incoming: (Stream, SocketAddr),
+               outgoing: (Stream, BackendId),
+               token: IncomingToken,
+               buffer_size: usize,
+               pool: Rc<RefCell<BackendPool>>,
+               metrics: Arc<Metrics>)
+               -> IOResult<Connection> {
+        let (incoming_stream, client_addr) = incoming;
+        let (outgoing_stream, target) = outgoing;
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        set_socket_options(id, &incoming_stream, &pool.borrow());
+        set_socket_options(id, &outgoing_stream, &pool.borrow());
+        let mut front = EndPoint::new(incoming_stream, buffer_size, id);
+        let mut backend = EndPoint::new(outgoing_stream, buffer_size, id);
+        // The target was handed over for this connection, so it goes back
+        // to the pool if there won't be one.
+        if let Err(e) = pair_up(&mut front, &mut backend) {
+            pool.borrow_mut().release_target(&target);
+            return Err(e);
+        }
+        let backend_metrics = metrics.backend(&target);
+        backend_metrics.connection_opened();
+        let pool_metrics = metrics.pool(pool.borrow().name());
+        pool_metrics.connection_opened();
+        let tried_targets = vec![target.clone()];
+
+        let proxy_format = pool.borrow().proxy_header_format();
+        let proxy_destination = proxy_format.as_ref().and_then(|_| front.stream.local_addr().ok());
+
+        let mut connection = Connection {
+            id: id,
+            points: EndPointList::new(front, backend),
+            front_token: token,
+            backend_token: token.outgoing(),
+            pool: pool,
+            target: target,
+            client_addr: client_addr,
+            source_addr: None,
+            header_rejected: false,
+            opened: Instant::now(),
+            last_activity: Instant::now(),
+            access_log: false,
+            connect_state: ConnectState::Connecting,
+            connect_started: Instant::now(),
+            connected_at: None,
+            tried_targets: tried_targets,
+            target_failure_reported: false,
+            metrics: metrics,
+            backend_metrics: backend_metrics,
+            pool_metrics: pool_metrics,
+            proxy_format: proxy_format,
+            proxy_destination: proxy_destination,
+            proxy_header: None,
+            server_name: None,
+            race_delay: None,
+            race: None,
+            bandwidth: None,
+            detects_stalls: false,
+        };
+        connection.queue_proxy_header();
+        Ok(connection)
+    }
+
+    /// Swaps in a freshly dialed backend stream after the previous one
+    /// failed to connect. Bytes already absorbed from the client stay
+    /// buffered and are relayed to the new backend. `tried` lists every
+    /// target dialed so far, including the new one.
+    ///
+    /// Returns the stream it replaced, for the caller to deregister. If the
+    /// streams can't be paired up, the new target goes back to the pool
+    /// and the connection is left as it was.
+    pub fn replace_outgoing(&mut self,
+                            outgoing_stream: Stream,
+                            target: BackendId,
+                            tried: Vec<BackendId>)
+                            -> IOResult<Stream> {
+        let mut backend = EndPoint::new(outgoing_stream, self.buffer_size(), self.id);
+        if let Err(e) = pair_up(&mut self.points[EndPointType::Front], &mut backend) {
+            self.pool.borrow_mut().release_target(&target);
+            return Err(e);
+        }
+
+        self.release_target();
+        set_socket_options(self.id, &backend.stream, &self.pool.borrow());
+        backend.splice_pipe = self.points[EndPointType::Back].splice_pipe.take();
+        backend.throttle = self.points[EndPointType::Back].throttle.take();
+        backend.read_throttled_until = self.points[EndPointType::Back].read_throttled_until;
+        backend.write_throttled_until = self.points[EndPointType::Back].write_throttled_until;
+        let replaced = mem::replace(&mut self.points[EndPointType::Back], backend);
+
+        self.backend_metrics = self.metrics.backend(&target);
+        self.backend_metrics.connection_opened();
+
+        self.target = target;
+        // The new target hasn't seen any of the header yet, and may not
+        // want one at all.
+        self.queue_preamble();
+        self.connect_state = ConnectState::Connecting;
+        self.target_failure_reported = false;
+        self.connect_started = Instant::now();
+        self.connected_at = None;
+        self.tried_targets = tried;
+        Ok(replaced.stream)
+    }
+
+    /// Tells this connection's log lines apart from those of others. Ids
+    /// go up by one for each connection opened.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Bytes read from either side that haven't been written to the other.
+    pub fn buffered_bytes(&self) -> usize {
+        self.points.iter().map(|(_, point)| point.buffered_bytes()).sum()
+    }
+
+    /// Stops reading from both sides, or starts again, without affecting
+    /// the flushing of what is already buffered.
+    pub fn set_read_paused(&mut self, paused: bool) {
+        let now = Instant::now();
+
+        for (_, point) in self.points.iter_mut() {
+            point.read_paused = paused;
+            point.update_timers(now, 0, 0);
+        }
+    }
+
+    /// Caps the bytes read from each side to `bytes_per_sec`, with a
+    /// second's worth allowed in a burst.
+    pub fn limit_bandwidth(&mut self, bytes_per_sec: u64) {
+        let now = Instant::now();
+
+        for (_, point) in self.points.iter_mut() {
+            point.throttle = Some(Throttle::new(bytes_per_sec, now));
+        }
+    }
+
+    /// Keeps track of whether the peers acknowledge what is written to
+    /// them, so one that has vanished without a FIN or reset can be told
+    /// apart from one that is merely quiet.
+    pub fn detect_stalls(&mut self) {
+        self.detects_stalls = true;
+    }
+
+    /// Asks the kernel how much of what was written to each side is still
+    /// unacknowledged, starting a side's stall timer when some is and
+    /// restarting it whenever the peer acknowledges more.
+    pub fn probe_unacked(&mut self, now: Instant) {
+        if !self.detects_stalls {
+            return;
+        }
+
+        for &direction in &[Direction::Upstream, Direction::Downstream] {
+            let written = self.points[direction.source()].bytes_piped;
+            let point = &mut self.points[direction.destination()];
+            let unacked = point.stream.unacked_bytes().unwrap_or(0) as u64;
+            let acked = written.saturating_sub(unacked);
+
+            point.unacked_since = if unacked == 0 {
+                None
+            } else if acked > point.acked {
+                Some(now)
+            } else {
+                point.unacked_since.or(Some(now))
+            };
+            point.acked = acked;
+        }
+    }
+
+    /// The side whose peer has acknowledged none of the bytes written to
+    /// it for `timeout`, if any.
+    pub fn stalled(&self, now: Instant, timeout: Duration) -> Option<EndPointType> {
+        [EndPointType::Front, EndPointType::Back]
+            .iter()
+            .filter_map(|&end_type| {
+                            self.points[end_type]
+                                .unacked_since
+                                .map(|since| (since, end_type))
+                        })
+            .min_by_key(|&(since, _)| since)
+            .filter(|&(since, _)| now.duration_since(since) >= timeout)
+            .map(|(_, end_type)| end_type)
+    }
+
+    /// Has writes to both sides count against `cap`, which every
+    /// connection shares.
+    pub fn share_bandwidth(&mut self, cap: Rc<RefCell<BandwidthCap>>) {
+        self.bandwidth = Some(cap);
+    }
+
+    /// Lets sides whose throttle or share of the bandwidth cap has refilled
+    /// read or write again. Returns whether any did, in which case the
+    /// sockets need to be re-armed.
+    pub fn resume_throttled(&mut self, now: Instant) -> bool {
+        let mut resumed = false;
+        let is_over = |until: Option<Instant>| until.map_or(false, |until| until <= now);
+
+        for (_, point) in self.points.iter_mut() {
+            if is_over(point.read_throttled_until) {
+                point.read_throttled_until = None;
+                resumed = true;
+            }
+            if is_over(point.write_throttled_until) {
+                point.write_throttled_until = None;
+                resumed = true;
+            }
+            point.update_timers(now, 0, 0);
         }
+        resumed
+    }
+
+    pub fn is_read_paused(&self) -> bool {
+        self.points[EndPointType::Front].read_paused
+    }
+
+    /// How many bytes each direction buffers before reading pauses.
+    pub fn buffer_size(&self) -> usize {
+        self.points[EndPointType::Front].buffer.len()
     }
 
     pub fn incoming_ready(&mut self, events: Ready) {
@@ -166,9 +813,103 @@ impl Connection {
     }
 
     pub fn outgoing_ready(&mut self, events: Ready) {
+        let unix_ready = UnixReady::from(events);
+
+        if self.connect_state == ConnectState::Connecting &&
+           (events.is_writable() || unix_ready.is_error() || unix_ready.is_hup()) {
+            self.connect_state = self.connect_result();
+            if self.connect_state == ConnectState::Connected {
+                self.report_connect_time();
+            }
+        }
         self.points[EndPointType::Back].state.insert(events);
     }
 
+    /// Feeds how long the connect took into the target's average, which
+    /// `least_response_time` picks by.
+    fn report_connect_time(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.connect_started);
+
+        if let Some(average) = self.pool.borrow_mut().report_connect_time(&self.target, elapsed) {
+            self.backend_metrics.set_connect_time(average);
+        }
+        self.connected_at = Some(now);
+    }
+
+    /// Feeds the wait for the target's first byte into its average, once
+    /// the byte is in.
+    fn report_first_byte_time(&mut self) {
+        if self.points[EndPointType::Back].bytes_absorbed == 0 {
+            return;
+        }
+
+        if let Some(connected_at) = self.connected_at.take() {
+            let elapsed = connected_at.elapsed();
+            let average = self.pool.borrow_mut().report_first_byte_time(&self.target, elapsed);
+
+            if let Some(average) = average {
+                self.backend_metrics.set_first_byte_time(average);
+            }
+        }
+    }
+
+    /// The first writable event only says the connect has finished, not
+    /// whether it worked: a refused connect is reported the same way, with
+    /// the reason left in the socket's pending error.
+    fn connect_result(&self) -> ConnectState {
+        let stream = &self.points[EndPointType::Back].stream;
+
+        match stream.take_error() {
+            Ok(None) if stream.is_connected() => ConnectState::Connected,
+            Ok(None) => {
+                warn!("[conn={}] Connect to backend {} failed", self.id, self.target);
+                ConnectState::Failed
+            }
+            Ok(Some(e)) | Err(e) => {
+                warn!("[conn={}] Connect to backend {} failed: {}", self.id, self.target, e);
+                ConnectState::Failed
+            }
+        }
+    }
+
+    pub fn is_connect_failed(&self) -> bool {
+        self.connect_state == ConnectState::Failed
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connect_state == ConnectState::Connected
+    }
+
+    /// Has a connect that hasn't finished `delay` after it started raced by
+    /// one to the other IP version of the target's host name.
+    pub fn race_connect_after(&mut self, delay: Duration) {
+        self.race_delay = Some(delay);
+    }
+
+    /// Whether a connect has been slow for long enough to be raced. Only
+    /// true once for each connection.
+    pub fn take_due_race(&mut self, now: Instant) -> bool {
+        match self.race_delay {
+            Some(delay) if self.connect_state == ConnectState::Connecting &&
+                           now.duration_since(self.connect_started) >= delay => {
+                self.race_delay = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Ties the connect racing this connection's to it.
+    pub fn set_race(&mut self, token: AuxiliaryToken) {
+        self.race = Some(token);
+    }
+
+    /// Unties the connect racing this connection's, if one is under way.
+    pub fn take_race(&mut self) -> Option<AuxiliaryToken> {
+        self.race.take()
+    }
+
     pub fn is_outgoing_closed(&self) -> bool {
         let unix_ready = UnixReady::from(self.points[EndPointType::Back].state);
 
@@ -181,76 +922,1848 @@ impl Connection {
         unix_ready.is_error() || unix_ready.is_hup()
     }
 
-    pub fn incoming_stream<'a>(&'a self) -> &'a TcpStream {
+    pub fn incoming_stream<'a>(&'a self) -> &'a Stream {
         &self.points[EndPointType::Front].stream
     }
 
-    pub fn outgoing_stream<'a>(&'a self) -> &'a TcpStream {
+    pub fn outgoing_stream<'a>(&'a self) -> &'a Stream {
         &self.points[EndPointType::Back].stream
     }
 
+    /// Readiness to register an endpoint's socket for: readable while it
+    /// has room to absorb, writable only while its peer has bytes queued
+    /// for it. Asking for more than that wakes the event loop for nothing.
+    /// A side is watched for writability for as long as its peer has bytes
+    /// queued for it, including what a partial write left over, so the
+    /// next writable event picks up where the write stopped.
+    fn interest(&self, end_type: EndPointType, peer_type: EndPointType) -> Ready {
+        let point = &self.points[end_type];
+        let mut interest = Ready::empty();
+
+        if point.wants_read() {
+            interest.insert(Ready::readable());
+        }
+        let peer = &self.points[peer_type];
+        if peer.wants_write() && peer.write_throttled_until.is_none() && !point.write_closed {
+            interest.insert(Ready::writable());
+        }
+        interest
+    }
+
+    pub fn incoming_interest(&self) -> Ready {
+        self.interest(EndPointType::Front, EndPointType::Back)
+    }
+
+    pub fn outgoing_interest(&self) -> Ready {
+        let interest = self.interest(EndPointType::Back, EndPointType::Front);
+
+        // Writability is how a pending connect reports completion.
+        if self.connect_state == ConnectState::Connected {
+            interest
+        } else {
+            interest | Ready::writable()
+        }
+    }
+
+    pub fn incoming_token(&self) -> IncomingToken {
+        self.front_token
+    }
+
     pub fn outgoing_token(&self) -> OutgoingToken {
         self.backend_token
     }
 
-    pub fn tick(&mut self) -> bool {
-        let mut sended = false;
-        let need_pipe: Vec<bool> = self.points
-            .0
-            .iter_mut()
-            .map(|point| {
-                if point.state.is_readable() {
-                    point.absorb();
-                    point.state.remove(Ready::readable());
-                }
-                if point.state.is_writable() {
-                    point.state.remove(Ready::writable());
-                    true
-                } else {
-                    false
-                }
-            })
-            .rev()
-            .collect();
+    pub fn pool(&self) -> &Rc<RefCell<BackendPool>> {
+        &self.pool
+    }
+
+    pub fn target(&self) -> &BackendId {
+        &self.target
+    }
+
+    pub fn client_addr(&self) -> &SocketAddr {
+        &self.client_addr
+    }
+
+    /// The original client address, as reported by a PROXY protocol header
+    /// if one was received, otherwise the peer address.
+    pub fn source_addr(&self) -> &SocketAddr {
+        self.source_addr.as_ref().unwrap_or(&self.client_addr)
+    }
+
+    /// Queues bytes that were read from the client before the connection
+    /// was set up, as if `absorb` had read them. They must fit the buffer.
+    pub fn prefill_incoming(&mut self, bytes: &[u8]) {
+        let front = &mut self.points[EndPointType::Front];
+
+        front.buffer[..bytes.len()].copy_from_slice(bytes);
+        front.buffer_index = bytes.len();
+        front.bytes_absorbed += bytes.len() as u64;
+    }
+
+    /// Records the host name the client was routed on, for backends that
+    /// are told it in their PROXY protocol header. Must come before
+    /// anything is relayed.
+    pub fn set_server_name(&mut self, name: &str) {
+        self.server_name = Some(name.to_owned());
+        self.queue_proxy_header();
+    }
 
-        for (index, point) in self.points.0.iter_mut().enumerate() {
-            if need_pipe[index] {
-                sended |= (*point).pipe_to_peer() > 0;
+    /// Makes the connection read a PROXY protocol header from the client
+    /// before relaying anything. The header is stripped, and its addresses
+    /// replace the peer's in any header sent on to the backend.
+    pub fn expect_proxy_header(&mut self) {
+        let front = &mut self.points[EndPointType::Front];
+
+        front.awaiting_proxy_header = true;
+        front.preamble.clear();
+    }
+
+    /// Tears the connection down. Both sockets are deregistered and shut
+    /// down in both directions, so the peers see the close straight away
+    /// rather than once the last duplicate of each socket is dropped.
+    /// Bytes the kernel has already taken are still sent ahead of the FIN.
+    ///
+    /// With `reset_on_close` the shutdown is left out, so the sockets are
+    /// reset as they are dropped and the peers see a RST instead, with
+    /// unsent bytes thrown away. A side that was already half-closed, as
+    /// when a target is removed with `force`, got its FIN first.
+    pub fn close(&mut self, poll: &Poll) {
+        let resets = self.pool.borrow().resets_on_close();
+        self.pool_metrics.connection_closed();
+
+        for (_, point) in self.points.iter_mut() {
+            if let Err(e) = poll.deregister(&point.stream) {
+                debug!("[conn={}] Deregistering socket failed: {}", self.id, e);
+            }
+            point.read_closed = true;
+            point.write_closed = true;
+            if resets {
+                continue;
+            }
+            match point.stream.shutdown(Shutdown::Both) {
+                Ok(()) => {}
+                // Already reset by the peer.
+                Err(ref e) if e.kind() == ErrorKind::NotConnected => {}
+                Err(e) => debug!("[conn={}] Shutdown failed: {}", self.id, e),
             }
         }
-        sended
     }
-}
 
-impl TokenType {
-    pub fn from_raw_token(t: Token) -> TokenType {
-        let i = usize::from(t);
+    /// Has the driver log a summary line when this connection closes.
+    pub fn enable_access_log(&mut self) {
+        self.access_log = true;
+    }
 
-        match i & 3 {
-            0 => TokenType::Listener(ListenerToken(i >> 2)),
-            1 => TokenType::Incoming(IncomingToken(i >> 2)),
-            2 => TokenType::Outgoing(OutgoingToken(i >> 2)),
-            _ => unreachable!(),
+    pub fn logs_access(&self) -> bool {
+        self.access_log
+    }
+
+    /// What the access log says about the connection: client and backend,
+    /// bytes relayed each way and how long it was open.
+    pub fn access_log_entry(&self, now: Instant) -> AccessLogEntry {
+        let stats = self.stats(now);
+
+        AccessLogEntry {
+            time: SystemTime::now(),
+            conn: self.id,
+            client: *self.source_addr(),
+            backend: stats.backend_addr,
+            bytes_up: stats.bytes_up,
+            bytes_down: stats.bytes_down,
+            duration_ms: stats.duration.as_secs() * 1000 +
+                         stats.duration.subsec_nanos() as u64 / 1_000_000,
         }
     }
-}
 
-impl ListenerToken {
-    pub fn as_raw_token(self) -> Token {
-        Token(self.0 << 2)
+    /// What the connection has relayed so far, and for how long.
+    pub fn stats(&self, now: Instant) -> ConnectionStats {
+        ConnectionStats {
+            backend_addr: self.target.clone(),
+            bytes_up: self.points[EndPointType::Front].bytes_piped,
+            bytes_down: self.points[EndPointType::Back].bytes_piped,
+            duration: now.duration_since(self.opened),
+        }
     }
-}
 
-impl IncomingToken {
-    pub fn as_raw_token(self) -> Token {
-        Token((self.0 << 2) + 1)
+    pub fn info(&self, now: Instant) -> ConnectionInfo {
+        let stats = self.stats(now);
+
+        ConnectionInfo {
+            id: self.id,
+            client: *self.source_addr(),
+            backend: stats.backend_addr,
+            bytes_up: stats.bytes_up,
+            bytes_down: stats.bytes_down,
+            buffered: self.buffered_bytes(),
+            idle: self.idle_for(now),
+        }
     }
-}
 
-impl OutgoingToken {
-    pub fn as_raw_token(self) -> Token {
-        Token((self.0 << 2) + 2)
+    /// Relays through kernel pipes with `splice(2)` instead of copying
+    /// through the buffers, where the platform allows. Bytes still have to
+    /// pass through the buffers when they are looked at, as with PROXY
+    /// protocol headers from the client.
+    pub fn use_splice(&mut self) {
+        for (_, point) in self.points.iter_mut() {
+            match SplicePipe::new(point.buffer.len()) {
+                Ok(pipe) => point.splice_pipe = Some(pipe),
+                Err(e) => {
+                    debug!("[conn={}] Relaying through buffers, splice is unavailable: {}",
+                           self.id,
+                           e);
+                    return;
+                }
+            }
+        }
     }
-}
 
-create_trait!(ListenerToken, IncomingToken, OutgoingToken);
+    /// Builds the PROXY protocol header from what is known about the client
+    /// so far and queues it ahead of the client's bytes, in place of any
+    /// header queued before.
+    fn queue_proxy_header(&mut self) {
+        let header = match (&self.proxy_format, &self.proxy_destination) {
+            (Some(format), Some(destination)) => {
+                format.header(self.source_addr(),
+                              destination,
+                              self.server_name.as_deref(),
+                              self.id)
+            }
+            _ => return,
+        };
+
+        self.proxy_header = Some(header);
+        self.queue_preamble();
+    }
+
+    /// Puts the PROXY protocol header ahead of the client's bytes if the
+    /// current target is sent one, in place of whatever was there.
+    fn queue_preamble(&mut self) {
+        let preamble = match self.proxy_header {
+            Some(ref header) if self.pool.borrow().sends_proxy_header_to(&self.target) => {
+                header.clone()
+            }
+            _ => Vec::new(),
+        };
+
+        self.points[EndPointType::Front].preamble = preamble;
+    }
+
+    fn consume_proxy_header(&mut self) {
+        let front = &mut self.points[EndPointType::Front];
+
+        if !front.awaiting_proxy_header {
+            return;
+        }
+
+        match proxy_protocol::parse_header(&front.buffer[..front.buffer_index]) {
+            Parsed::Complete(header) => {
+                front.buffer.copy_within(header.len..front.buffer_index, 0);
+                front.buffer_index -= header.len;
+                front.awaiting_proxy_header = false;
+
+                if let Some((source, destination)) = header.addrs {
+                    debug!("[conn={}] Connection from {} is proxied for {}",
+                           self.id,
+                           self.client_addr,
+                           source);
+                    self.source_addr = Some(source);
+                    if self.proxy_destination.is_some() {
+                        self.proxy_destination = Some(Address::Tcp(destination));
+                    }
+                }
+                self.queue_proxy_header();
+            }
+            // A header that fills the buffer or is cut short by EOF will
+            // never complete.
+            Parsed::Incomplete if front.wants_read() => {}
+            Parsed::Incomplete | Parsed::Invalid => {
+                warn!("[conn={}] Closing connection from {}: malformed PROXY protocol header",
+                      self.id,
+                      self.client_addr);
+                self.header_rejected = true;
+            }
+        }
+    }
+
+    /// Every target this connection has been dialed to, in order. The
+    /// last one is the current target.
+    pub fn tried_targets(&self) -> &[BackendId] {
+        &self.tried_targets
+    }
+
+    /// Records targets that failed before this connection was set up.
+    pub fn set_tried_targets(&mut self, tried: Vec<BackendId>) {
+        self.tried_targets = tried;
+    }
+
+    pub fn connect_attempts(&self) -> u32 {
+        self.tried_targets.len() as u32
+    }
+
+    pub fn is_connect_timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        self.connect_state == ConnectState::Connecting &&
+        now.duration_since(self.connect_started) >= timeout
+    }
+
+    pub fn release_target(&self) {
+        self.pool.borrow_mut().release_target(&self.target);
+        self.backend_metrics.connection_closed();
+    }
+
+    /// Whether the connection has relayed its backend's
+    /// `max_connection_bytes` and still reads from the client.
+    pub fn is_over_byte_limit(&self) -> bool {
+        let limit = match self.pool.borrow().max_connection_bytes() {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let front = &self.points[EndPointType::Front];
+        let back = &self.points[EndPointType::Back];
+
+        front.bytes_piped + back.bytes_piped >= limit && !front.read_closed
+    }
+
+    /// Winds down a connection over its byte limit as though the client
+    /// had closed its side: nothing more is read from it, and the target
+    /// gets a FIN once what was read is through. The target then finishes
+    /// its answer and closes in turn, so the client still gets all of it.
+    /// Returns whether the connection can be removed right away.
+    pub fn close_at_byte_limit(&mut self) -> bool {
+        info!("[conn={}] Closing after relaying {} bytes to and from {}",
+              self.id,
+              self.points[EndPointType::Front].bytes_piped +
+              self.points[EndPointType::Back].bytes_piped,
+              self.target);
+        self.points[EndPointType::Front].read_closed = true;
+        if self.points[EndPointType::Front].is_drained() {
+            self.points[EndPointType::Back].shutdown_write();
+        }
+        self.should_close(false)
+    }
+
+    /// Whether the target reset or closed the connection before anything
+    /// was relayed either way. The client can then be sent on to another
+    /// target without noticing, as after a failed connect. Once a byte has
+    /// gone through this is never true again, so nothing is sent twice.
+    pub fn is_early_failure(&self) -> bool {
+        let front = &self.points[EndPointType::Front];
+        let back = &self.points[EndPointType::Back];
+
+        self.is_connected() && !self.header_rejected && !front.is_errored() &&
+        front.bytes_piped == 0 && back.bytes_absorbed == 0 &&
+        (back.is_errored() || back.read_closed || self.is_outgoing_closed())
+    }
+
+    /// A backend that refused the connect, or hung up before a single byte
+    /// has moved to or from it, most likely dropped the connection.
+    pub fn is_outgoing_failed(&self) -> bool {
+        self.is_connect_failed() ||
+        self.is_outgoing_closed() && self.points[EndPointType::Back].bytes_absorbed == 0 &&
+        self.points[EndPointType::Front].bytes_piped == 0
+    }
+
+    /// Counts a failure against the current target, once. Returns false if
+    /// it had already been reported.
+    pub fn report_target_failure(&mut self) -> bool {
+        if self.target_failure_reported {
+            return false;
+        }
+        self.target_failure_reported = true;
+        self.pool.borrow_mut().report_failure(&self.target);
+        true
+    }
+
+    /// A connection is done once both directions have been half-closed, or
+    /// as soon as either socket reports an error.
+    pub fn is_finished(&self) -> bool {
+        self.is_errored() || self.points.iter().all(|(_, point)| point.write_closed)
+    }
+
+    /// Whether the connection can be removed after a tick. A failed socket
+    /// ends it at once; otherwise it waits for both directions to close.
+    /// Either way, nothing must be left that could still be written.
+    pub fn should_close(&mut self, data_sent: bool) -> bool {
+        (self.is_errored() || !data_sent && self.is_finished()) && !self.flush_pending()
+    }
+
+    pub fn is_errored(&self) -> bool {
+        self.header_rejected || self.points.iter().any(|(_, point)| point.is_errored())
+    }
+
+    /// Writes whatever is still buffered towards a side that can take it,
+    /// so tearing down doesn't truncate a response. Bytes headed for a
+    /// socket that has errored are given up on. Returns whether anything is
+    /// left to write.
+    pub fn flush_pending(&mut self) -> bool {
+        let mut pending = false;
+
+        for &direction in &[Direction::Upstream, Direction::Downstream] {
+            let (from, to) = (direction.source(), direction.destination());
+            if self.points[to].is_errored() || self.points[to].write_closed {
+                continue;
+            }
+            while self.points[from].wants_write() && self.pipe(direction) > 0 {}
+            pending |= self.points[from].wants_write();
+        }
+        pending
+    }
+
+    /// Winds the connection down as though both peers had sent EOF: nothing
+    /// more is read, what is buffered is still written out, and each side
+    /// is half-closed once its bytes are through, so the peers see a FIN
+    /// rather than a reset. Returns whether it can be removed right away.
+    pub fn begin_close(&mut self) -> bool {
+        if !self.is_connected() {
+            return true;
+        }
+
+        for (_, point) in self.points.iter_mut() {
+            point.read_closed = true;
+        }
+        self.flush_pending();
+        for &direction in &[Direction::Upstream, Direction::Downstream] {
+            if self.points[direction.source()].is_drained() {
+                self.points[direction.destination()].shutdown_write();
+            }
+        }
+        self.should_close(false)
+    }
+
+    /// Moves bytes one way through the connection without waiting for
+    /// readiness: reads what the source has for it, then writes what the
+    /// destination takes, forwarding an EOF once everything before it is
+    /// through. Returns how many bytes reached the destination.
+    pub fn relay(&mut self, direction: Direction) -> IOResult<usize> {
+        try!(self.points[direction.source()].absorb());
+        self.consume_proxy_header();
+        self.report_first_byte_time();
+
+        let n_piped = try!(self.write_through(direction));
+        if self.points[direction.source()].is_drained() {
+            self.points[direction.destination()].shutdown_write();
+        }
+        Ok(n_piped)
+    }
+
+    /// Relays buffered bytes from one side to the other. A write error
+    /// marks the receiving side as failed and counts as nothing relayed.
+    fn pipe(&mut self, direction: Direction) -> usize {
+        match self.write_through(direction) {
+            Ok(n_piped) => n_piped,
+            Err(e) => {
+                self.metrics.write_failed();
+                self.points[direction.destination()].fail("Writing", e);
+                0
+            }
+        }
+    }
+
+    fn write_through(&mut self, direction: Direction) -> IOResult<usize> {
+        let from = direction.source();
+        let now = Instant::now();
+        let max_len = match self.bandwidth {
+            Some(ref cap) => cap.borrow_mut().share(now),
+            None => usize::MAX,
+        };
+        if max_len == 0 {
+            self.points[from].write_throttled_until =
+                self.bandwidth.as_ref().map(|cap| cap.borrow().resumes_at());
+            return Ok(0);
+        }
+
+        let n_piped = try!(self.points[from].pipe_to_peer(max_len));
+        if self.points[from].is_partial_write_burst(now) {
+            self.report_partial_write_burst(direction);
+        }
+        match direction {
+            Direction::Upstream => {
+                self.metrics.relayed_upstream(n_piped);
+                self.backend_metrics.relayed_upstream(n_piped);
+                self.pool_metrics.relayed_upstream(n_piped);
+            }
+            Direction::Downstream => {
+                self.metrics.relayed_downstream(n_piped);
+                self.backend_metrics.relayed_downstream(n_piped);
+                self.pool_metrics.relayed_downstream(n_piped);
+            }
+        }
+        if let Some(ref cap) = self.bandwidth {
+            cap.borrow_mut().take(n_piped, now);
+        }
+        Ok(n_piped)
+    }
+
+    fn report_partial_write_burst(&self, direction: Direction) {
+        let partial_writes = self.points[direction.source()].partial_writes;
+
+        match direction {
+            Direction::Upstream => {
+                warn!("[conn={}] Backend {} is slow to take bytes, {} partial writes so far",
+                      self.id,
+                      self.target,
+                      partial_writes);
+                self.backend_metrics.partial_write_burst();
+            }
+            Direction::Downstream => {
+                debug!("[conn={}] Client {} is slow to take bytes, {} partial writes so far",
+                       self.id,
+                       self.client_addr,
+                       partial_writes);
+            }
+        }
+    }
+
+    /// Whether the client has spent `timeout` on its opening handshake,
+    /// which here is the PROXY protocol header, without finishing it.
+    pub fn is_handshake_timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        self.points[EndPointType::Front].awaiting_proxy_header &&
+        now.duration_since(self.opened) >= timeout
+    }
+
+    pub fn idle_for(&self, now: Instant) -> Duration {
+        now.duration_since(self.last_activity)
+    }
+
+    pub fn age(&self, now: Instant) -> Duration {
+        now.duration_since(self.opened)
+    }
+
+    /// The side that has been ready to read for `timeout` without sending a
+    /// byte, if any. If both have, the one that has waited longer.
+    pub fn read_timed_out(&self, now: Instant, timeout: Duration) -> Option<EndPointType> {
+        [EndPointType::Front, EndPointType::Back]
+            .iter()
+            .filter_map(|&end_type| {
+                            self.points[end_type]
+                                .read_waiting_since
+                                .map(|since| (since, end_type))
+                        })
+            .min_by_key(|&(since, _)| since)
+            .filter(|&(since, _)| now.duration_since(since) >= timeout)
+            .map(|(_, end_type)| end_type)
+    }
+
+    /// The side that has had bytes waiting for it for `timeout` without
+    /// taking any of them, if any. If both have, the one that has waited
+    /// longer.
+    pub fn write_timed_out(&self, now: Instant, timeout: Duration) -> Option<EndPointType> {
+        // Bytes waiting in one endpoint's buffer are written to the other.
+        [(EndPointType::Front, EndPointType::Back), (EndPointType::Back, EndPointType::Front)]
+            .iter()
+            .filter_map(|&(from, to)| {
+                            self.points[from]
+                                .write_waiting_since
+                                .map(|since| (since, to))
+                        })
+            .min_by_key(|&(since, _)| since)
+            .filter(|&(since, _)| now.duration_since(since) >= timeout)
+            .map(|(_, to)| to)
+    }
+
+    /// The soonest any of `timeouts` could run out for this connection, as
+    /// its timers stand now.
+    pub fn next_deadline(&self, timeouts: &TimeoutConfig) -> Option<Instant> {
+        let after = |since: Option<Instant>, timeout_ms: Option<u64>| {
+            since.and_then(|since| timeout_ms.map(|ms| since + Duration::from_millis(ms)))
+        };
+        let connecting = if self.connect_state == ConnectState::Connecting {
+            Some(self.connect_started)
+        } else {
+            None
+        };
+        let reading = self.points.iter().filter_map(|(_, point)| point.read_waiting_since).min();
+        let writing = self.points.iter().filter_map(|(_, point)| point.write_waiting_since).min();
+        let handshaking = if self.points[EndPointType::Front].awaiting_proxy_header {
+            Some(self.opened)
+        } else {
+            None
+        };
+        let stalled = self.points.iter().filter_map(|(_, point)| point.unacked_since).min();
+        let racing = self.race_delay.and_then(|delay| connecting.map(|since| since + delay));
+        let throttled = self.points
+            .0
+            .iter()
+            .flat_map(|point| point.read_throttled_until.into_iter().chain(point.write_throttled_until))
+            .min();
+
+        [after(connecting, timeouts.connect_ms),
+         after(handshaking, timeouts.handshake_ms),
+         after(Some(self.last_activity), timeouts.idle_ms),
+         after(reading, timeouts.read_ms),
+         after(writing, timeouts.write_ms),
+         after(Some(self.opened), timeouts.max_lifetime_ms),
+         after(stalled, timeouts.stall_ms),
+         racing,
+         throttled]
+            .iter()
+            .filter_map(|&deadline| deadline)
+            .min()
+    }
+
+    pub fn tick(&mut self) -> bool {
+        let mut n_read = EndPointList::new(0, 0);
+        let mut n_written = EndPointList::new(0, 0);
+        let mut writable = EndPointList::new(false, false);
+
+        for (end_type, point) in self.points.iter_mut() {
+            if point.state.is_readable() {
+                match point.absorb() {
+                    Ok(n) => n_read[end_type] = n,
+                    Err(e) => {
+                        self.metrics.read_failed();
+                        point.fail("Reading", e)
+                    }
+                }
+                point.state.remove(Ready::readable());
+            }
+            if point.state.is_writable() {
+                point.state.remove(Ready::writable());
+                writable[end_type] = true;
+            }
+        }
+
+        self.consume_proxy_header();
+        self.report_first_byte_time();
+
+        // Bytes going one way are written when their destination is
+        // writable. The counts are kept by the endpoint whose buffer they
+        // come out of.
+        for &direction in &[Direction::Upstream, Direction::Downstream] {
+            if writable[direction.destination()] {
+                n_written[direction.source()] = self.pipe(direction);
+            }
+        }
+
+        // Forward an EOF only once everything read before it has been written
+        // to the peer, so no bytes are lost to the shutdown. The client isn't
+        // told about a target that closed before relaying anything, since it
+        // may yet be sent to another.
+        let early_failure = self.is_early_failure();
+        for &direction in &[Direction::Upstream, Direction::Downstream] {
+            if direction == Direction::Downstream && early_failure {
+                continue;
+            }
+            if self.points[direction.source()].is_drained() {
+                self.points[direction.destination()].shutdown_write();
+            }
+        }
+
+        let now = Instant::now();
+        self.probe_unacked(now);
+        for (end_type, point) in self.points.iter_mut() {
+            point.update_timers(now, n_read[end_type], n_written[end_type]);
+        }
+
+        let sended = n_written.iter().any(|(_, &n)| n > 0);
+        if sended || n_read.iter().any(|(_, &n)| n > 0) {
+            trace!("[conn={}] Read {} bytes from the client and {} from the backend, \
+                    wrote {} to the backend and {} to the client",
+                   self.id,
+                   n_read[EndPointType::Front],
+                   n_read[EndPointType::Back],
+                   n_written[EndPointType::Front],
+                   n_written[EndPointType::Back]);
+            self.last_activity = now;
+        }
+        sended
+    }
+}
+
+impl TokenType {
+    pub fn from_raw_token(t: Token) -> TokenType {
+        if t == SHUTDOWN_TOKEN {
+            return TokenType::Shutdown;
+        }
+        if t == INSPECT_TOKEN {
+            return TokenType::Inspect;
+        }
+        let i = usize::from(t);
+
+        match i & 3 {
+            0 => TokenType::Listener(ListenerToken(i >> 2)),
+            1 => TokenType::Incoming(IncomingToken(i >> 2)),
+            2 => TokenType::Outgoing(OutgoingToken(i >> 2)),
+            3 => TokenType::Auxiliary(AuxiliaryToken(i >> 2)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The slot in the driver's connection slab that a token refers to. Both
+/// sockets of a connection share the slot, so either of their tokens gives
+/// the same index. Listener and auxiliary tokens belong to no connection.
+pub fn connection_index(token: TokenType) -> Option<usize> {
+    match token {
+        TokenType::Incoming(IncomingToken(i)) |
+        TokenType::Outgoing(OutgoingToken(i)) => Some(i),
+        TokenType::Listener(_) |
+        TokenType::Auxiliary(_) |
+        TokenType::Shutdown |
+        TokenType::Inspect => None,
+    }
+}
+
+/// Fails if a slab with `n_slots` slots would hand out indices that don't
+/// fit in a token next to its kind.
+pub fn check_token_capacity(n_slots: usize, what: &str) -> IOResult<()> {
+    if n_slots > MAX_TOKEN_INDEX + 1 {
+        return Err(IOError::new(ErrorKind::InvalidInput,
+                                format!("Can't have more than {} {}, asked for {}",
+                                        MAX_TOKEN_INDEX + 1,
+                                        what,
+                                        n_slots)));
+    }
+    Ok(())
+}
+
+/// Packs an index and a kind into a raw token. Slab capacities are checked
+/// against `MAX_TOKEN_INDEX` up front, so an index past it is a bug, and
+/// is stopped here before it can spill into the kind bits.
+fn raw_token(index: usize, kind: usize) -> Token {
+    assert!(index <= MAX_TOKEN_INDEX, "Token index {} is out of range", index);
+    Token((index << 2) | kind)
+}
+
+impl ListenerToken {
+    pub fn as_raw_token(self) -> Token {
+        raw_token(self.0, 0)
+    }
+}
+
+impl IncomingToken {
+    pub fn as_raw_token(self) -> Token {
+        raw_token(self.0, 1)
+    }
+
+    /// The token of the backend socket of the same connection.
+    pub fn outgoing(self) -> OutgoingToken {
+        OutgoingToken(connection_index(TokenType::Incoming(self)).unwrap())
+    }
+}
+
+impl OutgoingToken {
+    pub fn as_raw_token(self) -> Token {
+        raw_token(self.0, 2)
+    }
+
+    /// The token of the client socket of the same connection.
+    pub fn incoming(self) -> IncomingToken {
+        IncomingToken(connection_index(TokenType::Outgoing(self)).unwrap())
+    }
+}
+
+impl AuxiliaryToken {
+    pub fn as_raw_token(self) -> Token {
+        raw_token(self.0, 3)
+    }
+}
+
+create_trait!(ListenerToken, IncomingToken, OutgoingToken, AuxiliaryToken);
+
+/// Has each endpoint keep a duplicate of the other's socket to write to.
+/// The front one is left alone unless the back one got its peer first, so
+/// a front already relaying to another target still is on failure.
+fn pair_up<S: EndPointIo>(front: &mut EndPoint<S>, back: &mut EndPoint<S>) -> IOResult<()> {
+    try!(back.set_peer_stream(&front.stream));
+    front.set_peer_stream(&back.stream)
+}
+
+fn set_socket_options(conn_id: usize, stream: &Stream, pool: &BackendPool) {
+    if let Err(e) = stream.set_nodelay(pool.tcp_nodelay()) {
+        debug!("[conn={}] Could not set TCP_NODELAY: {}", conn_id, e);
+    }
+
+    if pool.resets_on_close() {
+        if let Err(e) = stream.set_linger(Some(Duration::from_secs(0))) {
+            debug!("[conn={}] Could not set SO_LINGER: {}", conn_id, e);
+        }
+    }
+
+    if let Some(keepalive) = pool.keepalive() {
+        let idle = Duration::from_millis(keepalive.idle_ms);
+        let interval = keepalive.interval_ms.map(Duration::from_millis);
+
+        if let Err(e) = stream.set_keepalive(idle, interval) {
+            debug!("[conn={}] Could not set TCP keepalive: {}", conn_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{EndPoint, EndPointIo, EndPointList, EndPointType, Connection, Direction,
+                SocketEndPoint, TokenType, ListenerToken, IncomingToken, OutgoingToken,
+                AuxiliaryToken, MAX_TOKEN_INDEX, PARTIAL_WRITE_BURST, PARTIAL_WRITE_BURST_MS,
+                check_token_capacity, connection_index, pair_up};
+
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write, ErrorKind, Result as IOResult, Error as IOError};
+    use std::mem;
+    use std::net::{self, Shutdown, TcpListener};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use mio::{Poll, PollOpt, Ready, Token};
+    use mio::unix::UnixReady;
+    use mio::tcp::TcpStream;
+
+    use libc;
+
+    use access_log::AccessLogFormat;
+    use backend::{Backend, BackendPool};
+    use config::{BackendConfig, KeepaliveConfig};
+    use metrics::Metrics;
+    use proxy_protocol;
+    use selector::RoundRobin;
+    use stream::{Address, Stream};
+
+    struct PartialWriter {
+        written: Vec<u8>,
+        max_write: usize,
+    }
+
+    impl Write for PartialWriter {
+        fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+            let n = buf.len().min(self.max_write);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> IOResult<()> {
+            Ok(())
+        }
+    }
+
+    /// Claims to have written more than it was given.
+    struct OverreportingWriter;
+
+    impl Write for OverreportingWriter {
+        fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+            Ok(buf.len() + 1)
+        }
+
+        fn flush(&mut self) -> IOResult<()> {
+            Ok(())
+        }
+    }
+
+    /// Stands in for a socket. Reads play back a script of results, with
+    /// `WouldBlock` once it runs out, and each write takes no more than the
+    /// next entry of a script of its own allows. Clones share the scripts,
+    /// the way clones of a socket share the socket.
+    #[derive(Clone, Default)]
+    struct ScriptedIo {
+        reads: Rc<RefCell<VecDeque<IOResult<Vec<u8>>>>>,
+        write_limits: Rc<RefCell<VecDeque<usize>>>,
+        written: Rc<RefCell<Vec<u8>>>,
+        clone_fails: bool,
+    }
+
+    impl ScriptedIo {
+        fn script_read(&self, result: IOResult<&[u8]>) {
+            self.reads.borrow_mut().push_back(result.map(|bytes| bytes.to_vec()));
+        }
+
+        fn script_write(&self, max_len: usize) {
+            self.write_limits.borrow_mut().push_back(max_len);
+        }
+    }
+
+    impl Read for ScriptedIo {
+        fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+            let mut reads = self.reads.borrow_mut();
+
+            match reads.pop_front() {
+                Some(Ok(mut bytes)) => {
+                    let n = bytes.len().min(buf.len());
+                    buf[..n].copy_from_slice(&bytes[..n]);
+                    if n < bytes.len() {
+                        reads.push_front(Ok(bytes.split_off(n)));
+                    }
+                    Ok(n)
+                }
+                Some(Err(e)) => Err(e),
+                None => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl Write for ScriptedIo {
+        fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+            match self.write_limits.borrow_mut().pop_front() {
+                Some(max_len) => {
+                    let n = buf.len().min(max_len);
+                    self.written.borrow_mut().extend_from_slice(&buf[..n]);
+                    Ok(n)
+                }
+                None => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+
+        fn flush(&mut self) -> IOResult<()> {
+            Ok(())
+        }
+    }
+
+    impl EndPointIo for ScriptedIo {
+        fn try_clone(&self) -> IOResult<ScriptedIo> {
+            if self.clone_fails {
+                return Err(IOError::from_raw_os_error(libc::EMFILE));
+            }
+            Ok(self.clone())
+        }
+
+        fn shutdown(&self, _how: Shutdown) -> IOResult<()> {
+            Ok(())
+        }
+
+        fn raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    /// An endpoint on a scripted socket, and the scripted peer it writes to.
+    fn scripted_endpoint(buffer_size: usize) -> (EndPoint<ScriptedIo>, ScriptedIo, ScriptedIo) {
+        let (io, peer) = (ScriptedIo::default(), ScriptedIo::default());
+        let mut endpoint = EndPoint::new(io.clone(), buffer_size, 0);
+        endpoint.set_peer_stream(&peer).unwrap();
+
+        (endpoint, io, peer)
+    }
+
+    /// Makes closing the stream send a reset instead of a FIN, as when a
+    /// peer crashes.
+    pub fn reset_on_close(stream: &net::TcpStream) {
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        let result = unsafe {
+            libc::setsockopt(stream.as_raw_fd(),
+                             libc::SOL_SOCKET,
+                             libc::SO_LINGER,
+                             &linger as *const _ as *const libc::c_void,
+                             mem::size_of::<libc::linger>() as libc::socklen_t)
+        };
+        assert_eq!(result, 0);
+    }
+
+    fn socket_option(stream: &Stream, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let fd = stream.as_raw_fd();
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(fd,
+                             level,
+                             name,
+                             &mut value as *mut _ as *mut libc::c_void,
+                             &mut len)
+        };
+        assert_eq!(result, 0);
+
+        value
+    }
+
+    fn nodelay(stream: &Stream) -> bool {
+        socket_option(stream, libc::IPPROTO_TCP, libc::TCP_NODELAY) != 0
+    }
+
+    fn keepalive(stream: &Stream) -> bool {
+        socket_option(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE) != 0
+    }
+
+    fn make_endpoint(buffer_size: usize) -> SocketEndPoint {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+
+        EndPoint::new(Stream::Tcp(stream), buffer_size, 0)
+    }
+
+    /// Returns a non-blocking stream and the blocking peer it is connected to.
+    fn socket_pair() -> (Stream, net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+
+        (Stream::Tcp(stream), peer)
+    }
+
+    fn make_connection(buffer_size: usize) -> (Connection, net::TcpStream, net::TcpStream) {
+        make_connection_with(buffer_size, &Default::default())
+    }
+
+    fn make_connection_with(buffer_size: usize,
+                            config: &BackendConfig)
+                            -> (Connection, net::TcpStream, net::TcpStream) {
+        let (incoming, client) = socket_pair();
+        let (outgoing, server) = socket_pair();
+        let target = Address::Tcp(server.local_addr().unwrap());
+        let client_addr = client.local_addr().unwrap();
+        let pool = BackendPool::new(Vec::new(), Box::new(RoundRobin::new()), config);
+        let connection = Connection::new((incoming, client_addr),
+                                         (outgoing, target),
+                                         IncomingToken(0),
+                                         buffer_size,
+                                         pool,
+                                         Arc::new(Metrics::new()))
+            .unwrap();
+
+        (connection, client, server)
+    }
+
+    fn pump(connection: &mut Connection) {
+        for _ in 0..20 {
+            connection.incoming_ready(Ready::readable() | Ready::writable());
+            connection.outgoing_ready(Ready::readable() | Ready::writable());
+            connection.tick();
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn end_point_lists_iterate_with_their_types() {
+        let mut list = EndPointList::new("client", "backend");
+        list[EndPointType::Back] = "server";
+
+        assert_eq!(list.iter().collect::<Vec<_>>(),
+                   vec![(EndPointType::Front, &"client"), (EndPointType::Back, &"server")]);
+        for (end_type, value) in list.iter_mut() {
+            if end_type == EndPointType::Front {
+                *value = "peer";
+            }
+        }
+        assert_eq!(list[EndPointType::Front], "peer");
+    }
+
+    #[test]
+    fn access_log_line_sums_up_the_connection() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+        client.write_all(b"request").unwrap();
+        server.write_all(b"response").unwrap();
+        pump(&mut connection);
+
+        let opened = connection.opened;
+        let entry = connection.access_log_entry(opened + Duration::from_millis(1500));
+        assert_eq!(AccessLogFormat::default().render(&entry),
+                   format!("conn={} client={} backend={} bytes_up=7 bytes_down=8 \
+                            duration_ms=1500",
+                           connection.id(),
+                           client.local_addr().unwrap(),
+                           server.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn paused_reads_still_flush_buffered_bytes() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+        client.write_all(b"first").unwrap();
+        pump(&mut connection);
+        client.write_all(b" second").unwrap();
+
+        connection.prefill_incoming(b"buffered");
+        assert_eq!(connection.buffered_bytes(), 8);
+        connection.set_read_paused(true);
+        assert!(!connection.incoming_interest().is_readable());
+        assert!(!connection.outgoing_interest().is_readable());
+        assert!(connection.outgoing_interest().is_writable());
+        pump(&mut connection);
+        assert_eq!(connection.buffered_bytes(), 0);
+
+        connection.set_read_paused(false);
+        client.shutdown(Shutdown::Write).unwrap();
+        pump(&mut connection);
+
+        let mut relayed = Vec::new();
+        server.read_to_end(&mut relayed).unwrap();
+        assert_eq!(relayed, b"firstbuffered second".to_vec());
+    }
+
+    #[test]
+    fn close_ends_both_sockets_while_the_connection_lives() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+        let poll = Poll::new().unwrap();
+        poll.register(connection.incoming_stream(),
+                      Token(1),
+                      connection.incoming_interest(),
+                      PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+        poll.register(connection.outgoing_stream(),
+                      Token(2),
+                      connection.outgoing_interest(),
+                      PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+
+        connection.close(&poll);
+
+        // The endpoints still hold duplicates of each other's socket.
+        let mut buffer = [0; 8];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+        assert_eq!(server.read(&mut buffer).unwrap(), 0);
+        assert!(poll.deregister(connection.incoming_stream()).is_err());
+    }
+
+    #[test]
+    fn raw_tokens_round_trip_for_every_kind() {
+        for &i in &[0, 1, 2, 4095, 1 << 40] {
+            match TokenType::from_raw_token(ListenerToken(i).as_raw_token()) {
+                TokenType::Listener(token) => assert_eq!(token, ListenerToken(i)),
+                other => panic!("Listener token came back as {:?}", other),
+            }
+            match TokenType::from_raw_token(IncomingToken(i).as_raw_token()) {
+                TokenType::Incoming(token) => assert_eq!(token, IncomingToken(i)),
+                other => panic!("Incoming token came back as {:?}", other),
+            }
+            match TokenType::from_raw_token(OutgoingToken(i).as_raw_token()) {
+                TokenType::Outgoing(token) => assert_eq!(token, OutgoingToken(i)),
+                other => panic!("Outgoing token came back as {:?}", other),
+            }
+            match TokenType::from_raw_token(AuxiliaryToken(i).as_raw_token()) {
+                TokenType::Auxiliary(token) => assert_eq!(token, AuxiliaryToken(i)),
+                other => panic!("Auxiliary token came back as {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn raw_tokens_round_trip_at_the_largest_index() {
+        let raw = AuxiliaryToken(MAX_TOKEN_INDEX).as_raw_token();
+        assert!(raw != Token(usize::MAX));
+        match TokenType::from_raw_token(raw) {
+            TokenType::Auxiliary(token) => assert_eq!(token, AuxiliaryToken(MAX_TOKEN_INDEX)),
+            other => panic!("Auxiliary token came back as {:?}", other),
+        }
+        match TokenType::from_raw_token(IncomingToken(MAX_TOKEN_INDEX).as_raw_token()) {
+            TokenType::Incoming(token) => assert_eq!(token, IncomingToken(MAX_TOKEN_INDEX)),
+            other => panic!("Incoming token came back as {:?}", other),
+        }
+
+        assert!(check_token_capacity(MAX_TOKEN_INDEX + 1, "connections").is_ok());
+        let err = check_token_capacity(MAX_TOKEN_INDEX + 2, "connections").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn index_past_the_largest_makes_no_token() {
+        OutgoingToken(MAX_TOKEN_INDEX + 1).as_raw_token();
+    }
+
+    #[test]
+    fn both_sockets_of_a_connection_share_an_index() {
+        let incoming = IncomingToken(7);
+
+        assert_eq!(incoming.outgoing(), OutgoingToken(7));
+        assert_eq!(incoming.outgoing().incoming(), incoming);
+        assert!(incoming.as_raw_token() != incoming.outgoing().as_raw_token());
+
+        for &raw in &[incoming.as_raw_token(), incoming.outgoing().as_raw_token()] {
+            assert_eq!(connection_index(TokenType::from_raw_token(raw)), Some(7));
+        }
+        assert_eq!(connection_index(TokenType::Listener(ListenerToken(7))), None);
+        assert_eq!(connection_index(TokenType::Auxiliary(AuxiliaryToken(7))), None);
+    }
+
+    #[test]
+    fn connection_knows_both_of_its_tokens() {
+        let (connection, _client, _server) = make_connection(64);
+
+        assert_eq!(connection.incoming_token(), IncomingToken(0));
+        assert_eq!(connection.outgoing_token(), OutgoingToken(0));
+    }
+
+    #[test]
+    fn connections_get_increasing_ids() {
+        let (first, _client, _server) = make_connection(64);
+        let (second, _client, _server) = make_connection(64);
+
+        assert!(second.id() > first.id());
+    }
+
+    #[test]
+    fn absorb_reads_until_it_would_block() {
+        let (mut endpoint, io, _) = scripted_endpoint(64);
+
+        io.script_read(Ok(b"ab"));
+        io.script_read(Err(ErrorKind::Interrupted.into()));
+        io.script_read(Ok(b"cd"));
+        assert_eq!(endpoint.absorb().unwrap(), 4);
+        assert_eq!(&endpoint.buffer[..endpoint.buffer_index], b"abcd");
+        assert!(!endpoint.read_closed);
+
+        // EOF stops reading for good, though the bytes before it are kept.
+        io.script_read(Ok(b""));
+        assert_eq!(endpoint.absorb().unwrap(), 0);
+        assert!(endpoint.read_closed && !endpoint.wants_read());
+        assert!(!endpoint.is_drained());
+    }
+
+    #[test]
+    fn absorb_stops_at_a_full_buffer_and_passes_errors_on() {
+        let (mut endpoint, io, _) = scripted_endpoint(4);
+
+        io.script_read(Ok(b"abcdef"));
+        assert_eq!(endpoint.absorb().unwrap(), 4);
+        assert!(endpoint.is_buffer_full() && !endpoint.wants_read());
+
+        let (mut endpoint, io, _) = scripted_endpoint(4);
+        io.script_read(Err(ErrorKind::ConnectionReset.into()));
+        assert_eq!(endpoint.absorb().unwrap_err().kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn pipe_to_peer_keeps_what_the_peer_does_not_take() {
+        let (mut endpoint, io, peer) = scripted_endpoint(64);
+        io.script_read(Ok(b"hello"));
+        endpoint.absorb().unwrap();
+
+        peer.script_write(2);
+        assert_eq!(endpoint.pipe_to_peer(usize::MAX).unwrap(), 2);
+        assert_eq!(&endpoint.buffer[..endpoint.buffer_index], b"llo");
+        assert!(endpoint.wants_write());
+
+        peer.script_write(64);
+        assert_eq!(endpoint.pipe_to_peer(2).unwrap(), 2);
+        assert_eq!(endpoint.pipe_to_peer(usize::MAX).unwrap(), 0);
+        peer.script_write(64);
+        assert_eq!(endpoint.pipe_to_peer(usize::MAX).unwrap(), 1);
+        assert_eq!(*peer.written.borrow(), b"hello");
+        assert!(!endpoint.wants_write());
+    }
+
+    #[test]
+    fn partial_write_shifts_remaining_bytes() {
+        let mut endpoint = make_endpoint(16);
+        for (i, b) in endpoint.buffer.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        endpoint.buffer_index = 16;
+
+        let mut writer = PartialWriter {
+            written: Vec::new(),
+            max_write: 10,
+        };
+
+        assert_eq!(endpoint.write_buffer(&mut writer, usize::MAX).unwrap(), 10);
+        assert_eq!(endpoint.buffer_index, 6);
+        assert_eq!(&endpoint.buffer[..6], &[10, 11, 12, 13, 14, 15]);
+
+        assert_eq!(endpoint.write_buffer(&mut writer, usize::MAX).unwrap(), 6);
+        assert_eq!(endpoint.buffer_index, 0);
+        assert_eq!(writer.written, (0..16).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn many_partial_writes_in_a_short_while_are_a_burst() {
+        let mut endpoint = make_endpoint(16);
+        let mut writer = PartialWriter {
+            written: Vec::new(),
+            max_write: 1,
+        };
+        let start = Instant::now();
+        let mut partial_write = |endpoint: &mut SocketEndPoint| {
+            endpoint.buffer_index = 2;
+            endpoint.write_buffer(&mut writer, usize::MAX).unwrap();
+        };
+
+        for _ in 1..PARTIAL_WRITE_BURST {
+            partial_write(&mut endpoint);
+        }
+        assert!(!endpoint.is_partial_write_burst(start));
+        partial_write(&mut endpoint);
+        assert_eq!(endpoint.partial_writes, PARTIAL_WRITE_BURST);
+        assert!(endpoint.is_partial_write_burst(start));
+        assert!(!endpoint.is_partial_write_burst(start));
+
+        // Just as many, but spread out too far to be a burst.
+        for _ in 0..PARTIAL_WRITE_BURST {
+            partial_write(&mut endpoint);
+        }
+        let later = start + Duration::from_millis(PARTIAL_WRITE_BURST_MS);
+        assert!(!endpoint.is_partial_write_burst(later));
+    }
+
+    #[test]
+    fn partial_preamble_write_resumes_before_buffer() {
+        let mut endpoint = make_endpoint(16);
+        endpoint.preamble = b"HEADER".to_vec();
+        endpoint.buffer[..4].copy_from_slice(b"data");
+        endpoint.buffer_index = 4;
+
+        let mut writer = PartialWriter {
+            written: Vec::new(),
+            max_write: 4,
+        };
+
+        assert!(!endpoint.write_preamble(&mut writer).unwrap());
+        assert!(endpoint.wants_write());
+        assert!(endpoint.write_preamble(&mut writer).unwrap());
+        assert_eq!(endpoint.write_buffer(&mut writer, usize::MAX).unwrap(), 4);
+        assert_eq!(writer.written, b"HEADERdata".to_vec());
+        assert!(!endpoint.wants_write());
+    }
+
+    #[test]
+    fn pipe_writes_until_buffer_is_empty() {
+        let mut endpoint = make_endpoint(16);
+        endpoint.preamble = b"HEADER".to_vec();
+        endpoint.buffer[..10].copy_from_slice(b"0123456789");
+        endpoint.buffer_index = 10;
+
+        let mut writer = PartialWriter {
+            written: Vec::new(),
+            max_write: 3,
+        };
+
+        assert_eq!(endpoint.pipe_into(&mut writer, usize::MAX).unwrap(), 10);
+        assert_eq!(writer.written, b"HEADER0123456789".to_vec());
+        assert!(!endpoint.wants_write());
+    }
+
+    #[test]
+    fn writes_reporting_too_many_bytes_fail() {
+        let mut endpoint = make_endpoint(16);
+        endpoint.buffer[..4].copy_from_slice(b"data");
+        endpoint.buffer_index = 4;
+
+        assert!(endpoint.pipe_into(&mut OverreportingWriter, usize::MAX).is_err());
+        assert_eq!(endpoint.buffer_index, 4);
+
+        endpoint.preamble = b"HEADER".to_vec();
+        assert!(endpoint.write_preamble(&mut OverreportingWriter).is_err());
+        assert_eq!(endpoint.preamble, b"HEADER".to_vec());
+    }
+
+    #[test]
+    fn peers_that_cant_be_cloned_are_an_error() {
+        let (mut endpoint, _io, _peer) = scripted_endpoint(16);
+        let peer = ScriptedIo {
+            clone_fails: true,
+            ..Default::default()
+        };
+
+        let error = endpoint.set_peer_stream(&peer).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EMFILE));
+    }
+
+    #[test]
+    fn front_keeps_relaying_to_its_old_peer_when_the_new_one_cant_be_paired_up() {
+        let (mut front, io, old_peer) = scripted_endpoint(16);
+        let replacement = ScriptedIo {
+            clone_fails: true,
+            ..Default::default()
+        };
+        let mut back = EndPoint::new(replacement, 16, 0);
+
+        let error = pair_up(&mut front, &mut back).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EMFILE));
+
+        io.script_read(Ok(b"data"));
+        front.absorb().unwrap();
+        old_peer.script_write(16);
+        assert_eq!(front.pipe_to_peer(usize::MAX).unwrap(), 4);
+        assert_eq!(*old_peer.written.borrow(), b"data".to_vec());
+    }
+
+    #[test]
+    fn small_writes_are_sent_without_delay() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+        assert!(nodelay(connection.incoming_stream()));
+        assert!(nodelay(connection.outgoing_stream()));
+
+        // Each keystroke goes out on its own rather than waiting behind the
+        // unacknowledged one before it.
+        let mut echoed = [0; 1];
+        for &key in b"ls -l\n" {
+            client.write_all(&[key]).unwrap();
+            pump(&mut connection);
+            server.read_exact(&mut echoed).unwrap();
+            assert_eq!(echoed[0], key);
+        }
+
+        let config = BackendConfig {
+            tcp_nodelay: Some(false),
+            ..Default::default()
+        };
+        let (connection, _client, _server) = make_connection_with(64, &config);
+        assert!(!nodelay(connection.incoming_stream()));
+        assert!(!nodelay(connection.outgoing_stream()));
+    }
+
+    #[test]
+    fn keepalive_is_off_unless_configured() {
+        let (connection, _client, _server) = make_connection(64);
+        assert!(!keepalive(connection.incoming_stream()));
+        assert!(!keepalive(connection.outgoing_stream()));
+
+        let config = BackendConfig {
+            keepalive: Some(KeepaliveConfig {
+                idle_ms: 30000,
+                interval_ms: Some(4500),
+            }),
+            ..Default::default()
+        };
+        let (connection, _client, _server) = make_connection_with(64, &config);
+
+        for stream in &[connection.incoming_stream(), connection.outgoing_stream()] {
+            assert!(keepalive(stream));
+            assert_eq!(socket_option(stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 30);
+            assert_eq!(socket_option(stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 5);
+        }
+    }
+
+    #[test]
+    fn splices_after_relaying_buffered_bytes() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+        connection.use_splice();
+        connection.prefill_incoming(b"peeked ");
+
+        client.write_all(b"and spliced").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        server.write_all(b"reply").unwrap();
+        server.shutdown(Shutdown::Write).unwrap();
+        pump(&mut connection);
+
+        let mut relayed = Vec::new();
+        server.read_to_end(&mut relayed).unwrap();
+        assert_eq!(relayed, b"peeked and spliced".to_vec());
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"reply".to_vec());
+
+        let snapshot = connection.metrics.snapshot();
+        assert_eq!(snapshot.bytes_upstream, 18);
+        assert_eq!(snapshot.bytes_downstream, 5);
+    }
+
+    #[test]
+    fn one_byte_writes_keep_the_rest_queued() {
+        let mut endpoint = make_endpoint(16);
+        endpoint.buffer[..5].copy_from_slice(b"bytes");
+        endpoint.buffer_index = 5;
+
+        let mut writer = PartialWriter {
+            written: Vec::new(),
+            max_write: 1,
+        };
+
+        for n_written in 1..6 {
+            assert!(endpoint.wants_write());
+            assert_eq!(endpoint.write_buffer(&mut writer, usize::MAX).unwrap(), 1);
+            assert_eq!(&endpoint.buffer[..endpoint.buffer_index], &b"bytes"[n_written..]);
+        }
+        assert!(!endpoint.wants_write());
+        assert_eq!(writer.written, b"bytes".to_vec());
+    }
+
+    #[test]
+    fn blocked_peer_keeps_writable_interest_until_flushed() {
+        let (mut connection, mut client, mut server) = make_connection(4096);
+        client.set_nonblocking(true).unwrap();
+
+        // Keep sending until the backend stops taking bytes and some are
+        // left over in the connection.
+        let chunk = [7; 65536];
+        let mut n_sent = 0;
+        for _ in 0..10000 {
+            match client.write(&chunk) {
+                Ok(n_written) => n_sent += n_written,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => panic!("Client write failed: {}", e),
+            }
+            connection.incoming_ready(Ready::readable() | Ready::writable());
+            connection.outgoing_ready(Ready::readable() | Ready::writable());
+            connection.tick();
+
+            if connection.points[EndPointType::Front].wants_write() {
+                break;
+            }
+        }
+        assert!(connection.points[EndPointType::Front].wants_write());
+        assert!(connection.outgoing_interest().is_writable());
+
+        client.shutdown(Shutdown::Write).unwrap();
+        let reader = thread::spawn(move || {
+            let mut relayed = Vec::new();
+            server.read_to_end(&mut relayed).unwrap();
+            relayed.len()
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !connection.points[EndPointType::Front].is_drained() {
+            assert!(Instant::now() < deadline, "Timed out flushing to the backend");
+            connection.incoming_ready(Ready::readable() | Ready::writable());
+            connection.outgoing_ready(Ready::readable() | Ready::writable());
+            connection.tick();
+        }
+        assert!(!connection.outgoing_interest().is_writable());
+        drop(connection);
+        assert_eq!(reader.join().unwrap(), n_sent);
+    }
+
+    #[test]
+    fn read_timer_restarts_on_every_byte() {
+        let (mut connection, mut client, _server) = make_connection(64);
+        let timeout = Duration::from_millis(500);
+        let opened = connection.points[EndPointType::Back].read_waiting_since.unwrap();
+
+        assert_eq!(connection.read_timed_out(opened + timeout / 2, timeout), None);
+
+        // The client keeps talking, the backend never answers.
+        client.write_all(b"hello?").unwrap();
+        pump(&mut connection);
+        let now = Instant::now();
+        assert_eq!(connection.read_timed_out(opened + timeout, timeout),
+                   Some(EndPointType::Back));
+        assert_eq!(connection.read_timed_out(now + timeout * 2, timeout),
+                   Some(EndPointType::Back));
+
+        // Neither side holds anything back for the other.
+        assert_eq!(connection.write_timed_out(now + timeout * 2, timeout), None);
+    }
+
+    #[test]
+    fn write_timer_runs_while_the_peer_takes_nothing() {
+        let (mut connection, mut client, _server) = make_connection(4096);
+        client.set_nonblocking(true).unwrap();
+
+        let chunk = [7; 65536];
+        let mut stalled_since = None;
+        for _ in 0..10000 {
+            let _ = client.write(&chunk);
+            connection.incoming_ready(Ready::readable() | Ready::writable());
+            connection.outgoing_ready(Ready::readable() | Ready::writable());
+            connection.tick();
+
+            stalled_since = connection.points[EndPointType::Front].write_waiting_since;
+            if stalled_since.is_some() {
+                break;
+            }
+        }
+        let stalled_since = stalled_since.expect("The backend never stopped taking bytes");
+        let timeout = Duration::from_millis(500);
+
+        // More events that move nothing don't restart the timer.
+        connection.outgoing_ready(Ready::writable());
+        connection.tick();
+        assert_eq!(connection.points[EndPointType::Front].write_waiting_since,
+                   Some(stalled_since));
+
+        assert_eq!(connection.write_timed_out(stalled_since + timeout / 2, timeout), None);
+        assert_eq!(connection.write_timed_out(stalled_since + timeout, timeout),
+                   Some(EndPointType::Back));
+    }
+
+    #[test]
+    fn proxy_header_precedes_client_bytes() {
+        let config = BackendConfig {
+            proxy_protocol: Some(true),
+            ..Default::default()
+        };
+        let (mut connection, mut client, mut server) = make_connection_with(64, &config);
+
+        client.write_all(b"hello").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        pump(&mut connection);
+
+        let mut relayed = Vec::new();
+        server.read_to_end(&mut relayed).unwrap();
+        assert_eq!(String::from_utf8(relayed).unwrap(),
+                   format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello",
+                           client.local_addr().unwrap().port(),
+                           client.peer_addr().unwrap().port()));
+        assert_eq!(connection.metrics.snapshot().bytes_upstream, 5);
+    }
+
+    #[test]
+    fn proxy_header_v2_carries_server_name_and_id() {
+        let config = BackendConfig {
+            proxy_protocol: Some(true),
+            proxy_protocol_version: Some(2),
+            proxy_protocol_tlvs: Some(vec!["authority".to_owned(), "unique_id".to_owned()]),
+            ..Default::default()
+        };
+        let (mut connection, mut client, mut server) = make_connection_with(64, &config);
+        connection.set_server_name("example.com");
+
+        client.write_all(b"hello").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        pump(&mut connection);
+
+        let mut relayed = Vec::new();
+        server.read_to_end(&mut relayed).unwrap();
+        let expected = proxy_protocol::v2_header(&client.local_addr().unwrap(),
+                                                 &Address::Tcp(client.peer_addr().unwrap()),
+                                                 &[(0x02, &b"example.com"[..]),
+                                                   (0x05, connection.id().to_string().as_bytes())]);
+        assert_eq!(&relayed[..expected.len()], &expected[..]);
+        assert_eq!(&relayed[expected.len()..], b"hello");
+    }
+
+    #[test]
+    fn failover_sends_the_proxy_header_only_to_targets_taking_it() {
+        let config = BackendConfig {
+            proxy_protocol: Some(true),
+            ..Default::default()
+        };
+        let (incoming, mut client) = socket_pair();
+        let (legacy, legacy_server) = socket_pair();
+        let (aware, mut aware_server) = socket_pair();
+        let legacy_target = Address::Tcp(legacy_server.local_addr().unwrap());
+        let aware_target = Address::Tcp(aware_server.local_addr().unwrap());
+
+        let mut legacy_backend = Backend::new(legacy_target.clone(), 1);
+        legacy_backend.proxy_protocol = false;
+        let pool = BackendPool::new(vec![legacy_backend, Backend::new(aware_target.clone(), 1)],
+                                    Box::new(RoundRobin::new()),
+                                    &config);
+        let mut connection = Connection::new((incoming, client.local_addr().unwrap()),
+                                             (legacy, legacy_target.clone()),
+                                             IncomingToken(0),
+                                             64,
+                                             pool,
+                                             Arc::new(Metrics::new()))
+            .unwrap();
+        assert!(connection.points[EndPointType::Front].preamble.is_empty());
+
+        let _legacy = connection.replace_outgoing(aware, aware_target, vec![legacy_target])
+            .unwrap();
+        client.write_all(b"hello").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        pump(&mut connection);
+
+        let mut relayed = Vec::new();
+        aware_server.read_to_end(&mut relayed).unwrap();
+        let expected = format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello",
+                               client.local_addr().unwrap().port(),
+                               client.peer_addr().unwrap().port());
+        assert_eq!(String::from_utf8(relayed).unwrap(), expected);
+    }
+
+    #[test]
+    fn split_incoming_proxy_header_is_stripped_and_forwarded() {
+        let config = BackendConfig {
+            proxy_protocol: Some(true),
+            ..Default::default()
+        };
+        let (mut connection, mut client, mut server) = make_connection_with(64, &config);
+        connection.expect_proxy_header();
+
+        client.write_all(b"PROXY TCP4 10.0.0.1 10.0.0.2 ").unwrap();
+        pump(&mut connection);
+        assert!(!connection.is_finished());
+
+        client.write_all(b"1000 80\r\nhello").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        pump(&mut connection);
+
+        let mut relayed = Vec::new();
+        server.read_to_end(&mut relayed).unwrap();
+        assert_eq!(relayed, b"PROXY TCP4 10.0.0.1 10.0.0.2 1000 80\r\nhello".to_vec());
+        assert_eq!(*connection.source_addr(), "10.0.0.1:1000".parse().unwrap());
+    }
+
+    #[test]
+    fn malformed_incoming_proxy_header_closes_connection() {
+        let (mut connection, mut client, _server) = make_connection(64);
+        connection.expect_proxy_header();
+
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        pump(&mut connection);
+
+        assert!(connection.is_finished());
+        assert!(connection.is_errored());
+        assert!(!connection.flush_pending());
+        assert_eq!(connection.metrics.snapshot().bytes_upstream, 0);
+    }
+
+    #[test]
+    fn reset_by_backend_errors_connection_without_error_readiness() {
+        let (mut connection, mut client, server) = make_connection(64);
+
+        reset_on_close(&server);
+        drop(server);
+
+        client.write_all(b"request").unwrap();
+        for _ in 0..20 {
+            connection.incoming_ready(Ready::readable());
+            connection.outgoing_ready(Ready::readable());
+            connection.tick();
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert!(connection.is_errored());
+        assert!(connection.is_finished());
+    }
+
+    #[test]
+    fn refused_connect_is_detected_on_first_writable_event() {
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (incoming, client) = socket_pair();
+        let outgoing = TcpStream::connect(&refused).unwrap();
+        let pool = BackendPool::new(Vec::new(), Box::new(RoundRobin::new()), &Default::default());
+        let mut connection = Connection::new((incoming, client.local_addr().unwrap()),
+                                             (Stream::Tcp(outgoing), Address::Tcp(refused)),
+                                             IncomingToken(0),
+                                             64,
+                                             pool,
+                                             Arc::new(Metrics::new()))
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+        connection.outgoing_ready(Ready::writable());
+
+        assert!(connection.is_connect_failed());
+        assert!(connection.is_outgoing_failed());
+        assert!(connection.report_target_failure());
+        assert!(!connection.report_target_failure());
+    }
+
+    #[test]
+    fn half_close_keeps_other_direction_open() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+
+        client.write_all(b"request").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        pump(&mut connection);
+
+        let mut request = Vec::new();
+        server.read_to_end(&mut request).unwrap();
+        assert_eq!(request, b"request");
+        assert!(!connection.is_finished());
+
+        server.write_all(b"response").unwrap();
+        server.shutdown(Shutdown::Write).unwrap();
+        pump(&mut connection);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"response");
+        assert!(connection.is_finished());
+    }
+
+    #[test]
+    fn writable_side_receives_its_peers_bytes() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+
+        server.write_all(b"response").unwrap();
+        for _ in 0..20 {
+            connection.outgoing_ready(Ready::readable());
+            connection.tick();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(connection.points[EndPointType::Back].wants_write());
+
+        // Only the client side is writable, which is where the backend's
+        // bytes are headed.
+        connection.incoming_ready(Ready::writable());
+        assert!(connection.tick());
+
+        let mut response = [0; 8];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"response");
+        assert!(!connection.points[EndPointType::Back].wants_write());
+    }
+
+    #[test]
+    fn full_buffer_pauses_reading() {
+        let (mut connection, mut client, mut server) = make_connection(16);
+
+        // Nothing is marked writable, so the bytes pile up in the buffer.
+        client.write_all(&[7; 16]).unwrap();
+        for _ in 0..20 {
+            connection.incoming_ready(Ready::readable());
+            connection.tick();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(connection.incoming_interest(), Ready::empty());
+        assert_eq!(connection.outgoing_interest(), Ready::readable() | Ready::writable());
+
+        connection.outgoing_ready(Ready::writable());
+        assert!(connection.tick());
+        assert_eq!(connection.incoming_interest(), Ready::readable());
+        assert_eq!(connection.outgoing_interest(), Ready::readable());
+
+        let mut relayed = [0; 16];
+        server.read_exact(&mut relayed).unwrap();
+        assert_eq!(relayed, [7; 16]);
+    }
+
+    #[test]
+    fn flush_pending_delivers_buffered_bytes() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+
+        client.write_all(b"tail").unwrap();
+        for _ in 0..20 {
+            connection.incoming_ready(Ready::readable());
+            connection.tick();
+            thread::sleep(Duration::from_millis(5));
+        }
+        connection.incoming_ready(Ready::from(UnixReady::error()));
+        assert!(connection.is_finished());
+
+        assert!(!connection.flush_pending());
+
+        let mut relayed = [0; 4];
+        server.read_exact(&mut relayed).unwrap();
+        assert_eq!(&relayed, b"tail");
+        assert!(!connection.points[EndPointType::Front].wants_write());
+    }
+
+    #[test]
+    fn relay_moves_bytes_only_the_given_way() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+        assert_eq!((Direction::Upstream.source(), Direction::Upstream.destination()),
+                   (EndPointType::Front, EndPointType::Back));
+
+        client.write_all(b"ping").unwrap();
+        server.write_all(b"pong").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut n_relayed = 0;
+        for _ in 0..20 {
+            n_relayed += connection.relay(Direction::Upstream).unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(n_relayed, 4);
+
+        // The EOF follows the bytes upstream, while the backend's bytes
+        // are still waiting to go the other way.
+        let mut request = Vec::new();
+        server.read_to_end(&mut request).unwrap();
+        assert_eq!(request, b"ping");
+        assert_eq!(connection.metrics.snapshot().bytes_downstream, 0);
+
+        assert_eq!(connection.relay(Direction::Downstream).unwrap(), 4);
+        let mut response = [0; 4];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"pong");
+    }
+
+    #[test]
+    fn relayed_bytes_are_counted_per_direction() {
+        let (mut connection, mut client, mut server) = make_connection(64);
+
+        client.write_all(b"ping").unwrap();
+        pump(&mut connection);
+        server.write_all(b"pong!").unwrap();
+        pump(&mut connection);
+
+        let snapshot = connection.metrics.snapshot();
+        assert_eq!(snapshot.bytes_upstream, 4);
+        assert_eq!(snapshot.bytes_downstream, 5);
+
+        let backends = connection.metrics.backend_snapshots();
+        assert_eq!(backends.len(), 1);
+        assert_eq!(backends[0].addr, *connection.target());
+        assert_eq!(backends[0].connections_active, 1);
+        assert_eq!(backends[0].bytes_upstream, 4);
+        assert_eq!(backends[0].bytes_downstream, 5);
+
+        connection.release_target();
+        assert_eq!(connection.metrics.backend_snapshots()[0].connections_active, 0);
+    }
+}
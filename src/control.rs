@@ -0,0 +1,427 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::prelude::*;
+use std::io::{ErrorKind, Result as IOResult};
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::Ready;
+use mio::unix::UnixReady;
+
+use slab::Slab;
+
+use backend::{Backend, BackendId, BackendPool};
+use connection::{Connection, IncomingToken};
+use frontend::Frontend;
+use observer::ConnectionInfo;
+use stream::{Address, Stream};
+
+const MAX_LINE_SIZE: usize = 1024;
+const CONTROL_IDLE_MS: u64 = 60000;
+
+pub type Pools = HashMap<String, Rc<RefCell<BackendPool>>>;
+pub type Frontends = HashMap<String, Rc<Frontend>>;
+pub type Connections = Slab<Connection, IncomingToken>;
+
+/// A target removed with `force`, and the pool it was in. The driver
+/// closes the connections still relaying to it.
+pub type ForcedRemoval = (Rc<RefCell<BackendPool>>, BackendId);
+
+/// A client on the control socket. Each line it sends is one command, and
+/// each command is answered with zero or more lines of output followed by
+/// `OK` or `ERR <reason>`. Like metrics scrapes, sessions are driven from
+/// the event loop, so commands take effect between relay events.
+pub struct ControlSession {
+    pub stream: Stream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    read_closed: bool,
+    deadline: Instant,
+    forced_removals: Vec<ForcedRemoval>,
+}
+
+impl ControlSession {
+    pub fn new(stream: Stream, now: Instant) -> ControlSession {
+        ControlSession {
+            stream: stream,
+            input: Vec::new(),
+            output: Vec::new(),
+            read_closed: false,
+            deadline: now + Duration::from_millis(CONTROL_IDLE_MS),
+            forced_removals: Vec::new(),
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Targets removed with `force` since the last call.
+    pub fn take_forced_removals(&mut self) -> Vec<ForcedRemoval> {
+        mem::take(&mut self.forced_removals)
+    }
+
+    pub fn interest(&self) -> Ready {
+        if self.output.is_empty() {
+            Ready::readable()
+        } else {
+            Ready::writable()
+        }
+    }
+
+    /// Runs whatever complete commands have arrived and writes out their
+    /// responses. Returns true once the session is over.
+    pub fn ready(&mut self,
+                 ready: Ready,
+                 pools: &Pools,
+                 frontends: &Frontends,
+                 connections: &Connections)
+                 -> bool {
+        if UnixReady::from(ready).is_error() {
+            return true;
+        }
+
+        if !self.read_closed {
+            if let Err(e) = self.read_input() {
+                debug!("Control socket read failed: {}", e);
+                return true;
+            }
+        }
+
+        while let Some(end) = self.input.iter().position(|&b| b == b'\n') {
+            let line = self.input.drain(..end + 1).collect::<Vec<u8>>();
+            let line = String::from_utf8_lossy(&line);
+
+            self.deadline = Instant::now() + Duration::from_millis(CONTROL_IDLE_MS);
+            let response = run_command(line.trim(),
+                                       pools,
+                                       frontends,
+                                       connections,
+                                       &mut self.forced_removals);
+            self.output.extend_from_slice(response.as_bytes());
+        }
+        if self.input.len() > MAX_LINE_SIZE {
+            self.input.clear();
+            self.output.extend_from_slice(b"ERR line too long\n");
+        }
+
+        match self.write_output() {
+            Ok(()) => self.read_closed && self.output.is_empty(),
+            Err(e) => {
+                debug!("Control socket write failed: {}", e);
+                true
+            }
+        }
+    }
+
+    fn read_input(&mut self) -> IOResult<()> {
+        let mut buffer = [0; 1024];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    return Ok(());
+                }
+                Ok(n_read) => self.input.extend_from_slice(&buffer[..n_read]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write_output(&mut self) -> IOResult<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(n_written) => {
+                    self.output.drain(..n_written);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs one command line and returns its full response.
+///
+/// * `list [<backend>]` prints `<backend> <addr> <up|down|draining>
+///   <connections>` for every target.
+/// * `add [<backend>] <addr>` adds a target to a pool.
+/// * `remove [<backend>] <addr> [force]` stops routing to a target.
+///   Connections already relaying to it carry on until they close, or
+///   with `force` are wound down straight away.
+/// * `drain [<backend>] <addr>` keeps new clients away from a target
+///   while it stays in the pool, so its connection count can be watched
+///   going down to zero before it is removed. `undrain` brings it back.
+/// * `connections` prints a line of `key=value` pairs for every open
+///   connection, in the order they were opened: its id, client and
+///   backend, bytes relayed each way, milliseconds since it last relayed
+///   anything and bytes waiting in its buffers.
+/// * `split <frontend>` prints `<frontend> <backend> <percent>` for every
+///   pool the frontend splits new clients between, its default backend
+///   first. `split <frontend> <backend> <percent>` gives one of the pools
+///   in its `backend_split` a new share, and the default backend whatever
+///   is left, until the configuration is next reloaded.
+///
+/// The backend name can be left out when only one is configured. Output
+/// is queued and written out as the socket takes it, so a slow reader of
+/// a long listing holds nothing up.
+fn run_command(line: &str,
+               pools: &Pools,
+               frontends: &Frontends,
+               connections: &Connections,
+               forced_removals: &mut Vec<ForcedRemoval>)
+               -> String {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or("");
+    let mut args = words.collect::<Vec<_>>();
+    let force = command == "remove" && args.len() > 1 && args[args.len() - 1] == "force";
+    if force {
+        args.pop();
+    }
+
+    let result = match command {
+        "list" if args.len() <= 1 => list(args.first().cloned(), pools),
+        "connections" if args.is_empty() => Ok(list_connections(connections, Instant::now())),
+        "split" if args.len() == 1 || args.len() == 3 => split(&args, frontends),
+        "add" | "remove" | "drain" | "undrain" if !args.is_empty() && args.len() <= 2 => {
+            let (name, addr) = if args.len() == 2 {
+                (Some(args[0]), args[1])
+            } else {
+                (None, args[0])
+            };
+
+            find_pool(name, pools).and_then(|(name, pool_ref)| {
+                let addr = try!(Address::resolve(addr).map_err(|e| e.to_string()));
+                let mut pool = pool_ref.borrow_mut();
+
+                match command {
+                    "add" => {
+                        let mut backend = Backend::new(addr.clone(), 1);
+                        backend.proxy_protocol = pool.sends_proxy_protocol(args[args.len() - 1]);
+                        if !pool.add(backend) {
+                            return Err(format!("{} is already in {}", addr, name));
+                        }
+                        info!("Added {} to backend {} over the control socket", addr, name);
+                    }
+                    "remove" => {
+                        if pool.remove(&addr).is_none() {
+                            return Err(format!("{} is not in {}", addr, name));
+                        }
+                        info!("Removed {} from backend {} over the control socket", addr, name);
+                        if force {
+                            forced_removals.push((pool_ref.clone(), addr.clone()));
+                        }
+                    }
+                    _ => {
+                        let draining = command == "drain";
+                        if !pool.set_draining(&addr, draining) {
+                            return Err(format!("{} is not in {}", addr, name));
+                        }
+                        info!("{} {} of backend {} over the control socket",
+                              if draining { "Draining" } else { "Undraining" },
+                              addr,
+                              name);
+                    }
+                }
+                Ok(String::new())
+            })
+        }
+        "" => Err("empty command".to_owned()),
+        _ => Err(format!("unknown command: {}", line)),
+    };
+
+    match result {
+        Ok(output) => output + "OK\n",
+        Err(reason) => format!("ERR {}\n", reason),
+    }
+}
+
+fn list(name: Option<&str>, pools: &Pools) -> Result<String, String> {
+    let mut names = match name {
+        Some(name) => vec![try!(find_pool(Some(name), pools)).0],
+        None => pools.keys().map(String::as_str).collect(),
+    };
+    names.sort();
+
+    let now = Instant::now();
+    let mut output = String::new();
+
+    for name in names {
+        for backend in pools[name].borrow().backends() {
+            let state = if backend.draining {
+                "draining"
+            } else if backend.is_available(now) {
+                "up"
+            } else {
+                "down"
+            };
+
+            writeln!(output,
+                     "{} {} {} {}",
+                     name,
+                     backend.addr,
+                     state,
+                     backend.active_connections)
+                .unwrap();
+        }
+    }
+    Ok(output)
+}
+
+fn split(args: &[&str], frontends: &Frontends) -> Result<String, String> {
+    let frontend = try!(frontends
+                            .get(args[0])
+                            .ok_or_else(|| format!("unknown frontend {}", args[0])));
+
+    if args.len() == 3 {
+        let percent = try!(args[2]
+                               .parse::<u32>()
+                               .map_err(|_| format!("invalid percentage {}", args[2])));
+        try!(frontend.set_split(args[1], percent));
+        info!("Split {}% of frontend {} to backend {} over the control socket",
+              percent,
+              args[0],
+              args[1]);
+        return Ok(String::new());
+    }
+
+    let mut output = String::new();
+    for (name, percent) in frontend.split() {
+        writeln!(output, "{} {} {}", args[0], name, percent).unwrap();
+    }
+    Ok(output)
+}
+
+fn list_connections(connections: &Connections, now: Instant) -> String {
+    let mut output = String::new();
+    for info in connection_infos(connections, now) {
+        writeln!(output,
+                 "conn={} client={} backend={} bytes_up={} bytes_down={} idle_ms={} buffered={}",
+                 info.id,
+                 info.client,
+                 info.backend,
+                 info.bytes_up,
+                 info.bytes_down,
+                 info.idle.as_secs() * 1000 + info.idle.subsec_nanos() as u64 / 1_000_000,
+                 info.buffered)
+            .unwrap();
+    }
+    output
+}
+
+/// Every open connection as it stands, in the order they were opened.
+pub fn connection_infos(connections: &Connections, now: Instant) -> Vec<ConnectionInfo> {
+    let mut infos = connections.iter().map(|connection| connection.info(now)).collect::<Vec<_>>();
+    infos.sort_by_key(|info| info.id);
+    infos
+}
+
+fn find_pool<'a>(name: Option<&'a str>,
+                 pools: &'a Pools)
+                 -> Result<(&'a str, &'a Rc<RefCell<BackendPool>>), String> {
+    match name {
+        Some(name) => {
+            pools
+                .get(name)
+                .map(|pool| (name, pool))
+                .ok_or_else(|| format!("unknown backend {}", name))
+        }
+        None if pools.len() == 1 => {
+            Ok(pools.iter().map(|(name, pool)| (name.as_str(), pool)).next().unwrap())
+        }
+        None => Err("backend name required".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_command, Frontends, Pools};
+
+    use slab::Slab;
+
+    use backend::{Backend, BackendPool};
+    use selector::RoundRobin;
+    use stream::Address;
+
+    fn pools(names: &[&str]) -> Pools {
+        names.iter()
+            .map(|name| {
+                     let backend = Backend::new(Address::resolve("127.0.0.1:8000").unwrap(), 1);
+                     let pool = BackendPool::new(vec![backend],
+                                                 Box::new(RoundRobin::new()),
+                                                 &Default::default());
+                     (name.to_string(), pool)
+                 })
+            .collect()
+    }
+
+    fn run(line: &str, pools: &Pools) -> String {
+        run_command(line, pools, &Frontends::new(), &Slab::with_capacity(0), &mut Vec::new())
+    }
+
+    #[test]
+    fn adds_lists_and_removes_targets() {
+        let pools = pools(&["web"]);
+
+        assert_eq!(run("add 127.0.0.1:8001", &pools), "OK\n");
+        assert_eq!(run("add web 127.0.0.1:8001", &pools),
+                   "ERR 127.0.0.1:8001 is already in web\n");
+        assert_eq!(run("list", &pools),
+                   "web 127.0.0.1:8000 up 0\nweb 127.0.0.1:8001 up 0\nOK\n");
+
+        assert_eq!(run("remove web 127.0.0.1:8000", &pools), "OK\n");
+        assert_eq!(run("remove 127.0.0.1:8000", &pools),
+                   "ERR 127.0.0.1:8000 is not in web\n");
+        assert_eq!(run("list web", &pools), "web 127.0.0.1:8001 up 0\nOK\n");
+
+        let mut forced = Vec::new();
+        assert_eq!(run_command("remove 127.0.0.1:8001 force",
+                               &pools,
+                               &Frontends::new(),
+                               &Slab::with_capacity(0),
+                               &mut forced),
+                   "OK\n");
+        assert_eq!(forced.len(), 1);
+        assert_eq!(forced[0].1, Address::resolve("127.0.0.1:8001").unwrap());
+        assert_eq!(run("list", &pools), "OK\n");
+    }
+
+    #[test]
+    fn drained_targets_get_no_new_clients() {
+        let pools = pools(&["web"]);
+        let client = "10.0.0.1:5000".parse().unwrap();
+
+        assert_eq!(run("add web 127.0.0.1:8001", &pools), "OK\n");
+        assert_eq!(run("drain web 127.0.0.1:8000", &pools), "OK\n");
+        assert_eq!(run("drain 127.0.0.1:8002", &pools),
+                   "ERR 127.0.0.1:8002 is not in web\n");
+        assert_eq!(run("list", &pools),
+                   "web 127.0.0.1:8000 draining 0\nweb 127.0.0.1:8001 up 0\nOK\n");
+
+        let remaining = Address::resolve("127.0.0.1:8001").unwrap();
+        for _ in 0..4 {
+            assert_eq!(pools["web"].borrow_mut().decide_target(&client), Some(remaining.clone()));
+        }
+
+        assert_eq!(run("undrain 127.0.0.1:8000", &pools), "OK\n");
+        assert_eq!(run("list web", &pools),
+                   "web 127.0.0.1:8000 up 0\nweb 127.0.0.1:8001 up 4\nOK\n");
+    }
+
+    #[test]
+    fn rejects_ambiguous_and_unknown_commands() {
+        let pools = pools(&["a", "b"]);
+
+        assert_eq!(run("add 127.0.0.1:8001", &pools),
+                   "ERR backend name required\n");
+        assert_eq!(run("add c 127.0.0.1:8001", &pools), "ERR unknown backend c\n");
+        assert_eq!(run("drop a", &pools), "ERR unknown command: drop a\n");
+        assert_eq!(run("list", &pools),
+                   "a 127.0.0.1:8000 up 0\nb 127.0.0.1:8000 up 0\nOK\n");
+    }
+}
@@ -1,18 +1,134 @@
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use mio::{Poll, PollOpt, Events, Ready};
-use mio::tcp::TcpStream;
+use mio::{Evented, Poll, PollOpt, Events, Ready};
+
+use libc;
 
 use slab::Slab;
 
+use backend::{BackendPool, BackendId};
+use config::TimeoutConfig;
+
 // use config::RootConfig;
-use connection::{TokenType, ListenerToken, IncomingToken, OutgoingToken, Connection};
-use driver_state::DriverState;
+use connection::{TokenType, ListenerToken, IncomingToken, OutgoingToken, AuxiliaryToken,
+                 Connection, EndPointType, DEFAULT_BUFFER_SIZE};
+use driver_state::{DriverState, ListenerRole, ListenerSocket};
+use health_check::HealthProbe;
+use metrics::Metrics;
+use observer::ConnectionObserver;
+use metrics_server::{self, HttpSession};
+use admin;
+use connect_race::ConnectRace;
+use control::{self, ControlSession, ForcedRemoval};
+use frontend::Frontend;
+use peek::{PeekSession, PeekState, DEFAULT_HANDSHAKE_MS};
+use rate_limit::BandwidthCap;
+use shutdown::ShutdownSignal;
+use inspect::InspectRequests;
+use signal;
+use stream::{Address, Stream};
+use timer_wheel::TimerWheel;
+use udp::{UdpAssociation, DEFAULT_UDP_IDLE_MS, MAX_DATAGRAM_SIZE};
+
+/// Connection timeouts fire this close to their deadline at worst.
+const TIMER_TICK_MS: u64 = 10;
+/// Enough slots that a turn of the wheel spans most read and write
+/// timeouts, so few deadlines get looked at more than once.
+const TIMER_SLOTS: usize = 4096;
+const DEFAULT_DRAIN_MS: u64 = 30000;
+const ACCEPT_BACKOFF_MS: u64 = 500;
+/// How long a racing connect is given without a `connect_ms` timeout.
+const CONNECT_RACE_MS: u64 = 30000;
+
+pub enum Auxiliary {
+    HealthProbe(HealthProbe),
+    MetricsScrape(HttpSession),
+    AdminProbe(HttpSession),
+    RoutingPeek(PeekSession),
+    Control(ControlSession),
+    ConnectRace(ConnectRace),
+    UdpAssociation(UdpAssociation),
+}
+
+impl Auxiliary {
+    fn socket(&self) -> &dyn Evented {
+        match *self {
+            Auxiliary::HealthProbe(ref probe) => &probe.stream,
+            Auxiliary::MetricsScrape(ref session) => &session.stream,
+            Auxiliary::AdminProbe(ref session) => &session.stream,
+            Auxiliary::RoutingPeek(ref session) => &session.stream,
+            Auxiliary::Control(ref session) => &session.stream,
+            Auxiliary::ConnectRace(ref race) => &race.stream,
+            Auxiliary::UdpAssociation(ref association) => &association.socket,
+        }
+    }
+
+    fn deadline(&self) -> Instant {
+        match *self {
+            Auxiliary::HealthProbe(ref probe) => probe.deadline(),
+            Auxiliary::MetricsScrape(ref session) => session.deadline(),
+            Auxiliary::AdminProbe(ref session) => session.deadline(),
+            Auxiliary::RoutingPeek(ref session) => session.deadline(),
+            Auxiliary::Control(ref session) => session.deadline(),
+            Auxiliary::ConnectRace(ref race) => race.deadline(),
+            Auxiliary::UdpAssociation(ref association) => association.deadline(),
+        }
+    }
+
+    /// Called when the deadline passes before the socket is done.
+    fn expire(&self, metrics: &Metrics) {
+        match *self {
+            Auxiliary::HealthProbe(ref probe) => probe.report(false),
+            Auxiliary::MetricsScrape(_) => debug!("Metrics scrape timed out"),
+            Auxiliary::AdminProbe(_) => debug!("Admin probe timed out"),
+            Auxiliary::Control(_) => debug!("Closing idle control session"),
+            Auxiliary::RoutingPeek(ref session) => {
+                debug!("Timed out waiting for first request from {}",
+                       session.client_addr());
+                metrics.handshake_timed_out();
+            }
+            Auxiliary::ConnectRace(ref race) => {
+                warn!("[conn={}] Racing connect to backend {} timed out",
+                      race.conn_id,
+                      race.target);
+                metrics.connect_failed();
+                race.cancel(true);
+            }
+            Auxiliary::UdpAssociation(ref association) => association.close(),
+        }
+    }
+}
 
 pub struct Driver {
     to_reregister: HashSet<IncomingToken>,
+    /// Both sockets of a connection share its slot: the incoming and
+    /// outgoing tokens carry the same index and differ only in their tag.
     connections: Slab<Connection, IncomingToken>,
-    connection_tokens: Slab<Option<IncomingToken>, OutgoingToken>,
+    auxiliaries: Slab<Auxiliary, AuxiliaryToken>,
+    /// The association of each client of a UDP frontend.
+    udp_associations: HashMap<(ListenerToken, SocketAddr), AuxiliaryToken>,
+    /// Datagrams are read into this one at a time.
+    datagram_buffer: Vec<u8>,
+    /// The next timeout deadline of every connection that has one.
+    timers: TimerWheel<IncomingToken>,
+    paused_listeners: HashMap<ListenerToken, Instant>,
+    spare_fd: Option<File>,
+    drain_deadline: Option<Instant>,
+    metrics: Arc<Metrics>,
+    observers: Vec<Box<dyn ConnectionObserver>>,
+    /// Shared by every connection when `total_bytes_per_sec` is set.
+    bandwidth: Option<Rc<RefCell<BandwidthCap>>>,
+    max_poll_timeout: Option<Duration>,
+    shutdown: Option<ShutdownSignal>,
+    inspections: Option<InspectRequests>,
     state: DriverState,
 }
 
@@ -23,183 +139,3520 @@ pub struct Driver {
 
 impl Driver {
     pub fn new(state: DriverState) -> Driver {
+        let bandwidth = state.config
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.total_bytes_per_sec)
+            .map(|bytes_per_sec| {
+                     Rc::new(RefCell::new(BandwidthCap::new(bytes_per_sec, Instant::now())))
+                 });
+
         Driver {
             to_reregister: HashSet::new(),
             connections: Slab::with_capacity(state.config.buffers.connections),
-            connection_tokens: Slab::with_capacity(state.config.buffers.connections),
+            auxiliaries: Slab::with_capacity(state.config.buffers.listeners),
+            udp_associations: HashMap::new(),
+            datagram_buffer: vec![0; MAX_DATAGRAM_SIZE],
+            timers: TimerWheel::new(Duration::from_millis(TIMER_TICK_MS),
+                                    TIMER_SLOTS,
+                                    Instant::now()),
+            paused_listeners: HashMap::new(),
+            spare_fd: File::open("/dev/null").ok(),
+            drain_deadline: None,
+            metrics: Arc::new(Metrics::new()),
+            observers: Vec::new(),
+            bandwidth: bandwidth,
+            max_poll_timeout: None,
+            shutdown: None,
+            inspections: None,
             state: state,
         }
     }
 
+    /// Has the event loop wait for sockets no longer than `timeout` at a
+    /// time, even with no deadline coming up.
+    pub fn set_max_poll_timeout(&mut self, timeout: Duration) {
+        self.max_poll_timeout = Some(timeout);
+    }
+
+    /// Has `run` return once one of `signal`'s handles asks it to.
+    pub fn set_shutdown_signal(&mut self, signal: ShutdownSignal) {
+        self.shutdown = Some(signal);
+    }
+
+    /// Runs the inspections asked for through `requests`' handles.
+    pub fn set_inspect_requests(&mut self, requests: InspectRequests) {
+        self.inspections = Some(requests);
+    }
+
+    /// A handle to the counters, which can be read from another thread
+    /// while the event loop runs.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Has `observer` told about every connection opened or closed from
+    /// now on.
+    pub fn add_observer(&mut self, observer: Box<dyn ConnectionObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// The addresses the proxy listeners are bound to, with any port 0
+    /// resolved to the one the system picked.
+    pub fn listen_addrs(&self) -> Vec<Address> {
+        self.state
+            .listeners
+            .iter()
+            .filter(|listener| matches!(listener.role, ListenerRole::Proxy(_)))
+            .filter_map(|listener| listener.socket.local_addr().ok())
+            .collect()
+    }
+
     fn listener_ready(&mut self, poll: &mut Poll, token: ListenerToken, event: Ready) {
         assert!(event.is_readable());
 
-        if let Some(listener) = self.state.listeners.get(token) {
-            info!("Accepting connection");
+        if !self.state.listeners.contains(token) {
+            error!("Listener event on unknown token {:?}", token);
+            return;
+        }
+        if let ListenerRole::Udp(frontend) = self.state.listeners[token].role.clone() {
+            self.receive_datagrams(poll, token, &frontend);
+            self.rearm_listener(poll, token);
+            return;
+        }
 
-            let incoming = match listener.tcp_listener.accept() {
-                Ok((sock, _)) => sock,
+        // Readiness is edge-triggered, so every queued client has to be
+        // taken now or it waits until another one arrives.
+        loop {
+            let (stream, client_addr) = match self.state.listeners[token].socket.accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                // The client gave up before being accepted, which says
+                // nothing about the listener.
+                Err(ref e) if e.kind() == ErrorKind::ConnectionAborted ||
+                              e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if is_out_of_fds(e) => {
+                    error!("Out of file descriptors accepting on listener {:?}: {}", token, e);
+                    self.metrics.accept_failed();
+                    self.shed_client(token);
+                    self.pause_listener(token);
+                    return;
+                }
                 Err(e) => {
-                    error!("Accept error: {}", e);
+                    error!("Accept error on listener {:?}: {}", token, e);
+                    self.metrics.accept_failed();
+                    self.pause_listener(token);
                     return;
                 }
             };
 
-            let backend = listener.frontend.decide_backend();
-            let target = backend.borrow_mut().decide_target();
+            match self.state.listeners[token].role.clone() {
+                ListenerRole::Proxy(frontend) => {
+                    self.accept_connection(poll, token, &frontend, stream, client_addr)
+                }
+                ListenerRole::Metrics => self.accept_metrics_scrape(poll, stream),
+                ListenerRole::Admin => self.accept_admin_probe(poll, stream),
+                ListenerRole::Control => self.accept_control_session(poll, stream),
+                ListenerRole::Udp(_) => unreachable!("UDP listeners take no connections"),
+            }
+        }
+
+        self.rearm_listener(poll, token);
+    }
+
+    /// Leaves a listener disarmed for a while after an accept error, rather
+    /// than spinning on an error that is likely to repeat.
+    fn pause_listener(&mut self, token: ListenerToken) {
+        warn!("Pausing listener {:?} for {} ms", token, ACCEPT_BACKOFF_MS);
+
+        self.paused_listeners
+            .insert(token, Instant::now() + Duration::from_millis(ACCEPT_BACKOFF_MS));
+    }
+
+    /// Accepts one queued client and closes it straight away, by giving up
+    /// the spare descriptor kept for this. Otherwise the client would sit
+    /// in the queue with no answer until descriptors free up.
+    fn shed_client(&mut self, token: ListenerToken) {
+        if let Some(spare_fd) = self.spare_fd.take() {
+            drop(spare_fd);
+
+            if let Ok((_, client_addr)) = self.state.listeners[token].socket.accept() {
+                warn!("Closed connection from {} for lack of file descriptors",
+                      client_addr);
+                self.metrics.connection_accepted();
+                self.metrics.connection_rejected();
+            }
+            self.spare_fd = File::open("/dev/null").ok();
+        }
+    }
+
+    fn resume_listeners(&mut self, poll: &mut Poll) {
+        let now = Instant::now();
+        let resumed = self.paused_listeners
+            .iter()
+            .filter(|&(_, &until)| until <= now)
+            .map(|(&token, _)| token)
+            .collect::<Vec<_>>();
+
+        for token in resumed {
+            self.paused_listeners.remove(&token);
+            if self.state.listeners.contains(token) {
+                debug!("Resuming listener {:?}", token);
+                self.rearm_listener(poll, token);
+            }
+        }
+    }
+
+    fn rearm_listener(&self, poll: &mut Poll, token: ListenerToken) {
+        let listener = &self.state.listeners[token];
+        // A UDP socket is nearly always writable, which says nothing here.
+        let interest = match listener.role {
+            ListenerRole::Udp(_) => Ready::readable(),
+            _ => Ready::readable() | Ready::writable(),
+        };
+        poll.reregister(&listener.socket,
+                        token.as_raw_token(),
+                        interest,
+                        PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+    }
+
+    /// Reads every datagram waiting on a UDP frontend and forwards each to
+    /// the target of its client's association, opening one for clients
+    /// that have none.
+    fn receive_datagrams(&mut self, poll: &mut Poll, token: ListenerToken, frontend: &Frontend) {
+        let mut buffer = mem::take(&mut self.datagram_buffer);
 
-            let outgoing = match TcpStream::connect(&target) {
-                Ok(client) => client,
+        loop {
+            let received = match self.state.listeners[token].socket {
+                ListenerSocket::Datagram(ref socket) => socket.recv_from(&mut buffer),
+                ListenerSocket::Stream(_) => break,
+            };
+            let (n_read, client_addr) = match received {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
                 Err(e) => {
-                    error!("Connect error: {}", e);
-                    return;
+                    debug!("Receiving on UDP listener {:?} failed: {}", token, e);
+                    break;
+                }
+            };
+
+            let now = Instant::now();
+            let association = match self.udp_associations.get(&(token, client_addr)) {
+                Some(&association) => association,
+                None => {
+                    match self.open_udp_association(poll, token, frontend, client_addr, now) {
+                        Some(association) => association,
+                        None => continue,
+                    }
                 }
             };
+            if let Some(&mut Auxiliary::UdpAssociation(ref mut association)) =
+                self.auxiliaries.get_mut(association) {
+                association.forward(&buffer[..n_read], now);
+            }
+        }
 
-            let outgoing_token = self.connection_tokens
-                .insert(None)
-                .expect("Outgoing buffer full");
+        self.datagram_buffer = buffer;
+    }
 
-            let incoming_token = self.connections
-                .insert(Connection::new(incoming, outgoing, outgoing_token))
-                .map_err(|_| "Incoming buffer full")
-                .unwrap();
+    fn open_udp_association(&mut self,
+                            poll: &mut Poll,
+                            token: ListenerToken,
+                            frontend: &Frontend,
+                            client_addr: SocketAddr,
+                            now: Instant)
+                            -> Option<AuxiliaryToken> {
+        self.metrics.connection_accepted();
+        if !frontend.permits(client_addr.ip()) {
+            debug!("Denying datagrams from {}", client_addr.ip());
+            self.metrics.connection_denied();
+            return None;
+        }
+        if let Some(ref mut limiter) = self.state.rate_limiter {
+            if !limiter.allow(client_addr.ip(), now) {
+                debug!("Rate limiting datagrams from {}", client_addr);
+                self.metrics.connection_rejected();
+                return None;
+            }
+        }
+
+        let idle_timeout = self.timeouts().udp_idle_ms.unwrap_or(DEFAULT_UDP_IDLE_MS);
+        let association = match UdpAssociation::open(token,
+                                                     client_addr,
+                                                     frontend.decide_backend(),
+                                                     self.metrics.clone(),
+                                                     Duration::from_millis(idle_timeout),
+                                                     now) {
+            Ok(association) => association,
+            Err(e) => {
+                warn!("Could not relay datagrams from {}: {}", client_addr, e);
+                self.metrics.connect_failed();
+                return None;
+            }
+        };
+        debug!("Relaying datagrams from {} on listener {:?} to backend {}",
+               client_addr,
+               token,
+               frontend.backend_name());
 
-            self.connection_tokens[outgoing_token] = Some(incoming_token);
+        let association = self.insert_auxiliary(Auxiliary::UdpAssociation(association));
+        // Replies are read until there are none left, so the registration
+        // never needs renewing.
+        poll.register(self.auxiliaries[association].socket(),
+                      association.as_raw_token(),
+                      Ready::readable(),
+                      PollOpt::edge())
+            .unwrap();
+        self.udp_associations.insert((token, client_addr), association);
+        Some(association)
+    }
 
-            let connection = self.connections.get(incoming_token).unwrap();
+    fn accept_connection(&mut self,
+                         poll: &mut Poll,
+                         token: ListenerToken,
+                         frontend: &Rc<Frontend>,
+                         incoming: Stream,
+                         client_addr: SocketAddr) {
+        info!("Accepting connection");
+        self.metrics.connection_accepted();
 
-            info!("IncomingToken {:?}", incoming_token.as_raw_token());
-            info!("OutgoingToken {:?}", outgoing_token.as_raw_token());
-            poll.register(connection.incoming_stream(),
-                          incoming_token.as_raw_token(),
-                          Ready::readable() | Ready::writable(),
-                          PollOpt::edge() | PollOpt::oneshot())
-                .unwrap();
-            poll.register(connection.outgoing_stream(),
-                          outgoing_token.as_raw_token(),
-                          Ready::readable() | Ready::writable(),
+        if !incoming.is_unix() && !frontend.permits(client_addr.ip()) {
+            debug!("Denying connection from {}", client_addr.ip());
+            self.metrics.connection_denied();
+            return;
+        }
+
+        // Unix domain clients have no address of their own to limit by.
+        if let Some(ref mut limiter) = self.state.rate_limiter {
+            if !incoming.is_unix() && !limiter.allow(client_addr.ip(), Instant::now()) {
+                debug!("Rate limiting connection from {}", client_addr);
+                self.metrics.connection_rejected();
+                return;
+            }
+        }
+
+        if !self.has_room_for(&client_addr) {
+            return;
+        }
+
+        // The backend can't be picked before the name the client wants has
+        // been read, so the client waits as an auxiliary socket until then.
+        if let Some(routing) = frontend.name_routing() {
+            let buffer_size = self.buffer_size_for(frontend);
+            let session = PeekSession::new(incoming,
+                                           client_addr,
+                                           frontend.clone(),
+                                           routing,
+                                           frontend
+                                               .max_routing_bytes()
+                                               .map_or(buffer_size, |max| max.min(buffer_size)),
+                                           self.handshake_timeout(),
+                                           Instant::now());
+            let token = self.insert_auxiliary(Auxiliary::RoutingPeek(session));
+
+            poll.register(self.auxiliaries[token].socket(),
+                          token.as_raw_token(),
+                          Ready::readable(),
                           PollOpt::edge() | PollOpt::oneshot())
                 .unwrap();
+            return;
+        }
 
-            poll.reregister(&listener.tcp_listener,
-                            token.as_raw_token(),
-                            Ready::readable() | Ready::writable(),
-                            PollOpt::edge() | PollOpt::oneshot())
-                .unwrap();
-        } else {
-            error!("Listener event on unknown token {:?}", token);
+        debug!("Routing {} from listener {:?} to backend {}",
+               client_addr,
+               token,
+               frontend.backend_name());
+        self.open_connection(poll, incoming, client_addr, frontend, None, &[]);
+    }
+
+    /// Dials a backend for an accepted client and starts relaying. `initial`
+    /// holds anything already read from the client, which is relayed first.
+    fn open_connection(&mut self,
+                       poll: &mut Poll,
+                       incoming: Stream,
+                       client_addr: SocketAddr,
+                       frontend: &Frontend,
+                       name: Option<&str>,
+                       initial: &[u8]) {
+        // Clients routed by name were let in before their name was read,
+        // and others may have taken up the room since.
+        if !self.has_room_for(&client_addr) {
+            return;
+        }
+
+        let backend = frontend.decide_backend_for(name);
+        let mut tried = Vec::new();
+        let outgoing = match connect_untried(&backend, &client_addr, &mut tried, &self.metrics) {
+            Ok(connected) => connected,
+            Err(e) => {
+                error!("Connect error: {}", e);
+                self.metrics.connection_error();
+                return;
+            }
+        };
+
+        let buffer_size = self.buffer_size_for(frontend);
+        let entry = match self.connections.vacant_entry() {
+            Some(entry) => entry,
+            None => {
+                warn!("Rejecting connection from {}: connection buffer full", client_addr);
+                backend.borrow_mut().release_target(&outgoing.1);
+                self.metrics.connection_rejected();
+                return;
+            }
+        };
+        let incoming_token = entry.index();
+        let connection = match Connection::new((incoming, client_addr),
+                                               outgoing,
+                                               incoming_token,
+                                               buffer_size,
+                                               backend,
+                                               self.metrics.clone()) {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("Could not relay {}: {}", client_addr, e);
+                self.metrics.connection_error();
+                return;
+            }
+        };
+        entry.insert(connection);
+        self.metrics.connection_opened();
+
+        let splice = self.splice();
+        let max_bytes_per_sec = self.max_bytes_per_sec();
+        let detects_stalls = self.timeouts().stall_ms.is_some();
+        let connection = self.connections.get_mut(incoming_token).unwrap();
+        connection.set_tried_targets(tried);
+        if let Some(name) = name {
+            connection.set_server_name(name);
+        }
+        if frontend.accepts_proxy_protocol() {
+            connection.expect_proxy_header();
+        }
+        if splice {
+            connection.use_splice();
+        }
+        if let Some(bytes_per_sec) = max_bytes_per_sec {
+            connection.limit_bandwidth(bytes_per_sec);
+        }
+        if let Some(ref cap) = self.bandwidth {
+            connection.share_bandwidth(cap.clone());
+        }
+        if detects_stalls {
+            connection.detect_stalls();
         }
+        let race_delay = connection.pool().borrow().happy_eyeballs_delay();
+        connection.race_connect_after(race_delay);
+        if frontend.logs_access() {
+            connection.enable_access_log();
+        }
+        if !initial.is_empty() {
+            connection.prefill_incoming(initial);
+        }
+
+        info!("[conn={}] Relaying {} to backend {}",
+              connection.id(),
+              client_addr,
+              connection.target());
+        for observer in self.observers.iter_mut() {
+            observer.on_accept(&client_addr, connection.target(), connection.id());
+        }
+        debug!("[conn={}] Incoming token {:?}, outgoing token {:?}",
+               connection.id(),
+               connection.incoming_token(),
+               connection.outgoing_token());
+        poll.register(connection.incoming_stream(),
+                      connection.incoming_token().as_raw_token(),
+                      connection.incoming_interest(),
+                      PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+        poll.register(connection.outgoing_stream(),
+                      connection.outgoing_token().as_raw_token(),
+                      connection.outgoing_interest(),
+                      PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+        self.schedule_timeout(incoming_token);
     }
 
-    fn incoming_ready(&mut self, token: IncomingToken, ready: Ready) {
+    fn incoming_ready(&mut self, poll: &mut Poll, token: IncomingToken, ready: Ready) {
         let mut remove = false;
+        let mut retry = false;
 
         if let Some(mut connection) = self.connections.get_mut(token) {
-            debug!("in incoming ready {:?} {:?}", token, ready);
+            debug!("[conn={}] Incoming ready {:?}", connection.id(), ready);
             connection.incoming_ready(ready);
             let data_sent = connection.tick();
-            if !data_sent && (connection.is_incoming_closed() || connection.is_outgoing_closed()) {
+            if connection.is_early_failure() {
+                retry = true;
+            } else if connection.should_close(data_sent) ||
+                      connection.is_over_byte_limit() && connection.close_at_byte_limit() {
                 remove = true;
-            } else if data_sent || ready.is_readable() {
+            } else {
                 self.to_reregister.insert(token);
             }
         } else {
             warn!("Could not find incoming connection for {:?}", token);
         }
 
+        if retry {
+            if self.retry_early_failure(poll, token) {
+                self.to_reregister.insert(token);
+            } else {
+                remove = true;
+            }
+        }
+
         if remove {
-            self.remove_connection(token);
+            self.remove_connection(poll, token);
         }
     }
 
-    fn outgoing_ready(&mut self, token: OutgoingToken, ready: Ready) {
-        if let Some(&Some(incoming_token)) = self.connection_tokens.get(token) {
-            let mut remove = false;
+    fn outgoing_ready(&mut self, poll: &mut Poll, token: OutgoingToken, ready: Ready) {
+        let incoming_token = token.incoming();
+        let mut remove = false;
+        let mut retry = false;
+        let mut early_failure = false;
+        let mut lost_race = None;
+
+        if let Some(mut connection) = self.connections.get_mut(incoming_token) {
+            connection.outgoing_ready(ready);
+            if connection.is_connected() {
+                lost_race = connection.take_race();
+            }
 
-            if let Some(mut connection) = self.connections.get_mut(incoming_token) {
-                connection.outgoing_ready(ready);
+            if connection.is_connect_failed() {
+                retry = true;
+            } else {
                 let data_sent = connection.tick();
 
-                if !data_sent &&
-                   (connection.is_incoming_closed() || connection.is_outgoing_closed()) {
+                if connection.is_early_failure() {
+                    early_failure = true;
+                } else if connection.should_close(data_sent) ||
+                          connection.is_over_byte_limit() && connection.close_at_byte_limit() {
                     remove = true;
-                } else if data_sent || ready.is_readable() {
+                } else {
                     self.to_reregister.insert(incoming_token);
                 }
+            }
+        } else {
+            warn!("Could not find corresponding incoming connection for {:?} -> {:?}",
+                  token,
+                  incoming_token);
+        }
+
+        if let Some(race) = lost_race.and_then(|race| self.remove_connect_race(poll, race)) {
+            debug!("[conn={}] Dropping the racing connect to backend {}",
+                   race.conn_id,
+                   race.target);
+            race.cancel(false);
+        }
+
+        if retry {
+            if self.retry_outgoing(poll, incoming_token) {
+                self.to_reregister.insert(incoming_token);
+            } else {
+                remove = true;
+            }
+        } else if early_failure {
+            if self.retry_early_failure(poll, incoming_token) {
+                self.to_reregister.insert(incoming_token);
             } else {
-                warn!("Could not find corresponding incoming connection for {:?} -> {:?}",
-                      token,
-                      incoming_token);
+                remove = true;
+            }
+        }
+
+        if remove {
+            debug!("Clearing connection from {:?} -> {:?}",
+                   token,
+                   incoming_token);
+            self.remove_connection(poll, incoming_token);
+        }
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.state
+            .config
+            .buffers
+            .endpoint_size
+            .unwrap_or(DEFAULT_BUFFER_SIZE)
+    }
+
+    fn splice(&self) -> bool {
+        self.state.config.buffers.splice.unwrap_or(false)
+    }
+
+    fn handshake_timeout(&self) -> Duration {
+        Duration::from_millis(self.timeouts().handshake_ms.unwrap_or(DEFAULT_HANDSHAKE_MS))
+    }
+
+    fn max_bytes_per_sec(&self) -> Option<u64> {
+        self.state.config.limits.as_ref().and_then(|limits| limits.max_bytes_per_sec)
+    }
+
+    fn buffer_size_for(&self, frontend: &Frontend) -> usize {
+        frontend.endpoint_size().unwrap_or_else(|| self.buffer_size())
+    }
+
+    /// Whether one more connection fits under `max_connections`. A client
+    /// that doesn't is counted as rejected, and closed as it is dropped.
+    fn has_room_for(&self, client_addr: &SocketAddr) -> bool {
+        let max_connections = self.max_connections();
+        if self.connections.len() < max_connections {
+            return true;
+        }
+
+        warn!("Rejecting connection from {}: limit of {} connections reached",
+              client_addr,
+              max_connections);
+        self.metrics.connection_rejected();
+        false
+    }
+
+    /// The configured limit, which can never exceed what the connection
+    /// slab has room for.
+    fn max_connections(&self) -> usize {
+        let capacity = self.connections.capacity();
+
+        self.state
+            .config
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.max_connections)
+            .map_or(capacity, |max| max.min(capacity))
+    }
+
+    fn accept_metrics_scrape(&mut self, poll: &mut Poll, stream: Stream) {
+        let session = HttpSession::new(stream, Instant::now());
+        let interest = session.interest();
+        let token = self.insert_auxiliary(Auxiliary::MetricsScrape(session));
+
+        poll.register(self.auxiliaries[token].socket(),
+                      token.as_raw_token(),
+                      interest,
+                      PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+    }
+
+    fn accept_admin_probe(&mut self, poll: &mut Poll, stream: Stream) {
+        let session = HttpSession::new(stream, Instant::now());
+        let interest = session.interest();
+        let token = self.insert_auxiliary(Auxiliary::AdminProbe(session));
+
+        poll.register(self.auxiliaries[token].socket(),
+                      token.as_raw_token(),
+                      interest,
+                      PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+    }
+
+    fn accept_control_session(&mut self, poll: &mut Poll, stream: Stream) {
+        let session = ControlSession::new(stream, Instant::now());
+        let interest = session.interest();
+        let token = self.insert_auxiliary(Auxiliary::Control(session));
+
+        poll.register(self.auxiliaries[token].socket(),
+                      token.as_raw_token(),
+                      interest,
+                      PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+    }
+
+    fn auxiliary_ready(&mut self, poll: &mut Poll, token: AuxiliaryToken, ready: Ready) {
+        let mut routed = None;
+        let mut raced = false;
+        let mut forced_removals = Vec::new();
+        let done = match self.auxiliaries.get_mut(token) {
+            Some(Auxiliary::HealthProbe(probe)) => {
+                match probe.ready(ready, Instant::now()) {
+                    Some(success) => {
+                        probe.report(success);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Some(Auxiliary::MetricsScrape(session)) => {
+                let metrics = &self.metrics;
+                session.ready(ready, |request| metrics_server::render_response(request, metrics))
+            }
+            Some(Auxiliary::AdminProbe(session)) => {
+                let pools = &self.state.backends;
+                session.ready(ready,
+                              |request| admin::render_response(request, pools, Instant::now()))
+            }
+            Some(Auxiliary::Control(session)) => {
+                let done = session.ready(ready,
+                                         &self.state.backends,
+                                         &self.state.frontends,
+                                         &self.connections);
+                forced_removals = session.take_forced_removals();
+                done
+            }
+            Some(Auxiliary::RoutingPeek(session)) => {
+                match session.ready(ready) {
+                    PeekState::Pending => false,
+                    PeekState::Routed(name) => {
+                        routed = Some(name);
+                        true
+                    }
+                    PeekState::Failed => true,
+                    PeekState::Overflowed => {
+                        info!("Closing {}, which sent too much before naming a host",
+                              session.client_addr());
+                        self.metrics.connection_rejected();
+                        true
+                    }
+                }
+            }
+            Some(Auxiliary::ConnectRace(race)) => {
+                match race.connect_result(ready) {
+                    Some(connected) => {
+                        raced = connected;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Some(Auxiliary::UdpAssociation(association)) => {
+                // Ends with its listener, which replies would be sent from.
+                match self.state.listeners.get(association.listener).map(|l| &l.socket) {
+                    Some(ListenerSocket::Datagram(frontend)) => {
+                        association.relay_replies(frontend,
+                                                  &mut self.datagram_buffer,
+                                                  Instant::now());
+                        false
+                    }
+                    _ => true,
+                }
+            }
+            None => {
+                warn!("Could not find auxiliary socket for {:?}", token);
+                return;
             }
+        };
+
+        if done {
+            let auxiliary = match self.auxiliaries.remove(token) {
+                Some(auxiliary) => auxiliary,
+                None => return,
+            };
+            poll.deregister(auxiliary.socket()).unwrap();
 
-            if remove {
-                debug!("Clearing connection from {:?} -> {:?}",
-                       token,
-                       incoming_token);
-                self.connection_tokens[token] = None
+            match (auxiliary, routed) {
+                (Auxiliary::RoutingPeek(session), Some(name)) => {
+                    let (incoming, client_addr, frontend, initial) = session.into_parts();
+
+                    debug!("Routing {} for name {:?}", client_addr, name);
+                    self.open_connection(poll,
+                                         incoming,
+                                         client_addr,
+                                         &frontend,
+                                         name.as_deref(),
+                                         &initial);
+                }
+                (Auxiliary::ConnectRace(race), _) => self.finish_connect_race(poll, race, raced),
+                (Auxiliary::UdpAssociation(association), _) => {
+                    self.udp_associations.remove(&(association.listener, association.client_addr));
+                    association.close();
+                }
+                _ => {}
             }
-        } else {
-            warn!("Could not find outgoing connection for {:?}", token);
+        } else if let Some(&Auxiliary::MetricsScrape(ref session)) |
+                      Some(&Auxiliary::AdminProbe(ref session)) = self.auxiliaries.get(token) {
+            poll.reregister(&session.stream,
+                            token.as_raw_token(),
+                            session.interest(),
+                            PollOpt::edge() | PollOpt::oneshot())
+                .unwrap();
+        } else if let Some(Auxiliary::HealthProbe(probe)) = self.auxiliaries.get(token) {
+            poll.reregister(&probe.stream,
+                            token.as_raw_token(),
+                            probe.interest(),
+                            PollOpt::edge() | PollOpt::oneshot())
+                .unwrap();
+        } else if let Some(Auxiliary::Control(session)) = self.auxiliaries.get(token) {
+            poll.reregister(&session.stream,
+                            token.as_raw_token(),
+                            session.interest(),
+                            PollOpt::edge() | PollOpt::oneshot())
+                .unwrap();
+        } else if let Some(Auxiliary::RoutingPeek(session)) = self.auxiliaries.get(token) {
+            poll.reregister(&session.stream,
+                            token.as_raw_token(),
+                            Ready::readable(),
+                            PollOpt::edge() | PollOpt::oneshot())
+                .unwrap();
+        }
+
+        for removal in forced_removals {
+            self.close_connections_to(poll, &removal);
         }
     }
 
-    fn remove_connection(&mut self, token: IncomingToken) {
-        debug!("Removing connection on incoming token {:?}", token);
-        let connection = self.connections
-            .remove(token)
-            .expect("Can't remove already removed incoming connection");
-        self.connection_tokens
-            .remove(connection.outgoing_token())
-            .expect("Can't remove already removed outgoing connection");
+    /// Winds down the connections still relaying to a target removed with
+    /// `force`. Each goes as soon as its buffers are flushed and both of
+    /// its sides are closed.
+    fn close_connections_to(&mut self, poll: &mut Poll, removal: &ForcedRemoval) {
+        let (ref pool, ref target) = *removal;
+        let tokens = (0..self.connections.capacity())
+            .map(IncomingToken)
+            .filter(|token| match self.connections.get(*token) {
+                        Some(connection) => {
+                            Rc::ptr_eq(connection.pool(), pool) && connection.target() == target
+                        }
+                        None => false,
+                    })
+            .collect::<Vec<_>>();
+
+        info!("Closing {} connections to removed target {}", tokens.len(), target);
+        for token in tokens {
+            if self.connections.get_mut(token).map_or(false, Connection::begin_close) {
+                self.to_reregister.remove(&token);
+                self.remove_connection(poll, token);
+            } else {
+                self.to_reregister.insert(token);
+            }
+        }
     }
 
-    fn tick(&mut self, poll: &mut Poll) {
-        for token in self.to_reregister.iter() {
-            if let Some(connection) = self.connections.get(*token) {
-                poll.reregister(connection.incoming_stream(),
-                                token.as_raw_token(),
-                                Ready::readable() | Ready::writable(),
-                                PollOpt::edge() | PollOpt::oneshot())
-                    .unwrap();
+    fn run_inspections(&mut self) {
+        let inspections = match self.inspections {
+            Some(ref requests) => requests.take(),
+            None => return,
+        };
 
-                poll.reregister(connection.outgoing_stream(),
-                                connection.outgoing_token().as_raw_token(),
-                                Ready::readable() | Ready::writable(),
-                                PollOpt::edge() | PollOpt::oneshot())
+        for inspect in inspections {
+            let infos = control::connection_infos(&self.connections, Instant::now());
+            inspect(&mut infos.into_iter());
+        }
+    }
+
+    fn insert_auxiliary(&mut self, auxiliary: Auxiliary) -> AuxiliaryToken {
+        if !self.auxiliaries.has_available() {
+            let additional = self.auxiliaries.len().max(1);
+            self.auxiliaries.reserve_exact(additional);
+        }
+
+        self.auxiliaries
+            .insert(auxiliary)
+            .map_err(|_| "Auxiliary buffer full")
+            .unwrap()
+    }
+
+    fn run_health_checks(&mut self, poll: &mut Poll) {
+        let now = Instant::now();
+
+        for probe in self.state.health_checker.start_due_probes(now) {
+            let token = self.insert_auxiliary(Auxiliary::HealthProbe(probe));
+
+            if let Some(Auxiliary::HealthProbe(probe)) = self.auxiliaries.get(token) {
+                poll.register(&probe.stream,
+                              token.as_raw_token(),
+                              probe.interest(),
+                              PollOpt::edge() | PollOpt::oneshot())
                     .unwrap();
             }
         }
 
-        self.to_reregister.clear();
+        self.expire_auxiliaries(poll, now);
+    }
 
-        for token in self.state.listeners_to_remove.iter() {
-            info!("Removing listener on token {:?}", token);
+    fn expire_auxiliaries(&mut self, poll: &mut Poll, now: Instant) {
+        let metrics = &self.metrics;
+        let connections = &mut self.connections;
+        let udp_associations = &mut self.udp_associations;
 
-            let listener = self.state.listeners.remove(*token).unwrap();
+        self.auxiliaries
+            .retain(|auxiliary| {
+                        if auxiliary.deadline() > now {
+                            return true;
+                        }
 
-            poll.deregister(&listener.tcp_listener).unwrap();
-            drop(listener);
+                        poll.deregister(auxiliary.socket()).unwrap();
+                        auxiliary.expire(metrics);
+                        if let Auxiliary::ConnectRace(ref race) = *auxiliary {
+                            if let Some(connection) = connections.get_mut(race.connection) {
+                                if connection.id() == race.conn_id {
+                                    connection.take_race();
+                                }
+                            }
+                        }
+                        if let Auxiliary::UdpAssociation(ref association) = *auxiliary {
+                            udp_associations.remove(&(association.listener,
+                                                      association.client_addr));
+                        }
+                        false
+                    });
+    }
+
+    fn timeouts(&self) -> TimeoutConfig {
+        self.state.config.timeouts.clone().unwrap_or_default()
+    }
+
+    /// Splits the bandwidth cap between the connections there are now, and
+    /// reports how close to it relaying runs.
+    fn share_bandwidth(&mut self) {
+        if let Some(ref cap) = self.bandwidth {
+            let mut cap = cap.borrow_mut();
+
+            cap.set_sharers(self.connections.len());
+            self.metrics.set_throughput(cap.throughput(Instant::now()), cap.bytes_per_sec());
         }
+    }
 
-        self.state.listeners_to_remove.clear();
+    /// Keeps the bytes buffered across all connections near the configured
+    /// limit. Past it, the connections holding the most stop reading until
+    /// the total is back under, so peers that are slow to take their bytes
+    /// can't make memory grow without bound. The total is published as a
+    /// metric either way.
+    fn limit_buffered_bytes(&mut self) {
+        let buffered = (0..self.connections.capacity())
+            .map(IncomingToken)
+            .filter_map(|token| {
+                            self.connections
+                                .get(token)
+                                .map(|connection| (connection.buffered_bytes(), token))
+                        })
+            .collect::<Vec<_>>();
+        self.metrics.set_buffered_bytes(buffered.iter().map(|&(n, _)| n).sum());
+
+        let max_buffered_bytes = self.state
+            .config
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.max_buffered_bytes);
+        let max_buffered_bytes = match max_buffered_bytes {
+            Some(max_buffered_bytes) => max_buffered_bytes,
+            None => return,
+        };
+
+        let paused = connections_to_pause(buffered, max_buffered_bytes);
+        for token in (0..self.connections.capacity()).map(IncomingToken) {
+            if let Some(connection) = self.connections.get_mut(token) {
+                let pause = paused.contains(&token);
+
+                if connection.is_read_paused() != pause {
+                    debug!("[conn={}] {} reading, {} bytes buffered",
+                           connection.id(),
+                           if pause { "Pausing" } else { "Resuming" },
+                           connection.buffered_bytes());
+                    connection.set_read_paused(pause);
+                    self.to_reregister.insert(token);
+                }
+            }
+        }
     }
 
-    pub fn run(&mut self, poll: &mut Poll, events: &mut Events) {
-        loop {
-            poll.poll(events, None).unwrap();
+    /// Puts the connection on the timer wheel for its next deadline, or
+    /// takes it off if none of its timeouts are running.
+    fn schedule_timeout(&mut self, token: IncomingToken) {
+        let timeouts = self.timeouts();
+        match self.connections.get(token).and_then(|c| c.next_deadline(&timeouts)) {
+            Some(deadline) => self.timers.schedule(token, deadline),
+            None => self.timers.cancel(token),
+        }
+    }
 
-            for event in events.iter() {
-                match TokenType::from_raw_token(event.token()) {
-                    TokenType::Listener(token) => {
-                        self.listener_ready(poll, token, event.readiness())
-                    }
-                    TokenType::Incoming(token) => self.incoming_ready(token, event.readiness()),
-                    TokenType::Outgoing(token) => self.outgoing_ready(token, event.readiness()),
+    /// Closes or retries the connections whose deadlines have come up on
+    /// the timer wheel. Rounding to the wheel's ticks means a deadline can
+    /// come up a little before a timeout actually ran out; those
+    /// connections are just scheduled again.
+    fn reap_connections(&mut self, poll: &mut Poll) {
+        let now = Instant::now();
+        let expired = self.timers.expire(now);
+        if expired.is_empty() {
+            return;
+        }
+
+        let timeouts = self.timeouts();
+        let idle_timeout = timeouts.idle_ms.map(Duration::from_millis);
+        let connect_timeout = timeouts.connect_ms.map(Duration::from_millis);
+        let read_timeout = timeouts.read_ms.map(Duration::from_millis);
+        let write_timeout = timeouts.write_ms.map(Duration::from_millis);
+        let max_lifetime = timeouts.max_lifetime_ms.map(Duration::from_millis);
+        let stall_timeout = timeouts.stall_ms.map(Duration::from_millis);
+        let handshake_timeout = timeouts.handshake_ms.map(Duration::from_millis);
+
+        let mut idle_tokens = Vec::new();
+        let mut connect_tokens = Vec::new();
+        let mut pending_tokens = Vec::new();
+        let mut race_tokens = Vec::new();
+
+        for token in expired {
+            // A throttled connection waits on the wheel for its allowance
+            // to refill, and reads again once it has. A stall timer needs
+            // a fresh look at what the peers have acknowledged since.
+            if let Some(connection) = self.connections.get_mut(token) {
+                if connection.resume_throttled(now) {
+                    self.to_reregister.insert(token);
+                }
+                connection.probe_unacked(now);
+                if connection.take_due_race(now) {
+                    race_tokens.push(token);
+                }
+            }
+
+            if let Some(connection) = self.connections.get(token) {
+                if connect_timeout.map_or(false, |t| connection.is_connect_timed_out(now, t)) {
+                    connect_tokens.push(token);
+                } else if handshake_timeout
+                              .map_or(false, |t| connection.is_handshake_timed_out(now, t)) {
+                    info!("[conn={}] No PROXY protocol header in time, closing",
+                          connection.id());
+                    self.metrics.handshake_timed_out();
+                    idle_tokens.push(token);
+                } else if max_lifetime.map_or(false, |t| connection.age(now) >= t) {
+                    info!("[conn={}] Open for too long, closing", connection.id());
+                    self.metrics.lifetime_ended();
+                    idle_tokens.push(token);
+                } else if idle_timeout.map_or(false, |t| connection.idle_for(now) >= t) {
+                    self.metrics.idle_timed_out();
+                    idle_tokens.push(token);
+                } else if let Some(side) = write_timeout
+                              .and_then(|t| connection.write_timed_out(now, t)) {
+                    warn!("[conn={}] The {} stopped taking bytes, closing",
+                          connection.id(),
+                          side_name(side));
+                    self.metrics.write_timed_out();
+                    idle_tokens.push(token);
+                } else if let Some(side) = stall_timeout
+                              .and_then(|t| connection.stalled(now, t)) {
+                    warn!("[conn={}] The {} acknowledged nothing in time, closing",
+                          connection.id(),
+                          side_name(side));
+                    self.metrics.stall_timed_out();
+                    idle_tokens.push(token);
+                } else if let Some(side) = read_timeout
+                              .and_then(|t| connection.read_timed_out(now, t)) {
+                    info!("[conn={}] Nothing read from the {} in time, closing",
+                          connection.id(),
+                          side_name(side));
+                    self.metrics.read_timed_out();
+                    idle_tokens.push(token);
+                } else {
+                    pending_tokens.push(token);
                 }
             }
-            self.tick(poll);
         }
+
+        for token in race_tokens {
+            self.start_connect_race(poll, token);
+        }
+
+        for token in connect_tokens {
+            if let Some(connection) = self.connections.get(token) {
+                warn!("[conn={}] Connect to backend {} timed out",
+                      connection.id(),
+                      connection.target());
+            }
+            if self.retry_outgoing(poll, token) {
+                pending_tokens.push(token);
+            } else {
+                idle_tokens.push(token);
+            }
+        }
+
+        for token in pending_tokens {
+            self.schedule_timeout(token);
+        }
+
+        for token in idle_tokens {
+            if let Some(connection) = self.connections.get(token) {
+                info!("[conn={}] Closing timed out connection", connection.id());
+            }
+
+            self.to_reregister.remove(&token);
+            self.remove_connection(poll, token);
+        }
+    }
+
+    /// Sends a client on to another target after its own reset or closed
+    /// the connection before anything was relayed, like after a failed
+    /// connect. Returns false if the connection should be closed instead.
+    fn retry_early_failure(&mut self, poll: &mut Poll, token: IncomingToken) -> bool {
+        if let Some(connection) = self.connections.get(token) {
+            info!("[conn={}] Backend {} closed the connection before relaying anything",
+                  connection.id(),
+                  connection.target());
+        }
+        self.retry_outgoing(poll, token)
+    }
+
+    /// Abandons a backend connection that timed out or was refused,
+    /// reporting the backend as failed, and dials another backend if the
+    /// pool allows retries. Returns false if the connection should be
+    /// closed instead.
+    fn retry_outgoing(&mut self, poll: &mut Poll, token: IncomingToken) -> bool {
+        let connection = match self.connections.get_mut(token) {
+            Some(connection) => connection,
+            None => return false,
+        };
+
+        self.metrics.connect_failed();
+        if connection.report_target_failure() {
+            self.metrics.connection_error();
+        }
+
+        // A racing connect still under way is as good as a fresh one.
+        if let Some(race) = connection.take_race() {
+            return match self.remove_connect_race(poll, race) {
+                Some(race) => self.take_over_outgoing(poll, race),
+                None => false,
+            };
+        }
+
+        let pool = connection.pool().clone();
+        if connection.connect_attempts() > pool.borrow().connect_retries() {
+            return false;
+        }
+        self.metrics.connect_retried();
+
+        let mut tried = connection.tried_targets().to_vec();
+        let attempt = connect_untried(&pool, connection.client_addr(), &mut tried, &self.metrics);
+        let (outgoing, target) = match attempt {
+            Ok(connected) => connected,
+            Err(e) => {
+                error!("Connect error: {}", e);
+                return false;
+            }
+        };
+
+        info!("[conn={}] Retrying with backend {}", connection.id(), target);
+
+        match connection.replace_outgoing(outgoing, target, tried) {
+            Ok(replaced) => poll.deregister(&replaced).unwrap(),
+            Err(e) => {
+                error!("[conn={}] Could not relay to the next backend: {}", connection.id(), e);
+                self.metrics.connection_error();
+                return false;
+            }
+        }
+        poll.register(connection.outgoing_stream(),
+                      connection.outgoing_token().as_raw_token(),
+                      connection.outgoing_interest(),
+                      PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+
+        true
+    }
+
+    /// Races a connect to the other IP version of the target's host name
+    /// against the connection's, which is slow to finish. Nothing happens
+    /// if the host has no address to race with.
+    fn start_connect_race(&mut self, poll: &mut Poll, token: IncomingToken) {
+        let timeout = self.timeouts().connect_ms.unwrap_or(CONNECT_RACE_MS);
+        let deadline = Instant::now() + Duration::from_millis(timeout);
+
+        let race = {
+            let connection = match self.connections.get_mut(token) {
+                Some(connection) => connection,
+                None => return,
+            };
+            let pool = connection.pool().clone();
+            let mut tried = connection.tried_targets().to_vec();
+            let attempt = pool.borrow_mut().connect_other_family(connection.target(), &mut tried);
+
+            let connected = match attempt {
+                Some(Ok(connected)) => connected,
+                Some(Err(e)) => {
+                    warn!("[conn={}] Could not race a connect to {}: {}",
+                          connection.id(),
+                          tried.last().unwrap(),
+                          e);
+                    self.metrics.connect_failed();
+                    connection.set_tried_targets(tried);
+                    return;
+                }
+                None => return,
+            };
+
+            info!("[conn={}] Connect to backend {} is slow, racing {}",
+                  connection.id(),
+                  connection.target(),
+                  connected.1);
+            connection.set_tried_targets(tried);
+            ConnectRace::new(connected, token, connection.id(), pool, deadline)
+        };
+
+        let race_token = self.insert_auxiliary(Auxiliary::ConnectRace(race));
+        if let Some(Auxiliary::ConnectRace(race)) = self.auxiliaries.get(race_token) {
+            poll.register(&race.stream,
+                          race_token.as_raw_token(),
+                          Ready::writable(),
+                          PollOpt::edge() | PollOpt::oneshot())
+                .unwrap();
+        }
+        if let Some(connection) = self.connections.get_mut(token) {
+            connection.set_race(race_token);
+        }
+    }
+
+    /// Settles a racing connect that has finished. One that worked takes
+    /// over from the connect it raced, which is dropped.
+    fn finish_connect_race(&mut self, poll: &mut Poll, race: ConnectRace, connected: bool) {
+        let current = match self.connections.get_mut(race.connection) {
+            Some(connection) if connection.id() == race.conn_id => connection.take_race().is_some(),
+            _ => false,
+        };
+
+        if !current {
+            race.cancel(false);
+        } else if !connected {
+            warn!("[conn={}] Racing connect to backend {} failed",
+                  race.conn_id,
+                  race.target);
+            self.metrics.connect_failed();
+            race.cancel(true);
+        } else {
+            info!("[conn={}] Connect to backend {} won the race",
+                  race.conn_id,
+                  race.target);
+            let token = race.connection;
+            if self.take_over_outgoing(poll, race) {
+                self.to_reregister.insert(token);
+            } else {
+                self.to_reregister.remove(&token);
+                self.remove_connection(poll, token);
+            }
+        }
+    }
+
+    /// Takes the racing connect of a connection off the auxiliary sockets.
+    fn remove_connect_race(&mut self,
+                           poll: &mut Poll,
+                           token: AuxiliaryToken)
+                           -> Option<ConnectRace> {
+        match self.auxiliaries.get(token) {
+            Some(&Auxiliary::ConnectRace(_)) => {}
+            _ => return None,
+        }
+
+        match self.auxiliaries.remove(token) {
+            Some(Auxiliary::ConnectRace(race)) => {
+                poll.deregister(&race.stream).unwrap();
+                Some(race)
+            }
+            _ => None,
+        }
+    }
+
+    /// Relays the connection a racing connect was for to the raced target
+    /// from now on, dropping the outgoing stream it had. Returns false if
+    /// the connection should be closed instead.
+    fn take_over_outgoing(&mut self, poll: &mut Poll, race: ConnectRace) -> bool {
+        let connection = match self.connections.get_mut(race.connection) {
+            Some(connection) if connection.id() == race.conn_id => connection,
+            _ => {
+                race.cancel(false);
+                return false;
+            }
+        };
+        let tried = connection.tried_targets().to_vec();
+        let ConnectRace { stream, target, .. } = race;
+
+        match connection.replace_outgoing(stream, target, tried) {
+            Ok(replaced) => poll.deregister(&replaced).unwrap(),
+            Err(e) => {
+                error!("[conn={}] Could not relay to the raced backend: {}", connection.id(), e);
+                self.metrics.connection_error();
+                return false;
+            }
+        }
+        poll.register(connection.outgoing_stream(),
+                      connection.outgoing_token().as_raw_token(),
+                      connection.outgoing_interest(),
+                      PollOpt::edge() | PollOpt::oneshot())
+            .unwrap();
+
+        true
+    }
+
+    fn next_timeout(&self) -> Option<Duration> {
+        let auxiliary_deadlines = self.auxiliaries.iter().map(Auxiliary::deadline);
+
+        let timeout = self.state
+            .health_checker
+            .next_check()
+            .into_iter()
+            .chain(self.state.resolver.next_wakeup(Instant::now()))
+            .chain(self.state.backends.values().filter_map(|pool| pool.borrow().next_warm_sweep()))
+            .chain(auxiliary_deadlines)
+            .chain(self.timers.next_expiry())
+            .chain(self.paused_listeners.values().cloned())
+            .chain(self.drain_deadline)
+            .min()
+            .map(|deadline| {
+                     let now = Instant::now();
+                     if deadline > now {
+                         deadline - now
+                     } else {
+                         Duration::from_millis(0)
+                     }
+                 });
+
+        match (timeout, self.max_poll_timeout) {
+            (Some(timeout), Some(max)) => Some(timeout.min(max)),
+            (timeout, max) => timeout.or(max),
+        }
+    }
+
+    fn remove_connection(&mut self, poll: &mut Poll, token: IncomingToken) {
+        let mut connection = self.connections
+            .remove(token)
+            .expect("Can't remove already removed incoming connection");
+        self.timers.cancel(token);
+        if let Some(race) = connection.take_race() {
+            if let Some(race) = self.remove_connect_race(poll, race) {
+                race.cancel(false);
+            }
+        }
+        debug!("[conn={}] Removing connection from {}",
+               connection.id(),
+               connection.source_addr());
+        if connection.flush_pending() {
+            debug!("[conn={}] Discarding unsent bytes", connection.id());
+        }
+        if connection.logs_access() {
+            self.state.access_log.log(&connection.access_log_entry(Instant::now()));
+        }
+        if !self.observers.is_empty() {
+            let stats = connection.stats(Instant::now());
+            for observer in self.observers.iter_mut() {
+                observer.on_close(connection.id(), &stats);
+            }
+        }
+        connection.close(poll);
+        connection.release_target();
+        // A failed connect may already have been counted when it was
+        // given up on.
+        if connection.is_outgoing_failed() {
+            if connection.report_target_failure() {
+                self.metrics.connection_error();
+            }
+        } else if connection.is_errored() {
+            self.metrics.connection_error();
+        }
+        self.metrics.connection_closed();
+    }
+
+    /// Re-arms the sockets of every connection that saw events this turn.
+    /// They are registered edge-triggered and oneshot, so each one needs
+    /// its interest worked out afresh from what its buffers can take. The
+    /// events will have moved its timers on too.
+    fn tick(&mut self, poll: &mut Poll) {
+        let timeouts = self.timeouts();
+
+        for token in self.to_reregister.iter() {
+            if let Some(connection) = self.connections.get(*token) {
+                match connection.next_deadline(&timeouts) {
+                    Some(deadline) => self.timers.schedule(*token, deadline),
+                    None => self.timers.cancel(*token),
+                }
+
+                poll.reregister(connection.incoming_stream(),
+                                connection.incoming_token().as_raw_token(),
+                                connection.incoming_interest(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                    .unwrap();
+
+                poll.reregister(connection.outgoing_stream(),
+                                connection.outgoing_token().as_raw_token(),
+                                connection.outgoing_interest(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                    .unwrap();
+            }
+        }
+
+        self.to_reregister.clear();
+        self.remove_listeners(poll);
+    }
+
+    fn remove_listeners(&mut self, poll: &mut Poll) {
+        for token in self.state.listeners_to_remove.iter() {
+            info!("Removing listener on token {:?}", token);
+
+            let listener = self.state.listeners.remove(*token).unwrap();
+            self.paused_listeners.remove(token);
+
+            poll.deregister(&listener.socket).unwrap();
+            drop(listener);
+        }
+
+        self.state.listeners_to_remove.clear();
+    }
+
+    /// Stops accepting clients on every listener while connections that
+    /// are already open carry on. `run` returns once they have all closed,
+    /// or when the drain timeout passes, whichever comes first.
+    pub fn begin_drain(&mut self, poll: &mut Poll) {
+        if self.drain_deadline.is_some() {
+            return;
+        }
+
+        let drain_ms = self.timeouts().drain_ms.unwrap_or(DEFAULT_DRAIN_MS);
+        info!("Draining {} connections for up to {} ms",
+              self.connections.len(),
+              drain_ms);
+
+        self.stop_listening(poll);
+        self.drain_deadline = Some(Instant::now() + Duration::from_millis(drain_ms));
+    }
+
+    fn stop_listening(&mut self, poll: &mut Poll) {
+        let tokens = self.state
+            .listeners
+            .iter()
+            .map(|listener| listener.token)
+            .collect::<Vec<_>>();
+        self.state.listeners_to_remove.extend(tokens);
+        self.remove_listeners(poll);
+    }
+
+    fn is_drain_finished(&self, now: Instant) -> bool {
+        self.drain_deadline
+            .map_or(false,
+                    |deadline| self.connections.is_empty() || now >= deadline)
+    }
+
+    /// Closes whatever connections outlived the drain.
+    fn close_remaining_connections(&mut self, poll: &mut Poll) {
+        let tokens = (0..self.connections.capacity())
+            .map(IncomingToken)
+            .filter(|token| self.connections.contains(*token))
+            .collect::<Vec<_>>();
+
+        if !tokens.is_empty() {
+            warn!("Closing {} connections still open after draining", tokens.len());
+        }
+
+        for token in tokens {
+            self.to_reregister.remove(&token);
+            self.remove_connection(poll, token);
+        }
+    }
+
+    /// Runs until a drain finishes or a shutdown is requested, then closes
+    /// whatever connections are left. Fails only if polling does.
+    pub fn run(&mut self, poll: &mut Poll, events: &mut Events) -> IOResult<()> {
+        loop {
+            if signal::take_termination_request() {
+                self.begin_drain(poll);
+            }
+            if self.shutdown.as_ref().map_or(false, ShutdownSignal::is_requested) {
+                info!("Shutting down with {} connections open", self.connections.len());
+                self.stop_listening(poll);
+                break;
+            }
+            if self.is_drain_finished(Instant::now()) {
+                break;
+            }
+            if let Err(e) = self.turn(poll, events) {
+                error!("Poll failed: {}", e);
+                self.close_remaining_connections(poll);
+                return Err(e);
+            }
+        }
+
+        self.close_remaining_connections(poll);
+        Ok(())
+    }
+
+    fn turn(&mut self, poll: &mut Poll, events: &mut Events) -> IOResult<()> {
+        let timeout = self.next_timeout();
+        self.turn_within(poll, events, timeout)
+    }
+
+    /// Waits for events for no longer than `timeout` and handles them.
+    fn turn_within(&mut self,
+                   poll: &mut Poll,
+                   events: &mut Events,
+                   timeout: Option<Duration>)
+                   -> IOResult<()> {
+        // A signal cuts the wait short so `run` can act on it.
+        match poll.poll_interruptible(events, timeout) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        for event in events.iter() {
+            match TokenType::from_raw_token(event.token()) {
+                TokenType::Listener(token) => self.listener_ready(poll, token, event.readiness()),
+                TokenType::Incoming(token) => {
+                    self.incoming_ready(poll, token, event.readiness())
+                }
+                TokenType::Outgoing(token) => {
+                    self.outgoing_ready(poll, token, event.readiness())
+                }
+                TokenType::Auxiliary(token) => {
+                    self.auxiliary_ready(poll, token, event.readiness())
+                }
+                // Seen to at the top of `run`.
+                TokenType::Shutdown => {}
+                TokenType::Inspect => self.run_inspections(),
+            }
+        }
+        self.run_health_checks(poll);
+        self.state.resolver.run(Instant::now());
+        for pool in self.state.backends.values() {
+            pool.borrow_mut().sweep_warm(Instant::now());
+        }
+        self.resume_listeners(poll);
+        self.reap_connections(poll);
+        self.limit_buffered_bytes();
+        self.share_bandwidth();
+        self.tick(poll);
+        Ok(())
+    }
+}
+
+fn side_name(side: EndPointType) -> &'static str {
+    match side {
+        EndPointType::Front => "client",
+        EndPointType::Back => "backend",
+    }
+}
+
+/// Picks the connections holding the most buffered bytes until the rest
+/// hold no more than `max_buffered_bytes` between them.
+fn connections_to_pause(mut buffered: Vec<(usize, IncomingToken)>,
+                        max_buffered_bytes: usize)
+                        -> HashSet<IncomingToken> {
+    let total = buffered.iter().map(|&(n, _)| n).sum::<usize>();
+    let mut excess = total.saturating_sub(max_buffered_bytes);
+
+    buffered.sort_by(|a, b| b.0.cmp(&a.0));
+    buffered.into_iter()
+        .take_while(|&(n_buffered, _)| {
+                        let pause = excess > 0;
+                        excess = excess.saturating_sub(n_buffered);
+                        pause
+                    })
+        .map(|(_, token)| token)
+        .collect()
+}
+
+/// EMFILE and ENFILE, for the process and system descriptor limits.
+fn is_out_of_fds(e: &IOError) -> bool {
+    e.raw_os_error().map_or(false, |errno| errno == libc::EMFILE || errno == libc::ENFILE)
+}
+
+/// Dials targets from the pool, skipping those in `tried`, until a connect
+/// gets under way or the pool's retries run out. A target that fails right
+/// away is retried just like one that refuses the connect later.
+fn connect_untried(pool: &RefCell<BackendPool>,
+                   client_addr: &SocketAddr,
+                   tried: &mut Vec<BackendId>,
+                   metrics: &Metrics)
+                   -> IOResult<(Stream, BackendId)> {
+    loop {
+        let n_tried = tried.len();
+        let result = pool.borrow_mut().connect(client_addr, tried);
+        if result.is_err() && tried.len() > n_tried {
+            metrics.connect_failed();
+        }
+
+        match result {
+            Err(ref e) if tried.len() > n_tried &&
+                          tried.len() as u32 <= pool.borrow().connect_retries() => {
+                warn!("Connect to backend {} failed: {}", tried[n_tried], e);
+                metrics.connect_retried();
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Driver, connections_to_pause, is_out_of_fds};
+
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write, ErrorKind, Error as IOError};
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::process;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use mio::{Events, Poll};
+
+    use libc;
+
+    use net2::TcpBuilder;
+
+    use config::RootConfig;
+    use load_balancer;
+    use connection::{IncomingToken, OutgoingToken};
+    use connection::test::reset_on_close;
+    use driver_state::{DriverState, ListenerRole};
+    use sni::test::{client_hello, server_name_extension};
+    use stream::Address;
+
+    fn start_driver(config: &str) -> (Driver, Poll, SocketAddr) {
+        let config = RootConfig::from_str(config).unwrap();
+        let mut poll = Poll::new().unwrap();
+
+        let mut state = DriverState::new(&config.buffers);
+        state.reconfigure(&mut poll, &config).unwrap();
+
+        let driver = Driver::new(state);
+        let frontend_addr = listener_addr(&driver, |role| matches!(*role, ListenerRole::Proxy(_)));
+
+        (driver, poll, frontend_addr)
+    }
+
+    fn listener_addr<F>(driver: &Driver, matches: F) -> SocketAddr
+        where F: Fn(&ListenerRole) -> bool
+    {
+        driver.state
+            .listeners
+            .iter()
+            .find(|listener| matches(&listener.role))
+            .map(|listener| match listener.socket.local_addr().unwrap() {
+                     Address::Tcp(addr) => addr,
+                     Address::Unix(path) => panic!("Unexpected Unix listener {:?}", path),
+                 })
+            .unwrap()
+    }
+
+    /// Starts a blocking echo server on `addr`, serving one connection.
+    fn echo_backend(addr: &str) -> Option<SocketAddr> {
+        let listener = match TcpListener::bind(addr) {
+            Ok(listener) => listener,
+            // IPv6 may be unavailable in the test environment.
+            Err(_) => return None,
+        };
+        let local_addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 1024];
+
+            loop {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n_read) => stream.write_all(&buffer[..n_read]).unwrap(),
+                }
+            }
+        });
+
+        Some(local_addr)
+    }
+
+    fn assert_echoes(driver: &mut Driver, poll: &mut Poll, frontend_addr: SocketAddr) {
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"hello over v6").unwrap();
+
+        run_for(driver, poll, Duration::from_millis(200));
+
+        let mut echoed = [0; 13];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"hello over v6");
+    }
+
+    fn run_for(driver: &mut Driver, poll: &mut Poll, duration: Duration) {
+        let mut events = Events::with_capacity(1024);
+        let deadline = Instant::now() + duration;
+
+        // Nothing wakes the driver up on its own before its next deadline,
+        // which may be far past the end of the run.
+        let mut now = Instant::now();
+        while now < deadline {
+            let timeout = driver.next_timeout().map_or(deadline - now, |t| t.min(deadline - now));
+            driver.turn_within(poll, &mut events, Some(timeout)).unwrap();
+            now = Instant::now();
+        }
+    }
+
+    #[test]
+    fn freed_tokens_are_reused_by_later_connections() {
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 2
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             load_balancer::test::echo_backend());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        // Many more clients than there are slots, one after the other.
+        for i in 0..5 {
+            let mut client = TcpStream::connect(frontend_addr).unwrap();
+            let message = format!("client {}", i);
+            client.write_all(message.as_bytes()).unwrap();
+            client.set_nonblocking(true).unwrap();
+
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while driver.connections.is_empty() && Instant::now() < deadline {
+                run_for(&mut driver, &mut poll, Duration::from_millis(10));
+            }
+            let token = (0..2).map(IncomingToken).find(|&t| driver.connections.contains(t));
+            assert_eq!(token, Some(IncomingToken(0)));
+            assert_eq!(driver.connections[IncomingToken(0)].incoming_token(),
+                       IncomingToken(0));
+            assert_eq!(driver.connections[IncomingToken(0)].outgoing_token(),
+                       OutgoingToken(0));
+
+            let mut echoed = Vec::new();
+            while echoed.len() < message.len() && Instant::now() < deadline {
+                run_for(&mut driver, &mut poll, Duration::from_millis(10));
+                let mut buffer = [0; 64];
+                if let Ok(n_read) = client.read(&mut buffer) {
+                    echoed.extend_from_slice(&buffer[..n_read]);
+                }
+            }
+            assert_eq!(echoed, message.into_bytes());
+
+            drop(client);
+            while !driver.connections.is_empty() && Instant::now() < deadline {
+                run_for(&mut driver, &mut poll, Duration::from_millis(10));
+            }
+            assert_eq!(driver.connections.len(), 0);
+        }
+    }
+
+    #[test]
+    fn refused_connect_fails_over_to_next_backend() {
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\", \"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             refused,
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        assert_echoes(&mut driver, &mut poll, frontend_addr);
+        assert_eq!(driver.metrics.snapshot().connection_errors, 1);
+        assert_eq!(driver.metrics.snapshot().connect_errors, 1);
+        assert_eq!(driver.metrics.snapshot().connect_retries, 1);
+    }
+
+    #[test]
+    fn reset_before_relaying_fails_over_to_next_backend() {
+        let resetting = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\", \"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+",
+                             resetting.local_addr().unwrap(),
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        assert!(driver.connections.iter().next().unwrap().is_connected());
+
+        let (stream, _) = resetting.accept().unwrap();
+        reset_on_close(&stream);
+        drop(stream);
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+
+        // The client never learns of the first target.
+        client.write_all(b"hello").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        let mut echoed = [0; 5];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"hello");
+        assert_eq!(driver.metrics.snapshot().connect_retries, 1);
+    }
+
+    #[test]
+    fn failover_leaves_the_proxy_header_out_for_targets_not_taking_it() {
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{0}\", \"{1}\"]
+proxy_protocol = true
+proxy_protocol_targets = [\"{0}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+",
+                             refused,
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        // The echo is exactly what was sent, with no header in front.
+        assert_echoes(&mut driver, &mut poll, frontend_addr);
+        assert_eq!(driver.metrics.snapshot().connect_retries, 1);
+    }
+
+    #[test]
+    fn failed_dial_moves_on_to_next_backend() {
+        let missing = env::temp_dir().join(format!("lb-test-{}-missing.sock", process::id()));
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"unix:{}\", \"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             missing.display(),
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        assert_echoes(&mut driver, &mut poll, frontend_addr);
+        assert_eq!(driver.metrics.snapshot().connect_errors, 1);
+        assert_eq!(driver.metrics.snapshot().connect_retries, 1);
+        assert_eq!(driver.connections.len(), 1);
+    }
+
+    #[test]
+    fn relaying_times_the_connect_and_first_byte_of_the_target() {
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+strategy = \"least_response_time\"
+
+[buffers]
+connections = 16
+listeners = 4
+",
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        assert_echoes(&mut driver, &mut poll, frontend_addr);
+
+        let pool = driver.state.backends["out"].clone();
+        let pool = pool.borrow();
+        assert!(pool.backends()[0].connect_time.is_some());
+        assert!(pool.backends()[0].first_byte_time.is_some());
+    }
+
+    #[test]
+    fn relays_over_a_connection_made_ahead_of_time() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        backend.set_nonblocking(true).unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[backends.out.connection_pool]
+min_idle = 1
+max_idle = 2
+
+[buffers]
+connections = 16
+listeners = 4
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        // Made before any client showed up, and the only one echoed on.
+        let (mut warm, _) = backend.accept().unwrap();
+        warm.set_nonblocking(false).unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            loop {
+                match warm.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n_read) => warm.write_all(&buffer[..n_read]).unwrap(),
+                }
+            }
+        });
+
+        assert_echoes(&mut driver, &mut poll, frontend_addr);
+    }
+
+    #[test]
+    fn health_checks_take_out_targets_answering_wrong() {
+        let answering = TcpListener::bind("127.0.0.1:0").unwrap();
+        let answering_addr = answering.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = answering.accept().unwrap();
+            let mut buffer = [0; 6];
+            stream.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"PING\r\n");
+            stream.write_all(b"+PONG\r\n").unwrap();
+        });
+        // Takes the connect, but only says the probe back, so its check
+        // fails once the expected response is overdue.
+        let echoing_addr = echo_backend("127.0.0.1:0").unwrap();
+
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\", \"{}\"]
+
+[backends.out.health_check]
+interval_ms = 60000
+timeout_ms = 1000
+fall = 1
+rise = 1
+send = \"PING\\r\\n\"
+expect = \"PONG\"
+expect_timeout_ms = 100
+
+[buffers]
+connections = 16
+listeners = 4
+",
+                             answering_addr,
+                             echoing_addr);
+        let (mut driver, mut poll, _) = start_driver(&config);
+        run_for(&mut driver, &mut poll, Duration::from_millis(300));
+
+        let pool = driver.state.backends["out"].clone();
+        let pool = pool.borrow();
+        let now = Instant::now();
+        assert!(pool.backends()[0].is_available(now));
+        assert!(!pool.backends()[1].is_available(now));
+        assert_eq!(driver.auxiliaries.len(), 0);
+    }
+
+    #[test]
+    fn access_log_lines_go_to_the_configured_file() {
+        let path = env::temp_dir().join(format!("lb-test-{}-driver-access.log", process::id()));
+        let _ = fs::remove_file(&path);
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+access_log = true
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[access_log]
+path = \"{}\"
+format = \"{{client}} -> {{backend}} sent {{bytes_up}}\"
+
+[buffers]
+connections = 16
+listeners = 4
+",
+                             backend_addr,
+                             path.display());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        assert_echoes(&mut driver, &mut poll, frontend_addr);
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+
+        // Written on a thread of its own, some time after the close.
+        let expected = format!(" -> {} sent 13\n", backend_addr);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut logged = String::new();
+        while !logged.ends_with(&expected) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            logged = fs::read_to_string(&path).unwrap();
+        }
+        assert!(logged.starts_with("127.0.0.1:"), "{:?}", logged);
+        assert!(logged.ends_with(&expected), "{:?}", logged);
+        assert_eq!(logged.lines().count(), 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn relays_datagrams_per_client_until_idle() {
+        let backend = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            loop {
+                let (n_read, peer) = backend.recv_from(&mut buffer).unwrap();
+                let mut reply = format!("{} says ", peer).into_bytes();
+                reply.extend_from_slice(&buffer[..n_read]);
+                backend.send_to(&reply, peer).unwrap();
+            }
+        });
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[frontends.datagrams]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+protocol = \"udp\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+udp_idle_ms = 200
+",
+                             backend_addr);
+        let (mut driver, mut poll, _) = start_driver(&config);
+        let frontend_addr = listener_addr(&driver, |role| matches!(*role, ListenerRole::Udp(_)));
+
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut replies = Vec::new();
+        for &(client, datagram) in &[(&first, "one"), (&second, "two"), (&first, "three")] {
+            client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            client.send_to(datagram.as_bytes(), frontend_addr).unwrap();
+            run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+            let mut buffer = [0; 1024];
+            let (n_read, from) = client.recv_from(&mut buffer).unwrap();
+            assert_eq!(from, frontend_addr);
+            replies.push(String::from_utf8(buffer[..n_read].to_vec()).unwrap());
+        }
+
+        // Each client has a socket of its own towards the target, kept for
+        // as long as it sends.
+        let sender = |reply: &str| reply.split(" says ").next().unwrap().to_owned();
+        assert!(replies[0].ends_with(" says one"));
+        assert!(replies[1].ends_with(" says two"));
+        assert!(replies[2].ends_with(" says three"));
+        assert_eq!(sender(&replies[0]), sender(&replies[2]));
+        assert!(sender(&replies[0]) != sender(&replies[1]));
+        assert_eq!(driver.udp_associations.len(), 2);
+        assert_eq!(driver.metrics.snapshot().connections_active, 2);
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(400));
+        assert!(driver.udp_associations.is_empty());
+        assert!(driver.auxiliaries.is_empty());
+        assert_eq!(driver.metrics.snapshot().connections_active, 0);
+    }
+
+    #[test]
+    fn pauses_the_largest_buffers_until_under_the_limit() {
+        let buffered = vec![(100, IncomingToken(0)),
+                            (4000, IncomingToken(1)),
+                            (0, IncomingToken(2)),
+                            (2500, IncomingToken(3)),
+                            (1000, IncomingToken(4))];
+
+        assert!(connections_to_pause(buffered.clone(), 8000).is_empty());
+        assert_eq!(connections_to_pause(buffered.clone(), 4000),
+                   [IncomingToken(1)].iter().cloned().collect());
+        assert_eq!(connections_to_pause(buffered.clone(), 3000),
+                   [IncomingToken(1), IncomingToken(3)].iter().cloned().collect());
+        assert_eq!(connections_to_pause(buffered, 0).len(), 4);
+    }
+
+    #[test]
+    fn recognizes_descriptor_exhaustion() {
+        assert!(is_out_of_fds(&IOError::from_raw_os_error(libc::EMFILE)));
+        assert!(is_out_of_fds(&IOError::from_raw_os_error(libc::ENFILE)));
+        assert!(!is_out_of_fds(&IOError::from_raw_os_error(libc::ECONNABORTED)));
+        assert!(!is_out_of_fds(&IOError::new(ErrorKind::Other, "other")));
+    }
+
+    #[test]
+    fn accepts_every_queued_client_on_one_event() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let _clients = (0..5)
+            .map(|_| TcpStream::connect(frontend_addr).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut events = Events::with_capacity(1024);
+        driver.turn(&mut poll, &mut events).unwrap();
+
+        assert_eq!(driver.connections.len(), 5);
+        assert_eq!(driver.metrics.snapshot().connections_accepted, 5);
+    }
+
+    #[test]
+    fn connect_timeout_closes_connection() {
+        let unroutable: SocketAddr = "10.255.255.1:81".parse().unwrap();
+        if TcpStream::connect_timeout(&unroutable, Duration::from_millis(300)).is_ok() {
+            // Some sandboxed networks accept every outgoing connection.
+            return;
+        }
+
+        let (mut driver, mut poll, frontend_addr) = start_driver("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"10.255.255.1:81\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+connect_ms = 200
+");
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        assert_eq!(driver.connections.len(), 1);
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(400));
+        assert_eq!(driver.connections.len(), 0);
+
+        let mut buffer = [0; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_timeout_closes_connection_to_silent_backend() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+read_ms = 1000
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        // The client keeps the idle timeout at bay, but the backend accepts
+        // and then never says a word.
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        let _server = backend.accept().unwrap();
+        for _ in 0..3 {
+            client.write_all(b"ping").unwrap();
+            run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        }
+        assert_eq!(driver.connections.len(), 1);
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(1200));
+        assert_eq!(driver.connections.len(), 0);
+        let snapshot = driver.metrics().snapshot();
+        assert_eq!((snapshot.read_timeouts, snapshot.write_timeouts, snapshot.idle_timeouts),
+                   (1, 0, 0));
+    }
+
+    #[test]
+    fn max_lifetime_closes_busy_connection() {
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+read_ms = 60000
+max_lifetime_ms = 600
+",
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        for _ in 0..4 {
+            client.write_all(b"ping").unwrap();
+            run_for(&mut driver, &mut poll, Duration::from_millis(50));
+            let mut echoed = [0; 4];
+            client.read_exact(&mut echoed).unwrap();
+        }
+        assert_eq!(driver.connections.len(), 1);
+
+        // Still busy when the lifetime runs out, and closed regardless.
+        client.write_all(b"last").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(600));
+        assert_eq!(driver.connections.len(), 0);
+        let snapshot = driver.metrics().snapshot();
+        assert_eq!((snapshot.lifetime_timeouts, snapshot.idle_timeouts), (1, 0));
+
+        let mut rest = Vec::new();
+        let _ = client.read_to_end(&mut rest);
+        assert_eq!(rest, b"last");
+    }
+
+    #[test]
+    fn reset_on_close_tears_connections_down_with_a_reset() {
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+reset_on_close = true
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 200
+",
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"ping").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        let mut echoed = [0; 4];
+        client.read_exact(&mut echoed).unwrap();
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(300));
+        assert_eq!(driver.connections.len(), 0);
+        let error = client.read(&mut echoed).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn max_connection_bytes_closes_connections_with_a_fin() {
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+max_connection_bytes = 10
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"ping").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        let mut echoed = [0; 6];
+        client.read_exact(&mut echoed[..4]).unwrap();
+        assert_eq!(driver.connections.len(), 1);
+
+        // Eight bytes so far, so relaying these passes the limit. The
+        // target still gets them and its echo still comes back, then the
+        // client's FIN goes through and both sides close.
+        client.write_all(b"ping!!").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"ping!!");
+        assert_eq!(client.read(&mut echoed).unwrap(), 0);
+        assert_eq!(driver.connections.len(), 0);
+    }
+
+    #[test]
+    fn max_bytes_per_sec_throttles_relaying() {
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[limits]
+max_bytes_per_sec = 10000
+",
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+        let mut writer = client.try_clone().unwrap();
+        let sent = (0..30000).map(|i| i as u8).collect::<Vec<u8>>();
+        let to_send = sent.clone();
+        let sender = thread::spawn(move || writer.write_all(&to_send).unwrap());
+
+        // A second's worth goes through at once; the other two take their
+        // time.
+        let started = Instant::now();
+        let mut echoed = Vec::new();
+        while echoed.len() < sent.len() && started.elapsed() < Duration::from_secs(5) {
+            run_for(&mut driver, &mut poll, Duration::from_millis(20));
+            let mut chunk = [0; 4096];
+            match client.read(&mut chunk) {
+                Ok(n_read) => echoed.extend_from_slice(&chunk[..n_read]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => panic!("Read failed: {}", e),
+            }
+        }
+        sender.join().unwrap();
+
+        assert!(echoed == sent, "Only {} of {} bytes echoed", echoed.len(), sent.len());
+        assert!(started.elapsed() >= Duration::from_millis(1800),
+                "Relayed in {:?}",
+                started.elapsed());
+    }
+
+    #[test]
+    fn total_bytes_per_sec_caps_all_connections_together() {
+        let backend_addrs = [echo_backend("127.0.0.1:0").unwrap(),
+                             echo_backend("127.0.0.1:0").unwrap()];
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\", \"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[limits]
+total_bytes_per_sec = 20000
+",
+                             backend_addrs[0],
+                             backend_addrs[1]);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        // Each byte is written twice, on the way up and on the way back, so
+        // the 40000 bytes take a burst and another second.
+        let sent = (0..10000).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut clients = (0..2)
+            .map(|_| {
+                     let mut client = TcpStream::connect(frontend_addr).unwrap();
+                     client.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+                     client.write_all(&sent).unwrap();
+                     (client, Vec::new())
+                 })
+            .collect::<Vec<_>>();
+
+        let started = Instant::now();
+        while clients.iter().any(|(_, echoed)| echoed.len() < sent.len()) &&
+              started.elapsed() < Duration::from_secs(5) {
+            run_for(&mut driver, &mut poll, Duration::from_millis(20));
+            for (client, echoed) in clients.iter_mut() {
+                let mut chunk = [0; 4096];
+                match client.read(&mut chunk) {
+                    Ok(n_read) => echoed.extend_from_slice(&chunk[..n_read]),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => panic!("Read failed: {}", e),
+                }
+            }
+        }
+
+        for (_, echoed) in &clients {
+            assert!(*echoed == sent, "Only {} of {} bytes echoed", echoed.len(), sent.len());
+        }
+        assert!(started.elapsed() >= Duration::from_millis(800),
+                "Relayed in {:?}",
+                started.elapsed());
+        assert_eq!(driver.metrics().snapshot().bandwidth_cap, 20000);
+    }
+
+    #[test]
+    fn stall_ms_closes_connection_to_peer_acknowledging_nothing() {
+        // The backend never reads, so once its receive window is full
+        // nothing written to it is acknowledged any more.
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+stall_ms = 300
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        let sender = thread::spawn(move || {
+                                       let _ = client.write_all(&vec![0; 16 << 20]);
+                                   });
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        let (_stalled, _) = backend.accept().unwrap();
+        assert_eq!(driver.connections.len(), 1);
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(1000));
+        assert_eq!(driver.connections.len(), 0);
+        assert_eq!(driver.metrics().snapshot().stall_timeouts, 1);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn handshake_ms_closes_clients_that_never_get_to_the_point() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.routed]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[frontends.routed.sni_backends]
+\"api.example.com\" = \"out\"
+
+[frontends.proxied]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+accept_proxy_protocol = true
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+handshake_ms = 200
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, _) = start_driver(&config);
+        let routed_addr = listener_addr(&driver, |role| {
+            matches!(*role, ListenerRole::Proxy(ref frontend) if frontend.name_routing().is_some())
+        });
+        let proxied_addr = listener_addr(&driver, |role| {
+            matches!(*role, ListenerRole::Proxy(ref frontend) if frontend.accepts_proxy_protocol())
+        });
+
+        // Neither client sends a byte.
+        let _routed = TcpStream::connect(routed_addr).unwrap();
+        let _proxied = TcpStream::connect(proxied_addr).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        assert_eq!((driver.auxiliaries.len(), driver.connections.len()), (1, 1));
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(300));
+        assert_eq!((driver.auxiliaries.len(), driver.connections.len()), (0, 0));
+        assert_eq!(driver.metrics().snapshot().handshake_timeouts, 2);
+    }
+
+    #[test]
+    fn max_connections_rejects_excess_clients() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+
+[limits]
+max_connections = 1
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let _first = TcpStream::connect(frontend_addr).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        let mut second = TcpStream::connect(frontend_addr).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        assert_eq!(driver.connections.len(), 1);
+        assert_eq!(driver.metrics.snapshot().connections_rejected, 1);
+
+        let mut buffer = [0; 16];
+        assert_eq!(second.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn denied_clients_are_closed_without_dialing() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        backend.set_nonblocking(true).unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+allow = [\"127.0.0.0/8\"]
+deny = [\"127.0.0.1/32\"]
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        assert_eq!(driver.connections.len(), 0);
+        assert_eq!(driver.metrics.snapshot().connections_denied, 1);
+        assert_eq!(backend.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        let mut buffer = [0; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn listeners_route_to_their_own_pools() {
+        let backend_a = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_b = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.a_in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"a\"
+
+[frontends.b_in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"b\"
+
+[backends.a]
+target_addrs = [\"{}\"]
+
+[backends.b]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             backend_a.local_addr().unwrap(),
+                             backend_b.local_addr().unwrap());
+        let (mut driver, mut poll, _) = start_driver(&config);
+        let frontend_addr = |driver: &Driver, name: &str| {
+            listener_addr(driver, |role| match *role {
+                ListenerRole::Proxy(ref frontend) => frontend.backend_name() == name,
+                ListenerRole::Udp(_) |
+                ListenerRole::Metrics |
+                ListenerRole::Control |
+                ListenerRole::Admin => false,
+            })
+        };
+        let addr_a = frontend_addr(&driver, "a");
+        let addr_b = frontend_addr(&driver, "b");
+        assert!(addr_a != addr_b);
+
+        let mut client_a = TcpStream::connect(addr_a).unwrap();
+        let mut client_b = TcpStream::connect(addr_b).unwrap();
+        client_a.write_all(b"to a").unwrap();
+        client_b.write_all(b"to b").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+
+        for &(ref backend, expected) in &[(backend_a, b"to a"), (backend_b, b"to b")] {
+            let (mut stream, _) = backend.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+            let mut received = [0; 4];
+            stream.read_exact(&mut received).unwrap();
+            assert_eq!(&received, expected);
+        }
+    }
+
+    #[test]
+    fn backend_split_sends_a_share_of_clients_to_each_pool() {
+        let stable = TcpListener::bind("127.0.0.1:0").unwrap();
+        let canary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"stable\"
+
+[frontends.in.backend_split]
+canary = 25
+
+[backends.stable]
+target_addrs = [\"{}\"]
+
+[backends.canary]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             stable.local_addr().unwrap(),
+                             canary.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+        let mut clients = Vec::new();
+        let mut connect_clients = |driver: &mut Driver, poll: &mut Poll, count: usize| {
+            for _ in 0..count {
+                let mut client = TcpStream::connect(frontend_addr).unwrap();
+                client.write_all(b"x").unwrap();
+                clients.push(client);
+                run_for(driver, poll, Duration::from_millis(20));
+            }
+            run_for(driver, poll, Duration::from_millis(100));
+            driver.metrics
+                .pool_snapshots()
+                .iter()
+                .map(|pool| (pool.name.clone(), pool.connections_total, pool.bytes_upstream))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(connect_clients(&mut driver, &mut poll, 4),
+                   vec![("canary".to_owned(), 1, 1), ("stable".to_owned(), 3, 3)]);
+
+        let frontend = driver.state.frontends["in"].clone();
+        assert_eq!(frontend.split(),
+                   vec![("stable".to_owned(), 75), ("canary".to_owned(), 25)]);
+        assert!(frontend.set_split("stable", 50).is_err());
+        assert!(frontend.set_split("canary", 101).is_err());
+        frontend.set_split("canary", 50).unwrap();
+        assert_eq!(frontend.split(),
+                   vec![("stable".to_owned(), 50), ("canary".to_owned(), 50)]);
+
+        assert_eq!(connect_clients(&mut driver, &mut poll, 2),
+                   vec![("canary".to_owned(), 2, 2), ("stable".to_owned(), 4, 4)]);
+    }
+
+    #[test]
+    fn routes_tls_clients_by_server_name() {
+        let default_backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"default\"
+
+[frontends.in.sni_backends]
+\"API.example.com\" = \"api\"
+
+[backends.default]
+target_addrs = [\"{}\"]
+
+[backends.api]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             default_backend.local_addr().unwrap(),
+                             api_backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+        let hello = client_hello(&server_name_extension("api.example.com"));
+
+        // The ClientHello arrives in two pieces.
+        let mut tls_client = TcpStream::connect(frontend_addr).unwrap();
+        tls_client.write_all(&hello[..10]).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        assert_eq!(driver.connections.len(), 0);
+
+        tls_client.write_all(&hello[10..]).unwrap();
+        let mut plain_client = TcpStream::connect(frontend_addr).unwrap();
+        plain_client.write_all(b"plain").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        assert_eq!(driver.connections.len(), 2);
+
+        let expected: [(&TcpListener, &[u8]); 2] = [(&api_backend, &hello),
+                                                    (&default_backend, b"plain")];
+        for &(backend, expected) in &expected {
+            let (mut stream, _) = backend.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+            let mut received = vec![0; expected.len()];
+            stream.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], expected);
+        }
+    }
+
+    #[test]
+    fn max_connections_holds_for_clients_routed_by_name() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[frontends.in.sni_backends]
+\"api.example.com\" = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+
+[limits]
+max_connections = 1
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+        let hello = client_hello(&server_name_extension("api.example.com"));
+
+        // Both are let in while nothing is relayed yet.
+        let mut first = TcpStream::connect(frontend_addr).unwrap();
+        let mut second = TcpStream::connect(frontend_addr).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        assert_eq!(driver.metrics.snapshot().connections_rejected, 0);
+
+        first.write_all(&hello).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        second.write_all(&hello).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        assert_eq!(driver.connections.len(), 1);
+        assert_eq!(driver.metrics.snapshot().connections_rejected, 1);
+        let mut buffer = [0; 16];
+        assert_eq!(second.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn routes_http_clients_by_host_header() {
+        let default_backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"default\"
+
+[frontends.in.host_backends]
+\"api.example.com\" = \"api\"
+
+[backends.default]
+target_addrs = [\"{}\"]
+
+[backends.api]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+endpoint_size = 128
+
+[timeouts]
+idle_ms = 60000
+",
+                             default_backend.local_addr().unwrap(),
+                             api_backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let request = b"POST / HTTP/1.1\r\nHost: api.example.com\r\nContent-Length: 4\r\n\r\n".to_vec();
+        let mut api_client = TcpStream::connect(frontend_addr).unwrap();
+        api_client.write_all(&request).unwrap();
+
+        // Headers that outgrow the buffer go to the default backend.
+        let mut oversized = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        oversized.extend_from_slice(&[b'a'; 200]);
+        oversized.extend_from_slice(b"\r\nHost: api.example.com\r\n\r\n");
+        let mut other_client = TcpStream::connect(frontend_addr).unwrap();
+        other_client.write_all(&oversized).unwrap();
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        assert_eq!(driver.connections.len(), 2);
+
+        let expected: [(&TcpListener, &[u8]); 2] = [(&api_backend, &request),
+                                                    (&default_backend, &oversized)];
+        for &(backend, expected) in &expected {
+            let (mut stream, _) = backend.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+            let mut received = vec![0; expected.len()];
+            stream.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], expected);
+        }
+    }
+
+    #[test]
+    fn closes_clients_sending_too_much_before_naming_a_host() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"default\"
+max_routing_bytes = 64
+routing_overflow = \"close\"
+
+[frontends.in.host_backends]
+\"api.example.com\" = \"default\"
+
+[backends.default]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut oversized = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        oversized.extend_from_slice(&[b'a'; 200]);
+        oversized.extend_from_slice(b"\r\nHost: api.example.com\r\n\r\n");
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(&oversized).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+
+        // It is closed without a backend being dialed for it.
+        let mut response = Vec::new();
+        let _ = client.read_to_end(&mut response);
+        assert!(response.is_empty());
+        assert_eq!(driver.connections.len(), 0);
+        assert!(driver.auxiliaries.is_empty());
+        assert_eq!(driver.metrics.snapshot().connections_rejected, 1);
+
+        let request = b"GET / HTTP/1.1\r\nHost: api.example.com\r\n\r\n";
+        let mut small_client = TcpStream::connect(frontend_addr).unwrap();
+        small_client.write_all(request).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        assert_eq!(driver.connections.len(), 1);
+        backend.accept().unwrap();
+    }
+
+    #[test]
+    fn drain_stops_accepting_but_keeps_open_connections() {
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        driver.begin_drain(&mut poll);
+        assert!(TcpStream::connect(frontend_addr).is_err());
+        assert!(!driver.is_drain_finished(Instant::now()));
+
+        client.write_all(b"still here").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        let mut echoed = [0; 10];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"still here");
+
+        drop(client);
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        assert!(driver.is_drain_finished(Instant::now()));
+    }
+
+    #[test]
+    fn drain_deadline_closes_remaining_connections() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+drain_ms = 100
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        driver.begin_drain(&mut poll);
+        let mut events = Events::with_capacity(1024);
+        let started = Instant::now();
+        driver.run(&mut poll, &mut events).unwrap();
+
+        assert!(started.elapsed() >= Duration::from_millis(90));
+        assert_eq!(driver.connections.len(), 0);
+
+        let mut buffer = [0; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn slow_reader_gets_every_byte() {
+        assert_slow_reader_gets_every_byte("", "", 1024);
+    }
+
+    #[test]
+    fn slow_reader_gets_every_spliced_byte() {
+        assert_slow_reader_gets_every_byte("", "splice = true", 1024);
+    }
+
+    #[test]
+    fn large_listener_buffer_still_applies_backpressure() {
+        assert_slow_reader_gets_every_byte("endpoint_size = 65536", "", 65536);
+    }
+
+    #[test]
+    fn endpoint_size_must_be_positive() {
+        for &(frontend_options, buffer_options) in &[("endpoint_size = 0", ""),
+                                                     ("", "endpoint_size = 0")] {
+            let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+{}
+
+[backends.out]
+target_addrs = [\"127.0.0.1:1\"]
+
+[buffers]
+connections = 16
+listeners = 4
+{}
+",
+                                 frontend_options,
+                                 buffer_options);
+            let config = RootConfig::from_str(&config).unwrap();
+            let mut poll = Poll::new().unwrap();
+            let mut state = DriverState::new(&config.buffers);
+
+            let error = state.reconfigure(&mut poll, &config).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+            assert!(state.listeners.is_empty());
+        }
+    }
+
+    fn assert_slow_reader_gets_every_byte(frontend_options: &str,
+                                          buffer_options: &str,
+                                          buffer_size: usize) {
+        const RESPONSE_LEN: usize = 4 * 1024 * 1024;
+
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+{}
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+endpoint_size = 1024
+{}
+
+[timeouts]
+idle_ms = 60000
+",
+                             frontend_options,
+                             backend.local_addr().unwrap(),
+                             buffer_options);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        thread::spawn(move || {
+            let (mut server, _) = backend.accept().unwrap();
+            let response = (0..RESPONSE_LEN).map(|i| i as u8).collect::<Vec<_>>();
+            server.write_all(&response).unwrap();
+        });
+
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut client = TcpStream::connect(frontend_addr).unwrap();
+            // Let the relay back up before reading anything.
+            thread::sleep(Duration::from_millis(300));
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            done_tx.send(response).unwrap();
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let response = loop {
+            run_for(&mut driver, &mut poll, Duration::from_millis(50));
+            for connection in driver.connections.iter() {
+                assert_eq!(connection.buffer_size(), buffer_size);
+            }
+            if let Ok(response) = done_rx.try_recv() {
+                break response;
+            }
+            assert!(Instant::now() < deadline, "Timed out relaying to a slow reader");
+        };
+
+        assert_eq!(response.len(), RESPONSE_LEN);
+        assert!(response.iter().enumerate().all(|(i, &b)| b == i as u8));
+    }
+
+    #[test]
+    fn backend_reset_mid_stream_removes_connection() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             backend.local_addr().unwrap());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"first").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        let (mut server, _) = backend.accept().unwrap();
+        let mut request = [0; 5];
+        server.read_exact(&mut request).unwrap();
+        server.write_all(b"partial").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        reset_on_close(&server);
+        drop(server);
+        client.write_all(b"second").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+
+        assert_eq!(driver.connections.len(), 0);
+        let snapshot = driver.metrics.snapshot();
+        assert_eq!(snapshot.connection_errors, 1);
+        assert!(snapshot.read_errors + snapshot.write_errors >= 1);
+        assert_eq!(snapshot.connect_errors, 0);
+
+        let mut response = Vec::new();
+        let _ = client.read_to_end(&mut response);
+        assert_eq!(response, b"partial");
+    }
+
+    #[test]
+    fn control_socket_swaps_backends_without_dropping_connections() {
+        let old_backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let new_backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let control_path = env::temp_dir().join(format!("lb-test-{}-control.sock", process::id()));
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+
+[control]
+listen_addr = \"unix:{}\"
+",
+                             old_backend.local_addr().unwrap(),
+                             control_path.display());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut first_client = TcpStream::connect(frontend_addr).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        let (mut first_server, _) = old_backend.accept().unwrap();
+        first_server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+        let mut control = UnixStream::connect(&control_path).unwrap();
+        control.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        write!(control,
+               "add out {}\nremove out {}\n",
+               new_backend.local_addr().unwrap(),
+               old_backend.local_addr().unwrap())
+            .unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        let mut response = [0; 6];
+        control.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"OK\nOK\n");
+
+        // New clients go to the added backend, the old connection stays up.
+        let _second_client = TcpStream::connect(frontend_addr).unwrap();
+        first_client.write_all(b"still").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+        assert_eq!(driver.connections.len(), 2);
+
+        new_backend.accept().unwrap();
+        let mut relayed = [0; 5];
+        first_server.read_exact(&mut relayed).unwrap();
+        assert_eq!(&relayed, b"still");
+
+        let _ = fs::remove_file(&control_path);
+    }
+
+    #[test]
+    fn control_socket_lists_open_connections() {
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let control_path = env::temp_dir().join(format!("lb-test-{}-connections.sock",
+                                                        process::id()));
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+
+[control]
+listen_addr = \"unix:{}\"
+",
+                             backend_addr,
+                             control_path.display());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"hello").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+        let mut echoed = [0; 5];
+        client.read_exact(&mut echoed).unwrap();
+
+        let mut control = UnixStream::connect(&control_path).unwrap();
+        control.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        control.write_all(b"connections\n").unwrap();
+        control.shutdown(Shutdown::Write).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(50));
+
+        let mut response = String::new();
+        control.read_to_string(&mut response).unwrap();
+        let id = driver.connections.iter().next().unwrap().id();
+        let prefix = format!("conn={} client={} backend={} bytes_up=5 bytes_down=5 idle_ms=",
+                             id,
+                             client.local_addr().unwrap(),
+                             backend_addr);
+        assert!(response.starts_with(&prefix), "{}", response);
+        assert!(response.ends_with(" buffered=0\nOK\n"), "{}", response);
+
+        let _ = fs::remove_file(&control_path);
+    }
+
+    #[test]
+    fn forced_remove_closes_connections_to_the_target_with_a_fin() {
+        let backend_addr = echo_backend("127.0.0.1:0").unwrap();
+        let control_path = env::temp_dir().join(format!("lb-test-{}-force.sock", process::id()));
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+
+[control]
+listen_addr = \"unix:{}\"
+",
+                             backend_addr,
+                             control_path.display());
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"hello").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+
+        let mut control = UnixStream::connect(&control_path).unwrap();
+        control.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        writeln!(control, "remove {} force", backend_addr).unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+
+        let mut response = [0; 3];
+        control.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"OK\n");
+
+        // The echo comes first, then a clean EOF rather than a reset.
+        let mut relayed = Vec::new();
+        client.read_to_end(&mut relayed).unwrap();
+        assert_eq!(relayed, b"hello");
+        assert_eq!(driver.connections.len(), 0);
+
+        let _ = fs::remove_file(&control_path);
+    }
+
+    #[test]
+    fn slow_connect_is_raced_by_the_other_ip_version() {
+        let fast_addr = match echo_backend("[::1]:0") {
+            Some(addr) => addr,
+            None => return,
+        };
+        // Connects to a listener whose accept queue is full hang.
+        let slow = TcpBuilder::new_v4().unwrap().bind("127.0.0.1:0").unwrap().listen(0).unwrap();
+        let slow_addr = slow.local_addr().unwrap();
+        let _queued = TcpStream::connect(slow_addr).unwrap();
+        let config = format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+happy_eyeballs_delay_ms = 50
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+connect_ms = 5000
+",
+                             slow_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+
+        // As if both addresses had been resolved from one host name.
+        let (slow_addr, fast_addr) = (Address::Tcp(slow_addr), Address::Tcp(fast_addr));
+        let pool = driver.state.backends["out"].clone();
+        pool.borrow_mut().remove(&slow_addr);
+        pool.borrow_mut().update_resolved("app:80", &[slow_addr.clone(), fast_addr.clone()], 1);
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"hello").unwrap();
+        run_for(&mut driver, &mut poll, Duration::from_millis(300));
+
+        let mut echoed = [0; 5];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"hello");
+
+        let connection = driver.connections.iter().next().unwrap();
+        assert_eq!(*connection.target(), fast_addr);
+        assert_eq!(connection.tried_targets(), &[slow_addr, fast_addr][..]);
+        let active = pool.borrow()
+            .backends()
+            .iter()
+            .map(|b| b.active_connections)
+            .collect::<Vec<_>>();
+        assert_eq!(active, vec![0, 1]);
+        assert!(driver.auxiliaries.is_empty());
+    }
+
+    #[test]
+    fn relays_between_ipv6_client_and_backend() {
+        let backend_addr = match echo_backend("[::1]:0") {
+            Some(addr) => addr,
+            None => return,
+        };
+        let config = format!("
+[frontends.in]
+listen_addr = \"[::1]:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+        assert!(frontend_addr.is_ipv6());
+
+        assert_echoes(&mut driver, &mut poll, frontend_addr);
+    }
+
+    #[test]
+    fn dual_stack_listener_relays_ipv4_client_to_ipv6_backend() {
+        let backend_addr = match echo_backend("[::1]:0") {
+            Some(addr) => addr,
+            None => return,
+        };
+        let config = format!("
+[frontends.in]
+listen_addr = \"[::]:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             backend_addr);
+        let (mut driver, mut poll, frontend_addr) = start_driver(&config);
+        let ipv4_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), frontend_addr.port());
+
+        assert_echoes(&mut driver, &mut poll, ipv4_addr);
+    }
+
+    #[test]
+    fn relays_between_unix_sockets() {
+        let dir = env::temp_dir();
+        let frontend_path = dir.join(format!("lb-test-{}-front.sock", process::id()));
+        let backend_path = dir.join(format!("lb-test-{}-back.sock", process::id()));
+        let _ = fs::remove_file(&backend_path);
+
+        let backend = UnixListener::bind(&backend_path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+            let mut buffer = [0; 1024];
+
+            loop {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n_read) => stream.write_all(&buffer[..n_read]).unwrap(),
+                }
+            }
+        });
+
+        let config = format!("
+[frontends.in]
+listen_addr = \"unix:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"unix:{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+",
+                             frontend_path.display(),
+                             backend_path.display());
+        let config = RootConfig::from_str(&config).unwrap();
+        let mut poll = Poll::new().unwrap();
+        let mut state = DriverState::new(&config.buffers);
+        state.reconfigure(&mut poll, &config).unwrap();
+        let mut driver = Driver::new(state);
+
+        let mut client = UnixStream::connect(&frontend_path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"over unix").unwrap();
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(200));
+
+        let mut echoed = [0; 9];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"over unix");
+
+        let _ = fs::remove_file(&frontend_path);
+        let _ = fs::remove_file(&backend_path);
+    }
+
+    #[test]
+    fn metrics_listener_serves_prometheus_text() {
+        let (mut driver, mut poll, _) = start_driver("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:1\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[timeouts]
+idle_ms = 60000
+
+[metrics]
+listen_addr = \"127.0.0.1:0\"
+");
+        let metrics_addr = listener_addr(&driver, |role| matches!(*role, ListenerRole::Metrics));
+
+        let mut scraper = TcpStream::connect(metrics_addr).unwrap();
+        scraper.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        scraper.write_all(b"GET /metrics HTTP/1.1\r\nHost: lb\r\n\r\n").unwrap();
+
+        run_for(&mut driver, &mut poll, Duration::from_millis(100));
+
+        let mut response = String::new();
+        scraper.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("\nlb_connections_total 0\n"));
+        assert_eq!(driver.auxiliaries.len(), 0);
+    }
+
+    #[test]
+    fn admin_listener_is_ready_only_with_a_target_up() {
+        let (mut driver, mut poll, _) = start_driver("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:1\"]
+
+[buffers]
+connections = 16
+listeners = 4
+
+[admin]
+listen_addr = \"127.0.0.1:0\"
+");
+        let admin_addr = listener_addr(&driver, |role| matches!(*role, ListenerRole::Admin));
+
+        let probe = |driver: &mut Driver, poll: &mut Poll, path: &str| {
+            let mut prober = TcpStream::connect(admin_addr).unwrap();
+            prober.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            write!(prober, "GET {} HTTP/1.1\r\nHost: lb\r\n\r\n", path).unwrap();
+            run_for(driver, poll, Duration::from_millis(100));
+
+            let mut response = String::new();
+            prober.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        assert!(probe(&mut driver, &mut poll, "/healthz").starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(probe(&mut driver, &mut poll, "/ready").starts_with("HTTP/1.0 200 OK\r\n"));
+
+        let target = Address::resolve("127.0.0.1:1").unwrap();
+        driver.state.backends["out"].borrow_mut().remove(&target);
+        assert!(probe(&mut driver, &mut poll, "/ready")
+                    .starts_with("HTTP/1.0 503 Service Unavailable\r\n"));
+        assert_eq!(driver.auxiliaries.len(), 0);
     }
 }
 
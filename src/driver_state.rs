@@ -1,30 +1,122 @@
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::net::{ToSocketAddrs, SocketAddr};
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use mio::{Ready, Poll, PollOpt};
-use mio::tcp::TcpListener;
+use mio::{Evented, Ready, Poll, PollOpt, Token};
+use mio::net::UdpSocket;
 
 use slab::Slab;
 
-use backend::Backend;
-use frontend::Frontend;
-use connection::ListenerToken;
+use access_log::AccessLog;
+use acl::Acl;
+use backend::{Backend, BackendPool, check_proxy_protocol_targets, parse_proxy_header_format,
+              parse_source_addr, sends_proxy_protocol};
+use selector::{BackendSelector, RoundRobin, LeastConnections, LeastResponseTime,
+               WeightedRoundRobin, IpHash, WeightedRandom, Maglev};
+use frontend::{BackendShare, Frontend};
+use connection::{ListenerToken, check_token_capacity};
+use health_check::HealthChecker;
+use resolver::{Resolver, SystemResolver, is_host_name};
+use control::{Frontends, Pools};
+use peek::{NameRouting, RoutingOverflow};
+use rate_limit::RateLimiter;
+use stream::{Address, ListenOptions, Stream, StreamListener};
 use config::{RootConfig, BackendConfig, FrontendConfig, BufferConfig};
+use udp;
+use warm_pool::WarmConnections;
+
+#[derive(Clone)]
+pub enum ListenerRole {
+    Proxy(Rc<Frontend>),
+    /// A frontend with `protocol = "udp"`, relaying datagrams.
+    Udp(Rc<Frontend>),
+    Metrics,
+    Control,
+    Admin,
+}
+
+/// What a listener is bound with: a socket clients connect to, or, for a
+/// UDP frontend, the socket their datagrams arrive on.
+pub enum ListenerSocket {
+    Stream(StreamListener),
+    Datagram(UdpSocket),
+}
+
+impl ListenerSocket {
+    pub fn accept(&self) -> IOResult<(Stream, SocketAddr)> {
+        match *self {
+            ListenerSocket::Stream(ref l) => l.accept(),
+            ListenerSocket::Datagram(_) => {
+                Err(IOError::new(ErrorKind::InvalidInput, "UDP sockets take no connections"))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> IOResult<Address> {
+        match *self {
+            ListenerSocket::Stream(ref l) => l.local_addr(),
+            ListenerSocket::Datagram(ref s) => s.local_addr().map(Address::Tcp),
+        }
+    }
+
+    fn is_datagram(&self) -> bool {
+        matches!(*self, ListenerSocket::Datagram(_))
+    }
+
+    fn evented(&self) -> &dyn Evented {
+        match *self {
+            ListenerSocket::Stream(ref l) => l,
+            ListenerSocket::Datagram(ref s) => s,
+        }
+    }
+}
+
+impl Evented for ListenerSocket {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> IOResult<()> {
+        self.evented().register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> IOResult<()> {
+        self.evented().reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> IOResult<()> {
+        self.evented().deregister(poll)
+    }
+}
 
 pub struct Listener {
-    pub tcp_listener: TcpListener,
-    pub listen_addr: SocketAddr,
-    pub frontend: Rc<Frontend>,
+    pub socket: ListenerSocket,
+    pub listen_addr: Address,
+    pub role: ListenerRole,
     pub token: ListenerToken,
 }
 
 pub struct DriverState {
     pub listeners: Slab<Listener, ListenerToken>,
     pub listeners_to_remove: HashSet<ListenerToken>,
+    pub health_checker: HealthChecker,
+    pub resolver: Resolver,
+    pub rate_limiter: Option<RateLimiter>,
+    /// Backend pools by name, for the control socket.
+    pub backends: Pools,
+    /// Frontends by name, for the control socket.
+    pub frontends: Frontends,
+    pub access_log: AccessLog,
     pub config: RootConfig,
 }
 
@@ -33,6 +125,12 @@ impl DriverState {
         DriverState {
             listeners: Slab::with_capacity(buffers.listeners),
             listeners_to_remove: HashSet::new(),
+            health_checker: HealthChecker::new(),
+            resolver: Resolver::new(Box::new(SystemResolver)),
+            rate_limiter: None,
+            backends: HashMap::new(),
+            frontends: HashMap::new(),
+            access_log: AccessLog::default(),
             config: RootConfig {
                 buffers: (*buffers).clone(),
                 ..Default::default()
@@ -42,36 +140,104 @@ impl DriverState {
 
     pub fn reconfigure(&mut self, poll: &mut Poll, config: &RootConfig) -> IOResult<()> {
         info!("Reconfiguring driver state: {:#?}", config);
+        try!(check_token_capacity(config.buffers.connections, "connections"));
+        try!(check_token_capacity(config.buffers.listeners, "listeners"));
+        if config.buffers.endpoint_size == Some(0) {
+            return Err(IOError::new(ErrorKind::InvalidInput, "endpoint_size must be positive"));
+        }
 
         let mut backends = HashMap::new();
         let mut frontends = HashMap::new();
 
         for (name, config) in config.backends.iter() {
-            backends.insert(name, try!(make_backend(config)));
+            let pool = try!(make_backend(config));
+            pool.borrow_mut().set_name(name);
+            backends.insert(name, pool);
         }
 
         for (name, config) in config.frontends.iter() {
             frontends.insert(name, try!(make_frontend(config, &backends)));
         }
 
-        let mut listeners_to_add: HashMap<SocketAddr, Rc<Frontend>> = HashMap::new();
+        // Reopened only when changed, so an unrelated reload doesn't start
+        // a second writer on the same file.
+        let access_log = if config.access_log == self.config.access_log {
+            None
+        } else {
+            Some(match config.access_log {
+                     Some(ref access_log_config) => try!(AccessLog::from_config(access_log_config)),
+                     None => AccessLog::default(),
+                 })
+        };
+
+        let mut health_checker = HealthChecker::new();
+
+        let mut resolver = Resolver::new(Box::new(SystemResolver));
+
+        for (name, pool) in backends.iter() {
+            let backend_config = &config.backends[*name];
+
+            if let Some(ref check_config) = backend_config.health_check {
+                health_checker.add_pool(pool.clone(), check_config);
+            }
+            if let Some(interval_ms) = backend_config.resolve_interval_ms {
+                add_host_names(&mut resolver, pool, backend_config, interval_ms);
+            }
+        }
+
+        // Socket options only take effect when a listener is first bound.
+        let mut wanted_listeners: Vec<(Address, ListenerRole, ListenOptions)> = Vec::new();
+
+        for (name, frontend) in frontends.iter() {
+            let options = listen_options(&config.frontends[*name]);
+            let udp = try!(is_udp(&config.frontends[*name]));
+
+            for listen_addr in frontend.listen_addrs() {
+                let role = if udp {
+                    ListenerRole::Udp(frontend.clone())
+                } else {
+                    ListenerRole::Proxy(frontend.clone())
+                };
+                wanted_listeners.push((listen_addr, role, options));
+            }
+        }
+
+        if let Some(ref metrics_config) = config.metrics {
+            wanted_listeners.push((try!(listen_address(&metrics_config.listen_addr)),
+                                   ListenerRole::Metrics,
+                                   ListenOptions::default()));
+        }
+
+        if let Some(ref admin_config) = config.admin {
+            wanted_listeners.push((try!(listen_address(&admin_config.listen_addr)),
+                                   ListenerRole::Admin,
+                                   ListenOptions::default()));
+        }
+
+        if let Some(ref control_config) = config.control {
+            wanted_listeners.push((try!(listen_address(&control_config.listen_addr)),
+                                   ListenerRole::Control,
+                                   ListenOptions::default()));
+        }
+
+        let mut listeners_to_add: Vec<(Address, ListenerRole, ListenOptions)> = Vec::new();
 
         {
+            // A TCP and a UDP frontend can share a port.
             let mut listeners_by_addr = self.listeners
                 .iter_mut()
-                .map(|l| (l.listen_addr, l))
-                .collect::<HashMap<SocketAddr, &mut Listener>>();
-
-            for (_, frontend) in frontends {
-                for listen_addr in frontend.listen_addrs() {
-                    match listeners_by_addr.entry(listen_addr) {
-                        Occupied(mut e) => {
-                            e.get_mut().frontend = frontend.clone();
-                            e.remove();
-                        }
-                        Vacant(_) => {
-                            listeners_to_add.insert(listen_addr, frontend.clone());
-                        }
+                .map(|l| ((l.listen_addr.clone(), l.socket.is_datagram()), l))
+                .collect::<HashMap<(Address, bool), &mut Listener>>();
+
+            for (listen_addr, role, options) in wanted_listeners {
+                let datagram = matches!(role, ListenerRole::Udp(_));
+                match listeners_by_addr.entry((listen_addr, datagram)) {
+                    Occupied(mut e) => {
+                        e.get_mut().role = role;
+                        e.remove();
+                    }
+                    Vacant(e) => {
+                        listeners_to_add.push((e.into_key().0, role, options));
                     }
                 }
             }
@@ -81,15 +247,19 @@ impl DriverState {
             }
         }
 
-        for (addr, frontend) in listeners_to_add.into_iter() {
-            let tcp_listener = try!(TcpListener::bind(&addr));
+        for (addr, role, options) in listeners_to_add.into_iter() {
+            let socket = match role {
+                ListenerRole::Udp(_) => udp::bind(&addr).map(ListenerSocket::Datagram),
+                _ => StreamListener::bind(&addr, &options).map(ListenerSocket::Stream),
+            };
+            let socket = try!(socket.map_err(|e| bind_error(&addr, e)));
             let token = match self.listeners.vacant_entry() {
                 Some(entry) => {
                     let listener = Listener {
-                        tcp_listener: tcp_listener,
+                        socket: socket,
                         listen_addr: addr,
                         token: entry.index(),
-                        frontend: frontend,
+                        role: role,
                     };
                     entry.insert(listener).index()
                 }
@@ -102,49 +272,257 @@ impl DriverState {
 
             info!("Added listener with token {:?}", token);
 
-            try!(poll.register(&listener.tcp_listener,
+            try!(poll.register(&listener.socket,
                                listener.token.as_raw_token(),
                                Ready::readable(),
                                PollOpt::edge() | PollOpt::oneshot()));
         }
 
+        self.health_checker = health_checker;
+        self.resolver = resolver;
+        self.backends = backends
+            .into_iter()
+            .map(|(name, pool)| (name.clone(), pool))
+            .collect();
+        self.frontends = frontends
+            .into_iter()
+            .map(|(name, frontend)| (name.clone(), frontend))
+            .collect();
+        self.rate_limiter = config.limits.as_ref().and_then(RateLimiter::from_config);
+        if let Some(access_log) = access_log {
+            self.access_log = access_log;
+        }
         self.config = (*config).clone();
 
         Ok(())
     }
 }
 
-fn resolve_name(s: &str) -> IOResult<SocketAddr> {
-    let addrs: Vec<SocketAddr> = try!(s.to_socket_addrs()).collect();
+/// Listeners bind to exactly the address given, so it has to be a literal
+/// IP address and port, or a Unix socket path. `0.0.0.0` or `[::]` binds
+/// every interface.
+fn listen_address(addr: &str) -> IOResult<Address> {
+    if is_host_name(addr) {
+        return Err(IOError::new(ErrorKind::InvalidInput,
+                                format!("Listen address {} is not an IP address and port, \
+                                         like 127.0.0.1:8080",
+                                        addr)));
+    }
+
+    Address::resolve(addr)
+}
+
+/// Says which address could not be bound, and what usually fixes it.
+fn bind_error(addr: &Address, e: IOError) -> IOError {
+    let hint = match (e.kind(), addr) {
+        (ErrorKind::AddrInUse, _) => ", is another process listening on it?",
+        (ErrorKind::PermissionDenied, &Address::Tcp(addr)) if addr.port() < 1024 => {
+            ", ports below 1024 need root or CAP_NET_BIND_SERVICE"
+        }
+        (ErrorKind::AddrNotAvailable, _) => ", it does not belong to any local interface",
+        _ => "",
+    };
 
-    assert_eq!(addrs.len(), 1);
+    IOError::new(e.kind(), format!("Could not listen on {}: {}{}", addr, e, hint))
+}
 
-    Ok(addrs[0])
+/// Whether a frontend relays UDP rather than the default TCP. Routing by
+/// name and the PROXY protocol need a stream to read from, so they are
+/// TCP only.
+fn is_udp(config: &FrontendConfig) -> IOResult<bool> {
+    match config.protocol.as_ref().map(|protocol| &protocol[..]) {
+        None | Some("tcp") => Ok(false),
+        Some("udp") => {
+            if config.sni_backends.is_some() || config.host_backends.is_some() ||
+               config.accept_proxy_protocol.unwrap_or(false) {
+                return Err(IOError::new(ErrorKind::InvalidInput,
+                                        format!("Frontend on {} can't route by name or take \
+                                                 PROXY protocol headers over UDP",
+                                                config.listen_addr)));
+            }
+            Ok(true)
+        }
+        Some(protocol) => {
+            Err(IOError::new(ErrorKind::InvalidInput,
+                             format!("Unknown frontend protocol {}, expected tcp or udp",
+                                     protocol)))
+        }
+    }
+}
+
+fn listen_options(config: &FrontendConfig) -> ListenOptions {
+    let defaults = ListenOptions::default();
+
+    ListenOptions {
+        reuse_addr: config.reuse_addr.unwrap_or(defaults.reuse_addr),
+        backlog: config.backlog.unwrap_or(defaults.backlog),
+    }
 }
 
-fn make_backend(config: &BackendConfig) -> IOResult<Rc<RefCell<Backend>>> {
-    let target_addrs = config
-        .target_addrs
-        .iter()
-        .flat_map(|s| match resolve_name(s) {
-                      Ok(a) => Ok(a),
-                      Err(e) => {
-            println!("Could not resolve TARGET argument {}: {}", s, e);
-            Err(e)
+fn make_backend(config: &BackendConfig) -> IOResult<Rc<RefCell<BackendPool>>> {
+    let weights = config
+        .weights
+        .clone()
+        .unwrap_or_else(|| vec![1; config.target_addrs.len()]);
+
+    if weights.len() != config.target_addrs.len() {
+        return Err(IOError::new(ErrorKind::InvalidInput,
+                                "Backend weights must match target addresses"));
+    }
+
+    // A host name becomes one target for every address it resolves to,
+    // all with the weight given for the name.
+    let mut backends = Vec::new();
+
+    for (target, &weight) in config.target_addrs.iter().zip(weights.iter()) {
+        let addrs = match Address::resolve_all(target) {
+            Ok(ref addrs) if addrs.is_empty() => {
+                return Err(IOError::new(ErrorKind::NotFound, "Could not resolve target address"))
+            }
+            Ok(addrs) => addrs,
+            Err(e) => {
+                println!("Could not resolve TARGET argument {}: {}", target, e);
+                return Err(IOError::new(ErrorKind::NotFound, "Could not resolve target address"));
+            }
+        };
+
+        for addr in addrs {
+            let mut backend = Backend::new(addr, weight);
+            backend.proxy_protocol = sends_proxy_protocol(config, target);
+            if is_host_name(target) {
+                backend.host = Some(target.clone());
+            }
+            backends.push(backend);
         }
-                  })
-        .collect::<Vec<SocketAddr>>();
+    }
 
-    if target_addrs.len() != config.target_addrs.len() {
-        Err(IOError::new(ErrorKind::NotFound, "Could not resolve target address"))
-    } else {
-        Ok(Backend::new(target_addrs))
+    if let Some(ref source_addr) = config.source_addr {
+        try!(parse_source_addr(source_addr));
+    }
+    if config.max_connection_bytes == Some(0) {
+        return Err(IOError::new(ErrorKind::InvalidInput,
+                                "max_connection_bytes must be positive"));
+    }
+    try!(parse_proxy_header_format(config));
+    try!(check_proxy_protocol_targets(config));
+    try!(WarmConnections::from_config(config, Instant::now()));
+
+    let selector = try!(make_selector(config, &backends));
+    Ok(BackendPool::new(backends, selector, config))
+}
+
+/// Has the resolver look the host name targets of a backend up again every
+/// `interval_ms`. Literal addresses never change and are left alone.
+fn add_host_names(resolver: &mut Resolver,
+                  pool: &Rc<RefCell<BackendPool>>,
+                  config: &BackendConfig,
+                  interval_ms: u64) {
+    let weights = config
+        .weights
+        .clone()
+        .unwrap_or_else(|| vec![1; config.target_addrs.len()]);
+
+    for (target, weight) in config.target_addrs.iter().zip(weights) {
+        if is_host_name(target) {
+            resolver.add_host(pool.clone(), target, weight, Duration::from_millis(interval_ms));
+        }
+    }
+}
+
+fn make_selector(config: &BackendConfig,
+                 backends: &[Backend])
+                 -> IOResult<Box<dyn BackendSelector>> {
+    match config.strategy.as_deref() {
+        None | Some("round_robin") => Ok(Box::new(RoundRobin::new())),
+        Some("least_connections") => {
+            let selector = match config.slow_start_ms {
+                Some(ms) => LeastConnections::with_slow_start(Duration::from_millis(ms)),
+                None => LeastConnections::new(),
+            };
+            Ok(Box::new(selector))
+        }
+        Some("least_response_time") => Ok(Box::new(LeastResponseTime::new())),
+        Some("ip_hash") => Ok(Box::new(IpHash::new())),
+        Some("maglev") => Ok(Box::new(Maglev::new())),
+        Some("weighted_random") => Ok(Box::new(WeightedRandom::new(config.random_seed))),
+        Some("weighted_round_robin") => {
+            Ok(Box::new(WeightedRoundRobin::new(backends
+                                                    .iter()
+                                                    .map(|b| (b.addr.clone(), b.weight))
+                                                    .collect())))
+        }
+        Some(other) => {
+            Err(IOError::new(ErrorKind::InvalidInput,
+                             format!("Unknown backend strategy {}", other)))
+        }
     }
 }
 
 fn make_frontend(config: &FrontendConfig,
-                 backends: &HashMap<&String, Rc<RefCell<Backend>>>)
+                 backends: &HashMap<&String, Rc<RefCell<BackendPool>>>)
                  -> IOResult<Rc<Frontend>> {
-    Ok(Frontend::new(try!(resolve_name(&config.listen_addr)),
-                     vec![backends[&config.backend].clone()]))
+    let find_pool = |name: &String| match backends.get(name) {
+        Some(pool) => Ok(pool.clone()),
+        None => Err(IOError::new(ErrorKind::NotFound, format!("Unknown backend {}", name))),
+    };
+
+    try!(is_udp(config));
+    let pool = try!(find_pool(&config.backend));
+    let (name_routing, routes) = match (config.sni_backends.as_ref(),
+                                        config.host_backends.as_ref()) {
+        (Some(_), Some(_)) => {
+            return Err(IOError::new(ErrorKind::InvalidInput,
+                                    "A frontend can route by SNI or by Host header, not both"));
+        }
+        (Some(routes), None) => (Some(NameRouting::ServerName), Some(routes)),
+        (None, Some(routes)) => (Some(NameRouting::Host), Some(routes)),
+        (None, None) => (None, None),
+    };
+
+    let mut name_routes = HashMap::new();
+    for (name, backend) in routes.iter().flat_map(|routes| routes.iter()) {
+        name_routes.insert(name.clone(), try!(find_pool(backend)));
+    }
+
+    if config.max_routing_bytes == Some(0) {
+        return Err(IOError::new(ErrorKind::InvalidInput, "max_routing_bytes must be positive"));
+    }
+    if config.endpoint_size == Some(0) {
+        return Err(IOError::new(ErrorKind::InvalidInput, "endpoint_size must be positive"));
+    }
+    let routing_overflow = try!(RoutingOverflow::from_name(config.routing_overflow.as_deref()));
+
+    // Sorted, so the order clients are interleaved in doesn't change from
+    // one run to the next.
+    let mut split = config.backend_split.iter().flat_map(|split| split.iter()).collect::<Vec<_>>();
+    split.sort();
+    let mut backends = vec![BackendShare::new(config.backend.clone(), pool, 100)];
+    for (name, &percent) in split {
+        if *name == config.backend {
+            return Err(IOError::new(ErrorKind::InvalidInput,
+                                    format!("backend_split can't name the default backend {}",
+                                            name)));
+        }
+        if percent > backends[0].percent {
+            return Err(IOError::new(ErrorKind::InvalidInput,
+                                    "backend_split percentages add up to more than 100"));
+        }
+        backends[0].percent -= percent;
+        backends.push(BackendShare::new(name.clone(), try!(find_pool(name)), percent));
+    }
+
+    let acl = try!(Acl::parse(config.allow.as_ref().map_or(&[], |ranges| &ranges[..]),
+                              config.deny.as_ref().map_or(&[], |ranges| &ranges[..])));
+
+    Ok(Frontend::new(try!(listen_address(&config.listen_addr)),
+                     config.backend.clone(),
+                     backends,
+                     config.accept_proxy_protocol.unwrap_or(false),
+                     name_routing,
+                     name_routes,
+                     config.max_routing_bytes,
+                     routing_overflow,
+                     config.endpoint_size,
+                     config.access_log.unwrap_or(false),
+                     acl))
 }
@@ -1,27 +1,190 @@
-use std::net::SocketAddr;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
 
-use backend::Backend;
+use acl::Acl;
+use backend::BackendPool;
+use peek::{NameRouting, RoutingOverflow};
+use stream::Address;
+
+/// A pool a frontend sends a share of its clients to.
+pub struct BackendShare {
+    pub name: String,
+    pub pool: Rc<RefCell<BackendPool>>,
+    pub percent: u32,
+    /// Running score for smooth weighted round-robin between the shares.
+    current: i64,
+}
+
+impl BackendShare {
+    pub fn new(name: String, pool: Rc<RefCell<BackendPool>>, percent: u32) -> BackendShare {
+        BackendShare {
+            name: name,
+            pool: pool,
+            percent: percent,
+            current: 0,
+        }
+    }
+}
 
 pub struct Frontend {
-    listen_addr: SocketAddr,
-    backends: Vec<Rc<RefCell<Backend>>>,
+    listen_addr: Address,
+    backend_name: String,
+    /// The default backend first, with whatever percentage the others
+    /// leave it.
+    backends: RefCell<Vec<BackendShare>>,
+    accept_proxy_protocol: bool,
+    name_routing: Option<NameRouting>,
+    name_routes: HashMap<String, Rc<RefCell<BackendPool>>>,
+    max_routing_bytes: Option<usize>,
+    routing_overflow: RoutingOverflow,
+    endpoint_size: Option<usize>,
+    access_log: bool,
+    acl: Acl,
 }
 
 impl Frontend {
-    pub fn new(listen_addr: SocketAddr, backends: Vec<Rc<RefCell<Backend>>>) -> Rc<Frontend> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(listen_addr: Address,
+               backend_name: String,
+               backends: Vec<BackendShare>,
+               accept_proxy_protocol: bool,
+               name_routing: Option<NameRouting>,
+               name_routes: HashMap<String, Rc<RefCell<BackendPool>>>,
+               max_routing_bytes: Option<usize>,
+               routing_overflow: RoutingOverflow,
+               endpoint_size: Option<usize>,
+               access_log: bool,
+               acl: Acl)
+               -> Rc<Frontend> {
         Rc::new(Frontend {
                     listen_addr: listen_addr,
-                    backends: backends,
+                    backend_name: backend_name,
+                    backends: RefCell::new(backends),
+                    accept_proxy_protocol: accept_proxy_protocol,
+                    name_routing: name_routing,
+                    name_routes: name_routes
+                        .into_iter()
+                        .map(|(name, pool)| (name.to_lowercase(), pool))
+                        .collect(),
+                    max_routing_bytes: max_routing_bytes,
+                    routing_overflow: routing_overflow,
+                    endpoint_size: endpoint_size,
+                    access_log: access_log,
+                    acl: acl,
                 })
     }
 
-    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
-        vec![self.listen_addr]
+    /// Name of the `[backends]` entry this frontend routes to.
+    pub fn backend_name(&self) -> &str {
+        &self.backend_name
+    }
+
+    /// Whether clients are expected to open with a PROXY protocol header.
+    pub fn accepts_proxy_protocol(&self) -> bool {
+        self.accept_proxy_protocol
+    }
+
+    /// Buffer size for connections accepted here, when it differs from the
+    /// one under `[buffers]`. Bulk transfers move in fewer, bigger reads
+    /// and writes with a large one.
+    pub fn endpoint_size(&self) -> Option<usize> {
+        self.endpoint_size
+    }
+
+    /// Whether a summary line is logged for each connection when it closes.
+    pub fn logs_access(&self) -> bool {
+        self.access_log
+    }
+
+    /// Whether clients from `ip` may connect here.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.acl.permits(ip)
+    }
+
+    pub fn listen_addrs(&self) -> Vec<Address> {
+        vec![self.listen_addr.clone()]
+    }
+
+    /// What has to be read from a client before its backend can be picked,
+    /// if anything.
+    pub fn name_routing(&self) -> Option<NameRouting> {
+        self.name_routing
+    }
+
+    /// How many bytes are read while looking for the name to route by, if
+    /// fewer than a connection's buffer holds.
+    pub fn max_routing_bytes(&self) -> Option<usize> {
+        self.max_routing_bytes
+    }
+
+    /// What to do with clients that send more than that before naming a
+    /// host.
+    pub fn routing_overflow(&self) -> RoutingOverflow {
+        self.routing_overflow
+    }
+
+    /// The pool a new client goes to. With a `backend_split`, clients are
+    /// spread between the pools by percentage, in an even interleaving
+    /// rather than at random, before the pool picks one of its targets.
+    pub fn decide_backend(&self) -> Rc<RefCell<BackendPool>> {
+        let mut backends = self.backends.borrow_mut();
+        if backends.len() == 1 {
+            return backends[0].pool.clone();
+        }
+
+        for share in backends.iter_mut() {
+            share.current += share.percent as i64;
+        }
+        let best = (1..backends.len()).fold(0, |best, index| {
+            if backends[index].current > backends[best].current {
+                index
+            } else {
+                best
+            }
+        });
+        backends[best].current -= 100;
+        backends[best].pool.clone()
+    }
+
+    /// Each pool clients are split between and its percentage, the
+    /// default backend first.
+    pub fn split(&self) -> Vec<(String, u32)> {
+        self.backends.borrow().iter().map(|share| (share.name.clone(), share.percent)).collect()
+    }
+
+    /// Gives `name`, one of the pools in the frontend's `backend_split`,
+    /// `percent` of new clients, leaving the default backend the rest.
+    pub fn set_split(&self, name: &str, percent: u32) -> Result<(), String> {
+        let mut backends = self.backends.borrow_mut();
+        let index = match backends.iter().skip(1).position(|share| share.name == name) {
+            Some(index) => index + 1,
+            None => return Err(format!("{} is not split to from {}", name, self.backend_name)),
+        };
+        let others = backends
+            .iter()
+            .skip(1)
+            .filter(|share| share.name != name)
+            .map(|share| share.percent)
+            .sum::<u32>();
+        if others + percent > 100 {
+            return Err("split percentages add up to more than 100".to_owned());
+        }
+
+        backends[index].percent = percent;
+        backends[0].percent = 100 - others - percent;
+        for share in backends.iter_mut() {
+            share.current = 0;
+        }
+        Ok(())
     }
 
-    pub fn decide_backend(&self) -> Rc<RefCell<Backend>> {
-        self.backends[0].clone()
+    /// The pool for a host name, falling back to the default backend for
+    /// names without a route and clients that didn't send one.
+    pub fn decide_backend_for(&self, name: Option<&str>) -> Rc<RefCell<BackendPool>> {
+        name.and_then(|name| self.name_routes.get(&name.to_lowercase()))
+            .cloned()
+            .unwrap_or_else(|| self.decide_backend())
     }
 }
@@ -0,0 +1,206 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use mio::Ready;
+
+use backend::{BackendPool, BackendId};
+use config::HealthCheckConfig;
+use stream::Stream;
+
+/// How much of a target's answer is searched for the expected response
+/// before the check gives up on it.
+const MAX_RESPONSE_BYTES: usize = 4096;
+
+struct CheckedPool {
+    pool: Rc<RefCell<BackendPool>>,
+    config: HealthCheckConfig,
+    next_check: Instant,
+}
+
+pub struct HealthChecker {
+    pools: Vec<CheckedPool>,
+}
+
+enum ProbeStage {
+    Connecting,
+    Sending,
+    Expecting,
+}
+
+pub struct HealthProbe {
+    pub stream: Stream,
+    pool: Rc<RefCell<BackendPool>>,
+    addr: BackendId,
+    deadline: Instant,
+    rise: u32,
+    fall: u32,
+    stage: ProbeStage,
+    send: Vec<u8>,
+    written: usize,
+    expect: Option<Vec<u8>>,
+    received: Vec<u8>,
+    expect_timeout: Duration,
+}
+
+impl HealthChecker {
+    pub fn new() -> HealthChecker {
+        HealthChecker { pools: Vec::new() }
+    }
+
+    pub fn add_pool(&mut self, pool: Rc<RefCell<BackendPool>>, config: &HealthCheckConfig) {
+        self.pools
+            .push(CheckedPool {
+                      pool: pool,
+                      config: config.clone(),
+                      next_check: Instant::now(),
+                  });
+    }
+
+    pub fn next_check(&self) -> Option<Instant> {
+        self.pools.iter().map(|p| p.next_check).min()
+    }
+
+    pub fn start_due_probes(&mut self, now: Instant) -> Vec<HealthProbe> {
+        let mut probes = Vec::new();
+
+        for checked in self.pools.iter_mut().filter(|p| p.next_check <= now) {
+            checked.next_check = now + Duration::from_millis(checked.config.interval_ms);
+            let config = &checked.config;
+            let expect_timeout_ms = config.expect_timeout_ms.unwrap_or(config.timeout_ms);
+
+            let addrs = checked.pool.borrow().backend_ids();
+            for addr in addrs {
+                let connected = checked.pool.borrow().connect_to(&addr);
+                let probe = HealthProbe {
+                    stream: match connected {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            debug!("Health check connect to {} failed: {}", addr, e);
+                            checked
+                                .pool
+                                .borrow_mut()
+                                .report_check(&addr, false, checked.config.rise, checked.config.fall);
+                            continue;
+                        }
+                    },
+                    pool: checked.pool.clone(),
+                    addr: addr,
+                    deadline: now + Duration::from_millis(checked.config.timeout_ms),
+                    rise: checked.config.rise,
+                    fall: checked.config.fall,
+                    stage: ProbeStage::Connecting,
+                    send: config.send.as_ref().map_or(Vec::new(), |s| s.as_bytes().to_vec()),
+                    written: 0,
+                    expect: config
+                        .expect
+                        .as_ref()
+                        .filter(|s| !s.is_empty())
+                        .map(|s| s.as_bytes().to_vec()),
+                    received: Vec::new(),
+                    expect_timeout: Duration::from_millis(expect_timeout_ms),
+                };
+
+                probes.push(probe);
+            }
+        }
+
+        probes
+    }
+}
+
+impl HealthProbe {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn interest(&self) -> Ready {
+        match self.stage {
+            ProbeStage::Connecting | ProbeStage::Sending => Ready::writable(),
+            ProbeStage::Expecting => Ready::readable(),
+        }
+    }
+
+    /// Makes as much progress as a readiness event on the probe socket
+    /// allows, returning the check result once there is one. Without a
+    /// `send` or `expect`, a target passes as soon as it takes the
+    /// connect; with them, once the payload is written and the expected
+    /// response has turned up in its answer.
+    pub fn ready(&mut self, ready: Ready, now: Instant) -> Option<bool> {
+        if let ProbeStage::Connecting = self.stage {
+            match self.stream.connect_result(ready) {
+                Some(true) => {}
+                result => return result,
+            }
+            if self.send.is_empty() && self.expect.is_none() {
+                return Some(true);
+            }
+            self.stage = ProbeStage::Sending;
+            self.deadline = now + self.expect_timeout;
+        }
+
+        if let ProbeStage::Sending = self.stage {
+            while self.written < self.send.len() {
+                match self.stream.write(&self.send[self.written..]) {
+                    Ok(n_written) => self.written += n_written,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return None,
+                    Err(e) => {
+                        debug!("Health check send to {} failed: {}", self.addr, e);
+                        return Some(false);
+                    }
+                }
+            }
+            if self.expect.is_none() {
+                return Some(true);
+            }
+            self.stage = ProbeStage::Expecting;
+        }
+
+        self.read_response()
+    }
+
+    fn read_response(&mut self) -> Option<bool> {
+        let expect = match self.expect {
+            Some(ref expect) => expect,
+            None => return Some(true),
+        };
+        let mut buffer = [0; 1024];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    debug!("Health check of {} got no expected response before EOF",
+                           self.addr);
+                    return Some(false);
+                }
+                Ok(n_read) => self.received.extend_from_slice(&buffer[..n_read]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) => {
+                    debug!("Health check read from {} failed: {}", self.addr, e);
+                    return Some(false);
+                }
+            }
+
+            if self.received.windows(expect.len()).any(|window| window == &expect[..]) {
+                return Some(true);
+            }
+            if self.received.len() >= MAX_RESPONSE_BYTES {
+                debug!("Health check of {} got no expected response in {} bytes",
+                       self.addr,
+                       self.received.len());
+                return Some(false);
+            }
+        }
+    }
+
+    pub fn report(&self, success: bool) {
+        if !success {
+            debug!("Health check of {} failed", self.addr);
+        }
+
+        self.pool
+            .borrow_mut()
+            .report_check(&self.addr, success, self.rise, self.fall);
+    }
+}
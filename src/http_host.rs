@@ -0,0 +1,118 @@
+use std::str;
+
+use peek::Peeked;
+
+/// Reads the Host header from the start of a plaintext HTTP/1 request.
+/// Only the request line and headers are looked at, so a request body is
+/// never waited for. The port, if any, is left out of the name.
+pub fn parse_host(buf: &[u8]) -> Peeked {
+    let head_len = match end_of_head(buf) {
+        Some(head_len) => head_len,
+        None if is_request_line_prefix(buf) => return Peeked::Incomplete,
+        None => return Peeked::Invalid,
+    };
+    let head = match str::from_utf8(&buf[..head_len]) {
+        Ok(head) => head,
+        Err(_) => return Peeked::Invalid,
+    };
+
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or("");
+    if !is_request_line(request_line) {
+        return Peeked::Invalid;
+    }
+
+    for line in lines {
+        let mut header = line.splitn(2, ':');
+        let name = header.next().unwrap_or("");
+        let value = header.next().unwrap_or("").trim();
+
+        if name.eq_ignore_ascii_case("host") {
+            return Peeked::Name(Some(strip_port(value).to_owned()));
+        }
+    }
+
+    Peeked::Name(None)
+}
+
+/// Length of the request line and headers, up to the blank line that ends
+/// them.
+fn end_of_head(buf: &[u8]) -> Option<usize> {
+    let crlf = buf.windows(4).position(|w| w == b"\r\n\r\n");
+    let lf = buf.windows(2).position(|w| w == b"\n\n");
+
+    match (crlf, lf) {
+        (Some(crlf), Some(lf)) => Some(crlf.min(lf)),
+        (crlf, lf) => crlf.or(lf),
+    }
+}
+
+/// Whether the first line, as far as it has been read, can still turn
+/// out to be an HTTP request line, so it is worth waiting for more.
+fn is_request_line_prefix(buf: &[u8]) -> bool {
+    match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            str::from_utf8(&buf[..end])
+                .map(is_request_line)
+                .unwrap_or(false)
+        }
+        None => {
+            buf.iter()
+                .take_while(|&&b| b != b' ')
+                .all(|b| b.is_ascii_uppercase())
+        }
+    }
+}
+
+fn is_request_line(line: &str) -> bool {
+    let mut parts = line.trim_end_matches('\r').split(' ');
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("");
+    let version = parts.next().unwrap_or("");
+
+    !method.is_empty() && method.bytes().all(|b| b.is_ascii_uppercase()) &&
+    !target.is_empty() && version.starts_with("HTTP/1.") && parts.next().is_none()
+}
+
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // An IPv6 literal, which has colons of its own.
+        return host.find(']').map_or(host, |end| &host[..end + 1]);
+    }
+
+    host.rfind(':').map_or(host, |colon| &host[..colon])
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_host;
+
+    use peek::Peeked;
+
+    #[test]
+    fn reads_host_header_without_waiting_for_body() {
+        let request = b"POST /upload HTTP/1.1\r\nContent-Length: 100\r\nhost: Example.com:8080\r\n\r\n";
+
+        assert_eq!(parse_host(request),
+                   Peeked::Name(Some("Example.com".to_owned())));
+        assert_eq!(parse_host(b"GET / HTTP/1.0\r\n\r\n"), Peeked::Name(None));
+        assert_eq!(parse_host(b"GET / HTTP/1.1\nHost: [::1]:80\n\n"),
+                   Peeked::Name(Some("[::1]".to_owned())));
+    }
+
+    #[test]
+    fn waits_for_the_end_of_the_headers() {
+        let request = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+        for len in 0..request.len() - 1 {
+            assert_eq!(parse_host(&request[..len]), Peeked::Incomplete);
+        }
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        assert_eq!(parse_host(&[0x16, 0x03, 0x01]), Peeked::Invalid);
+        assert_eq!(parse_host(b"hello\r\n"), Peeked::Invalid);
+        assert_eq!(parse_host(b"GET / SPDY/3\r\nHost: a\r\n\r\n"), Peeked::Invalid);
+    }
+}
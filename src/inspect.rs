@@ -0,0 +1,76 @@
+use std::io::Result as IOResult;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use mio::{Poll, PollOpt, Ready, Registration, SetReadiness};
+
+use connection::INSPECT_TOKEN;
+use observer::ConnectionInfo;
+
+type Inspection = Box<dyn FnOnce(&mut dyn Iterator<Item = ConnectionInfo>) + Send>;
+
+/// Looks at the open connections of a running load balancer from any
+/// thread. Connections hold sockets that can't leave the event loop
+/// thread, so instead of handing them out, each inspection is a callback
+/// sent to that thread and run there between relay events, with the
+/// connections as they stand. It can send on what it finds over a channel
+/// of its own.
+#[derive(Clone)]
+pub struct InspectHandle {
+    requests: Sender<Inspection>,
+    readiness: SetReadiness,
+}
+
+impl InspectHandle {
+    /// Has `inspect` called on the event loop thread with every open
+    /// connection, in the order they were opened. It should return
+    /// quickly, since nothing is relayed while it runs. Returns false, and
+    /// `inspect` is never called, once the load balancer is gone.
+    pub fn inspect<F>(&self, inspect: F) -> bool
+        where F: FnOnce(&mut dyn Iterator<Item = ConnectionInfo>) + Send + 'static
+    {
+        if self.requests.send(Box::new(inspect)).is_err() {
+            return false;
+        }
+
+        // Wakes the event loop from its poll.
+        let _ = self.readiness.set_readiness(Ready::readable());
+        true
+    }
+}
+
+/// The event loop's end of its inspect handles.
+pub struct InspectRequests {
+    requests: Receiver<Inspection>,
+    handle: InspectHandle,
+    // Deregisters from the poll when dropped.
+    _registration: Registration,
+}
+
+impl InspectRequests {
+    pub fn new(poll: &Poll) -> IOResult<InspectRequests> {
+        let (registration, readiness) = Registration::new2();
+        try!(poll.register(&registration, INSPECT_TOKEN, Ready::readable(), PollOpt::edge()));
+
+        let (sender, requests) = mpsc::channel();
+        Ok(InspectRequests {
+               requests: requests,
+               handle: InspectHandle {
+                   requests: sender,
+                   readiness: readiness,
+               },
+               _registration: registration,
+           })
+    }
+
+    pub fn handle(&self) -> InspectHandle {
+        self.handle.clone()
+    }
+
+    /// The inspections asked for since the last call. Readiness is cleared
+    /// first, so one asked for while these run wakes the loop again.
+    pub fn take(&self) -> Vec<Inspection> {
+        let _ = self.handle.readiness.set_readiness(Ready::empty());
+
+        self.requests.try_iter().collect()
+    }
+}
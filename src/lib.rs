@@ -0,0 +1,49 @@
+#![cfg_attr(feature="dev", allow(unstable_features))]
+#![cfg_attr(feature="dev", feature(plugin))]
+#![cfg_attr(featrue="dev", plugin(clippy))]
+
+extern crate mio;
+extern crate slab;
+extern crate toml;
+extern crate rustc_serialize;
+extern crate libc;
+extern crate net2;
+
+#[macro_use]
+extern crate log;
+
+pub mod config;
+mod connection;
+mod frontend;
+pub mod backend;
+pub mod selector;
+mod health_check;
+mod connect_race;
+mod warm_pool;
+pub mod metrics;
+pub mod observer;
+mod metrics_server;
+mod admin;
+mod control;
+mod rate_limit;
+mod acl;
+mod access_log;
+mod proxy_protocol;
+mod peek;
+mod sni;
+mod http_host;
+mod splice;
+mod udp;
+mod timer_wheel;
+mod resolver;
+pub mod stream;
+pub mod signal;
+mod shutdown;
+mod inspect;
+mod driver_state;
+mod driver;
+mod load_balancer;
+
+pub use load_balancer::{LoadBalancer, LoadBalancerBuilder};
+pub use shutdown::ShutdownHandle;
+pub use inspect::InspectHandle;
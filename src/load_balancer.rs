@@ -0,0 +1,689 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mio::{Events, Poll};
+
+use config::{RootConfig, FrontendConfig, BackendConfig, LimitConfig};
+use driver::Driver;
+use driver_state::DriverState;
+use metrics::Metrics;
+use observer::ConnectionObserver;
+use selector::BackendSelector;
+use shutdown::{ShutdownHandle, ShutdownSignal};
+use inspect::{InspectHandle, InspectRequests};
+use stream::Address;
+
+const BACKEND_NAME: &str = "default";
+const EVENTS_CAPACITY: usize = 4096;
+
+/// A load balancer with its listeners bound, ready to run.
+pub struct LoadBalancer {
+    poll: Poll,
+    driver: Driver,
+    events_capacity: usize,
+    shutdown: ShutdownHandle,
+    inspect: InspectHandle,
+}
+
+/// Sets up a load balancer that spreads every listen address over one
+/// pool of backends, without going through a config file.
+///
+/// ```no_run
+/// use loadbalancer::LoadBalancerBuilder;
+///
+/// let mut load_balancer = LoadBalancerBuilder::new()
+///     .listen("0.0.0.0:8080")
+///     .backend("10.0.0.1:80")
+///     .backend("10.0.0.2:80")
+///     .build()
+///     .unwrap();
+///
+/// load_balancer.run().unwrap();
+/// ```
+#[derive(Default)]
+pub struct LoadBalancerBuilder {
+    listen_addrs: Vec<String>,
+    backend_addrs: Vec<String>,
+    selector: Option<Box<dyn BackendSelector>>,
+    buffer_size: Option<usize>,
+    max_connections: Option<usize>,
+    reuse_addr: Option<bool>,
+    backlog: Option<i32>,
+    events_capacity: Option<usize>,
+    poll_timeout: Option<Duration>,
+    observers: Vec<Box<dyn ConnectionObserver>>,
+}
+
+impl LoadBalancer {
+    pub fn from_config(config: &RootConfig) -> IOResult<LoadBalancer> {
+        let mut poll = try!(Poll::new());
+
+        let mut driver_state = DriverState::new(&config.buffers);
+        try!(driver_state.reconfigure(&mut poll, config));
+
+        let mut driver = Driver::new(driver_state);
+        let shutdown = try!(ShutdownSignal::new(&poll));
+        let shutdown_handle = shutdown.handle();
+        driver.set_shutdown_signal(shutdown);
+        let inspections = try!(InspectRequests::new(&poll));
+        let inspect_handle = inspections.handle();
+        driver.set_inspect_requests(inspections);
+
+        Ok(LoadBalancer {
+            poll: poll,
+            driver: driver,
+            events_capacity: EVENTS_CAPACITY,
+            shutdown: shutdown_handle,
+            inspect: inspect_handle,
+        })
+    }
+
+    fn from_builder(builder: LoadBalancerBuilder) -> IOResult<LoadBalancer> {
+        let config = try!(builder.config());
+        let mut poll = try!(Poll::new());
+
+        let mut driver_state = DriverState::new(&config.buffers);
+        try!(driver_state.reconfigure(&mut poll, &config));
+
+        if let Some(selector) = builder.selector {
+            driver_state.backends[BACKEND_NAME].borrow_mut().set_selector(selector);
+        }
+
+        let mut driver = Driver::new(driver_state);
+        for observer in builder.observers {
+            driver.add_observer(observer);
+        }
+        if let Some(timeout) = builder.poll_timeout {
+            driver.set_max_poll_timeout(timeout);
+        }
+        let shutdown = try!(ShutdownSignal::new(&poll));
+        let shutdown_handle = shutdown.handle();
+        driver.set_shutdown_signal(shutdown);
+        let inspections = try!(InspectRequests::new(&poll));
+        let inspect_handle = inspections.handle();
+        driver.set_inspect_requests(inspections);
+
+        Ok(LoadBalancer {
+            poll: poll,
+            driver: driver,
+            events_capacity: builder.events_capacity.unwrap_or(EVENTS_CAPACITY),
+            shutdown: shutdown_handle,
+            inspect: inspect_handle,
+        })
+    }
+
+    /// The addresses clients can connect to. A listen address with port 0
+    /// shows up here with the port that was picked for it.
+    pub fn listen_addrs(&self) -> Vec<Address> {
+        self.driver.listen_addrs()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.driver.metrics()
+    }
+
+    /// Calls `observer` whenever a connection is opened or closed.
+    pub fn add_observer(&mut self, observer: Box<dyn ConnectionObserver>) {
+        self.driver.add_observer(observer);
+    }
+
+    /// Stops accepting clients and lets `run` return once open connections
+    /// have closed.
+    pub fn begin_drain(&mut self) {
+        self.driver.begin_drain(&mut self.poll);
+    }
+
+    /// A handle that makes `run` return, which can be sent to and used
+    /// from other threads.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// A handle for looking at open connections while `run` is going,
+    /// which can be sent to and used from other threads.
+    pub fn inspect_handle(&self) -> InspectHandle {
+        self.inspect.clone()
+    }
+
+    /// Runs the event loop until a drain, started by SIGTERM, finishes or
+    /// a shutdown handle is used. Fails if the poller does.
+    pub fn run(&mut self) -> IOResult<()> {
+        let mut events = Events::with_capacity(self.events_capacity);
+
+        self.driver.run(&mut self.poll, &mut events)
+    }
+}
+
+impl LoadBalancerBuilder {
+    pub fn new() -> LoadBalancerBuilder {
+        Default::default()
+    }
+
+    /// Adds an address to accept clients on, in the same `host:port` or
+    /// `unix:<path>` form as the config file.
+    pub fn listen(mut self, addr: &str) -> LoadBalancerBuilder {
+        self.listen_addrs.push(addr.to_owned());
+        self
+    }
+
+    /// Adds a local address to accept clients on. Only clients connecting
+    /// to that IP are accepted, unless it is the unspecified address, and
+    /// port 0 has the system pick a free port.
+    pub fn listen_addr(self, addr: SocketAddr) -> LoadBalancerBuilder {
+        self.listen(&addr.to_string())
+    }
+
+    /// Adds a target for clients to be relayed to.
+    pub fn backend(mut self, addr: &str) -> LoadBalancerBuilder {
+        self.backend_addrs.push(addr.to_owned());
+        self
+    }
+
+    /// Picks the target for each client. Defaults to round robin.
+    pub fn strategy(mut self, selector: Box<dyn BackendSelector>) -> LoadBalancerBuilder {
+        self.selector = Some(selector);
+        self
+    }
+
+    /// Bytes buffered in each direction of a connection.
+    pub fn buffer_size(mut self, buffer_size: usize) -> LoadBalancerBuilder {
+        self.buffer_size = Some(buffer_size);
+        self
+    }
+
+    /// How many clients can be connected at once. Clients beyond this are
+    /// accepted and closed right away.
+    pub fn max_connections(mut self, max_connections: usize) -> LoadBalancerBuilder {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Whether listeners set `SO_REUSEADDR`, which lets a restart bind
+    /// right away. On by default.
+    pub fn reuse_addr(mut self, reuse_addr: bool) -> LoadBalancerBuilder {
+        self.reuse_addr = Some(reuse_addr);
+        self
+    }
+
+    /// How many not yet accepted clients the kernel keeps queued for each
+    /// listener. Defaults to 1024.
+    pub fn backlog(mut self, backlog: i32) -> LoadBalancerBuilder {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// How many readiness events one turn of the event loop takes from the
+    /// poller, 4096 by default. Sockets past that wait for the next turn,
+    /// so a busy load balancer with too few relays less per turn and
+    /// spends more of its time polling. More of them cost some memory and
+    /// make a turn longer, which delays timeouts and health checks
+    /// handled between turns.
+    pub fn events_capacity(mut self, events_capacity: usize) -> LoadBalancerBuilder {
+        self.events_capacity = Some(events_capacity);
+        self
+    }
+
+    /// The longest the event loop waits for sockets before going round
+    /// again. It always wakes up for its own deadlines, which fall on
+    /// 10 ms ticks, so this is only a ceiling: a short one keeps a loop
+    /// with nothing to do spinning for nothing, while a zero one polls
+    /// without ever sleeping, for the lowest latency at the cost of a
+    /// whole CPU. Unlimited by default.
+    pub fn poll_timeout(mut self, timeout: Duration) -> LoadBalancerBuilder {
+        self.poll_timeout = Some(timeout);
+        self
+    }
+
+    /// Calls `observer` whenever a connection is opened or closed.
+    pub fn observer(mut self, observer: Box<dyn ConnectionObserver>) -> LoadBalancerBuilder {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Binds the listeners. Fails if no listen address or backend was
+    /// given, if a listen address is not an IP address and port, or if an
+    /// address can't be resolved or bound.
+    pub fn build(self) -> IOResult<LoadBalancer> {
+        LoadBalancer::from_builder(self)
+    }
+
+    fn config(&self) -> IOResult<RootConfig> {
+        if self.listen_addrs.is_empty() {
+            return Err(IOError::new(ErrorKind::InvalidInput, "No listen address given"));
+        }
+        if self.backend_addrs.is_empty() {
+            return Err(IOError::new(ErrorKind::InvalidInput, "No backend given"));
+        }
+        if self.events_capacity == Some(0) {
+            return Err(IOError::new(ErrorKind::InvalidInput, "Events capacity must not be zero"));
+        }
+
+        let frontends = self.listen_addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                     (format!("listener{}", i),
+                      FrontendConfig {
+                          listen_addr: addr.clone(),
+                          backend: BACKEND_NAME.to_owned(),
+                          reuse_addr: self.reuse_addr,
+                          backlog: self.backlog,
+                          ..Default::default()
+                      })
+                 })
+            .collect();
+
+        let mut backends = HashMap::new();
+        backends.insert(BACKEND_NAME.to_owned(),
+                        BackendConfig {
+                            target_addrs: self.backend_addrs.clone(),
+                            ..Default::default()
+                        });
+
+        let mut config = RootConfig {
+            frontends: frontends,
+            backends: backends,
+            ..Default::default()
+        };
+
+        config.buffers.endpoint_size = self.buffer_size;
+
+        if let Some(max_connections) = self.max_connections {
+            // Leave room in the slab for every allowed client.
+            config.buffers.connections = config.buffers.connections.max(max_connections);
+            config.limits = Some(LimitConfig {
+                                     max_connections: Some(max_connections),
+                                     ..Default::default()
+                                 });
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{LoadBalancer, LoadBalancerBuilder};
+
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    use config::RootConfig;
+    use selector::BackendSelector;
+    use backend::Backend;
+    use metrics::Metrics;
+    use observer::{ConnectionObserver, ConnectionStats};
+    use stream::Address;
+
+    /// A load balancer running on a thread of its own, for tests that
+    /// relay through it end to end. The thread is left running when the
+    /// test is over.
+    pub struct TestBalancer {
+        pub addr: SocketAddr,
+        pub metrics: Arc<Metrics>,
+    }
+
+    impl TestBalancer {
+        pub fn start<F>(build: F) -> TestBalancer
+            where F: FnOnce() -> LoadBalancer + Send + 'static
+        {
+            let (ready_tx, ready_rx) = mpsc::channel();
+
+            // Nothing in the load balancer can be sent between threads, so
+            // it is built on the one it runs on.
+            thread::spawn(move || {
+                let mut load_balancer = build();
+                ready_tx.send((load_balancer.listen_addrs(), load_balancer.metrics())).unwrap();
+                load_balancer.run().unwrap();
+            });
+
+            let (listen_addrs, metrics) = ready_rx.recv().unwrap();
+            let addr = match listen_addrs[0] {
+                Address::Tcp(addr) => addr,
+                ref addr => panic!("Unexpected listener {}", addr),
+            };
+
+            TestBalancer {
+                addr: addr,
+                metrics: metrics,
+            }
+        }
+
+        pub fn from_builder<F>(configure: F) -> TestBalancer
+            where F: FnOnce(LoadBalancerBuilder) -> LoadBalancerBuilder + Send + 'static
+        {
+            TestBalancer::start(move || configure(LoadBalancerBuilder::new()).build().unwrap())
+        }
+
+        /// Runs a load balancer set up by a config file. Its first frontend
+        /// is the one clients connect to.
+        pub fn from_config(config: &str) -> TestBalancer {
+            let config = config.to_owned();
+
+            TestBalancer::start(move || {
+                LoadBalancer::from_config(&RootConfig::from_str(&config).unwrap()).unwrap()
+            })
+        }
+
+        pub fn connect(&self) -> TcpStream {
+            let client = TcpStream::connect(self.addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            client
+        }
+    }
+
+    /// Starts a backend that echoes back whatever each client sends.
+    pub fn echo_backend() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+
+                thread::spawn(move || {
+                    let mut buffer = [0; 1024];
+                    loop {
+                        match stream.read(&mut buffer) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n_read) => {
+                                if stream.write_all(&buffer[..n_read]).is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// Sends `data` and checks the same bytes come back.
+    pub fn assert_echoes(client: &mut TcpStream, data: &[u8]) {
+        client.write_all(data).unwrap();
+
+        let mut echoed = vec![0; data.len()];
+        client.read_exact(&mut echoed).unwrap();
+        assert!(echoed == data, "Echoed bytes differ from the ones sent");
+    }
+
+    /// Always picks the last target.
+    struct LastBackend;
+
+    impl BackendSelector for LastBackend {
+        fn select(&mut self, backends: &[&Backend], _: &SocketAddr) -> usize {
+            backends.len() - 1
+        }
+    }
+
+    #[test]
+    fn build_requires_listen_address_and_backend() {
+        let err = LoadBalancerBuilder::new().backend("127.0.0.1:1").build().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let err = LoadBalancerBuilder::new().listen("127.0.0.1:0").build().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn binds_the_given_local_address() {
+        let load_balancer = LoadBalancerBuilder::new()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .backend("127.0.0.1:1")
+            .build()
+            .unwrap();
+
+        let listen_addrs = load_balancer.listen_addrs();
+        assert_eq!(listen_addrs.len(), 1);
+        let addr = match listen_addrs[0] {
+            Address::Tcp(addr) => addr,
+            ref addr => panic!("Unexpected listener {}", addr),
+        };
+        assert_eq!(addr.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert!(addr.port() != 0);
+        TcpStream::connect(addr).unwrap();
+
+        let err = LoadBalancerBuilder::new()
+            .listen_addr(addr)
+            .backend("127.0.0.1:1")
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert!(err.to_string().starts_with(&format!("Could not listen on {}: ", addr)));
+    }
+
+    #[test]
+    fn rejects_listen_addresses_that_are_not_ip_addresses() {
+        for listen_addr in &["localhost:8080", "8080", "127.0.0.1"] {
+            let err = LoadBalancerBuilder::new()
+                .listen(listen_addr)
+                .backend("127.0.0.1:1")
+                .build()
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn built_load_balancer_relays_with_chosen_strategy() {
+        let unused = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unused_addr = unused.local_addr().unwrap();
+        let backend_addr = echo_backend();
+
+        let balancer = TestBalancer::from_builder(move |builder| {
+            builder.listen("127.0.0.1:0")
+                .backend(&unused_addr.to_string())
+                .backend(&backend_addr.to_string())
+                .strategy(Box::new(LastBackend))
+                .buffer_size(64)
+                .max_connections(8)
+                .backlog(64)
+        });
+
+        let mut client = balancer.connect();
+        assert_echoes(&mut client, b"hello through the builder");
+        assert_eq!(balancer.metrics.snapshot().connections_active, 1);
+    }
+
+    #[test]
+    fn relays_with_one_event_per_turn_and_a_short_poll_timeout() {
+        let err = LoadBalancerBuilder::new()
+            .listen("127.0.0.1:0")
+            .backend("127.0.0.1:1")
+            .events_capacity(0)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let backend_addr = echo_backend();
+        let balancer = TestBalancer::from_builder(move |builder| {
+            builder.listen("127.0.0.1:0")
+                .backend(&backend_addr.to_string())
+                .events_capacity(1)
+                .poll_timeout(Duration::from_millis(1))
+        });
+
+        let mut clients = (0..3).map(|_| balancer.connect()).collect::<Vec<_>>();
+        for client in clients.iter_mut() {
+            assert_echoes(client, b"one event at a time");
+        }
+    }
+
+    #[test]
+    fn shutdown_handle_makes_run_return() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        let running = thread::spawn(move || {
+            let mut load_balancer = LoadBalancerBuilder::new()
+                .listen("127.0.0.1:0")
+                .backend(&backend_addr.to_string())
+                .build()
+                .unwrap();
+            ready_tx.send((load_balancer.listen_addrs(), load_balancer.shutdown_handle())).unwrap();
+            load_balancer.run()
+        });
+        let (listen_addrs, shutdown) = ready_rx.recv().unwrap();
+        let addr = match listen_addrs[0] {
+            Address::Tcp(addr) => addr,
+            ref addr => panic!("Unexpected listener {}", addr),
+        };
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let _relayed = backend.accept().unwrap();
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+
+        let mut buffer = [0; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn inspect_handle_lists_open_connections_from_another_thread() {
+        let backend_addr = echo_backend();
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut load_balancer = LoadBalancerBuilder::new()
+                .listen("127.0.0.1:0")
+                .backend(&backend_addr.to_string())
+                .build()
+                .unwrap();
+            ready_tx.send((load_balancer.listen_addrs(), load_balancer.inspect_handle())).unwrap();
+            load_balancer.run().unwrap();
+        });
+        let (listen_addrs, inspect) = ready_rx.recv().unwrap();
+        let addr = match listen_addrs[0] {
+            Address::Tcp(addr) => addr,
+            ref addr => panic!("Unexpected listener {}", addr),
+        };
+
+        let mut clients = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect::<Vec<_>>();
+        for client in clients.iter_mut() {
+            client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            assert_echoes(client, b"hello");
+        }
+
+        // Asked twice, to see the loop is woken for each.
+        for _ in 0..2 {
+            let (infos_tx, infos_rx) = mpsc::channel();
+            assert!(inspect.inspect(move |connections| {
+                                        infos_tx.send(connections.collect::<Vec<_>>()).unwrap();
+                                    }));
+            let infos = infos_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+
+            assert_eq!(infos.len(), 2);
+            assert!(infos[0].id < infos[1].id);
+            for (info, client) in infos.iter().zip(&clients) {
+                assert_eq!(info.client, client.local_addr().unwrap());
+                assert_eq!(info.backend, Address::Tcp(backend_addr));
+                assert_eq!((info.bytes_up, info.bytes_down, info.buffered), (5, 5, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn relays_a_few_kilobytes_both_ways() {
+        let balancer = TestBalancer::from_config(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:0\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"{}\"]
+
+[buffers]
+connections = 16
+listeners = 4
+endpoint_size = 1024
+",
+                                                          echo_backend()));
+
+        let data = (0..8 * 1024).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        let mut client = balancer.connect();
+        assert_echoes(&mut client, &data);
+        assert_echoes(&mut client, b"and a little more");
+    }
+
+    #[test]
+    fn relays_concurrent_clients_separately() {
+        let backend_addr = echo_backend();
+        let balancer = TestBalancer::from_builder(move |builder| {
+            builder.listen("127.0.0.1:0").backend(&backend_addr.to_string())
+        });
+
+        let mut clients = (0..4).map(|_| balancer.connect()).collect::<Vec<_>>();
+        for round in 0..3 {
+            for (i, client) in clients.iter_mut().enumerate() {
+                assert_echoes(client, format!("client {} round {}", i, round).as_bytes());
+            }
+        }
+        assert_eq!(balancer.metrics.snapshot().connections_active, 4);
+    }
+
+    enum Observed {
+        Accept(SocketAddr, Address, usize),
+        Close(usize, ConnectionStats),
+    }
+
+    struct ChannelObserver(mpsc::Sender<Observed>);
+
+    impl ConnectionObserver for ChannelObserver {
+        fn on_accept(&mut self, client_addr: &SocketAddr, backend_addr: &Address, conn_id: usize) {
+            self.0.send(Observed::Accept(*client_addr, backend_addr.clone(), conn_id)).unwrap();
+        }
+
+        fn on_close(&mut self, conn_id: usize, stats: &ConnectionStats) {
+            self.0.send(Observed::Close(conn_id, stats.clone())).unwrap();
+        }
+    }
+
+    #[test]
+    fn observers_hear_about_accept_and_close() {
+        let backend_addr = echo_backend();
+        let (observed_tx, observed_rx) = mpsc::channel();
+        let balancer = TestBalancer::from_builder(move |builder| {
+            builder
+                .listen("127.0.0.1:0")
+                .backend(&backend_addr.to_string())
+                .observer(Box::new(ChannelObserver(observed_tx)))
+        });
+
+        let mut client = balancer.connect();
+        assert_echoes(&mut client, b"observed");
+        let client_addr = client.local_addr().unwrap();
+        drop(client);
+
+        let timeout = Duration::from_secs(2);
+        let accepted_id = match observed_rx.recv_timeout(timeout).unwrap() {
+            Observed::Accept(addr, backend, conn_id) => {
+                assert_eq!(addr, client_addr);
+                assert_eq!(backend, Address::Tcp(backend_addr));
+                conn_id
+            }
+            Observed::Close(..) => panic!("Closed before being accepted"),
+        };
+        match observed_rx.recv_timeout(timeout).unwrap() {
+            Observed::Close(conn_id, stats) => {
+                assert_eq!(conn_id, accepted_id);
+                assert_eq!(stats.backend_addr, Address::Tcp(backend_addr));
+                assert_eq!((stats.bytes_up, stats.bytes_down), (8, 8));
+            }
+            Observed::Accept(..) => panic!("Accepted twice"),
+        }
+    }
+}
@@ -1,30 +1,17 @@
-#![cfg_attr(feature="dev", allow(unstable_features))]
-#![cfg_attr(feature="dev", feature(plugin))]
-#![cfg_attr(featrue="dev", plugin(clippy))]
-
 extern crate clap;
-extern crate mio;
-extern crate slab;
-extern crate toml;
-extern crate rustc_serialize;
+extern crate loadbalancer;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
-mod config;
-mod connection;
-mod frontend;
-mod backend;
-mod driver_state;
-mod driver;
+use std::process;
 
 use clap::{Arg, App};
-use mio::{Events, Poll};
 
-use config::RootConfig;
-use driver_state::DriverState;
-use driver::Driver;
+use loadbalancer::LoadBalancer;
+use loadbalancer::config::RootConfig;
+use loadbalancer::signal;
 
 fn main() {
     env_logger::init().unwrap();
@@ -45,19 +32,20 @@ fn main() {
         .value_of("CONFIG")
         .expect("Config parameter must be set");
 
-    let mut poll = Poll::new().expect("Failed to create Poll");
     let config = RootConfig::read_config(&config_path).unwrap();
 
     debug!("Using config: {:#?}", config);
 
-    let mut events = Events::with_capacity(4096);
-
-    let mut driver_state = DriverState::new(&config.buffers);
-    driver_state.reconfigure(&mut poll, &config).unwrap();
+    let mut load_balancer = LoadBalancer::from_config(&config).unwrap();
 
-    let mut driver = Driver::new(driver_state);
+    signal::install_handlers().expect("Failed to install signal handlers");
 
     info!("Starting event loop");
 
-    driver.run(&mut poll, &mut events);
+    if let Err(e) = load_balancer.run() {
+        error!("Event loop failed: {}", e);
+        process::exit(1);
+    }
+
+    info!("Shut down");
 }
@@ -0,0 +1,737 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use backend::BackendId;
+
+/// Process wide counters. Everything is atomic so a handle can be shared
+/// with a monitoring thread that reads it without involving the event loop.
+#[derive(Default)]
+pub struct Metrics {
+    connections_accepted: AtomicU64,
+    connections_active: AtomicU64,
+    max_active_connections: AtomicU64,
+    connections_rejected: AtomicU64,
+    connections_denied: AtomicU64,
+    connection_errors: AtomicU64,
+    connect_retries: AtomicU64,
+    bytes_upstream: AtomicU64,
+    bytes_downstream: AtomicU64,
+    buffered_bytes: AtomicU64,
+    throughput: AtomicU64,
+    bandwidth_cap: AtomicU64,
+    idle_timeouts: AtomicU64,
+    read_timeouts: AtomicU64,
+    write_timeouts: AtomicU64,
+    lifetime_timeouts: AtomicU64,
+    stall_timeouts: AtomicU64,
+    handshake_timeouts: AtomicU64,
+    accept_errors: AtomicU64,
+    connect_errors: AtomicU64,
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+    backends: Mutex<HashMap<BackendId, Arc<BackendMetrics>>>,
+    pools: Mutex<HashMap<String, Arc<PoolMetrics>>>,
+}
+
+/// Counters for a single target address. Connections hold on to their
+/// backend's handle, so the map is only locked when a connection is routed
+/// and when taking a snapshot.
+#[derive(Default)]
+pub struct BackendMetrics {
+    connections_active: AtomicU64,
+    bytes_upstream: AtomicU64,
+    bytes_downstream: AtomicU64,
+    connect_time_us: AtomicU64,
+    first_byte_time_us: AtomicU64,
+    partial_write_bursts: AtomicU64,
+}
+
+/// Counters for a backend pool as a whole, whichever of its targets the
+/// clients went to, so the pools a frontend splits clients between can be
+/// told apart.
+#[derive(Default)]
+pub struct PoolMetrics {
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    bytes_upstream: AtomicU64,
+    bytes_downstream: AtomicU64,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub connections_accepted: u64,
+    pub connections_active: u64,
+    /// The most connections that were active at once since the start.
+    pub max_active_connections: u64,
+    /// Connections closed right after accepting because a limit was hit.
+    pub connections_rejected: u64,
+    /// Connections closed right after accepting because a frontend's
+    /// `allow` or `deny` ranges turned the client away.
+    pub connections_denied: u64,
+    pub connection_errors: u64,
+    /// Clients sent on to another target after a connect failed.
+    pub connect_retries: u64,
+    /// Bytes relayed from clients to backends.
+    pub bytes_upstream: u64,
+    /// Bytes relayed from backends to clients.
+    pub bytes_downstream: u64,
+    /// Bytes read from one side of a connection and not yet written to the
+    /// other, across all connections.
+    pub buffered_bytes: u64,
+    /// Bytes per second relayed over the last second, kept track of while
+    /// a bandwidth cap is set.
+    pub throughput: u64,
+    /// The bytes per second relayed across all connections may not exceed
+    /// this, unless it is 0 for no cap.
+    pub bandwidth_cap: u64,
+    /// Connections closed because nothing moved either way for too long.
+    pub idle_timeouts: u64,
+    /// Connections closed because a side that could send sent nothing.
+    pub read_timeouts: u64,
+    /// Connections closed because a side stopped taking its bytes.
+    pub write_timeouts: u64,
+    /// Connections closed for having been open longer than allowed.
+    pub lifetime_timeouts: u64,
+    /// Connections closed because a peer stopped acknowledging bytes.
+    pub stall_timeouts: u64,
+    /// Clients closed for not getting through their opening handshake in
+    /// time: the request routed on, or a PROXY protocol header.
+    pub handshake_timeouts: u64,
+    /// Failed accepts on the proxy listeners.
+    pub accept_errors: u64,
+    /// Connects to targets that failed or timed out, including ones a
+    /// client was then sent on from.
+    pub connect_errors: u64,
+    /// Relayed sockets, on either side, that failed a read.
+    pub read_errors: u64,
+    /// Relayed sockets, on either side, that failed a write.
+    pub write_errors: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendMetricsSnapshot {
+    pub addr: BackendId,
+    pub connections_active: u64,
+    pub bytes_upstream: u64,
+    pub bytes_downstream: u64,
+    /// Moving average of the backend's connect times, in microseconds, or
+    /// 0 before the first connect has finished.
+    pub connect_time_us: u64,
+    /// Moving average of how long the backend takes to send its first
+    /// byte once connected, in microseconds, or 0 before it has sent one.
+    pub first_byte_time_us: u64,
+    /// Times a connection's writes to the backend kept leaving bytes
+    /// behind, many times over within a short while.
+    pub partial_write_bursts: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolMetricsSnapshot {
+    /// The pool's name under `[backends]`.
+    pub name: String,
+    /// Clients routed to the pool since the start.
+    pub connections_total: u64,
+    pub connections_active: u64,
+    pub bytes_upstream: u64,
+    pub bytes_downstream: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        let active = self.connections_active.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_active_connections.fetch_max(active, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_denied(&self) {
+        self.connections_denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_error(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_retried(&self) {
+        self.connect_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn relayed_upstream(&self, n_bytes: usize) {
+        self.bytes_upstream.fetch_add(n_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn relayed_downstream(&self, n_bytes: usize) {
+        self.bytes_downstream.fetch_add(n_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_buffered_bytes(&self, n_bytes: usize) {
+        self.buffered_bytes.store(n_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_throughput(&self, bytes_per_sec: u64, cap: u64) {
+        self.throughput.store(bytes_per_sec, Ordering::Relaxed);
+        self.bandwidth_cap.store(cap, Ordering::Relaxed);
+    }
+
+    pub fn idle_timed_out(&self) {
+        self.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn read_timed_out(&self) {
+        self.read_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn write_timed_out(&self) {
+        self.write_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn lifetime_ended(&self) {
+        self.lifetime_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stall_timed_out(&self) {
+        self.stall_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_timed_out(&self) {
+        self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn accept_failed(&self) {
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_failed(&self) {
+        self.connect_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn read_failed(&self) {
+        self.read_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn write_failed(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn backend(&self, addr: &BackendId) -> Arc<BackendMetrics> {
+        let mut backends = self.backends.lock().unwrap();
+
+        backends.entry(addr.clone()).or_default().clone()
+    }
+
+    /// Per-backend counters, ordered by address.
+    pub fn backend_snapshots(&self) -> Vec<BackendMetricsSnapshot> {
+        let backends = self.backends.lock().unwrap();
+        let mut snapshots = backends
+            .iter()
+            .map(|(addr, backend)| backend.snapshot(addr.clone()))
+            .collect::<Vec<_>>();
+
+        snapshots.sort_by(|a, b| a.addr.cmp(&b.addr));
+        snapshots
+    }
+
+    pub fn pool(&self, name: &str) -> Arc<PoolMetrics> {
+        let mut pools = self.pools.lock().unwrap();
+
+        pools.entry(name.to_owned()).or_default().clone()
+    }
+
+    /// Per-pool counters, ordered by name.
+    pub fn pool_snapshots(&self) -> Vec<PoolMetricsSnapshot> {
+        let pools = self.pools.lock().unwrap();
+        let mut snapshots = pools
+            .iter()
+            .map(|(name, pool)| pool.snapshot(name.clone()))
+            .collect::<Vec<_>>();
+
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+        snapshots
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connections_accepted: self.connections_accepted.load(Ordering::Relaxed),
+            connections_active: self.connections_active.load(Ordering::Relaxed),
+            max_active_connections: self.max_active_connections.load(Ordering::Relaxed),
+            connections_rejected: self.connections_rejected.load(Ordering::Relaxed),
+            connections_denied: self.connections_denied.load(Ordering::Relaxed),
+            connection_errors: self.connection_errors.load(Ordering::Relaxed),
+            connect_retries: self.connect_retries.load(Ordering::Relaxed),
+            bytes_upstream: self.bytes_upstream.load(Ordering::Relaxed),
+            bytes_downstream: self.bytes_downstream.load(Ordering::Relaxed),
+            buffered_bytes: self.buffered_bytes.load(Ordering::Relaxed),
+            throughput: self.throughput.load(Ordering::Relaxed),
+            bandwidth_cap: self.bandwidth_cap.load(Ordering::Relaxed),
+            idle_timeouts: self.idle_timeouts.load(Ordering::Relaxed),
+            read_timeouts: self.read_timeouts.load(Ordering::Relaxed),
+            write_timeouts: self.write_timeouts.load(Ordering::Relaxed),
+            lifetime_timeouts: self.lifetime_timeouts.load(Ordering::Relaxed),
+            stall_timeouts: self.stall_timeouts.load(Ordering::Relaxed),
+            handshake_timeouts: self.handshake_timeouts.load(Ordering::Relaxed),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            connect_errors: self.connect_errors.load(Ordering::Relaxed),
+            read_errors: self.read_errors.load(Ordering::Relaxed),
+            write_errors: self.write_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl BackendMetrics {
+    pub fn connection_opened(&self) {
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn relayed_upstream(&self, n_bytes: usize) {
+        self.bytes_upstream.fetch_add(n_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn relayed_downstream(&self, n_bytes: usize) {
+        self.bytes_downstream.fetch_add(n_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_connect_time(&self, average: Duration) {
+        self.connect_time_us.store(as_micros(average), Ordering::Relaxed);
+    }
+
+    pub fn set_first_byte_time(&self, average: Duration) {
+        self.first_byte_time_us.store(as_micros(average), Ordering::Relaxed);
+    }
+
+    pub fn partial_write_burst(&self) {
+        self.partial_write_bursts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, addr: BackendId) -> BackendMetricsSnapshot {
+        BackendMetricsSnapshot {
+            addr: addr,
+            connections_active: self.connections_active.load(Ordering::Relaxed),
+            bytes_upstream: self.bytes_upstream.load(Ordering::Relaxed),
+            bytes_downstream: self.bytes_downstream.load(Ordering::Relaxed),
+            connect_time_us: self.connect_time_us.load(Ordering::Relaxed),
+            first_byte_time_us: self.first_byte_time_us.load(Ordering::Relaxed),
+            partial_write_bursts: self.partial_write_bursts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl PoolMetrics {
+    pub fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn relayed_upstream(&self, n_bytes: usize) {
+        self.bytes_upstream.fetch_add(n_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn relayed_downstream(&self, n_bytes: usize) {
+        self.bytes_downstream.fetch_add(n_bytes as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, name: String) -> PoolMetricsSnapshot {
+        PoolMetricsSnapshot {
+            name: name,
+            connections_total: self.connections_total.load(Ordering::Relaxed),
+            connections_active: self.connections_active.load(Ordering::Relaxed),
+            bytes_upstream: self.bytes_upstream.load(Ordering::Relaxed),
+            bytes_downstream: self.bytes_downstream.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn render_prometheus(&self,
+                             backends: &[BackendMetricsSnapshot],
+                             pools: &[PoolMetricsSnapshot])
+                             -> String {
+        let mut out = String::new();
+        let unlabeled = |value| vec![(String::new(), value)];
+
+        write_metric(&mut out,
+                     "lb_connections_total",
+                     "counter",
+                     "Connections accepted from clients.",
+                     &unlabeled(self.connections_accepted));
+        write_metric(&mut out,
+                     "lb_connections_active",
+                     "gauge",
+                     "Connections currently being relayed.",
+                     &unlabeled(self.connections_active));
+        write_metric(&mut out,
+                     "lb_max_active_connections",
+                     "gauge",
+                     "The most connections relayed at once since the start.",
+                     &unlabeled(self.max_active_connections));
+        write_metric(&mut out,
+                     "lb_connections_rejected_total",
+                     "counter",
+                     "Connections closed on accept because a limit was reached.",
+                     &unlabeled(self.connections_rejected));
+        write_metric(&mut out,
+                     "lb_connections_denied_total",
+                     "counter",
+                     "Connections closed on accept because the client address is not allowed.",
+                     &unlabeled(self.connections_denied));
+        write_metric(&mut out,
+                     "lb_connection_errors_total",
+                     "counter",
+                     "Connections that failed or ended with a socket error.",
+                     &unlabeled(self.connection_errors));
+        write_metric(&mut out,
+                     "lb_connect_retries_total",
+                     "counter",
+                     "Clients sent to another backend after a connect failed.",
+                     &unlabeled(self.connect_retries));
+        write_metric(&mut out,
+                     "lb_bytes_total",
+                     "counter",
+                     "Bytes relayed between clients and backends.",
+                     &[("direction=\"upstream\"".to_owned(), self.bytes_upstream),
+                       ("direction=\"downstream\"".to_owned(), self.bytes_downstream)]);
+        write_metric(&mut out,
+                     "lb_buffered_bytes",
+                     "gauge",
+                     "Bytes held in connection buffers waiting to be relayed.",
+                     &unlabeled(self.buffered_bytes));
+        write_metric(&mut out,
+                     "lb_throughput_bytes_per_second",
+                     "gauge",
+                     "Bytes relayed over the last second, while a bandwidth cap is set.",
+                     &unlabeled(self.throughput));
+        write_metric(&mut out,
+                     "lb_bandwidth_cap_bytes_per_second",
+                     "gauge",
+                     "The cap on bytes relayed per second, or 0 for none.",
+                     &unlabeled(self.bandwidth_cap));
+        write_metric(&mut out,
+                     "lb_timeouts_total",
+                     "counter",
+                     "Connections closed by a timeout, by the kind of timeout.",
+                     &[("kind=\"idle\"".to_owned(), self.idle_timeouts),
+                       ("kind=\"read\"".to_owned(), self.read_timeouts),
+                       ("kind=\"write\"".to_owned(), self.write_timeouts),
+                       ("kind=\"lifetime\"".to_owned(), self.lifetime_timeouts),
+                       ("kind=\"stall\"".to_owned(), self.stall_timeouts),
+                       ("kind=\"handshake\"".to_owned(), self.handshake_timeouts)]);
+        write_metric(&mut out,
+                     "lb_errors_total",
+                     "counter",
+                     "Socket errors, by where they happened.",
+                     &[("kind=\"accept\"".to_owned(), self.accept_errors),
+                       ("kind=\"connect\"".to_owned(), self.connect_errors),
+                       ("kind=\"read\"".to_owned(), self.read_errors),
+                       ("kind=\"write\"".to_owned(), self.write_errors)]);
+
+        let mut backend_connections = Vec::new();
+        let mut backend_bytes = Vec::new();
+        let mut backend_connect_times = Vec::new();
+        let mut backend_first_byte_times = Vec::new();
+        let mut backend_partial_write_bursts = Vec::new();
+
+        for backend in backends {
+            let label = format!("backend=\"{}\"", backend.addr);
+
+            backend_connections.push((label.clone(), backend.connections_active));
+            backend_bytes.push((format!("{},direction=\"upstream\"", label),
+                                backend.bytes_upstream));
+            backend_bytes.push((format!("{},direction=\"downstream\"", label),
+                                backend.bytes_downstream));
+            backend_connect_times.push((label.clone(), backend.connect_time_us));
+            backend_first_byte_times.push((label.clone(), backend.first_byte_time_us));
+            backend_partial_write_bursts.push((label, backend.partial_write_bursts));
+        }
+
+        write_metric(&mut out,
+                     "lb_backend_connections_active",
+                     "gauge",
+                     "Connections currently routed to each backend.",
+                     &backend_connections);
+        write_metric(&mut out,
+                     "lb_backend_bytes_total",
+                     "counter",
+                     "Bytes relayed to and from each backend.",
+                     &backend_bytes);
+        write_metric(&mut out,
+                     "lb_backend_connect_time_microseconds",
+                     "gauge",
+                     "Moving average of connect times to each backend.",
+                     &backend_connect_times);
+        write_metric(&mut out,
+                     "lb_backend_first_byte_time_microseconds",
+                     "gauge",
+                     "Moving average of the wait for each backend's first byte once connected.",
+                     &backend_first_byte_times);
+        write_metric(&mut out,
+                     "lb_backend_partial_write_bursts_total",
+                     "counter",
+                     "Bursts of writes to each backend that left bytes behind.",
+                     &backend_partial_write_bursts);
+
+        let mut pool_connections_total = Vec::new();
+        let mut pool_connections = Vec::new();
+        let mut pool_bytes = Vec::new();
+
+        for pool in pools {
+            let label = format!("pool=\"{}\"", pool.name);
+
+            pool_connections_total.push((label.clone(), pool.connections_total));
+            pool_connections.push((label.clone(), pool.connections_active));
+            pool_bytes.push((format!("{},direction=\"upstream\"", label), pool.bytes_upstream));
+            pool_bytes.push((format!("{},direction=\"downstream\"", label),
+                             pool.bytes_downstream));
+        }
+
+        write_metric(&mut out,
+                     "lb_pool_connections_total",
+                     "counter",
+                     "Clients routed to each backend pool.",
+                     &pool_connections_total);
+        write_metric(&mut out,
+                     "lb_pool_connections_active",
+                     "gauge",
+                     "Connections currently routed to each backend pool.",
+                     &pool_connections);
+        write_metric(&mut out,
+                     "lb_pool_bytes_total",
+                     "counter",
+                     "Bytes relayed to and from each backend pool.",
+                     &pool_bytes);
+        out
+    }
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64
+}
+
+fn write_metric(out: &mut String,
+                name: &str,
+                kind: &str,
+                help: &str,
+                samples: &[(String, u64)]) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+    for &(ref labels, value) in samples {
+        if labels.is_empty() {
+            writeln!(out, "{} {}", name, value).unwrap();
+        } else {
+            writeln!(out, "{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Metrics, MetricsSnapshot, BackendMetricsSnapshot, PoolMetricsSnapshot};
+
+    use std::time::Duration;
+
+    use stream::Address;
+
+    #[test]
+    fn snapshot_reflects_counters() {
+        let metrics = Metrics::new();
+
+        metrics.connection_accepted();
+        metrics.connection_accepted();
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        metrics.connection_rejected();
+        metrics.connection_denied();
+        metrics.connection_denied();
+        metrics.connection_error();
+        metrics.connect_retried();
+        metrics.relayed_upstream(10);
+        metrics.relayed_downstream(32);
+        metrics.relayed_downstream(8);
+        metrics.set_buffered_bytes(100);
+        metrics.set_buffered_bytes(64);
+        metrics.set_throughput(900, 1000);
+        metrics.idle_timed_out();
+        metrics.read_timed_out();
+        metrics.read_timed_out();
+        metrics.write_timed_out();
+        metrics.lifetime_ended();
+        metrics.stall_timed_out();
+        metrics.handshake_timed_out();
+        metrics.handshake_timed_out();
+        metrics.accept_failed();
+        metrics.connect_failed();
+        metrics.connect_failed();
+        metrics.read_failed();
+        metrics.write_failed();
+        metrics.write_failed();
+        metrics.write_failed();
+
+        assert_eq!(metrics.snapshot(),
+                   MetricsSnapshot {
+                       connections_accepted: 2,
+                       connections_active: 1,
+                       max_active_connections: 2,
+                       connections_rejected: 1,
+                       connections_denied: 2,
+                       connection_errors: 1,
+                       connect_retries: 1,
+                       bytes_upstream: 10,
+                       bytes_downstream: 40,
+                       buffered_bytes: 64,
+                       throughput: 900,
+                       bandwidth_cap: 1000,
+                       idle_timeouts: 1,
+                       read_timeouts: 2,
+                       write_timeouts: 1,
+                       lifetime_timeouts: 1,
+                       stall_timeouts: 1,
+                       handshake_timeouts: 2,
+                       accept_errors: 1,
+                       connect_errors: 2,
+                       read_errors: 1,
+                       write_errors: 3,
+                   });
+    }
+
+    #[test]
+    fn renders_prometheus_text_format() {
+        let snapshot = MetricsSnapshot {
+            connections_accepted: 3,
+            connections_active: 1,
+            max_active_connections: 6,
+            connections_rejected: 4,
+            connections_denied: 5,
+            connection_errors: 0,
+            connect_retries: 2,
+            bytes_upstream: 100,
+            bytes_downstream: 2048,
+            buffered_bytes: 512,
+            throughput: 4096,
+            bandwidth_cap: 8192,
+            idle_timeouts: 0,
+            read_timeouts: 5,
+            write_timeouts: 1,
+            lifetime_timeouts: 6,
+            stall_timeouts: 8,
+            handshake_timeouts: 9,
+            accept_errors: 0,
+            connect_errors: 7,
+            read_errors: 3,
+            write_errors: 0,
+        };
+        let backends = [BackendMetricsSnapshot {
+                            addr: Address::resolve("127.0.0.1:8000").unwrap(),
+                            connections_active: 2,
+                            bytes_upstream: 60,
+                            bytes_downstream: 2000,
+                            connect_time_us: 350,
+                            first_byte_time_us: 1200,
+                            partial_write_bursts: 4,
+                        }];
+        let pools = [PoolMetricsSnapshot {
+                         name: "canary".to_owned(),
+                         connections_total: 9,
+                         connections_active: 2,
+                         bytes_upstream: 60,
+                         bytes_downstream: 2000,
+                     }];
+        let text = snapshot.render_prometheus(&backends, &pools);
+
+        assert!(text.contains("# TYPE lb_connections_total counter\nlb_connections_total 3\n"));
+        assert!(text.contains("# TYPE lb_connections_active gauge\nlb_connections_active 1\n"));
+        assert!(text.contains("# TYPE lb_max_active_connections gauge\n\
+                               lb_max_active_connections 6\n"));
+        assert!(text.contains("lb_connections_rejected_total 4\n"));
+        assert!(text.contains("lb_connections_denied_total 5\n"));
+        assert!(text.contains("lb_connect_retries_total 2\n"));
+        assert!(text.contains("lb_bytes_total{direction=\"upstream\"} 100\n"));
+        assert!(text.contains("lb_bytes_total{direction=\"downstream\"} 2048\n"));
+        assert!(text.contains("# TYPE lb_buffered_bytes gauge\nlb_buffered_bytes 512\n"));
+        assert!(text.contains("lb_throughput_bytes_per_second 4096\n"));
+        assert!(text.contains("lb_bandwidth_cap_bytes_per_second 8192\n"));
+        assert!(text.contains("lb_timeouts_total{kind=\"idle\"} 0\n"));
+        assert!(text.contains("lb_timeouts_total{kind=\"read\"} 5\n"));
+        assert!(text.contains("lb_timeouts_total{kind=\"write\"} 1\n"));
+        assert!(text.contains("lb_timeouts_total{kind=\"lifetime\"} 6\n"));
+        assert!(text.contains("lb_timeouts_total{kind=\"stall\"} 8\n"));
+        assert!(text.contains("lb_timeouts_total{kind=\"handshake\"} 9\n"));
+        assert!(text.contains("lb_errors_total{kind=\"accept\"} 0\n"));
+        assert!(text.contains("lb_errors_total{kind=\"connect\"} 7\n"));
+        assert!(text.contains("lb_errors_total{kind=\"read\"} 3\n"));
+        assert!(text.contains("lb_backend_connections_active{backend=\"127.0.0.1:8000\"} 2\n"));
+        assert!(text.contains("lb_backend_bytes_total{backend=\"127.0.0.1:8000\",\
+                               direction=\"downstream\"} 2000\n"));
+        assert!(text.contains("lb_backend_connect_time_microseconds{backend=\"127.0.0.1:8000\"} \
+                               350\n"));
+        assert!(text.contains("lb_backend_first_byte_time_microseconds{backend=\"127.0.0.1:8000\"} \
+                               1200\n"));
+        assert!(text.contains("lb_backend_partial_write_bursts_total{backend=\"127.0.0.1:8000\"} \
+                               4\n"));
+        assert!(text.contains("# TYPE lb_pool_connections_total counter\n\
+                               lb_pool_connections_total{pool=\"canary\"} 9\n"));
+        assert!(text.contains("lb_pool_connections_active{pool=\"canary\"} 2\n"));
+        assert!(text.contains("lb_pool_bytes_total{pool=\"canary\",direction=\"upstream\"} 60\n"));
+    }
+
+    #[test]
+    fn backend_counters_are_keyed_by_address() {
+        let metrics = Metrics::new();
+        let first = Address::resolve("127.0.0.1:8001").unwrap();
+        let second = Address::resolve("127.0.0.1:8000").unwrap();
+
+        metrics.backend(&first).connection_opened();
+        metrics.backend(&first).relayed_upstream(5);
+        metrics.backend(&second).connection_opened();
+        metrics.backend(&second).connection_closed();
+        metrics.backend(&second).relayed_downstream(7);
+        metrics.backend(&first).set_connect_time(Duration::from_millis(3));
+        metrics.backend(&second).partial_write_burst();
+
+        assert_eq!(metrics.backend_snapshots(),
+                   vec![BackendMetricsSnapshot {
+                            addr: second,
+                            connections_active: 0,
+                            bytes_upstream: 0,
+                            bytes_downstream: 7,
+                            connect_time_us: 0,
+                            first_byte_time_us: 0,
+                            partial_write_bursts: 1,
+                        },
+                        BackendMetricsSnapshot {
+                            addr: first,
+                            connections_active: 1,
+                            bytes_upstream: 5,
+                            bytes_downstream: 0,
+                            connect_time_us: 3000,
+                            first_byte_time_us: 0,
+                            partial_write_bursts: 0,
+                        }]);
+    }
+}
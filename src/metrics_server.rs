@@ -0,0 +1,184 @@
+use std::io::prelude::*;
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::time::{Duration, Instant};
+
+use mio::Ready;
+use mio::unix::UnixReady;
+
+use metrics::Metrics;
+use stream::Stream;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const SCRAPE_TIMEOUT_MS: u64 = 5000;
+
+/// One HTTP exchange on the metrics or admin listener. The socket is
+/// non-blocking and driven from the event loop like any other, so a slow
+/// scraper never holds up the relayed connections.
+pub struct HttpSession {
+    pub stream: Stream,
+    request: Vec<u8>,
+    response: Option<Vec<u8>>,
+    written: usize,
+    deadline: Instant,
+}
+
+impl HttpSession {
+    pub fn new(stream: Stream, now: Instant) -> HttpSession {
+        HttpSession {
+            stream: stream,
+            request: Vec::new(),
+            response: None,
+            written: 0,
+            deadline: now + Duration::from_millis(SCRAPE_TIMEOUT_MS),
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn interest(&self) -> Ready {
+        if self.response.is_some() {
+            Ready::writable()
+        } else {
+            Ready::readable()
+        }
+    }
+
+    /// Makes as much progress as the socket allows, answering the request
+    /// with what `respond` makes of it once it has been read. Returns true
+    /// once the session is over and the socket can be closed.
+    pub fn ready<F>(&mut self, ready: Ready, respond: F) -> bool
+        where F: FnOnce(&[u8]) -> Vec<u8>
+    {
+        if UnixReady::from(ready).is_error() {
+            return true;
+        }
+
+        if self.response.is_none() {
+            match self.read_request() {
+                Ok(true) => self.response = Some(respond(&self.request)),
+                Ok(false) => return false,
+                Err(e) => {
+                    debug!("HTTP request failed: {}", e);
+                    return true;
+                }
+            }
+        }
+
+        match self.write_response() {
+            Ok(done) => done,
+            Err(e) => {
+                debug!("HTTP response failed: {}", e);
+                true
+            }
+        }
+    }
+
+    /// Reads until the end of the request headers. Returns false if more
+    /// data is needed.
+    fn read_request(&mut self) -> IOResult<bool> {
+        let mut buffer = [0; 1024];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) if self.request.is_empty() => {
+                    return Err(IOError::new(ErrorKind::UnexpectedEof, "Empty HTTP request"));
+                }
+                Ok(0) => return Ok(true),
+                Ok(n_read) => {
+                    self.request.extend_from_slice(&buffer[..n_read]);
+                    if self.request.len() >= MAX_REQUEST_SIZE ||
+                       self.request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        return Ok(true);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns true once the whole response has been written.
+    fn write_response(&mut self) -> IOResult<bool> {
+        let response = match self.response {
+            Some(ref response) => response,
+            None => return Ok(false),
+        };
+
+        while self.written < response.len() {
+            match self.stream.write(&response[self.written..]) {
+                Ok(n_written) => self.written += n_written,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// The method and path from the request line.
+pub fn request_target(request: &[u8]) -> (String, String) {
+    let request = String::from_utf8_lossy(request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+
+    (method.to_owned(), path.to_owned())
+}
+
+pub fn http_response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    let mut response = format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                                Connection: close\r\n\r\n",
+                               status,
+                               content_type,
+                               body.len())
+        .into_bytes();
+    response.extend_from_slice(body.as_bytes());
+    response
+}
+
+pub fn render_response(request: &[u8], metrics: &Metrics) -> Vec<u8> {
+    let (method, path) = request_target(request);
+
+    let (status, body) = if method != "GET" {
+        ("405 Method Not Allowed", String::new())
+    } else if path == "/metrics" || path == "/" {
+        let snapshot = metrics.snapshot();
+        ("200 OK",
+         snapshot.render_prometheus(&metrics.backend_snapshots(), &metrics.pool_snapshots()))
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    http_response(status, "text/plain; version=0.0.4", &body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::render_response;
+
+    use metrics::Metrics;
+
+    #[test]
+    fn serves_metrics_path_only() {
+        let metrics = Metrics::new();
+        metrics.connection_accepted();
+
+        let ok = String::from_utf8(render_response(b"GET /metrics HTTP/1.1\r\n\r\n", &metrics))
+            .unwrap();
+        assert!(ok.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(ok.ends_with(&metrics.snapshot().render_prometheus(&[], &[])));
+        assert!(ok.contains("\nlb_connections_total 1\n"));
+
+        let missing = String::from_utf8(render_response(b"GET /other HTTP/1.1\r\n\r\n",
+                                                        &metrics))
+            .unwrap();
+        assert!(missing.starts_with("HTTP/1.0 404 Not Found\r\n"));
+
+        let post = String::from_utf8(render_response(b"POST /metrics HTTP/1.1\r\n\r\n",
+                                                     &metrics))
+            .unwrap();
+        assert!(post.starts_with("HTTP/1.0 405 Method Not Allowed\r\n"));
+    }
+}
@@ -0,0 +1,50 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use backend::BackendId;
+
+/// What a connection amounted to, handed to observers when it closes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The target the connection ended up relaying to, after any failover.
+    pub backend_addr: BackendId,
+    /// Bytes relayed from the client to the backend.
+    pub bytes_up: u64,
+    /// Bytes relayed from the backend to the client.
+    pub bytes_down: u64,
+    pub duration: Duration,
+}
+
+/// An open connection as it stands, for showing in an admin interface of
+/// your own. See `InspectHandle`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: usize,
+    /// The client's address, or the one its PROXY protocol header gave.
+    pub client: SocketAddr,
+    /// The target the connection is relaying to.
+    pub backend: BackendId,
+    /// Bytes relayed from the client to the backend.
+    pub bytes_up: u64,
+    /// Bytes relayed from the backend to the client.
+    pub bytes_down: u64,
+    /// Bytes read from either side that haven't been written to the other.
+    pub buffered: usize,
+    /// How long since anything was relayed either way.
+    pub idle: Duration,
+}
+
+/// Hooks into the lifecycle of relayed connections, for feeding them into
+/// logging or auditing of your own. Observers are called on the event loop
+/// thread when a connection is opened and when it is closed, never while
+/// bytes are relayed, but they should still return quickly.
+pub trait ConnectionObserver {
+    /// A client was accepted and a connect to `backend_addr` started.
+    fn on_accept(&mut self,
+                 _client_addr: &SocketAddr,
+                 _backend_addr: &BackendId,
+                 _conn_id: usize) {
+    }
+
+    fn on_close(&mut self, _conn_id: usize, _stats: &ConnectionStats) {}
+}
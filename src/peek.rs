@@ -0,0 +1,170 @@
+use std::io::prelude::*;
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::Ready;
+use mio::unix::UnixReady;
+
+use frontend::Frontend;
+use http_host;
+use sni;
+use stream::Stream;
+
+/// How long a client gets to send enough of its first request to be
+/// routed, unless `handshake_ms` says otherwise.
+pub const DEFAULT_HANDSHAKE_MS: u64 = 5000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Peeked {
+    /// Enough of the request was seen to find the name it is for, if any.
+    Name(Option<String>),
+    /// More bytes are needed to tell.
+    Incomplete,
+    /// Not the protocol that was expected.
+    Invalid,
+}
+
+/// What a frontend looks at to pick a backend pool before relaying.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NameRouting {
+    /// The SNI host name of a TLS ClientHello.
+    ServerName,
+    /// The Host header of a plaintext HTTP request.
+    Host,
+}
+
+impl NameRouting {
+    pub fn parse(self, buf: &[u8]) -> Peeked {
+        match self {
+            NameRouting::ServerName => sni::parse_server_name(buf),
+            NameRouting::Host => http_host::parse_host(buf),
+        }
+    }
+}
+
+/// What is done with a client whose first request doesn't fit in the
+/// bytes its frontend reads to route it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RoutingOverflow {
+    /// It goes to the frontend's default pool, as if it named no host.
+    Default,
+    /// It is closed.
+    Close,
+}
+
+impl RoutingOverflow {
+    pub fn from_name(name: Option<&str>) -> IOResult<RoutingOverflow> {
+        match name {
+            None | Some("default") => Ok(RoutingOverflow::Default),
+            Some("close") => Ok(RoutingOverflow::Close),
+            Some(other) => {
+                Err(IOError::new(ErrorKind::InvalidInput,
+                                 format!("Unknown routing_overflow {}", other)))
+            }
+        }
+    }
+}
+
+pub enum PeekState {
+    /// Still waiting for more of the first request.
+    Pending,
+    /// Enough has been read to route the connection. Clients that don't
+    /// speak the expected protocol or name no host get `None` and the
+    /// frontend's default pool.
+    Routed(Option<String>),
+    /// The client went away or errored before a decision could be made.
+    Failed,
+    /// The client sent more than its frontend will read to route it, and
+    /// is closed for that.
+    Overflowed,
+}
+
+/// A client on a name routed frontend whose backend hasn't been chosen
+/// yet. It is driven like the other auxiliary sockets until enough of its
+/// first request has been read.
+pub struct PeekSession {
+    pub stream: Stream,
+    client_addr: SocketAddr,
+    frontend: Rc<Frontend>,
+    routing: NameRouting,
+    buffer: Vec<u8>,
+    max_len: usize,
+    deadline: Instant,
+}
+
+impl PeekSession {
+    /// `max_len` bounds how much is read while looking for the name.
+    /// It should be no larger than a connection's buffer, since the bytes
+    /// are handed on to the connection to be relayed. The client is given
+    /// up on if that takes longer than `timeout`.
+    pub fn new(stream: Stream,
+               client_addr: SocketAddr,
+               frontend: Rc<Frontend>,
+               routing: NameRouting,
+               max_len: usize,
+               timeout: Duration,
+               now: Instant)
+               -> PeekSession {
+        PeekSession {
+            stream: stream,
+            client_addr: client_addr,
+            frontend: frontend,
+            routing: routing,
+            buffer: Vec::new(),
+            max_len: max_len,
+            deadline: now + timeout,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn client_addr(&self) -> &SocketAddr {
+        &self.client_addr
+    }
+
+    pub fn ready(&mut self, ready: Ready) -> PeekState {
+        if UnixReady::from(ready).is_error() {
+            return PeekState::Failed;
+        }
+
+        let mut chunk = [0; 1024];
+
+        while self.buffer.len() < self.max_len {
+            let want = chunk.len().min(self.max_len - self.buffer.len());
+
+            match self.stream.read(&mut chunk[..want]) {
+                Ok(0) => return PeekState::Failed,
+                Ok(n_read) => {
+                    self.buffer.extend_from_slice(&chunk[..n_read]);
+                    match self.routing.parse(&self.buffer) {
+                        Peeked::Name(name) => return PeekState::Routed(name),
+                        Peeked::Invalid => return PeekState::Routed(None),
+                        Peeked::Incomplete => {}
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return PeekState::Pending,
+                Err(e) => {
+                    debug!("Reading first request from {} failed: {}", self.client_addr, e);
+                    return PeekState::Failed;
+                }
+            }
+        }
+
+        debug!("Start of request from {} does not fit in {} bytes",
+               self.client_addr,
+               self.max_len);
+        match self.frontend.routing_overflow() {
+            RoutingOverflow::Default => PeekState::Routed(None),
+            RoutingOverflow::Close => PeekState::Overflowed,
+        }
+    }
+
+    /// Hands back the client socket along with everything read from it.
+    pub fn into_parts(self) -> (Stream, SocketAddr, Rc<Frontend>, Vec<u8>) {
+        (self.stream, self.client_addr, self.frontend, self.buffer)
+    }
+}
@@ -0,0 +1,393 @@
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str;
+
+use stream::Address;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest possible v1 line, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+/// Version 2 with the PROXY command.
+const V2_PROXY_COMMAND: u8 = 0x21;
+const V2_FAMILY_UNSPEC: u8 = 0x00;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+
+/// A PROXY protocol header read from the start of a client connection.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Number of bytes the header took up.
+    pub len: usize,
+    /// The original client and destination addresses, if the sender gave
+    /// any. `UNKNOWN` v1 headers, v2 `LOCAL` commands and non-TCP address
+    /// families carry none.
+    pub addrs: Option<(SocketAddr, SocketAddr)>,
+}
+
+/// Extra facts about a client a v2 header can carry after its addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tlv {
+    /// The host name the client asked for, by TLS SNI or HTTP `Host`, when
+    /// the connection was routed on one.
+    Authority,
+    /// The id of the connection, as in the `conn=` of its log lines.
+    UniqueId,
+}
+
+impl Tlv {
+    /// The TLV a `proxy_protocol_tlvs` entry names.
+    pub fn from_name(name: &str) -> Option<Tlv> {
+        match name {
+            "authority" => Some(Tlv::Authority),
+            "unique_id" => Some(Tlv::UniqueId),
+            _ => None,
+        }
+    }
+}
+
+/// Which PROXY protocol header a backend is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderFormat {
+    V1,
+    V2(Vec<Tlv>),
+}
+
+impl HeaderFormat {
+    /// The header announcing a client that connected to `local`. TLVs are
+    /// left out when there is nothing to put in them.
+    pub fn header(&self,
+                  client: &SocketAddr,
+                  local: &Address,
+                  authority: Option<&str>,
+                  conn_id: usize)
+                  -> Vec<u8> {
+        let tlvs = match *self {
+            HeaderFormat::V1 => return v1_header(client, local),
+            HeaderFormat::V2(ref tlvs) => tlvs,
+        };
+        let unique_id = conn_id.to_string();
+        let values = tlvs.iter()
+            .filter_map(|tlv| match *tlv {
+                            Tlv::Authority => {
+                                authority.map(|name| (PP2_TYPE_AUTHORITY, name.as_bytes()))
+                            }
+                            Tlv::UniqueId => Some((PP2_TYPE_UNIQUE_ID, unique_id.as_bytes())),
+                        })
+            .collect::<Vec<_>>();
+
+        v2_header(client, local, &values)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed {
+    Complete(ProxyHeader),
+    /// More bytes are needed to tell.
+    Incomplete,
+    Invalid,
+}
+
+/// Builds the PROXY protocol v1 line announcing a client to a backend.
+/// `local` is the address the client connected to. Clients that aren't
+/// on TCP, or whose address family differs from the listener's, are sent
+/// as `UNKNOWN`, which tells the backend to use the connection's own
+/// addresses.
+pub fn v1_header(client: &SocketAddr, local: &Address) -> Vec<u8> {
+    let header = match (*client, local) {
+        (SocketAddr::V4(src), &Address::Tcp(SocketAddr::V4(dst))) => {
+            format!("PROXY TCP4 {} {} {} {}\r\n",
+                    src.ip(),
+                    dst.ip(),
+                    src.port(),
+                    dst.port())
+        }
+        (SocketAddr::V6(src), &Address::Tcp(SocketAddr::V6(dst))) => {
+            format!("PROXY TCP6 {} {} {} {}\r\n",
+                    src.ip(),
+                    dst.ip(),
+                    src.port(),
+                    dst.port())
+        }
+        _ => "PROXY UNKNOWN\r\n".to_owned(),
+    };
+
+    header.into_bytes()
+}
+
+/// Builds the binary PROXY protocol v2 header announcing a client, with
+/// `tlvs` as type and value pairs after the addresses. Like v1, clients
+/// that can't be described as TCP to `local` get the unspecified family
+/// and no addresses.
+pub fn v2_header(client: &SocketAddr, local: &Address, tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    let family = match (*client, local) {
+        (SocketAddr::V4(src), &Address::Tcp(SocketAddr::V4(dst))) => {
+            payload.extend_from_slice(&src.ip().octets());
+            payload.extend_from_slice(&dst.ip().octets());
+            push_u16(&mut payload, src.port());
+            push_u16(&mut payload, dst.port());
+            V2_FAMILY_TCP4
+        }
+        (SocketAddr::V6(src), &Address::Tcp(SocketAddr::V6(dst))) => {
+            payload.extend_from_slice(&src.ip().octets());
+            payload.extend_from_slice(&dst.ip().octets());
+            push_u16(&mut payload, src.port());
+            push_u16(&mut payload, dst.port());
+            V2_FAMILY_TCP6
+        }
+        _ => V2_FAMILY_UNSPEC,
+    };
+
+    for &(kind, value) in tlvs {
+        payload.push(kind);
+        push_u16(&mut payload, value.len() as u16);
+        payload.extend_from_slice(value);
+    }
+
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(V2_PROXY_COMMAND);
+    header.push(family);
+    push_u16(&mut header, payload.len() as u16);
+    header.extend(payload);
+    header
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+/// Parses a v1 or v2 header from the first bytes sent by a client.
+pub fn parse_header(buf: &[u8]) -> Parsed {
+    if is_prefix(buf, V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if is_prefix(buf, V1_PREFIX) {
+        parse_v1(buf)
+    } else {
+        Parsed::Invalid
+    }
+}
+
+/// Whether `buf` starts with `prefix`, or could once more bytes arrive.
+fn is_prefix(buf: &[u8], prefix: &[u8]) -> bool {
+    let n = buf.len().min(prefix.len());
+
+    buf[..n] == prefix[..n]
+}
+
+fn parse_v1(buf: &[u8]) -> Parsed {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        Some(_) => return Parsed::Invalid,
+        None if buf.len() < V1_MAX_LEN => return Parsed::Incomplete,
+        None => return Parsed::Invalid,
+    };
+    let line = match str::from_utf8(&buf[..end]) {
+        Ok(line) => line,
+        Err(_) => return Parsed::Invalid,
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    let addrs = match fields[1] {
+        "UNKNOWN" => None,
+        "TCP4" | "TCP6" if fields.len() == 6 => {
+            let src_ip = fields[2].parse::<IpAddr>();
+            let dst_ip = fields[3].parse::<IpAddr>();
+            let src_port = fields[4].parse::<u16>();
+            let dst_port = fields[5].parse::<u16>();
+
+            match (src_ip, dst_ip, src_port, dst_port) {
+                (Ok(src_ip), Ok(dst_ip), Ok(src_port), Ok(dst_port))
+                    if src_ip.is_ipv4() == (fields[1] == "TCP4") &&
+                       dst_ip.is_ipv4() == src_ip.is_ipv4() => {
+                    Some((SocketAddr::new(src_ip, src_port), SocketAddr::new(dst_ip, dst_port)))
+                }
+                _ => return Parsed::Invalid,
+            }
+        }
+        _ => return Parsed::Invalid,
+    };
+
+    Parsed::Complete(ProxyHeader {
+                         len: end + 2,
+                         addrs: addrs,
+                     })
+}
+
+fn parse_v2(buf: &[u8]) -> Parsed {
+    if buf.len() < V2_HEADER_LEN {
+        return Parsed::Incomplete;
+    }
+
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0xf;
+    let family = buf[13];
+    let len = V2_HEADER_LEN + ((buf[14] as usize) << 8 | buf[15] as usize);
+
+    if version != 2 || command > 1 {
+        return Parsed::Invalid;
+    }
+    if buf.len() < len {
+        return Parsed::Incomplete;
+    }
+
+    let payload = &buf[V2_HEADER_LEN..len];
+    let port = |i: usize| (payload[i] as u16) << 8 | payload[i + 1] as u16;
+
+    // LOCAL connections come from the proxy itself, so their addresses,
+    // if any, don't describe a client.
+    let addrs = match (command, family) {
+        (0, _) => None,
+        (_, 0x11) if payload.len() >= 12 => {
+            let src = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let dst = Ipv4Addr::new(payload[4], payload[5], payload[6], payload[7]);
+
+            Some((SocketAddr::new(IpAddr::V4(src), port(8)),
+                  SocketAddr::new(IpAddr::V4(dst), port(10))))
+        }
+        (_, 0x21) if payload.len() >= 36 => {
+            let mut src = [0; 16];
+            let mut dst = [0; 16];
+            src.copy_from_slice(&payload[..16]);
+            dst.copy_from_slice(&payload[16..32]);
+
+            Some((SocketAddr::new(IpAddr::V6(Ipv6Addr::from(src)), port(32)),
+                  SocketAddr::new(IpAddr::V6(Ipv6Addr::from(dst)), port(34))))
+        }
+        (_, 0x11) | (_, 0x21) => return Parsed::Invalid,
+        _ => None,
+    };
+
+    Parsed::Complete(ProxyHeader {
+                         len: len,
+                         addrs: addrs,
+                     })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{v1_header, v2_header, parse_header, HeaderFormat, Parsed, ProxyHeader, Tlv};
+
+    use std::path::PathBuf;
+
+    use stream::Address;
+
+    #[test]
+    fn formats_v1_header_per_family() {
+        let local = Address::Tcp("192.168.0.1:443".parse().unwrap());
+        assert_eq!(v1_header(&"10.1.2.3:56324".parse().unwrap(), &local),
+                   b"PROXY TCP4 10.1.2.3 192.168.0.1 56324 443\r\n".to_vec());
+
+        let local = Address::Tcp("[2001:db8::1]:443".parse().unwrap());
+        assert_eq!(v1_header(&"[2001:db8::2]:4000".parse().unwrap(), &local),
+                   b"PROXY TCP6 2001:db8::2 2001:db8::1 4000 443\r\n".to_vec());
+
+        let local = Address::Unix(PathBuf::from("/run/lb.sock"));
+        assert_eq!(v1_header(&"0.0.0.0:0".parse().unwrap(), &local),
+                   b"PROXY UNKNOWN\r\n".to_vec());
+    }
+
+    #[test]
+    fn formats_v2_header_per_family() {
+        // As sent by HAProxy's send-proxy-v2 for the same addresses.
+        let local = Address::Tcp("192.168.0.1:443".parse().unwrap());
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        expected.extend_from_slice(&[10, 1, 2, 3, 192, 168, 0, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(v2_header(&"10.1.2.3:56324".parse().unwrap(), &local, &[]),
+                   expected);
+
+        let local = Address::Tcp("[2001:db8::1]:443".parse().unwrap());
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        expected.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0x0f, 0xa0, 0x01, 0xbb]);
+        assert_eq!(v2_header(&"[2001:db8::2]:4000".parse().unwrap(), &local, &[]),
+                   expected);
+
+        let local = Address::Unix(PathBuf::from("/run/lb.sock"));
+        assert_eq!(v2_header(&"0.0.0.0:0".parse().unwrap(), &local, &[]),
+                   b"\r\n\r\n\0\r\nQUIT\n\x21\x00\x00\x00".to_vec());
+    }
+
+    #[test]
+    fn v2_header_carries_requested_tlvs() {
+        let client = "10.1.2.3:56324".parse().unwrap();
+        let local = Address::Tcp("192.168.0.1:443".parse().unwrap());
+        let format = HeaderFormat::V2(vec![Tlv::Authority, Tlv::UniqueId]);
+
+        let header = format.header(&client, &local, Some("example.com"), 42);
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x1f".to_vec();
+        expected.extend_from_slice(&[10, 1, 2, 3, 192, 168, 0, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        expected.extend_from_slice(b"\x02\x00\x0bexample.com");
+        expected.extend_from_slice(b"\x05\x00\x0242");
+        assert_eq!(header, expected);
+
+        // Our own parser skips the TLVs to find where the client's bytes start.
+        assert_eq!(parse_header(&header),
+                   Parsed::Complete(ProxyHeader {
+                                        len: header.len(),
+                                        addrs: Some((client, "192.168.0.1:443".parse().unwrap())),
+                                    }));
+
+        // No name to tell means no authority TLV.
+        let header = format.header(&client, &local, None, 42);
+        assert_eq!(&header[14..16], b"\x00\x11");
+        assert_eq!(&header[28..], b"\x05\x00\x0242");
+
+        assert_eq!(HeaderFormat::V1.header(&client, &local, Some("example.com"), 42),
+                   v1_header(&client, &local));
+    }
+
+    #[test]
+    fn parses_v1_header() {
+        let buf = b"PROXY TCP4 10.1.2.3 192.168.0.1 56324 443\r\nGET /";
+
+        assert_eq!(parse_header(buf),
+                   Parsed::Complete(ProxyHeader {
+                                        len: 43,
+                                        addrs: Some(("10.1.2.3:56324".parse().unwrap(),
+                                                     "192.168.0.1:443".parse().unwrap())),
+                                    }));
+        assert_eq!(parse_header(&buf[..20]), Parsed::Incomplete);
+        assert_eq!(parse_header(b"PRO"), Parsed::Incomplete);
+        assert_eq!(parse_header(b"PROXY UNKNOWN\r\n"),
+                   Parsed::Complete(ProxyHeader {
+                                        len: 15,
+                                        addrs: None,
+                                    }));
+        assert_eq!(parse_header(b"PROXY TCP4 10.1.2.3 ::1 1 2\r\n"), Parsed::Invalid);
+        assert_eq!(parse_header(b"PROXY TCP4 10.1.2.3\r\n"), Parsed::Invalid);
+        assert_eq!(parse_header(b"GET / HTTP/1.1\r\n"), Parsed::Invalid);
+        assert_eq!(parse_header(&[b'P'; 4][..]), Parsed::Invalid);
+    }
+
+    #[test]
+    fn parses_v2_header() {
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        buf.extend_from_slice(&[10, 1, 2, 3, 192, 168, 0, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        buf.extend_from_slice(b"payload");
+
+        assert_eq!(parse_header(&buf),
+                   Parsed::Complete(ProxyHeader {
+                                        len: 28,
+                                        addrs: Some(("10.1.2.3:56324".parse().unwrap(),
+                                                     "192.168.0.1:443".parse().unwrap())),
+                                    }));
+        assert_eq!(parse_header(&buf[..5]), Parsed::Incomplete);
+        assert_eq!(parse_header(&buf[..20]), Parsed::Incomplete);
+
+        // A LOCAL command keeps its length but carries no client.
+        buf[12] = 0x20;
+        assert_eq!(parse_header(&buf),
+                   Parsed::Complete(ProxyHeader {
+                                        len: 28,
+                                        addrs: None,
+                                    }));
+
+        buf[12] = 0x11;
+        assert_eq!(parse_header(&buf), Parsed::Invalid);
+    }
+}
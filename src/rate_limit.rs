@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use config::LimitConfig;
+
+pub const DEFAULT_MAX_CLIENTS: usize = 65536;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token buckets for new connections, one per client IP.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    max_clients: usize,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32, max_clients: usize) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            max_clients: max_clients.max(1),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Returns a limiter if the configuration asks for one.
+    pub fn from_config(config: &LimitConfig) -> Option<RateLimiter> {
+        config
+            .connection_rate
+            .map(|rate| {
+                     RateLimiter::new(rate,
+                                      config.connection_burst.unwrap_or(rate),
+                                      config.rate_limited_clients.unwrap_or(DEFAULT_MAX_CLIENTS))
+                 })
+    }
+
+    /// Takes a token for a new connection from `ip`. Returns false if the
+    /// client has used up its burst and should be turned away.
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= self.max_clients {
+            self.evict(now);
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets
+            .entry(ip)
+            .or_insert(Bucket {
+                           tokens: burst,
+                           last_refill: now,
+                       });
+
+        bucket.refill(rate, burst, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drops every bucket that has refilled completely, since forgetting
+    /// those changes nothing. If all clients are busy, the least recently
+    /// seen one goes instead so the map never grows past its bound.
+    fn evict(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+
+        self.buckets
+            .retain(|_, bucket| {
+                        bucket.refill(rate, burst, now);
+                        bucket.tokens < burst
+                    });
+
+        if self.buckets.len() >= self.max_clients {
+            let oldest = self.buckets
+                .iter()
+                .min_by_key(|&(_, bucket)| bucket.last_refill)
+                .map(|(ip, _)| *ip);
+
+            if let Some(ip) = oldest {
+                self.buckets.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Caps the bytes per second read from one side of a connection, as a
+/// token bucket holding up to a second's worth.
+pub struct Throttle {
+    rate: f64,
+    bucket: Bucket,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64, now: Instant) -> Throttle {
+        let rate = bytes_per_sec.max(1) as f64;
+
+        Throttle {
+            rate: rate,
+            bucket: Bucket {
+                tokens: rate,
+                last_refill: now,
+            },
+        }
+    }
+
+    /// How many bytes may be read right now.
+    pub fn allowance(&mut self, now: Instant) -> usize {
+        self.bucket.refill(self.rate, self.rate, now);
+        self.bucket.tokens as usize
+    }
+
+    pub fn take(&mut self, n_bytes: usize) {
+        self.bucket.tokens = (self.bucket.tokens - n_bytes as f64).max(0.0);
+    }
+
+    /// When a hundredth of a second's worth will have come in, which is
+    /// the least worth waking up for.
+    pub fn resumes_at(&self) -> Instant {
+        let missing = (self.least_worthwhile() as f64 - self.bucket.tokens).max(0.0);
+
+        self.bucket.last_refill + Duration::from_nanos((missing / self.rate * 1e9).ceil() as u64)
+    }
+
+    fn least_worthwhile(&self) -> usize {
+        (self.rate / 100.0).max(1.0) as usize
+    }
+}
+
+/// Below this many bytes, a write gets all that the bandwidth cap has left
+/// rather than an even share of it, so a crowd of connections doesn't
+/// turn into a crowd of tiny writes.
+const MIN_SHARE: usize = 1024;
+
+/// Caps the bytes per second written across all connections, as one
+/// throttle they share. Every write gets an even share of what there is,
+/// so a busy connection can't starve the rest.
+pub struct BandwidthCap {
+    bytes_per_sec: u64,
+    throttle: Throttle,
+    sharers: usize,
+    window_start: Instant,
+    window_bytes: u64,
+    throughput: u64,
+}
+
+impl BandwidthCap {
+    pub fn new(bytes_per_sec: u64, now: Instant) -> BandwidthCap {
+        BandwidthCap {
+            bytes_per_sec: bytes_per_sec,
+            throttle: Throttle::new(bytes_per_sec, now),
+            sharers: 1,
+            window_start: now,
+            window_bytes: 0,
+            throughput: 0,
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// How many connections the cap is split between.
+    pub fn set_sharers(&mut self, sharers: usize) {
+        self.sharers = sharers.max(1);
+    }
+
+    /// How many bytes one write may take right now. Once the cap has run
+    /// dry, nothing until `resumes_at`, rather than a trickle of writes of
+    /// whatever has come in since.
+    pub fn share(&mut self, now: Instant) -> usize {
+        let allowance = self.throttle.allowance(now);
+        if allowance < self.throttle.least_worthwhile() {
+            return 0;
+        }
+
+        (allowance / self.sharers).max(allowance.min(MIN_SHARE))
+    }
+
+    pub fn take(&mut self, n_bytes: usize, now: Instant) {
+        self.throttle.take(n_bytes);
+        self.roll_window(now);
+        self.window_bytes += n_bytes as u64;
+    }
+
+    /// When writes that found the cap used up should try again.
+    pub fn resumes_at(&self) -> Instant {
+        self.throttle.resumes_at()
+    }
+
+    /// The bytes per second written over the last full second.
+    pub fn throughput(&mut self, now: Instant) -> u64 {
+        self.roll_window(now);
+        self.throughput
+    }
+
+    fn roll_window(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+
+        let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+        self.throughput = self.window_bytes * 1000 / elapsed_ms;
+        self.window_start = now;
+        self.window_bytes = 0;
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BandwidthCap, RateLimiter, Throttle};
+
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn ip(i: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, i])
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let mut limiter = RateLimiter::new(2, 3, 16);
+        let now = Instant::now();
+
+        assert!(limiter.allow(ip(1), now));
+        assert!(limiter.allow(ip(1), now));
+        assert!(limiter.allow(ip(1), now));
+        assert!(!limiter.allow(ip(1), now));
+
+        // Other clients have their own buckets.
+        assert!(limiter.allow(ip(2), now));
+
+        assert!(limiter.allow(ip(1), now + Duration::from_millis(500)));
+        assert!(!limiter.allow(ip(1), now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn throttle_refills_a_second_worth_at_most() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(1000, now);
+
+        assert_eq!(throttle.allowance(now), 1000);
+        throttle.take(1000);
+        assert_eq!(throttle.allowance(now), 0);
+        let resumes_in = throttle.resumes_at() - now;
+        assert!(resumes_in >= Duration::from_millis(10) && resumes_in < Duration::from_millis(11));
+
+        assert_eq!(throttle.allowance(now + Duration::from_millis(250)), 250);
+        throttle.take(100);
+        assert_eq!(throttle.allowance(now + Duration::from_secs(5)), 1000);
+    }
+
+    #[test]
+    fn bandwidth_cap_is_split_between_sharers() {
+        let now = Instant::now();
+        let mut cap = BandwidthCap::new(100_000, now);
+
+        cap.set_sharers(10);
+        assert_eq!(cap.share(now), 10_000);
+        cap.take(99_000, now);
+        // Too little left to split, so the next write gets all of it.
+        assert_eq!(cap.share(now), 1000);
+        cap.take(1000, now);
+        assert_eq!(cap.share(now), 0);
+        assert_eq!(cap.share(now + Duration::from_millis(9)), 0);
+        assert_eq!(cap.share(now + Duration::from_millis(10)), 1000);
+
+        assert_eq!(cap.throughput(now + Duration::from_millis(500)), 0);
+        assert_eq!(cap.throughput(now + Duration::from_secs(2)), 50_000);
+    }
+
+    #[test]
+    fn tracked_clients_stay_bounded() {
+        let mut limiter = RateLimiter::new(1, 1, 4);
+        let now = Instant::now();
+
+        for i in 0..100 {
+            assert!(limiter.allow(ip(i), now + Duration::from_millis(i as u64)));
+            assert!(limiter.buckets.len() <= 4);
+        }
+
+        // After a second every bucket is full again and can be discarded.
+        assert!(limiter.allow(ip(200), now + Duration::from_secs(2)));
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+}
@@ -0,0 +1,266 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::Result as IOResult;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backend::BackendPool;
+use stream::{Address, UNIX_PREFIX};
+
+/// While lookups are running on the helper thread, the event loop wakes
+/// up this often to pick up their results.
+const RESULT_POLL_MS: u64 = 50;
+
+/// Turns a `host:port` target into the addresses it currently stands for.
+/// Lookups run on a helper thread, so implementations are free to block.
+pub trait NameResolver: Send {
+    fn lookup(&self, target: &str) -> IOResult<Vec<Address>>;
+}
+
+/// Looks names up through the system resolver, `getaddrinfo(3)`, taking
+/// every A and AAAA record it returns.
+pub struct SystemResolver;
+
+impl NameResolver for SystemResolver {
+    fn lookup(&self, target: &str) -> IOResult<Vec<Address>> {
+        let mut addrs = try!(target.to_socket_addrs()).map(Address::Tcp).collect::<Vec<_>>();
+
+        addrs.sort();
+        addrs.dedup();
+        Ok(addrs)
+    }
+}
+
+/// Whether a target is written as a host name that is worth looking up
+/// again, rather than a literal socket address or a Unix socket path.
+pub fn is_host_name(target: &str) -> bool {
+    !target.starts_with(UNIX_PREFIX) && target.parse::<SocketAddr>().is_err()
+}
+
+struct ResolvedHost {
+    pool: Rc<RefCell<BackendPool>>,
+    target: String,
+    weight: u32,
+    interval: Duration,
+    next_lookup: Instant,
+    in_flight: bool,
+}
+
+struct Lookup {
+    host: usize,
+    target: String,
+}
+
+struct Resolution {
+    host: usize,
+    result: IOResult<Vec<Address>>,
+}
+
+/// Re-resolves host name targets on an interval and brings the target
+/// addresses of their pools in line with the answers. The lookups
+/// themselves happen on a helper thread that reports back over a channel,
+/// so a slow DNS server never stalls relaying.
+pub struct Resolver {
+    hosts: Vec<ResolvedHost>,
+    lookups: Option<Sender<Lookup>>,
+    resolutions: Receiver<Resolution>,
+    resolution_sender: Sender<Resolution>,
+    name_resolver: Option<Box<dyn NameResolver>>,
+}
+
+impl Resolver {
+    pub fn new(name_resolver: Box<dyn NameResolver>) -> Resolver {
+        let (resolution_sender, resolutions) = mpsc::channel();
+
+        Resolver {
+            hosts: Vec::new(),
+            lookups: None,
+            resolutions: resolutions,
+            resolution_sender: resolution_sender,
+            name_resolver: Some(name_resolver),
+        }
+    }
+
+    /// Looks `target` up again every `interval`, starting one interval from
+    /// now since the pool was just built from a fresh lookup.
+    pub fn add_host(&mut self,
+                    pool: Rc<RefCell<BackendPool>>,
+                    target: &str,
+                    weight: u32,
+                    interval: Duration) {
+        self.hosts
+            .push(ResolvedHost {
+                      pool: pool,
+                      target: target.to_owned(),
+                      weight: weight,
+                      interval: interval,
+                      next_lookup: Instant::now() + interval,
+                      in_flight: false,
+                  });
+    }
+
+    /// When the event loop needs to come back for the next lookup or for
+    /// the results of the ones still running.
+    pub fn next_wakeup(&self, now: Instant) -> Option<Instant> {
+        if self.hosts.iter().any(|h| h.in_flight) {
+            return Some(now + Duration::from_millis(RESULT_POLL_MS));
+        }
+
+        self.hosts.iter().map(|h| h.next_lookup).min()
+    }
+
+    /// Hands the lookups that are due to the helper thread, and applies the
+    /// results of the ones that have finished.
+    pub fn run(&mut self, now: Instant) {
+        self.apply_resolutions();
+
+        for index in 0..self.hosts.len() {
+            if self.hosts[index].in_flight || self.hosts[index].next_lookup > now {
+                continue;
+            }
+
+            let lookup = Lookup {
+                host: index,
+                target: self.hosts[index].target.clone(),
+            };
+            if self.lookup_sender().send(lookup).is_ok() {
+                self.hosts[index].in_flight = true;
+            } else {
+                error!("DNS resolver thread is gone, not looking up {}",
+                       self.hosts[index].target);
+            }
+            self.hosts[index].next_lookup = now + self.hosts[index].interval;
+        }
+    }
+
+    fn apply_resolutions(&mut self) {
+        loop {
+            let resolution = match self.resolutions.try_recv() {
+                Ok(resolution) => resolution,
+                Err(TryRecvError::Empty) |
+                Err(TryRecvError::Disconnected) => return,
+            };
+            let host = &mut self.hosts[resolution.host];
+            host.in_flight = false;
+
+            match resolution.result {
+                Ok(ref addrs) if !addrs.is_empty() => {
+                    host.pool.borrow_mut().update_resolved(&host.target, addrs, host.weight);
+                }
+                Ok(_) => warn!("{} resolved to no addresses, keeping the old ones", host.target),
+                Err(e) => {
+                    warn!("Could not resolve {}, keeping the old addresses: {}",
+                          host.target,
+                          e)
+                }
+            }
+        }
+    }
+
+    /// The helper thread is started with the first lookup, so configurations
+    /// without host names never get one. It exits once the resolver is
+    /// dropped.
+    fn lookup_sender(&mut self) -> &Sender<Lookup> {
+        if self.lookups.is_none() {
+            let (lookup_sender, lookups) = mpsc::channel::<Lookup>();
+            let resolutions = self.resolution_sender.clone();
+            let name_resolver = self.name_resolver.take().expect("Name resolver already taken");
+
+            thread::spawn(move || for lookup in lookups {
+                              let result = name_resolver.lookup(&lookup.target);
+                              let resolution = Resolution {
+                                  host: lookup.host,
+                                  result: result,
+                              };
+                              if resolutions.send(resolution).is_err() {
+                                  break;
+                              }
+                          });
+            self.lookups = Some(lookup_sender);
+        }
+
+        self.lookups.as_ref().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NameResolver, Resolver, is_host_name};
+
+    use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use backend::{Backend, BackendPool};
+    use selector::RoundRobin;
+    use stream::Address;
+
+    /// Answers lookups from a shared list that the test can change.
+    struct FakeResolver(Arc<Mutex<Option<Vec<Address>>>>);
+
+    impl NameResolver for FakeResolver {
+        fn lookup(&self, _target: &str) -> IOResult<Vec<Address>> {
+            self.0
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| IOError::new(ErrorKind::NotFound, "no such host"))
+        }
+    }
+
+    fn addr(port: u16) -> Address {
+        Address::resolve(&format!("127.0.0.1:{}", port)).unwrap()
+    }
+
+    fn run_until_settled(resolver: &mut Resolver, now: Instant) {
+        resolver.run(now);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while resolver.hosts.iter().any(|h| h.in_flight) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+            resolver.run(now);
+        }
+    }
+
+    #[test]
+    fn tells_host_names_from_literal_addresses() {
+        assert!(is_host_name("backend.internal:8080"));
+        assert!(is_host_name("localhost:80"));
+        assert!(!is_host_name("127.0.0.1:8080"));
+        assert!(!is_host_name("[::1]:8080"));
+        assert!(!is_host_name("unix:/run/app.sock"));
+    }
+
+    #[test]
+    fn replaces_the_addresses_of_a_host_with_each_answer() {
+        let answer = Arc::new(Mutex::new(Some(vec![addr(8001), addr(8002)])));
+        let mut resolver = Resolver::new(Box::new(FakeResolver(answer.clone())));
+        let pool = BackendPool::new(vec![Backend::new(addr(8000), 1)],
+                                    Box::new(RoundRobin::new()),
+                                    &Default::default());
+        pool.borrow_mut().update_resolved("app:80", &[addr(8001)], 3);
+
+        let interval = Duration::from_secs(10);
+        resolver.add_host(pool.clone(), "app:80", 3, interval);
+
+        let start = Instant::now();
+        run_until_settled(&mut resolver, start);
+        assert_eq!(pool.borrow().backend_ids(), vec![addr(8000), addr(8001)]);
+
+        run_until_settled(&mut resolver, start + interval);
+        assert_eq!(pool.borrow().backend_ids(),
+                   vec![addr(8000), addr(8001), addr(8002)]);
+        assert_eq!(pool.borrow().backends()[2].weight, 3);
+
+        *answer.lock().unwrap() = None;
+        run_until_settled(&mut resolver, start + interval * 2);
+        assert_eq!(pool.borrow().backend_ids().len(), 3);
+
+        *answer.lock().unwrap() = Some(vec![addr(8002)]);
+        run_until_settled(&mut resolver, start + interval * 3);
+        assert_eq!(pool.borrow().backend_ids(), vec![addr(8000), addr(8002)]);
+        assert_eq!(resolver.next_wakeup(start), Some(start + interval * 4));
+    }
+}
@@ -0,0 +1,632 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use backend::{Backend, BackendId};
+
+pub trait BackendSelector {
+    fn select(&mut self, backends: &[&Backend], client: &SocketAddr) -> usize;
+
+    fn update_weights(&mut self, _weights: &[(BackendId, u32)]) {}
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next_backend: usize,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin { next_backend: 0 }
+    }
+}
+
+impl BackendSelector for RoundRobin {
+    fn select(&mut self, backends: &[&Backend], _client: &SocketAddr) -> usize {
+        let index = self.next_backend % backends.len();
+        self.next_backend = index + 1;
+
+        index
+    }
+}
+
+/// How much of its full weight a target starts its slow start with.
+const SLOW_START_FLOOR_PERCENT: u32 = 10;
+
+/// Picks the backend with the fewest connections. With a slow start, a
+/// target that has just come back counts its connections as if it only
+/// had a share of its full capacity, growing from a tenth to all of it
+/// over the window, so clients ease onto it rather than all rushing to
+/// the one with none.
+#[derive(Default)]
+pub struct LeastConnections {
+    slow_start: Option<Duration>,
+}
+
+impl LeastConnections {
+    pub fn new() -> LeastConnections {
+        LeastConnections { slow_start: None }
+    }
+
+    pub fn with_slow_start(window: Duration) -> LeastConnections {
+        LeastConnections { slow_start: Some(window) }
+    }
+
+    /// The backend's connections, plus the one it would be given, scaled
+    /// up while it is in its slow start.
+    fn load(&self, backend: &Backend, now: Instant) -> u128 {
+        let connections = backend.active_connections as u128 + 1;
+        let full = connections * 1000;
+
+        match (self.slow_start, backend.up_since) {
+            (Some(window), Some(up_since)) if now < up_since + window => {
+                let ramp = now.duration_since(up_since)
+                    .max(window * SLOW_START_FLOOR_PERCENT / 100)
+                    .as_micros()
+                    .max(1);
+                full * window.as_micros() / ramp
+            }
+            _ => full,
+        }
+    }
+}
+
+impl BackendSelector for LeastConnections {
+    fn select(&mut self, backends: &[&Backend], _client: &SocketAddr) -> usize {
+        let now = Instant::now();
+
+        backends
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, b)| self.load(b, now))
+            .map(|(i, _)| i)
+            .expect("Can't select from an empty backend list")
+    }
+}
+
+/// Picks the backend that has recently been quickest to connect and send
+/// its first byte, going by the moving averages kept on each backend.
+/// Backends not heard from yet count as instant, so they get tried; ties
+/// go to the one with fewer connections.
+#[derive(Default)]
+pub struct LeastResponseTime;
+
+impl LeastResponseTime {
+    pub fn new() -> LeastResponseTime {
+        LeastResponseTime
+    }
+}
+
+impl BackendSelector for LeastResponseTime {
+    fn select(&mut self, backends: &[&Backend], _client: &SocketAddr) -> usize {
+        backends
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, b)| (b.response_time(), b.active_connections))
+            .map(|(i, _)| i)
+            .expect("Can't select from an empty backend list")
+    }
+}
+
+/// Smooth weighted round-robin, as used by nginx: every pick adds each
+/// backend's weight to its running score, chooses the highest score and
+/// subtracts the total weight from the winner. Backends without a
+/// configured weight use the weight from their `Backend` entry.
+pub struct WeightedRoundRobin {
+    weights: HashMap<BackendId, u32>,
+    current: HashMap<BackendId, i64>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(weights: Vec<(BackendId, u32)>) -> WeightedRoundRobin {
+        WeightedRoundRobin {
+            weights: weights.into_iter().collect(),
+            current: HashMap::new(),
+        }
+    }
+
+    fn weight(&self, backend: &Backend) -> i64 {
+        self.weights.get(&backend.addr).map_or(backend.weight, |w| *w) as i64
+    }
+}
+
+impl BackendSelector for WeightedRoundRobin {
+    fn select(&mut self, backends: &[&Backend], _client: &SocketAddr) -> usize {
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+
+        for (index, backend) in backends.iter().enumerate() {
+            let weight = self.weight(backend);
+            let current = self.current.entry(backend.addr.clone()).or_insert(0);
+
+            *current += weight;
+            total += weight;
+
+            if weight > 0 && best.map_or(true, |(_, score)| *current > score) {
+                best = Some((index, *current));
+            }
+        }
+
+        match best {
+            Some((index, _)) => {
+                *self.current.get_mut(&backends[index].addr).unwrap() -= total;
+                index
+            }
+            None => 0,
+        }
+    }
+
+    fn update_weights(&mut self, weights: &[(BackendId, u32)]) {
+        for &(ref id, weight) in weights {
+            self.weights.insert(id.clone(), weight);
+        }
+    }
+}
+
+/// Sticky routing on the client IP using rendezvous hashing: every backend
+/// is scored against the client and the highest score wins, so losing a
+/// backend only moves the clients that were routed to it.
+#[derive(Default)]
+pub struct IpHash;
+
+impl IpHash {
+    pub fn new() -> IpHash {
+        IpHash
+    }
+}
+
+fn rendezvous_score(client: &SocketAddr, backend: &BackendId) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    client.ip().hash(&mut hasher);
+    backend.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+impl BackendSelector for IpHash {
+    fn select(&mut self, backends: &[&Backend], client: &SocketAddr) -> usize {
+        backends
+            .iter()
+            .enumerate()
+            .max_by_key(|&(_, b)| rendezvous_score(client, &b.addr))
+            .map(|(i, _)| i)
+            .expect("Can't select from an empty backend list")
+    }
+}
+
+/// Size of the Maglev lookup table. It needs to be prime, and much larger
+/// than the number of backends for the table to be shared out evenly.
+pub const MAGLEV_TABLE_SIZE: usize = 65537;
+
+/// Sticky routing on the client IP with Maglev hashing: every slot of a
+/// large lookup table is given to a backend, each backend filling slots in
+/// its own permutation order in turns. A client is hashed to a slot, so
+/// backends get near equal shares, and losing one only moves a little more
+/// than its own clients. The table is rebuilt whenever the set of backends
+/// it is asked to pick from changes.
+pub struct Maglev {
+    table_size: usize,
+    backends: Vec<BackendId>,
+    table: Vec<usize>,
+}
+
+impl Maglev {
+    pub fn new() -> Maglev {
+        Maglev::with_table_size(MAGLEV_TABLE_SIZE)
+    }
+
+    /// A table of `table_size` slots, which must be prime. Any other size
+    /// could leave slots no backend's permutation ever reaches.
+    pub fn with_table_size(table_size: usize) -> Maglev {
+        assert!(is_prime(table_size),
+                "Maglev table size {} is not prime",
+                table_size);
+        Maglev {
+            table_size: table_size,
+            backends: Vec::new(),
+            table: Vec::new(),
+        }
+    }
+
+    fn rebuild(&mut self, backends: &[&Backend]) {
+        if backends.is_empty() {
+            self.backends.clear();
+            self.table.clear();
+            return;
+        }
+
+        let size = self.table_size as u64;
+        let permutations = backends
+            .iter()
+            .map(|b| {
+                     let offset = maglev_hash(&b.addr, 0) % size;
+                     let skip = maglev_hash(&b.addr, 1) % (size - 1) + 1;
+                     (offset, skip)
+                 })
+            .collect::<Vec<(u64, u64)>>();
+
+        let mut next = vec![0; backends.len()];
+        let mut table = vec![usize::MAX; self.table_size];
+        let mut filled = 0;
+
+        'fill: loop {
+            for (index, &(offset, skip)) in permutations.iter().enumerate() {
+                let mut slot = ((offset + next[index] * skip) % size) as usize;
+                while table[slot] != usize::MAX {
+                    next[index] += 1;
+                    slot = ((offset + next[index] * skip) % size) as usize;
+                }
+
+                table[slot] = index;
+                next[index] += 1;
+                filled += 1;
+                if filled == self.table_size {
+                    break 'fill;
+                }
+            }
+        }
+
+        self.backends = backends.iter().map(|b| b.addr.clone()).collect();
+        self.table = table;
+    }
+}
+
+impl Default for Maglev {
+    fn default() -> Maglev {
+        Maglev::new()
+    }
+}
+
+fn is_prime(n: usize) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| n % d != 0)
+}
+
+fn maglev_hash(addr: &BackendId, seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    addr.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+impl BackendSelector for Maglev {
+    fn select(&mut self, backends: &[&Backend], client: &SocketAddr) -> usize {
+        if backends.len() != self.backends.len() ||
+           backends.iter().zip(self.backends.iter()).any(|(b, addr)| b.addr != *addr) {
+            self.rebuild(backends);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        client.ip().hash(&mut hasher);
+
+        self.table[(hasher.finish() % self.table_size as u64) as usize]
+    }
+}
+
+/// Picks backends at random, each with a probability proportional to its
+/// weight. Backends that are down or have no weight are never picked. The
+/// pick is a binary search over the running total of the weights, so it
+/// stays cheap with many backends.
+pub struct WeightedRandom {
+    state: u64,
+}
+
+impl WeightedRandom {
+    /// A selector whose picks are decided by `seed`, or by the clock when
+    /// none is given.
+    pub fn new(seed: Option<u64>) -> WeightedRandom {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() ^ d.subsec_nanos() as u64)
+                .unwrap_or(0)
+        });
+
+        // xorshift gets stuck on zero.
+        WeightedRandom { state: if seed == 0 { 0x9e3779b97f4a7c15 } else { seed } }
+    }
+
+    /// The next number from an xorshift64* generator. Not for anything
+    /// that needs to be unpredictable.
+    fn next_random(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+}
+
+impl BackendSelector for WeightedRandom {
+    fn select(&mut self, backends: &[&Backend], _client: &SocketAddr) -> usize {
+        let mut total = 0;
+        let cumulative = backends
+            .iter()
+            .enumerate()
+            .filter(|&(_, b)| b.healthy && b.weight > 0)
+            .map(|(index, b)| {
+                     total += b.weight as u64;
+                     (total, index)
+                 })
+            .collect::<Vec<(u64, usize)>>();
+
+        if total == 0 {
+            return 0;
+        }
+
+        let point = self.next_random() % total;
+        cumulative[cumulative.partition_point(|&(sum, _)| sum <= point)].1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BackendSelector, RoundRobin, LeastConnections, LeastResponseTime,
+                WeightedRoundRobin, IpHash, WeightedRandom, Maglev};
+
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use backend::{Backend, BackendId};
+    use stream::Address;
+
+    fn make_backends(count: usize) -> Vec<Backend> {
+        (0..count)
+            .map(|i| {
+                     let addr: SocketAddr = format!("127.0.0.1:{}", 8000 + i).parse().unwrap();
+                     Backend::new(Address::Tcp(addr), 1)
+                 })
+            .collect()
+    }
+
+    fn refs(backends: &[Backend]) -> Vec<&Backend> {
+        backends.iter().collect()
+    }
+
+    fn client(i: usize) -> SocketAddr {
+        format!("10.0.{}.{}:5000", i / 256, i % 256).parse().unwrap()
+    }
+
+    #[test]
+    fn round_robin_cycles_through_backends() {
+        let backends = make_backends(3);
+        let mut selector = RoundRobin::new();
+
+        let picks = (0..7)
+            .map(|_| selector.select(&refs(&backends), &client(0)))
+            .collect::<Vec<usize>>();
+
+        assert_eq!(picks, vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn least_connections_picks_lowest_index_on_tie() {
+        let mut backends = make_backends(3);
+        let mut selector = LeastConnections::new();
+
+        backends[0].active_connections = 2;
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 1);
+
+        backends[1].active_connections = 2;
+        backends[2].active_connections = 1;
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 2);
+
+        backends[2].active_connections = 2;
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 0);
+    }
+
+    #[test]
+    fn least_connections_eases_clients_onto_recovered_backends() {
+        let mut backends = make_backends(2);
+        let mut selector = LeastConnections::with_slow_start(Duration::from_secs(10));
+        let now = Instant::now();
+
+        // Just back, it counts as having ten times its connections.
+        backends[0].active_connections = 5;
+        backends[1].up_since = Some(now);
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 0);
+        backends[0].active_connections = 10;
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 1);
+
+        // Half way through, twice.
+        backends[0].active_connections = 0;
+        backends[1].up_since = Some(now - Duration::from_secs(5));
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 0);
+        backends[0].active_connections = 2;
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 1);
+
+        backends[1].up_since = Some(now - Duration::from_secs(10));
+        backends[0].active_connections = 1;
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 1);
+
+        let mut selector = LeastConnections::new();
+        backends[1].up_since = Some(now);
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 1);
+    }
+
+    #[test]
+    fn least_response_time_tries_new_backends_then_the_fastest() {
+        let mut backends = make_backends(3);
+        let mut selector = LeastResponseTime::new();
+
+        backends[0].connect_time = Some(Duration::from_millis(5));
+        backends[0].first_byte_time = Some(Duration::from_millis(20));
+        backends[1].connect_time = Some(Duration::from_millis(30));
+        backends[2].active_connections = 1;
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 2);
+
+        backends[2].connect_time = Some(Duration::from_millis(40));
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 0);
+
+        backends[1].first_byte_time = Some(Duration::from_millis(1));
+        backends[1].connect_time = Some(Duration::from_millis(10));
+        assert_eq!(selector.select(&refs(&backends), &client(0)), 1);
+    }
+
+    #[test]
+    fn weighted_round_robin_spreads_picks() {
+        let backends = make_backends(3);
+        let mut selector = WeightedRoundRobin::new(vec![(backends[0].addr.clone(), 5),
+                                                        (backends[1].addr.clone(), 1),
+                                                        (backends[2].addr.clone(), 1)]);
+
+        let picks = (0..7)
+            .map(|_| selector.select(&refs(&backends), &client(0)))
+            .collect::<Vec<usize>>();
+
+        assert_eq!(picks, vec![0, 0, 1, 0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn weighted_round_robin_updates_weights() {
+        let backends = make_backends(2);
+        let mut selector = WeightedRoundRobin::new(vec![(backends[0].addr.clone(), 1),
+                                                        (backends[1].addr.clone(), 0)]);
+
+        assert!((0..4).all(|_| selector.select(&refs(&backends), &client(0)) == 0));
+
+        selector.update_weights(&[(backends[0].addr.clone(), 0), (backends[1].addr.clone(), 1)]);
+
+        assert!((0..4).all(|_| selector.select(&refs(&backends), &client(0)) == 1));
+    }
+
+    #[test]
+    fn weighted_random_picks_in_proportion_to_weight() {
+        let mut backends = make_backends(4);
+        backends[0].weight = 1;
+        backends[1].weight = 3;
+        backends[2].weight = 0;
+        backends[3].weight = 6;
+        let mut selector = WeightedRandom::new(Some(42));
+
+        let mut counts = [0; 4];
+        for _ in 0..10000 {
+            counts[selector.select(&refs(&backends), &client(0))] += 1;
+        }
+
+        assert_eq!(counts[2], 0);
+        assert!(counts[0] > 800 && counts[0] < 1200, "{:?}", counts);
+        assert!(counts[1] > 2700 && counts[1] < 3300, "{:?}", counts);
+        assert!(counts[3] > 5600 && counts[3] < 6400, "{:?}", counts);
+    }
+
+    #[test]
+    fn weighted_random_is_repeatable_with_a_seed_and_skips_unhealthy() {
+        let mut backends = make_backends(3);
+        let picks = |backends: &[Backend], seed| {
+            let mut selector = WeightedRandom::new(Some(seed));
+            (0..20)
+                .map(|_| selector.select(&refs(backends), &client(0)))
+                .collect::<Vec<usize>>()
+        };
+
+        assert_eq!(picks(&backends, 7), picks(&backends, 7));
+        assert!(picks(&backends, 7) != picks(&backends, 8));
+
+        backends[1].healthy = false;
+        assert!(picks(&backends, 7).iter().all(|&i| i != 1));
+    }
+
+    #[test]
+    fn ip_hash_only_moves_clients_of_removed_backend() {
+        let mut backends = make_backends(4);
+        let mut selector = IpHash::new();
+
+        let before = (0..1000)
+            .map(|i| backends[selector.select(&refs(&backends), &client(i))].addr.clone())
+            .collect::<Vec<BackendId>>();
+
+        let removed = backends.remove(1).addr;
+
+        for (i, addr) in before.iter().enumerate() {
+            let after = &backends[selector.select(&refs(&backends), &client(i))].addr;
+
+            if *addr == removed {
+                assert!(*after != removed);
+            } else {
+                assert_eq!(after, addr);
+            }
+        }
+    }
+
+    #[test]
+    fn maglev_shares_clients_evenly() {
+        let backends = make_backends(5);
+        let mut selector = Maglev::new();
+
+        let mut counts = [0; 5];
+        for i in 0..20000 {
+            counts[selector.select(&refs(&backends), &client(i))] += 1;
+        }
+
+        assert!(counts.iter().all(|&n| n > 3600 && n < 4400), "{:?}", counts);
+    }
+
+    #[test]
+    fn maglev_remaps_few_clients_when_a_backend_goes() {
+        let mut backends = make_backends(5);
+        let mut selector = Maglev::new();
+        let clients = 20000;
+
+        let before = (0..clients)
+            .map(|i| backends[selector.select(&refs(&backends), &client(i))].addr.clone())
+            .collect::<Vec<BackendId>>();
+
+        let removed = backends.remove(2).addr;
+
+        let mut moved = 0;
+        let mut moved_elsewhere = 0;
+        for (i, addr) in before.iter().enumerate() {
+            let after = &backends[selector.select(&refs(&backends), &client(i))].addr;
+
+            if after != addr {
+                moved += 1;
+                if *addr != removed {
+                    moved_elsewhere += 1;
+                }
+            }
+        }
+
+        // About a fifth of the clients were on the removed backend. Maglev
+        // allows some of the others to move as well, but only a few.
+        let moved_percent = moved * 100 / clients;
+        assert!((18..=25).contains(&moved_percent), "{}% moved", moved_percent);
+        assert!(moved_elsewhere * 100 / clients < 5,
+                "{} clients of remaining backends moved",
+                moved_elsewhere);
+    }
+
+    #[test]
+    #[should_panic(expected = "not prime")]
+    fn maglev_table_size_must_be_prime() {
+        Maglev::with_table_size(65536);
+    }
+
+    #[test]
+    #[should_panic(expected = "not prime")]
+    fn maglev_table_needs_more_than_one_slot() {
+        Maglev::with_table_size(1);
+    }
+
+    #[test]
+    fn maglev_with_a_small_table_fills_every_slot() {
+        let backends = make_backends(3);
+        let mut selector = Maglev::with_table_size(7);
+
+        selector.select(&refs(&backends), &client(0));
+        assert!(selector.table.iter().all(|&index| index < 3), "{:?}", selector.table);
+    }
+
+    #[test]
+    fn ip_hash_ignores_client_port() {
+        let backends = make_backends(4);
+        let mut selector = IpHash::new();
+
+        let first: SocketAddr = "10.1.2.3:1000".parse().unwrap();
+        let second: SocketAddr = "10.1.2.3:2000".parse().unwrap();
+
+        assert_eq!(selector.select(&refs(&backends), &first),
+                   selector.select(&refs(&backends), &second));
+    }
+}
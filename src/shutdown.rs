@@ -0,0 +1,59 @@
+use std::io::Result as IOResult;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use mio::{Poll, PollOpt, Ready, Registration, SetReadiness};
+
+use connection::SHUTDOWN_TOKEN;
+
+/// Stops a running load balancer from any thread: `run` stops accepting,
+/// closes every open connection and returns. Unlike SIGTERM, this doesn't
+/// wait for connections to finish first.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    readiness: SetReadiness,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+
+        // Wakes the event loop from its poll. It can only fail once the
+        // load balancer is gone, when there is nothing left to stop.
+        let _ = self.readiness.set_readiness(Ready::readable());
+    }
+}
+
+/// The event loop's end of its shutdown handles.
+pub struct ShutdownSignal {
+    requested: Arc<AtomicBool>,
+    handle: ShutdownHandle,
+    // Deregisters from the poll when dropped.
+    _registration: Registration,
+}
+
+impl ShutdownSignal {
+    pub fn new(poll: &Poll) -> IOResult<ShutdownSignal> {
+        let (registration, readiness) = Registration::new2();
+        try!(poll.register(&registration, SHUTDOWN_TOKEN, Ready::readable(), PollOpt::edge()));
+
+        let requested = Arc::new(AtomicBool::new(false));
+        Ok(ShutdownSignal {
+               requested: requested.clone(),
+               handle: ShutdownHandle {
+                   requested: requested,
+                   readiness: readiness,
+               },
+               _registration: registration,
+           })
+    }
+
+    pub fn handle(&self) -> ShutdownHandle {
+        self.handle.clone()
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,27 @@
+use std::io::{Result as IOResult, Error as IOError};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc;
+
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_termination(_: libc::c_int) {
+    TERMINATE.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGTERM ask for a graceful shutdown instead of killing the
+/// process. The handler only sets a flag; the signal also interrupts the
+/// event loop's poll so the flag is seen right away.
+pub fn install_handlers() -> IOResult<()> {
+    let handler = request_termination as extern "C" fn(libc::c_int);
+
+    if unsafe { libc::signal(libc::SIGTERM, handler as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(IOError::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns true once for every time SIGTERM has been received.
+pub fn take_termination_request() -> bool {
+    TERMINATE.swap(false, Ordering::SeqCst)
+}
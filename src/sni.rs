@@ -0,0 +1,235 @@
+use peek::Peeked;
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Like `try!`, for the Option returning reads of `Reader`.
+macro_rules! try_opt {
+    ($e:expr) => {
+        match $e {
+            Some(value) => value,
+            None => return None,
+        }
+    }
+}
+
+/// Reads the SNI host name from a TLS ClientHello without consuming it.
+/// The ClientHello may be spread over several handshake records.
+pub fn parse_server_name(buf: &[u8]) -> Peeked {
+    let hello = match client_hello(buf) {
+        Ok(hello) => hello,
+        Err(peeked) => return peeked,
+    };
+
+    match server_name(&mut Reader::new(&hello)) {
+        Some(name) => Peeked::Name(name),
+        None => Peeked::Invalid,
+    }
+}
+
+/// Joins the payloads of the leading handshake records until they hold a
+/// whole ClientHello message, and returns its body.
+fn client_hello(buf: &[u8]) -> Result<Vec<u8>, Peeked> {
+    let mut handshake = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let header = &buf[pos..buf.len().min(pos + RECORD_HEADER_LEN)];
+
+        if header.first().map_or(false, |&t| t != CONTENT_TYPE_HANDSHAKE) ||
+           header.get(1).map_or(false, |&major| major != 3) {
+            return Err(Peeked::Invalid);
+        }
+        if header.len() < RECORD_HEADER_LEN {
+            return Err(Peeked::Incomplete);
+        }
+
+        let record_len = (header[3] as usize) << 8 | header[4] as usize;
+        let end = pos + RECORD_HEADER_LEN + record_len;
+
+        if record_len == 0 {
+            return Err(Peeked::Invalid);
+        }
+        handshake.extend_from_slice(&buf[pos + RECORD_HEADER_LEN..buf.len().min(end)]);
+
+        if handshake.len() >= 4 {
+            if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err(Peeked::Invalid);
+            }
+
+            let hello_len = (handshake[1] as usize) << 16 | (handshake[2] as usize) << 8 |
+                            handshake[3] as usize;
+            if handshake.len() >= 4 + hello_len {
+                return Ok(handshake[4..4 + hello_len].to_vec());
+            }
+        }
+
+        if buf.len() < end {
+            return Err(Peeked::Incomplete);
+        }
+        pos = end;
+    }
+}
+
+/// Walks a ClientHello body to the server_name extension. Returns None if
+/// the message is malformed.
+fn server_name(hello: &mut Reader) -> Option<Option<String>> {
+    // Legacy version and random.
+    try_opt!(hello.bytes(2 + 32));
+    try_opt!(hello.vec_u8());
+    try_opt!(hello.vec_u16());
+    try_opt!(hello.vec_u8());
+
+    if hello.is_empty() {
+        return Some(None);
+    }
+
+    let mut extensions = Reader::new(try_opt!(hello.vec_u16()));
+
+    while !extensions.is_empty() {
+        let extension_type = try_opt!(extensions.u16());
+        let mut data = Reader::new(try_opt!(extensions.vec_u16()));
+
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader::new(try_opt!(data.vec_u16()));
+        while !names.is_empty() {
+            let name_type = try_opt!(names.u8());
+            let name = try_opt!(names.vec_u16());
+
+            if name_type == NAME_TYPE_HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok().map(Some);
+            }
+        }
+    }
+
+    Some(None)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf: buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+
+        let (bytes, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| (b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    /// A vector with a one byte length prefix.
+    fn vec_u8(&mut self) -> Option<&'a [u8]> {
+        let len = try_opt!(self.u8());
+        self.bytes(len as usize)
+    }
+
+    /// A vector with a two byte length prefix.
+    fn vec_u16(&mut self) -> Option<&'a [u8]> {
+        let len = try_opt!(self.u16());
+        self.bytes(len as usize)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::parse_server_name;
+
+    use peek::Peeked;
+
+    /// A ClientHello with the given extensions, wrapped in one record.
+    pub fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.push((extensions.len() >> 8) as u8);
+        body.push(extensions.len() as u8);
+        body.extend_from_slice(extensions);
+
+        let mut handshake = vec![0x01, 0, (body.len() >> 8) as u8, body.len() as u8];
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01, (handshake.len() >> 8) as u8,
+                              handshake.len() as u8];
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    pub fn server_name_extension(name: &str) -> Vec<u8> {
+        let len = name.len();
+        let mut extension = vec![0x00, 0x00, 0, (len + 5) as u8, 0, (len + 3) as u8, 0x00, 0,
+                                 len as u8];
+        extension.extend_from_slice(name.as_bytes());
+        extension
+    }
+
+    #[test]
+    fn reads_server_name_from_client_hello() {
+        // An unrelated extension comes first.
+        let mut extensions = vec![0x00, 0x0b, 0x00, 0x02, 0x01, 0x00];
+        extensions.extend_from_slice(&server_name_extension("api.example.com"));
+        let hello = client_hello(&extensions);
+
+        assert_eq!(parse_server_name(&hello),
+                   Peeked::Name(Some("api.example.com".to_owned())));
+        assert_eq!(parse_server_name(&client_hello(&[])), Peeked::Name(None));
+    }
+
+    #[test]
+    fn waits_for_the_whole_client_hello() {
+        let hello = client_hello(&server_name_extension("example.com"));
+
+        for len in 0..hello.len() {
+            assert_eq!(parse_server_name(&hello[..len]), Peeked::Incomplete);
+        }
+    }
+
+    #[test]
+    fn joins_client_hello_split_across_records() {
+        let hello = client_hello(&server_name_extension("example.com"));
+        let (first, second) = hello[5..].split_at(20);
+
+        let mut records = vec![0x16, 0x03, 0x01, 0, first.len() as u8];
+        records.extend_from_slice(first);
+        records.extend_from_slice(&[0x16, 0x03, 0x01, 0, second.len() as u8]);
+        records.extend_from_slice(second);
+
+        assert_eq!(parse_server_name(&records[..30]), Peeked::Incomplete);
+        assert_eq!(parse_server_name(&records),
+                   Peeked::Name(Some("example.com".to_owned())));
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        assert_eq!(parse_server_name(b"GET / HTTP/1.1\r\n"), Peeked::Invalid);
+        assert_eq!(parse_server_name(&[0x16, 0x01]), Peeked::Invalid);
+        assert_eq!(parse_server_name(&[0x16, 0x03, 0x01, 0x00, 0x04, 0x02, 0, 0, 0]),
+                   Peeked::Invalid);
+    }
+}
@@ -0,0 +1,168 @@
+use std::io::{Result as IOResult, Error as IOError};
+use std::os::unix::io::RawFd;
+
+use libc;
+
+/// A kernel pipe that relayed bytes pass through on their way from one
+/// socket to the other, moved by `splice(2)` so they are never copied into
+/// userspace. Only Linux has `splice`; elsewhere the pipe can't be opened
+/// and connections keep relaying through their buffers.
+pub struct SplicePipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+    len: usize,
+    capacity: usize,
+}
+
+impl SplicePipe {
+    /// Opens a pipe holding up to `capacity` bytes, or less if the kernel
+    /// won't grow it that far.
+    pub fn new(capacity: usize) -> IOResult<SplicePipe> {
+        let (read_fd, write_fd, pipe_size) = try!(open_pipe(capacity));
+
+        Ok(SplicePipe {
+               read_fd: read_fd,
+               write_fd: write_fd,
+               len: 0,
+               capacity: capacity.min(pipe_size),
+           })
+    }
+
+    /// Bytes moved in from a socket that haven't been moved out yet.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    /// Moves what `fd` has to read into the pipe, as much as there is room
+    /// for. `Ok(0)` means the socket has reached EOF.
+    pub fn fill_from(&mut self, fd: RawFd) -> IOResult<usize> {
+        self.fill_at_most_from(fd, self.capacity)
+    }
+
+    /// Like `fill_from`, but moves no more than `max_len` bytes.
+    pub fn fill_at_most_from(&mut self, fd: RawFd, max_len: usize) -> IOResult<usize> {
+        let n_moved = try!(splice(fd, self.write_fd, (self.capacity - self.len).min(max_len)));
+        self.len += n_moved;
+        Ok(n_moved)
+    }
+
+    /// Moves bytes from the pipe out to `fd`.
+    pub fn drain_into(&mut self, fd: RawFd) -> IOResult<usize> {
+        self.drain_at_most_into(fd, self.len)
+    }
+
+    /// Like `drain_into`, but moves no more than `max_len` bytes.
+    pub fn drain_at_most_into(&mut self, fd: RawFd, max_len: usize) -> IOResult<usize> {
+        let n_moved = try!(splice(self.read_fd, fd, self.len.min(max_len)));
+        self.len -= n_moved;
+        Ok(n_moved)
+    }
+}
+
+impl Drop for SplicePipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_pipe(capacity: usize) -> IOResult<(RawFd, RawFd, usize)> {
+    let mut fds = [0; 2];
+
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+        return Err(IOError::last_os_error());
+    }
+
+    // Growing past the default size is allowed to fail, the pipe is just
+    // drained more often then.
+    let pipe_size = unsafe {
+        libc::fcntl(fds[1], libc::F_SETPIPE_SZ, capacity as libc::c_int);
+        libc::fcntl(fds[1], libc::F_GETPIPE_SZ)
+    };
+    if pipe_size <= 0 {
+        let e = IOError::last_os_error();
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        return Err(e);
+    }
+
+    Ok((fds[0], fds[1], pipe_size as usize))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_pipe(_capacity: usize) -> IOResult<(RawFd, RawFd, usize)> {
+    Err(IOError::new(::std::io::ErrorKind::Other, "splice(2) is only available on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn splice(from: RawFd, to: RawFd, len: usize) -> IOResult<usize> {
+    let result = unsafe {
+        libc::splice(from,
+                     ::std::ptr::null_mut(),
+                     to,
+                     ::std::ptr::null_mut(),
+                     len,
+                     libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+    };
+
+    if result < 0 {
+        Err(IOError::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn splice(_from: RawFd, _to: RawFd, _len: usize) -> IOResult<usize> {
+    unreachable!("no SplicePipe can be opened without splice(2)")
+}
+
+#[cfg(test)]
+mod test {
+    use super::SplicePipe;
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn moves_bytes_between_sockets_through_the_pipe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (source, _) = listener.accept().unwrap();
+        let sink = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut receiver, _) = listener.accept().unwrap();
+
+        let mut pipe = SplicePipe::new(4).unwrap();
+        sender.write_all(b"spliced").unwrap();
+
+        let mut relayed = Vec::new();
+        while relayed.len() < 7 {
+            pipe.fill_from(source.as_raw_fd()).unwrap();
+            assert!(pipe.len() <= 4);
+            pipe.drain_into(sink.as_raw_fd()).unwrap();
+            assert!(pipe.is_empty());
+
+            let mut buf = [0; 16];
+            let n_read = receiver.read(&mut buf).unwrap();
+            relayed.extend_from_slice(&buf[..n_read]);
+        }
+        assert_eq!(relayed, b"spliced".to_vec());
+
+        drop(sender);
+        assert_eq!(pipe.fill_from(source.as_raw_fd()).unwrap(), 0);
+    }
+}
@@ -0,0 +1,574 @@
+use std::fmt;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::net::{SocketAddr, Shutdown, ToSocketAddrs, IpAddr, Ipv4Addr};
+use std::os::unix::fs::FileTypeExt;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixStream, UnixListener};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use libc;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio::tcp::{TcpStream, TcpListener};
+use mio::unix::{EventedFd, UnixReady};
+
+use net2::TcpBuilder;
+
+use resolver::{NameResolver, SystemResolver};
+
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Where to listen or connect: a TCP socket address, or a Unix domain
+/// socket path written as `unix:/path/to/socket` in the configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Unix domain peers have no IP address. Connections accepted on a Unix
+/// listener report this one to selectors, so they all hash alike.
+pub fn unix_peer_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
+}
+
+impl Address {
+    pub fn resolve(s: &str) -> IOResult<Address> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+
+        let addrs: Vec<SocketAddr> = try!(s.to_socket_addrs()).collect();
+
+        assert_eq!(addrs.len(), 1);
+
+        Ok(Address::Tcp(addrs[0]))
+    }
+
+    /// Like `resolve`, but a host name may stand for any number of
+    /// addresses, and all of them are returned.
+    pub fn resolve_all(s: &str) -> IOResult<Vec<Address>> {
+        if s.starts_with(UNIX_PREFIX) {
+            return Address::resolve(s).map(|addr| vec![addr]);
+        }
+
+        SystemResolver.lookup(s)
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Address {
+        Address::Tcp(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Tcp(ref addr) => write!(f, "{}", addr),
+            Address::Unix(ref path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// A non-blocking stream socket of either family. Relaying only needs
+/// `Read`/`Write` and registration, so this stays a thin dispatch layer.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Starts a non-blocking connect. Unix domain connects complete (or
+    /// fail) immediately, TCP ones report completion through writability.
+    pub fn connect(addr: &Address) -> IOResult<Stream> {
+        match *addr {
+            Address::Tcp(ref addr) => TcpStream::connect(addr).map(Stream::Tcp),
+            Address::Unix(ref path) => {
+                let stream = try!(UnixStream::connect(path));
+                try!(stream.set_nonblocking(true));
+                Ok(Stream::Unix(stream))
+            }
+        }
+    }
+
+    /// Like `connect`, but binds the socket to `source` first, so the
+    /// target sees the connection come from that address. A source with
+    /// port 0 is given a free port by the system. Unix domain targets have
+    /// no use for a source and ignore it.
+    pub fn connect_from(addr: &Address, source: &SocketAddr) -> IOResult<Stream> {
+        let addr = match *addr {
+            Address::Tcp(ref addr) => addr,
+            Address::Unix(_) => return Stream::connect(addr),
+        };
+        if addr.is_ipv4() != source.is_ipv4() {
+            return Err(IOError::new(ErrorKind::InvalidInput,
+                                    format!("Source address {} can't connect to {}",
+                                            source,
+                                            addr)));
+        }
+
+        let builder = try!(match *addr {
+                               SocketAddr::V4(_) => TcpBuilder::new_v4(),
+                               SocketAddr::V6(_) => TcpBuilder::new_v6(),
+                           });
+        if source.port() == 0 {
+            try!(defer_port_choice(&builder));
+        }
+        try!(builder.bind(source).map_err(|e| {
+            IOError::new(e.kind(), format!("Could not bind source address {}: {}", source, e))
+        }));
+
+        let stream = try!(builder.to_tcp_stream());
+        TcpStream::connect_stream(stream, addr).map(Stream::Tcp)
+    }
+
+    pub fn try_clone(&self) -> IOResult<Stream> {
+        match *self {
+            Stream::Tcp(ref s) => s.try_clone().map(Stream::Tcp),
+            Stream::Unix(ref s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> IOResult<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.shutdown(how),
+            Stream::Unix(ref s) => s.shutdown(how),
+        }
+    }
+
+    pub fn take_error(&self) -> IOResult<Option<IOError>> {
+        match *self {
+            Stream::Tcp(ref s) => s.take_error(),
+            Stream::Unix(ref s) => s.take_error(),
+        }
+    }
+
+    pub fn local_addr(&self) -> IOResult<Address> {
+        match *self {
+            Stream::Tcp(ref s) => s.local_addr().map(Address::Tcp),
+            Stream::Unix(ref s) => {
+                let addr = try!(s.local_addr());
+                Ok(Address::Unix(addr.as_pathname().map(PathBuf::from).unwrap_or_default()))
+            }
+        }
+    }
+
+    /// Whether the socket has a connected peer.
+    pub fn is_connected(&self) -> bool {
+        match *self {
+            Stream::Tcp(ref s) => s.peer_addr().is_ok(),
+            Stream::Unix(ref s) => s.peer_addr().is_ok(),
+        }
+    }
+
+    /// Interprets a readiness event on a socket that is connecting: true
+    /// once the connect has worked, false once it has failed, and None
+    /// while it is still under way.
+    pub fn connect_result(&self, ready: Ready) -> Option<bool> {
+        let unix_ready = UnixReady::from(ready);
+
+        if unix_ready.is_error() || unix_ready.is_hup() {
+            Some(false)
+        } else if ready.is_writable() {
+            let connected = match self.take_error() {
+                Ok(None) => self.is_connected(),
+                _ => false,
+            };
+            Some(connected)
+        } else {
+            None
+        }
+    }
+
+    /// Turns Nagle's algorithm off or on. Unix domain sockets don't batch
+    /// writes, so there is nothing to set on them.
+    pub fn set_nodelay(&self, nodelay: bool) -> IOResult<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.set_nodelay(nodelay),
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+
+    /// Sets SO_LINGER: with `Some(0)` closing the socket resets the
+    /// connection, throwing away whatever hasn't been sent. `None` is the
+    /// OS default of a FIN after the unsent bytes.
+    pub fn set_linger(&self, linger: Option<Duration>) -> IOResult<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.set_linger(linger),
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+
+    /// Has the OS probe an idle peer after `idle`, and again every
+    /// `interval` when given. Both are rounded up to whole seconds.
+    pub fn set_keepalive(&self, idle: Duration, interval: Option<Duration>) -> IOResult<()> {
+        match *self {
+            Stream::Tcp(ref s) => {
+                try!(s.set_keepalive(Some(Duration::from_secs(whole_secs(idle)))));
+                match interval {
+                    Some(interval) => {
+                        set_tcp_option(s.as_raw_fd(),
+                                       libc::TCP_KEEPINTVL,
+                                       whole_secs(interval) as libc::c_int)
+                    }
+                    None => Ok(()),
+                }
+            }
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+
+    /// Bytes written to the socket that the peer has yet to acknowledge,
+    /// including ones the kernel hasn't got round to sending. Always 0 off
+    /// Linux, where this can't be asked.
+    pub fn unacked_bytes(&self) -> IOResult<usize> {
+        send_queue_len(self.as_raw_fd())
+    }
+
+    pub fn is_unix(&self) -> bool {
+        match *self {
+            Stream::Tcp(_) => false,
+            Stream::Unix(_) => true,
+        }
+    }
+
+    /// Whether a socket that has been left alone since its connect started
+    /// is still good to relay over: the connect hasn't failed, and once it
+    /// has gone through, the peer hasn't closed the connection. Anything
+    /// the peer sent unprompted is left in place for the next read.
+    pub fn is_usable(&self) -> bool {
+        match self.take_error() {
+            Ok(None) if self.is_connected() => is_open(self.as_raw_fd()),
+            Ok(None) => true,
+            _ => false,
+        }
+    }
+}
+
+fn is_open(fd: RawFd) -> bool {
+    let mut byte = 0u8;
+    let result = unsafe {
+        libc::recv(fd,
+                   &mut byte as *mut u8 as *mut libc::c_void,
+                   1,
+                   libc::MSG_PEEK | libc::MSG_DONTWAIT)
+    };
+
+    result > 0 || (result < 0 && IOError::last_os_error().kind() == ErrorKind::WouldBlock)
+}
+
+fn whole_secs(duration: Duration) -> u64 {
+    let secs = duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 };
+
+    secs.max(1)
+}
+
+/// Binding to port 0 normally picks the port right away, which would keep
+/// it from being used towards any other target. Linux can put the choice
+/// off until the connect, when the whole address pair is known.
+#[cfg(target_os = "linux")]
+fn defer_port_choice(builder: &TcpBuilder) -> IOResult<()> {
+    set_socket_option(builder.as_raw_fd(), libc::IPPROTO_IP, libc::IP_BIND_ADDRESS_NO_PORT, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn defer_port_choice(_builder: &TcpBuilder) -> IOResult<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn send_queue_len(fd: RawFd) -> IOResult<usize> {
+    let mut len: libc::c_int = 0;
+    let result = unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut len as *mut libc::c_int) };
+
+    if result == 0 {
+        Ok(len.max(0) as usize)
+    } else {
+        Err(IOError::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_queue_len(_fd: RawFd) -> IOResult<usize> {
+    Ok(0)
+}
+
+fn set_tcp_option(fd: RawFd, name: libc::c_int, value: libc::c_int) -> IOResult<()> {
+    set_socket_option(fd, libc::IPPROTO_TCP, name, value)
+}
+
+fn set_socket_option(fd: RawFd,
+                     level: libc::c_int,
+                     name: libc::c_int,
+                     value: libc::c_int)
+                     -> IOResult<()> {
+    let result = unsafe {
+        libc::setsockopt(fd,
+                         level,
+                         name,
+                         &value as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(IOError::last_os_error())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Tcp(ref s) => s.as_raw_fd(),
+            Stream::Unix(ref s) => s.as_raw_fd(),
+        }
+    }
+}
+
+impl Evented for Stream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> IOResult<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.register(poll, token, interest, opts),
+            Stream::Unix(ref s) => EventedFd(&s.as_raw_fd()).register(poll, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> IOResult<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.reregister(poll, token, interest, opts),
+            Stream::Unix(ref s) => {
+                EventedFd(&s.as_raw_fd()).reregister(poll, token, interest, opts)
+            }
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> IOResult<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.deregister(poll),
+            Stream::Unix(ref s) => EventedFd(&s.as_raw_fd()).deregister(poll),
+        }
+    }
+}
+
+/// Socket options applied to TCP listeners before they start listening.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ListenOptions {
+    /// Sets `SO_REUSEADDR`, so a restarted load balancer can bind while
+    /// connections from the previous run linger in `TIME_WAIT`.
+    pub reuse_addr: bool,
+    /// How many connections the kernel queues up before they are accepted.
+    pub backlog: i32,
+}
+
+impl Default for ListenOptions {
+    fn default() -> ListenOptions {
+        ListenOptions {
+            reuse_addr: true,
+            backlog: 1024,
+        }
+    }
+}
+
+pub enum StreamListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl StreamListener {
+    /// Binds a listener. A socket file left behind at a Unix path by an
+    /// earlier run is removed first. The options only apply to TCP.
+    pub fn bind(addr: &Address, options: &ListenOptions) -> IOResult<StreamListener> {
+        match *addr {
+            Address::Tcp(ref addr) => {
+                let builder = try!(match *addr {
+                                       SocketAddr::V4(_) => TcpBuilder::new_v4(),
+                                       SocketAddr::V6(_) => TcpBuilder::new_v6(),
+                                   });
+                try!(builder.reuse_address(options.reuse_addr));
+                try!(builder.bind(addr));
+
+                let listener = try!(builder.listen(options.backlog));
+                TcpListener::from_std(listener).map(StreamListener::Tcp)
+            }
+            Address::Unix(ref path) => {
+                if let Ok(metadata) = fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        try!(fs::remove_file(path));
+                    }
+                }
+
+                let listener = try!(UnixListener::bind(path));
+                try!(listener.set_nonblocking(true));
+                Ok(StreamListener::Unix(listener))
+            }
+        }
+    }
+
+    /// Accepts a client. Unix domain clients are reported with
+    /// `unix_peer_addr()`.
+    pub fn accept(&self) -> IOResult<(Stream, SocketAddr)> {
+        match *self {
+            StreamListener::Tcp(ref l) => {
+                l.accept().map(|(stream, addr)| (Stream::Tcp(stream), addr))
+            }
+            StreamListener::Unix(ref l) => {
+                let (stream, _) = try!(l.accept());
+                try!(stream.set_nonblocking(true));
+                Ok((Stream::Unix(stream), unix_peer_addr()))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> IOResult<Address> {
+        match *self {
+            StreamListener::Tcp(ref l) => l.local_addr().map(Address::Tcp),
+            StreamListener::Unix(ref l) => {
+                let addr = try!(l.local_addr());
+                Ok(Address::Unix(addr.as_pathname().map(PathBuf::from).unwrap_or_default()))
+            }
+        }
+    }
+}
+
+impl Evented for StreamListener {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> IOResult<()> {
+        match *self {
+            StreamListener::Tcp(ref l) => l.register(poll, token, interest, opts),
+            StreamListener::Unix(ref l) => {
+                EventedFd(&l.as_raw_fd()).register(poll, token, interest, opts)
+            }
+        }
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> IOResult<()> {
+        match *self {
+            StreamListener::Tcp(ref l) => l.reregister(poll, token, interest, opts),
+            StreamListener::Unix(ref l) => {
+                EventedFd(&l.as_raw_fd()).reregister(poll, token, interest, opts)
+            }
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> IOResult<()> {
+        match *self {
+            StreamListener::Tcp(ref l) => l.deregister(poll),
+            StreamListener::Unix(ref l) => EventedFd(&l.as_raw_fd()).deregister(poll),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Address, ListenOptions, Stream, StreamListener};
+
+    use std::io::ErrorKind;
+    use std::mem;
+    use std::net::{IpAddr, TcpListener};
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+
+    use libc;
+
+    fn reuse_addr(listener: &StreamListener) -> bool {
+        let fd = match *listener {
+            StreamListener::Tcp(ref l) => l.as_raw_fd(),
+            StreamListener::Unix(ref l) => l.as_raw_fd(),
+        };
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(fd,
+                             libc::SOL_SOCKET,
+                             libc::SO_REUSEADDR,
+                             &mut value as *mut _ as *mut libc::c_void,
+                             &mut len)
+        };
+        assert_eq!(result, 0);
+
+        value != 0
+    }
+
+    #[test]
+    fn resolves_tcp_and_unix_addresses() {
+        assert_eq!(Address::resolve("127.0.0.1:80").unwrap(),
+                   Address::Tcp("127.0.0.1:80".parse().unwrap()));
+        assert_eq!(Address::resolve("unix:/run/app.sock").unwrap(),
+                   Address::Unix(PathBuf::from("/run/app.sock")));
+        assert_eq!(Address::resolve("unix:/run/app.sock").unwrap().to_string(),
+                   "unix:/run/app.sock");
+    }
+
+    #[test]
+    fn applies_listen_options() {
+        let addr = Address::resolve("127.0.0.1:0").unwrap();
+
+        let listener = StreamListener::bind(&addr, &Default::default()).unwrap();
+        assert!(reuse_addr(&listener));
+
+        let options = ListenOptions {
+            reuse_addr: false,
+            backlog: 16,
+        };
+        let listener = StreamListener::bind(&addr, &options).unwrap();
+        assert!(!reuse_addr(&listener));
+    }
+
+    #[test]
+    fn connects_from_the_given_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = Address::Tcp(listener.local_addr().unwrap());
+
+        let _stream = Stream::connect_from(&target, &"127.0.0.2:0".parse().unwrap()).unwrap();
+        let (_, peer_addr) = listener.accept().unwrap();
+        assert_eq!(peer_addr.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+
+        let taken = listener.local_addr().unwrap();
+        let err = Stream::connect_from(&target, &taken).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert!(err.to_string().starts_with(&format!("Could not bind source address {}", taken)));
+
+        let err = Stream::connect_from(&target, &"[::1]:0".parse().unwrap()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
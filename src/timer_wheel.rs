@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// A hashed timer wheel: deadlines are dropped into one of a ring of slots
+/// by the tick they fall on, and each tick only the slot whose turn it is
+/// gets looked at. Scheduling, rescheduling and cancelling are O(1), and so
+/// is firing, amortized over the deadlines that fire.
+///
+/// Every key has at most one deadline. Rescheduling or cancelling doesn't
+/// go looking for the old slot entry; it is left behind, recognized as
+/// stale by its deadline when its slot comes round, and dropped then.
+pub struct TimerWheel<K> {
+    tick: Duration,
+    slots: Vec<Vec<(K, Instant)>>,
+    deadlines: HashMap<K, Instant>,
+    start: Instant,
+    /// The last tick that has been fired.
+    current_tick: u64,
+}
+
+impl<K: Copy + Eq + Hash> TimerWheel<K> {
+    /// A wheel that fires deadlines `tick` apart at the finest, with
+    /// `n_slots` slots per turn. Deadlines more than a turn away stay in
+    /// their slot for as many turns as it takes.
+    pub fn new(tick: Duration, n_slots: usize, now: Instant) -> TimerWheel<K> {
+        assert!(tick > Duration::from_millis(0), "Timer wheel tick must not be zero");
+        assert!(n_slots > 0, "Timer wheel needs at least one slot");
+
+        TimerWheel {
+            tick: tick,
+            slots: (0..n_slots).map(|_| Vec::new()).collect(),
+            deadlines: HashMap::new(),
+            start: now,
+            current_tick: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    /// The deadline `key` is waiting for, if it has one.
+    pub fn deadline(&self, key: K) -> Option<Instant> {
+        self.deadlines.get(&key).cloned()
+    }
+
+    /// Has `key` fire at `deadline`, in place of any deadline it had. A
+    /// deadline that has already passed fires on the next tick.
+    pub fn schedule(&mut self, key: K, deadline: Instant) {
+        if self.deadlines.insert(key, deadline) == Some(deadline) {
+            return;
+        }
+
+        let tick = self.tick_after(deadline).max(self.current_tick + 1);
+        let slot = self.slot(tick);
+        self.slots[slot].push((key, deadline));
+    }
+
+    pub fn cancel(&mut self, key: K) {
+        self.deadlines.remove(&key);
+    }
+
+    /// Fires every tick up to `now`, returning the keys whose deadlines
+    /// have passed. They are unscheduled by firing.
+    pub fn expire(&mut self, now: Instant) -> Vec<K> {
+        let target_tick = self.tick_before(now);
+        let mut expired = Vec::new();
+
+        // A turn of the wheel visits every slot, so a long sleep never
+        // takes more than that to catch up on.
+        let first_tick = self.current_tick + 1;
+        let last_tick = target_tick.min(self.current_tick + self.slots.len() as u64);
+        for tick in first_tick..last_tick + 1 {
+            let slot = self.slot(tick);
+            let entries = ::std::mem::take(&mut self.slots[slot]);
+
+            for (key, deadline) in entries {
+                if self.deadlines.get(&key) != Some(&deadline) {
+                    continue;
+                }
+                if deadline <= now {
+                    self.deadlines.remove(&key);
+                    expired.push(key);
+                } else {
+                    self.slots[slot].push((key, deadline));
+                }
+            }
+        }
+
+        self.current_tick = self.current_tick.max(target_tick);
+        expired
+    }
+
+    /// When the next slot with anything in it comes round. This can be
+    /// early, for slots holding deadlines a turn or more away or ones that
+    /// have since been cancelled, but never late.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        if self.deadlines.is_empty() {
+            return None;
+        }
+
+        (1..self.slots.len() as u64 + 1)
+            .map(|offset| self.current_tick + offset)
+            .find(|&tick| !self.slots[self.slot(tick)].is_empty())
+            .map(|tick| self.start + Duration::from_nanos(as_nanos(self.tick) * tick))
+    }
+
+    fn slot(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+
+    /// The first tick at or after `at`.
+    fn tick_after(&self, at: Instant) -> u64 {
+        let elapsed = as_nanos(at.duration_since(self.start));
+        let tick = as_nanos(self.tick);
+        (elapsed + tick - 1) / tick
+    }
+
+    /// The last tick at or before `at`.
+    fn tick_before(&self, at: Instant) -> u64 {
+        as_nanos(at.duration_since(self.start)) / as_nanos(self.tick)
+    }
+}
+
+fn as_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::TimerWheel;
+
+    use std::time::{Duration, Instant};
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn fires_deadlines_in_order() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(ms(10), 8, start);
+
+        wheel.schedule(3, start + ms(290));
+        wheel.schedule(1, start + ms(25));
+        wheel.schedule(2, start + ms(40));
+        assert_eq!(wheel.next_expiry(), Some(start + ms(30)));
+
+        assert_eq!(wheel.expire(start + ms(20)), Vec::<u32>::new());
+        assert_eq!(wheel.expire(start + ms(30)), vec![1]);
+        assert_eq!(wheel.next_expiry(), Some(start + ms(40)));
+        assert_eq!(wheel.expire(start + ms(45)), vec![2]);
+
+        // 290 ms is more than a turn of the wheel away, so its slot comes
+        // round early first.
+        assert_eq!(wheel.expire(start + ms(200)), Vec::<u32>::new());
+        assert_eq!(wheel.len(), 1);
+        assert_eq!(wheel.expire(start + ms(290)), vec![3]);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_expiry(), None);
+    }
+
+    #[test]
+    fn catches_up_after_a_long_sleep() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(ms(10), 4, start);
+
+        wheel.schedule(1, start + ms(15));
+        wheel.schedule(2, start + ms(95));
+
+        let mut expired = wheel.expire(start + ms(1000));
+        expired.sort();
+        assert_eq!(expired, vec![1, 2]);
+
+        wheel.schedule(3, start);
+        assert_eq!(wheel.expire(start + ms(1000)), Vec::<u32>::new());
+        assert_eq!(wheel.expire(start + ms(1010)), vec![3]);
+    }
+
+    #[test]
+    fn rescheduling_and_cancelling_replace_the_old_deadline() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(ms(10), 8, start);
+
+        wheel.schedule(1, start + ms(20));
+        wheel.schedule(2, start + ms(20));
+        wheel.schedule(3, start + ms(20));
+
+        // Activity pushes one deadline back and brings another forward.
+        wheel.schedule(1, start + ms(60));
+        wheel.schedule(3, start + ms(10));
+        wheel.cancel(2);
+        assert_eq!(wheel.deadline(1), Some(start + ms(60)));
+        assert_eq!(wheel.deadline(2), None);
+
+        assert_eq!(wheel.expire(start + ms(20)), vec![3]);
+        assert_eq!(wheel.expire(start + ms(50)), Vec::<u32>::new());
+        assert_eq!(wheel.expire(start + ms(60)), vec![1]);
+        assert!(wheel.is_empty());
+    }
+}
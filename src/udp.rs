@@ -0,0 +1,176 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::net::UdpSocket;
+
+use backend::{BackendPool, BackendId};
+use connection::ListenerToken;
+use metrics::{Metrics, BackendMetrics, PoolMetrics};
+use stream::Address;
+
+pub const DEFAULT_UDP_IDLE_MS: u64 = 30000;
+
+/// Room for the largest datagram UDP can carry.
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Binds the socket of a UDP frontend, which takes every client's
+/// datagrams and sends the replies back from the same address.
+pub fn bind(addr: &Address) -> IOResult<UdpSocket> {
+    match *addr {
+        Address::Tcp(ref addr) => UdpSocket::bind(addr),
+        Address::Unix(ref path) => {
+            Err(IOError::new(ErrorKind::InvalidInput,
+                             format!("UDP can't listen on Unix socket {:?}", path)))
+        }
+    }
+}
+
+/// A client's flow of datagrams through a UDP frontend. They go to the
+/// target picked for the first one, over a socket of the association's
+/// own, and the target's replies are sent back to the client from the
+/// frontend's address. UDP has no FIN, so the association ends once no
+/// datagram has gone either way for its idle timeout.
+pub struct UdpAssociation {
+    pub socket: UdpSocket,
+    pub listener: ListenerToken,
+    pub client_addr: SocketAddr,
+    target: BackendId,
+    pool: Rc<RefCell<BackendPool>>,
+    metrics: Arc<Metrics>,
+    backend_metrics: Arc<BackendMetrics>,
+    pool_metrics: Arc<PoolMetrics>,
+    idle_timeout: Duration,
+    last_activity: Instant,
+}
+
+impl UdpAssociation {
+    pub fn open(listener: ListenerToken,
+                client_addr: SocketAddr,
+                pool: Rc<RefCell<BackendPool>>,
+                metrics: Arc<Metrics>,
+                idle_timeout: Duration,
+                now: Instant)
+                -> IOResult<UdpAssociation> {
+        let target = try!(pool.borrow_mut()
+                              .decide_target(&client_addr)
+                              .ok_or(IOError::new(ErrorKind::NotConnected,
+                                                  "No healthy backend available")));
+
+        let socket = match connect(&target) {
+            Ok(socket) => socket,
+            Err(e) => {
+                pool.borrow_mut().release_target(&target);
+                return Err(e);
+            }
+        };
+        let backend_metrics = metrics.backend(&target);
+        let pool_metrics = metrics.pool(pool.borrow().name());
+        metrics.connection_opened();
+        backend_metrics.connection_opened();
+        pool_metrics.connection_opened();
+
+        Ok(UdpAssociation {
+               socket: socket,
+               listener: listener,
+               client_addr: client_addr,
+               target: target,
+               pool: pool,
+               metrics: metrics,
+               backend_metrics: backend_metrics,
+               pool_metrics: pool_metrics,
+               idle_timeout: idle_timeout,
+               last_activity: now,
+           })
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.last_activity + self.idle_timeout
+    }
+
+    /// Sends a client's datagram on to the target. One that can't be sent
+    /// right away is dropped, as the network could have done.
+    pub fn forward(&mut self, datagram: &[u8], now: Instant) {
+        match self.socket.send(datagram) {
+            Ok(_) => {
+                self.last_activity = now;
+                self.metrics.relayed_upstream(datagram.len());
+                self.backend_metrics.relayed_upstream(datagram.len());
+                self.pool_metrics.relayed_upstream(datagram.len());
+            }
+            Err(e) => {
+                debug!("Dropping datagram from {} to {}: {}",
+                       self.client_addr,
+                       self.target,
+                       e);
+                self.note_error(&e);
+            }
+        }
+    }
+
+    /// Sends every reply the target has sent back to the client, from
+    /// `frontend`.
+    pub fn relay_replies(&mut self, frontend: &UdpSocket, buffer: &mut [u8], now: Instant) {
+        loop {
+            let n_read = match self.socket.recv(buffer) {
+                Ok(n_read) => n_read,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    debug!("Reading from {} for {} failed: {}", self.target, self.client_addr, e);
+                    self.note_error(&e);
+                    return;
+                }
+            };
+
+            self.last_activity = now;
+            match frontend.send_to(&buffer[..n_read], &self.client_addr) {
+                Ok(_) => {
+                    self.metrics.relayed_downstream(n_read);
+                    self.backend_metrics.relayed_downstream(n_read);
+                    self.pool_metrics.relayed_downstream(n_read);
+                }
+                Err(e) => debug!("Dropping datagram to {}: {}", self.client_addr, e),
+            }
+        }
+    }
+
+    /// A refused datagram comes back as an error on the next send or
+    /// receive, and counts against the target like a refused connect.
+    fn note_error(&self, e: &IOError) {
+        if e.kind() == ErrorKind::ConnectionRefused {
+            self.pool.borrow_mut().report_failure(&self.target);
+        }
+    }
+
+    pub fn close(&self) {
+        debug!("Closing association of {} with {}", self.client_addr, self.target);
+        self.pool.borrow_mut().release_target(&self.target);
+        self.metrics.connection_closed();
+        self.backend_metrics.connection_closed();
+        self.pool_metrics.connection_closed();
+    }
+}
+
+/// A socket that only exchanges datagrams with `target`, bound to an
+/// ephemeral port of the same IP version.
+fn connect(target: &BackendId) -> IOResult<UdpSocket> {
+    let target = match *target {
+        Address::Tcp(addr) => addr,
+        Address::Unix(ref path) => {
+            return Err(IOError::new(ErrorKind::InvalidInput,
+                                    format!("UDP can't reach Unix socket {:?}", path)));
+        }
+    };
+    let local = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+
+    let socket = try!(UdpSocket::bind(&local.parse().unwrap()));
+    try!(socket.connect(target));
+    Ok(socket)
+}
@@ -0,0 +1,235 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::time::{Duration, Instant};
+
+use backend::BackendId;
+use config::BackendConfig;
+use stream::Stream;
+
+/// How often idle connections are checked and topped back up.
+pub const SWEEP_INTERVAL_MS: u64 = 1000;
+
+struct Idle {
+    streams: VecDeque<Stream>,
+    /// How many connections to keep idle, between `min_idle` and
+    /// `max_idle`.
+    wanted: usize,
+    /// Whether a client found none idle since the last sweep.
+    missed: bool,
+}
+
+/// Connections to a backend's targets made ahead of time, so a client can
+/// be handed one that is already up instead of waiting for a connect. Each
+/// is used for a single client and then closed with it, like any other.
+///
+/// Every target starts out with `min_idle` connections kept ready. A
+/// client that finds none has one more kept for the target from then on,
+/// up to `max_idle`, and every sweep without such a miss lets the number
+/// fall back by one.
+pub struct WarmConnections {
+    min_idle: usize,
+    max_idle: usize,
+    targets: HashMap<BackendId, Idle>,
+    next_sweep: Instant,
+}
+
+impl WarmConnections {
+    /// Reads a backend's `connection_pool` section, if it has one.
+    pub fn from_config(config: &BackendConfig,
+                       now: Instant)
+                       -> IOResult<Option<WarmConnections>> {
+        let config = match config.connection_pool {
+            Some(ref config) => config,
+            None => return Ok(None),
+        };
+        if config.max_idle == 0 || config.min_idle > config.max_idle {
+            return Err(IOError::new(ErrorKind::InvalidInput,
+                                    "Connection pool max_idle must be positive and >= min_idle"));
+        }
+
+        Ok(Some(WarmConnections {
+                    min_idle: config.min_idle,
+                    max_idle: config.max_idle,
+                    targets: HashMap::new(),
+                    next_sweep: now,
+                }))
+    }
+
+    pub fn next_sweep(&self) -> Instant {
+        self.next_sweep
+    }
+
+    pub fn idle_count(&self, target: &BackendId) -> usize {
+        self.targets.get(target).map_or(0, |idle| idle.streams.len())
+    }
+
+    /// An idle connection to `target` that still looks usable, dropping
+    /// ones found closed on the way. None means the client has to wait for
+    /// a connect of its own.
+    pub fn take(&mut self, target: &BackendId) -> Option<Stream> {
+        let max_idle = self.max_idle;
+        let idle = self.idle(target);
+
+        while let Some(stream) = idle.streams.pop_front() {
+            if stream.is_usable() {
+                return Some(stream);
+            }
+            debug!("Dropping idle connection to {}, which has gone away", target);
+        }
+
+        idle.missed = true;
+        idle.wanted = (idle.wanted + 1).min(max_idle);
+        None
+    }
+
+    pub fn put(&mut self, target: &BackendId, stream: Stream) {
+        self.idle(target).streams.push_back(stream);
+    }
+
+    /// How many more connections to `target` should be made to have as
+    /// many idle as wanted.
+    pub fn shortfall(&mut self, target: &BackendId) -> usize {
+        let idle = self.idle(target);
+
+        idle.wanted.saturating_sub(idle.streams.len())
+    }
+
+    /// Drops idle connections that have gone bad, and those to targets
+    /// other than `available`, and lets targets without misses keep fewer.
+    /// Returns how many connections each available target is short of.
+    pub fn sweep(&mut self, available: &[BackendId], now: Instant) -> Vec<(BackendId, usize)> {
+        let min_idle = self.min_idle;
+
+        self.next_sweep = now + Duration::from_millis(SWEEP_INTERVAL_MS);
+        self.targets.retain(|target, _| available.contains(target));
+
+        for (target, idle) in self.targets.iter_mut() {
+            let before = idle.streams.len();
+            idle.streams.retain(Stream::is_usable);
+            if idle.streams.len() < before {
+                debug!("Dropped {} idle connections to {}, which have gone away",
+                       before - idle.streams.len(),
+                       target);
+            }
+
+            if !idle.missed {
+                idle.wanted = idle.wanted.saturating_sub(1).max(min_idle);
+            }
+            idle.missed = false;
+        }
+
+        available
+            .iter()
+            .map(|target| (target.clone(), self.shortfall(target)))
+            .filter(|&(_, shortfall)| shortfall > 0)
+            .collect()
+    }
+
+    fn idle(&mut self, target: &BackendId) -> &mut Idle {
+        let min_idle = self.min_idle;
+
+        self.targets.entry(target.clone()).or_insert_with(|| {
+            Idle {
+                streams: VecDeque::new(),
+                wanted: min_idle,
+                missed: false,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WarmConnections;
+
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use config::{BackendConfig, ConnectionPoolConfig};
+    use stream::{Address, Stream};
+
+    fn warm(min_idle: usize, max_idle: usize) -> WarmConnections {
+        let config = BackendConfig {
+            connection_pool: Some(ConnectionPoolConfig {
+                                      min_idle: min_idle,
+                                      max_idle: max_idle,
+                                  }),
+            ..Default::default()
+        };
+
+        WarmConnections::from_config(&config, Instant::now()).unwrap().unwrap()
+    }
+
+    /// A connection to `listener`, and the listener's end of it.
+    fn connected(listener: &TcpListener) -> (Stream, TcpStream) {
+        let addr = Address::Tcp(listener.local_addr().unwrap());
+        let stream = Stream::connect(&addr).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+
+        // The connect finishes in the background.
+        for _ in 0..100 {
+            if stream.is_connected() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        (stream, peer)
+    }
+
+    #[test]
+    fn misses_keep_more_connections_up_to_max_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = Address::Tcp(listener.local_addr().unwrap());
+        let mut warm = warm(1, 2);
+        let now = Instant::now();
+
+        assert_eq!(warm.sweep(&[target.clone()], now), vec![(target.clone(), 1)]);
+        assert!(warm.take(&target).is_none());
+        assert!(warm.take(&target).is_none());
+        assert_eq!(warm.shortfall(&target), 2);
+
+        let (first, _first_peer) = connected(&listener);
+        let (second, _second_peer) = connected(&listener);
+        warm.put(&target, first);
+        warm.put(&target, second);
+        assert!(warm.take(&target).is_some());
+        assert_eq!(warm.shortfall(&target), 1);
+
+        // Only a sweep without misses since the last one lets the target
+        // fall back to min_idle.
+        assert_eq!(warm.sweep(&[target.clone()], now), vec![(target.clone(), 1)]);
+        assert_eq!(warm.sweep(&[target.clone()], now), vec![]);
+        assert_eq!(warm.idle_count(&target), 1);
+
+        assert_eq!(warm.sweep(&[], now), vec![]);
+        assert_eq!(warm.idle_count(&target), 0);
+    }
+
+    #[test]
+    fn connections_the_target_closed_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = Address::Tcp(listener.local_addr().unwrap());
+        let mut warm = warm(2, 2);
+
+        let addr = Address::Tcp(listener.local_addr().unwrap());
+        let closed = Stream::connect(&addr).unwrap();
+        drop(listener.accept().unwrap());
+        thread::sleep(Duration::from_millis(20));
+
+        let (open, _open_peer) = connected(&listener);
+        warm.put(&target, closed);
+        warm.put(&target, open);
+        assert!(warm.take(&target).is_some());
+        assert_eq!(warm.idle_count(&target), 0);
+
+        let invalid = BackendConfig {
+            connection_pool: Some(ConnectionPoolConfig {
+                                      min_idle: 3,
+                                      max_idle: 2,
+                                  }),
+            ..Default::default()
+        };
+        assert!(WarmConnections::from_config(&invalid, Instant::now()).is_err());
+    }
+}